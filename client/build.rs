@@ -1,6 +1,8 @@
 fn main () -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .build_client(true)
+        //the client only serves the Connector in tests, as a fake tracker answering a few calls
+        .generate_default_stubs(true)
         .type_attribute("connection.FileHash", "#[derive(Hash, Eq)]")
         .type_attribute("connection.PieceHash", "#[derive(Hash, Eq)]")
         .type_attribute("connection.ClientId", "#[derive(Hash, Eq)]")
@@ -0,0 +1,217 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use crate::connection::connection::{ClientId, PeerIdentity, Scope, Visibility};
+
+/// location of the rules this client applies to peers downloading from it
+const ACCESS_RULES_PATH: &str = "resources/identity/access_rules";
+
+/// A peer that proved it holds a key found in the tracker's registry, either its certificate
+/// in a QUIC handshake or its Noise key in a relayed one. Peers that found us on the DHT may
+/// not be registered with our trackers, they only proved they hold their certificate.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedPeer {
+    /// the tracker id the peer is currently registered under, None if no tracker of ours knows it
+    pub client_id: Option<ClientId>,
+    /// fingerprint of the certificate the peer registered, which access rules refer to
+    pub fingerprint: [u8; 32],
+}
+
+impl AuthenticatedPeer {
+
+    ///from_identity()
+    /// parameters:
+    ///     - identity: the registry entry the tracker returned from verify_peer
+    ///
+    /// function:
+    /// Builds the peer access rules are checked against from the tracker's answer.
+    pub fn from_identity(identity: PeerIdentity) -> Result<AuthenticatedPeer, &'static str> {
+        Ok(AuthenticatedPeer {
+            client_id: Some(identity.client_id.ok_or("tracker returned no client id")?),
+            fingerprint: identity.cert_fingerprint.try_into().map_err(|_| "tracker returned an invalid fingerprint")?,
+        })
+    }
+
+    ///unregistered()
+    /// parameters:
+    ///     - fingerprint: the fingerprint of the certificate the peer presented
+    ///
+    /// function:
+    /// Builds a peer none of our trackers could vouch for.
+    pub fn unregistered(fingerprint: [u8; 32]) -> AuthenticatedPeer {
+        AuthenticatedPeer { client_id: None, fingerprint }
+    }
+
+    ///describe()
+    ///
+    /// function:
+    /// Returns how the peer is registered, for logs.
+    pub fn describe(&self) -> String {
+        match &self.client_id {
+            Some(client_id) => format!("client {}", client_id.uid),
+            None => String::from("an unregistered peer"),
+        }
+    }
+}
+
+/// AccessPolicy holds the per-peer rules a seeder applies before serving a piece.
+/// Peers are identified by certificate fingerprint since that is stable across runs.
+///
+/// The rules file has one rule per line:
+///     block <fingerprint hex>               never serve this peer
+///     allow <info hash hex> <fingerprint>   once a file has an allow rule, only listed peers get it
+///     group <info hash hex> <group name>    the file is only advertised to members of the group
+/// Files with allow rules are advertised invite-only to the listed peers.
+#[derive(Debug, Default)]
+pub struct AccessPolicy {
+    blocked: HashSet<[u8; 32]>,
+    allowed: HashMap<[u8; 20], HashSet<[u8; 32]>>,
+    groups: HashMap<[u8; 20], String>,
+}
+
+impl AccessPolicy {
+
+    ///load()
+    ///
+    /// function:
+    /// Loads the rules file, returning an allow-everyone policy if none exists yet.
+    /// Lines that cannot be parsed are reported and skipped.
+    pub fn load() -> std::io::Result<AccessPolicy> {
+        let mut policy = AccessPolicy::default();
+        let path = Path::new(ACCESS_RULES_PATH);
+        if !path.exists() {
+            return Ok(policy);
+        }
+
+        for line in read_to_string(path)?.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields.as_slice() {
+                ["block", peer] => decode(peer).map(|peer| {
+                    policy.blocked.insert(peer);
+                }),
+                ["allow", file, peer] => decode(file).zip(decode(peer)).map(|(file, peer)| {
+                    policy.allowed.entry(file).or_default().insert(peer);
+                }),
+                ["group", file, group] => decode(file).map(|file| {
+                    policy.groups.insert(file, group.to_string());
+                }),
+                [] => Some(()),
+                _ => None,
+            };
+
+            if parsed.is_none() {
+                eprintln!("ignoring invalid access rule: {}", line);
+            }
+        }
+
+        Ok(policy)
+    }
+
+    ///allows()
+    /// parameters:
+    ///     - peer: the authenticated peer requesting data
+    ///     - file_hash: the info hash of the file being requested
+    ///
+    /// function:
+    /// Returns whether the peer may download pieces of the file from us. Group membership is
+    /// kept by the tracker, so peers no tracker of ours knows never get group files.
+    pub fn allows(&self, peer: &AuthenticatedPeer, file_hash: &[u8; 20]) -> bool {
+        if self.blocked.contains(&peer.fingerprint) {
+            return false;
+        }
+        if peer.client_id.is_none() && self.groups.contains_key(file_hash) {
+            return false;
+        }
+
+        match self.allowed.get(file_hash) {
+            Some(peers) => peers.contains(&peer.fingerprint),
+            None => true,
+        }
+    }
+
+    ///block()
+    /// parameters:
+    ///     - fingerprint: certificate fingerprint of the peer to block
+    ///
+    /// function:
+    /// Blocks a peer from downloading anything and persists the rule.
+    pub fn block(&mut self, fingerprint: [u8; 32]) -> std::io::Result<()> {
+        if self.blocked.insert(fingerprint) {
+            append_rule(format!("block {}", hex::encode(fingerprint)))?;
+        }
+        Ok(())
+    }
+
+    ///allow()
+    /// parameters:
+    ///     - file_hash: info hash of the file to restrict
+    ///     - fingerprint: certificate fingerprint of the peer allowed to download it
+    ///
+    /// function:
+    /// Restricts a file to an explicit list of peers, adding this one, and persists the rule.
+    pub fn allow(&mut self, file_hash: [u8; 20], fingerprint: [u8; 32]) -> std::io::Result<()> {
+        if self.allowed.entry(file_hash).or_default().insert(fingerprint) {
+            append_rule(format!("allow {} {}", hex::encode(file_hash), hex::encode(fingerprint)))?;
+        }
+        Ok(())
+    }
+
+    ///share_with_group()
+    /// parameters:
+    ///     - file_hash: info hash of the file to restrict
+    ///     - group: the tracker group whose members may see it
+    ///
+    /// function:
+    /// Advertises a file to the members of a group only, replacing any previous group, and persists the rule.
+    pub fn share_with_group(&mut self, file_hash: [u8; 20], group: String) -> std::io::Result<()> {
+        if self.groups.get(&file_hash) != Some(&group) {
+            append_rule(format!("group {} {}", hex::encode(file_hash), group))?;
+            self.groups.insert(file_hash, group);
+        }
+        Ok(())
+    }
+
+    ///is_public()
+    /// parameters:
+    ///     - file_hash: the info hash of one of our files
+    ///
+    /// function:
+    /// Returns whether the file is shared with everyone, only those are announced on the DHT.
+    pub fn is_public(&self, file_hash: &[u8; 20]) -> bool {
+        !self.allowed.contains_key(file_hash) && !self.groups.contains_key(file_hash)
+    }
+
+    ///scope()
+    /// parameters:
+    ///     - file_hash: the info hash of one of our files
+    ///
+    /// function:
+    /// Returns who the tracker should show the file to. Explicitly allowed peers take precedence
+    /// over a group, files without either rule are public.
+    pub fn scope(&self, file_hash: &[u8; 20]) -> Scope {
+        if let Some(peers) = self.allowed.get(file_hash) {
+            return Scope {
+                visibility: Visibility::InviteOnly as i32,
+                group: String::new(),
+                invited: peers.iter().map(|peer| peer.to_vec()).collect(),
+            };
+        }
+        match self.groups.get(file_hash) {
+            Some(group) => Scope { visibility: Visibility::Group as i32, group: group.clone(), invited: Vec::new() },
+            None => Scope::default(),
+        }
+    }
+}
+
+// Decodes a hex string into a fixed size array
+fn decode<const N: usize>(hex_str: &str) -> Option<[u8; N]> {
+    hex::decode(hex_str).ok()?.try_into().ok()
+}
+
+// Appends a single rule to the rules file, creating it if needed
+fn append_rule(rule: String) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(ACCESS_RULES_PATH)?;
+    writeln!(file, "{}", rule)?;
+    file.flush()
+}
@@ -0,0 +1,52 @@
+use std::sync::{Arc, RwLock};
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::Channel;
+use tonic::{Request, Status};
+use crate::connection::connection::{connector_client::ConnectorClient, turn_client::TurnClient};
+
+/// the tracker, with our session token on every call
+pub type Tracker = ConnectorClient<InterceptedService<Channel, SessionAuth>>;
+
+/// the server's TURN service, with our session token on every call
+pub type Relay = TurnClient<InterceptedService<Channel, SessionAuth>>;
+
+/// SessionAuth attaches the session token the tracker handed out at registration to every
+/// Connector and Turn call, which is how the server knows which client is calling. Clones share
+/// the token, so renewing it renews it for every client of the tracker.
+#[derive(Debug, Clone)]
+pub struct SessionAuth {
+    header: Arc<RwLock<MetadataValue<Ascii>>>,
+}
+
+impl SessionAuth {
+
+    ///new()
+    /// parameters:
+    ///     - session_token: the token returned by register_client
+    ///
+    /// function:
+    /// Creates the interceptor, failing if the token cannot be sent as a header.
+    pub fn new(session_token: &str) -> Result<SessionAuth, Box<dyn std::error::Error>> {
+        Ok(SessionAuth { header: Arc::new(RwLock::new(format!("Bearer {}", session_token).parse()?)) })
+    }
+
+    ///renew()
+    /// parameters:
+    ///     - session_token: the token the tracker replaced ours with
+    ///
+    /// function:
+    /// Attaches the new token to every call from now on.
+    pub fn renew(&self, session_token: &str) -> Result<(), Box<dyn std::error::Error>> {
+        *self.header.write().unwrap() = format!("Bearer {}", session_token).parse()?;
+        Ok(())
+    }
+}
+
+impl Interceptor for SessionAuth {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        req.metadata_mut().insert("authorization", self.header.read().unwrap().clone());
+        Ok(req)
+    }
+}
@@ -0,0 +1,90 @@
+use std::env;
+use std::str::FromStr;
+
+/// default number of leechers a client uploads to at the same time
+const DEFAULT_MAX_UPLOADS: usize = 4;
+
+/// default number of files a client downloads at the same time
+const DEFAULT_MAX_DOWNLOADS: usize = 3;
+
+/// the tracker used when none are configured
+const DEFAULT_TRACKER: &str = "https://helpful-serf-server-1016068426296.us-south1.run.app:";
+
+/// ClientConfig holds the tunable settings of a client.
+/// Every setting has a sensible default and can be overridden with an environment variable.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// maximum number of leechers served concurrently (BEARTORRENT_MAX_UPLOADS)
+    pub max_uploads: usize,
+    /// maximum number of files downloaded concurrently, the others wait in the queue (BEARTORRENT_MAX_DOWNLOADS)
+    pub max_downloads: usize,
+    /// key the tracker's operator handed out, needed to register with trackers that require one (BEARTORRENT_API_KEY)
+    pub api_key: String,
+    /// trackers to register with in order of preference, comma separated (BEARTORRENT_TRACKERS)
+    pub trackers: Vec<String>,
+    /// DHT nodes to join through as host:port, comma separated, on top of the clients our trackers hand out (BEARTORRENT_DHT_NODES)
+    pub dht_nodes: Vec<String>,
+    /// serve peers no tracker knows when they found us on the DHT or the LAN (BEARTORRENT_ALLOW_UNREGISTERED_PEERS)
+    pub allow_unregistered_peers: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            max_uploads: DEFAULT_MAX_UPLOADS,
+            max_downloads: DEFAULT_MAX_DOWNLOADS,
+            api_key: String::new(),
+            trackers: vec![DEFAULT_TRACKER.to_string()],
+            dht_nodes: Vec::new(),
+            allow_unregistered_peers: false,
+        }
+    }
+}
+
+impl ClientConfig {
+
+    ///from_env()
+    ///
+    /// function:
+    /// Builds the config from the defaults, replacing any setting whose environment variable is set.
+    pub fn from_env() -> Self {
+        let defaults = ClientConfig::default();
+
+        ClientConfig {
+            max_uploads: env_or("BEARTORRENT_MAX_UPLOADS", defaults.max_uploads).max(1),
+            max_downloads: env_or("BEARTORRENT_MAX_DOWNLOADS", defaults.max_downloads).max(1),
+            api_key: env_or("BEARTORRENT_API_KEY", defaults.api_key),
+            trackers: env_list("BEARTORRENT_TRACKERS").unwrap_or(defaults.trackers),
+            dht_nodes: env_list("BEARTORRENT_DHT_NODES").unwrap_or(defaults.dht_nodes),
+            allow_unregistered_peers: env_or("BEARTORRENT_ALLOW_UNREGISTERED_PEERS", defaults.allow_unregistered_peers),
+        }
+    }
+}
+
+/// env_or (
+///     key: the environment variable to read
+///     default: value used when the variable is missing or cannot be parsed
+/// )
+/// helper function to read a single setting from the environment
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            eprintln!("ignoring invalid value {:?} for {}", value, key);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// env_list (
+///     key: the environment variable to read
+/// )
+/// helper function to read a comma separated setting, None when the variable is missing or lists nothing
+fn env_list(key: &str) -> Option<Vec<String>> {
+    let values: Vec<String> = env::var(key).ok()?
+        .split(',')
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect();
+    (!values.is_empty()).then_some(values)
+}
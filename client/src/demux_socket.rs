@@ -0,0 +1,144 @@
+use std::future::poll_fn;
+use std::io;
+use std::io::{ErrorKind, IoSliceMut};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use quinn::udp::{RecvMeta, Transmit};
+use quinn::{AsyncUdpSocket, Runtime, TokioRuntime, UdpPoller};
+use tokio::sync::mpsc;
+
+/// first byte of every DHT datagram. The first byte of a QUIC packet always has its fixed bit
+/// (0x40) set as long as neither side greases it, so the two cannot be mistaken for each other
+pub const DHT_PREFIX: u8 = 0x00;
+
+/// a DHT datagram without its prefix, and the address it came from
+pub type Datagram = (SocketAddr, Vec<u8>);
+
+/// DemuxSocket shares the client's UDP socket between its QUIC endpoint and its DHT node.
+/// quinn drives the receiving side, datagrams starting with DHT_PREFIX are taken out of what it
+/// receives and handed to the DHT, everything else is left to QUIC.
+#[derive(Debug)]
+pub struct DemuxSocket {
+    inner: Arc<dyn AsyncUdpSocket>,
+    dht: mpsc::UnboundedSender<Datagram>,
+}
+
+impl DemuxSocket {
+
+    ///new()
+    /// parameters:
+    ///     - socket: the bound, non-blocking socket to share
+    ///
+    /// function:
+    /// Wraps the socket, returning it along with the receiver of every DHT datagram it gets.
+    pub fn new(socket: std::net::UdpSocket) -> io::Result<(Arc<DemuxSocket>, mpsc::UnboundedReceiver<Datagram>)> {
+        let (dht, dht_rx) = mpsc::unbounded_channel();
+        let inner = TokioRuntime.wrap_udp_socket(socket)?;
+
+        Ok((Arc::new(DemuxSocket { inner, dht }), dht_rx))
+    }
+
+    ///send_dht()
+    /// parameters:
+    ///     - destination: the node to send to
+    ///     - payload: an encoded DHT message
+    ///
+    /// function:
+    /// Sends a DHT datagram from the shared socket, waiting for it to be writable if it is not.
+    pub async fn send_dht(&self, destination: SocketAddr, payload: &[u8]) -> io::Result<()> {
+        let mut datagram = Vec::with_capacity(payload.len() + 1);
+        datagram.push(DHT_PREFIX);
+        datagram.extend_from_slice(payload);
+        let transmit = Transmit { destination, ecn: None, contents: &datagram, segment_size: None, src_ip: None };
+
+        let mut poller = self.inner.clone().create_io_poller();
+        loop {
+            match self.inner.try_send(&transmit) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    poll_fn(|cx| poller.as_mut().poll_writable(cx)).await?;
+                }
+                sent => return sent,
+            }
+        }
+    }
+
+    ///take_dht()
+    /// parameters:
+    ///     - buf: a buffer quinn received into
+    ///     - meta: what was received, possibly several datagrams of `stride` bytes coalesced by GRO
+    ///
+    /// function:
+    /// Hands the DHT datagrams in the buffer over to the DHT and moves the QUIC ones after each
+    /// other, shrinking meta.len to what is left for quinn. Only the last datagram of a buffer can
+    /// be shorter than the stride and it stays last, so the buffer remains a valid GRO batch.
+    fn take_dht(&self, buf: &mut [u8], meta: &mut RecvMeta) {
+        let stride = if meta.stride == 0 { meta.len } else { meta.stride };
+        let mut kept = 0;
+        let mut start = 0;
+        while start < meta.len {
+            let end = (start + stride).min(meta.len);
+            if buf[start] == DHT_PREFIX {
+                //the DHT task only goes away with the client
+                let _ = self.dht.send((meta.addr, buf[start + 1..end].to_vec()));
+            } else {
+                buf.copy_within(start..end, kept);
+                kept += end - start;
+            }
+            start = end;
+        }
+        meta.len = kept;
+    }
+}
+
+impl AsyncUdpSocket for DemuxSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        self.inner.clone().create_io_poller()
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        self.inner.try_send(transmit)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let received = ready!(self.inner.poll_recv(cx, bufs, meta))?;
+
+            //buffers left with QUIC datagrams are moved to the front for quinn
+            let mut kept = 0;
+            for i in 0..received {
+                self.take_dht(&mut bufs[i], &mut meta[i]);
+                if meta[i].len > 0 {
+                    bufs.swap(i, kept);
+                    meta.swap(i, kept);
+                    kept += 1;
+                }
+            }
+            if kept > 0 {
+                return Poll::Ready(Ok(kept));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        self.inner.max_transmit_segments()
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.inner.max_receive_segments()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
@@ -0,0 +1,727 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use prost::Message;
+use sha1::{Digest, Sha1};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+use tokio::time::{interval, timeout};
+use crate::connection::connection::{dht_message, DhtMessage, DhtNode, DhtPeer, DhtQuery, DhtRequest, DhtResponse};
+use crate::demux_socket::{Datagram, DemuxSocket};
+
+/// node ids share the 160 bit space of info hashes, a file is stored by the nodes closest to its hash
+pub type NodeId = [u8; 20];
+
+/// size of a bucket and of the set of closest nodes a lookup converges on (Kademlia's k)
+const K: usize = 8;
+
+/// lookups query this many nodes at a time (Kademlia's alpha)
+const ALPHA: usize = 3;
+
+/// how long we wait for a node to answer before dropping it from the routing table
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// a node not heard from for this long may be replaced when a new one wants into its full bucket
+const NODE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// how often we look up our own id, which keeps the buckets around us filled
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// peers are forgotten unless they announce again within this time
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// most peers a node keeps and hands out for one file
+const MAX_PEERS: usize = 64;
+
+/// how often the secret announce tokens are derived from changes, tokens of the previous
+/// secret are still accepted so a lookup that just finished can announce
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// a node we know the address and id of
+#[derive(Debug, Clone, Copy)]
+struct Contact {
+    id: NodeId,
+    addr: SocketAddr,
+    last_seen: Instant,
+}
+
+/// RoutingTable keeps up to K nodes for every distance from our id, counted in leading bits
+/// shared with it. Nodes that keep answering stay, new ones only replace nodes that went quiet.
+#[derive(Debug)]
+struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Contact>>,
+}
+
+impl RoutingTable {
+
+    ///new()
+    /// parameters:
+    ///     - own: our node id
+    fn new(own: NodeId) -> RoutingTable {
+        RoutingTable { own, buckets: vec![Vec::new(); 160] }
+    }
+
+    ///insert()
+    /// parameters:
+    ///     - id: a node that just talked to us
+    ///     - addr: where it talked to us from
+    ///
+    /// function:
+    /// Records the node as seen, adding it if its bucket has room or a node in it went quiet.
+    fn insert(&mut self, id: NodeId, addr: SocketAddr) {
+        let Some(index) = bucket_index(&self.own, &id) else { return };
+        let bucket = &mut self.buckets[index];
+        let contact = Contact { id, addr, last_seen: Instant::now() };
+
+        if let Some(known) = bucket.iter_mut().find(|known| known.id == id) {
+            *known = contact;
+        } else if bucket.len() < K {
+            bucket.push(contact);
+        } else if let Some(stale) = bucket.iter_mut().find(|known| known.last_seen.elapsed() > NODE_TIMEOUT) {
+            *stale = contact;
+        }
+    }
+
+    ///remove()
+    /// parameters:
+    ///     - addr: a node that did not answer
+    fn remove(&mut self, addr: SocketAddr) {
+        self.buckets.iter_mut().for_each(|bucket| bucket.retain(|known| known.addr != addr));
+    }
+
+    ///closest()
+    /// parameters:
+    ///     - target: the id to measure distance to
+    ///     - count: how many nodes to return
+    ///
+    /// function:
+    /// Returns the nodes closest to the target, closest first.
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self.buckets.iter().flatten().copied().collect();
+        contacts.sort_by_key(|contact| distance(&contact.id, target));
+        contacts.truncate(count);
+        contacts
+    }
+
+    ///contains()
+    /// parameters:
+    ///     - id: the node's id
+    ///     - addr: the address it has to be known at
+    ///
+    /// function:
+    /// Returns whether the node is in the table at that address.
+    fn contains(&self, id: &NodeId, addr: SocketAddr) -> bool {
+        self.buckets.iter().flatten().any(|known| known.id == *id && known.addr == addr)
+    }
+
+    ///len()
+    ///
+    /// function:
+    /// Returns the number of nodes we know.
+    fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+/// a request waiting for its answer, with the node it was sent to
+type Pending = (SocketAddr, oneshot::Sender<DhtMessage>);
+
+/// the outcome of an iterative lookup
+#[derive(Debug, Default)]
+struct Lookup {
+    /// the closest nodes that answered, with the token each handed out
+    closest: Vec<(Contact, Vec<u8>)>,
+    /// peers any of the queried nodes knew for the target
+    peers: Vec<DhtPeer>,
+}
+
+/// Dht is this client's node of a Kademlia DHT that maps info hashes to the clients seeding
+/// them, so peers can be found without a tracker. It runs on the client's UDP socket next to
+/// the QUIC endpoint, nodes are reached at the same address as the client.
+#[derive(Debug)]
+pub struct Dht {
+    id: NodeId,
+    socket: Arc<DemuxSocket>,
+    table: Mutex<RoutingTable>,
+    /// peers announced to us, by info hash
+    peers: Mutex<HashMap<NodeId, Vec<(DhtPeer, Instant)>>>,
+    /// the current and previous secret announce tokens are derived from
+    secrets: Mutex<([u8; 20], [u8; 20])>,
+    /// requests waiting for their answer, by transaction, with the node they were sent to
+    pending: Mutex<HashMap<Vec<u8>, Pending>>,
+}
+
+impl Dht {
+
+    ///start()
+    /// parameters:
+    ///     - id: our node id, see node_id()
+    ///     - socket: the socket to send from
+    ///     - inbound: the DHT datagrams the socket receives
+    ///
+    /// function:
+    /// Creates the node and spawns the tasks answering other nodes and maintaining the routing
+    /// table and stored peers. The node knows nobody until it is bootstrapped.
+    pub fn start(id: NodeId, socket: Arc<DemuxSocket>, inbound: mpsc::UnboundedReceiver<Datagram>) -> Arc<Dht> {
+        let dht = Arc::new(Dht {
+            id,
+            socket,
+            table: Mutex::new(RoutingTable::new(id)),
+            peers: Mutex::new(HashMap::new()),
+            secrets: Mutex::new((rand::random(), rand::random())),
+            pending: Mutex::new(HashMap::new()),
+        });
+
+        tokio::spawn(Dht::receive_loop(Arc::downgrade(&dht), inbound));
+        tokio::spawn(Dht::maintain(Arc::downgrade(&dht)));
+        dht
+    }
+
+    ///bootstrap()
+    /// parameters:
+    ///     - nodes: addresses of nodes already in the DHT, their ids do not have to be known
+    ///
+    /// function:
+    /// Joins the DHT by asking the given nodes for the nodes closest to our id, then looking our
+    /// id up through them. Returns the number of nodes we know afterwards.
+    pub async fn bootstrap(self: Arc<Self>, nodes: Vec<SocketAddr>) -> usize {
+        let mut queries = JoinSet::new();
+        for addr in nodes {
+            let dht = self.clone();
+            queries.spawn(async move { dht.query(addr, DhtQuery::FindNode, dht.id.to_vec()).await });
+        }
+        while let Some(answer) = queries.join_next().await {
+            if let Ok(Ok((_, response))) = answer {
+                self.learn(&response.nodes);
+            }
+        }
+
+        self.lookup(self.id, DhtQuery::FindNode).await;
+        self.table.lock().unwrap().len()
+    }
+
+    ///get_peers()
+    /// parameters:
+    ///     - info_hash: the file to find seeders of
+    ///
+    /// function:
+    /// Looks up the peers the nodes closest to the file's hash were told about.
+    pub async fn get_peers(self: &Arc<Self>, info_hash: NodeId) -> Vec<DhtPeer> {
+        self.lookup(info_hash, DhtQuery::GetPeers).await.peers
+    }
+
+    ///announce()
+    /// parameters:
+    ///     - info_hash: a file we seed
+    ///     - peer: how to reach and authenticate us
+    ///
+    /// function:
+    /// Stores us as a peer of the file on the nodes closest to its hash. Returns how many of them
+    /// accepted the announce.
+    pub async fn announce(self: &Arc<Self>, info_hash: NodeId, peer: DhtPeer) -> usize {
+        let lookup = self.lookup(info_hash, DhtQuery::GetPeers).await;
+
+        let mut announces = JoinSet::new();
+        for (contact, token) in lookup.closest {
+            let dht = self.clone();
+            let request = DhtRequest {
+                query: DhtQuery::AnnouncePeer.into(),
+                target: info_hash.to_vec(),
+                token,
+                peer: Some(peer.clone()),
+            };
+            announces.spawn(async move { dht.request(contact.addr, request).await });
+        }
+
+        let mut accepted = 0;
+        while let Some(answer) = announces.join_next().await {
+            if let Ok(Ok(_)) = answer {
+                accepted += 1;
+            }
+        }
+        accepted
+    }
+
+    ///knows()
+    /// parameters:
+    ///     - id: the node's id
+    ///     - addr: the address it has to be known at
+    ///
+    /// function:
+    /// Returns whether the node is in our routing table at that address.
+    pub fn knows(&self, id: &NodeId, addr: SocketAddr) -> bool {
+        self.table.lock().unwrap().contains(id, addr)
+    }
+
+    ///ping()
+    /// parameters:
+    ///     - addr: the node to ping
+    ///
+    /// function:
+    /// Checks the node answers. Its answer also opens its NAT towards us, since the answer is
+    /// sent from the same socket as its QUIC endpoint.
+    pub async fn ping(self: &Arc<Self>, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.query(addr, DhtQuery::Ping, Vec::new()).await?;
+        Ok(())
+    }
+
+    ///lookup()
+    /// parameters:
+    ///     - target: the id or info hash to look up
+    ///     - query: FIND_NODE to find nodes, GET_PEERS to collect peers and announce tokens too
+    ///
+    /// function:
+    /// Iteratively queries the closest nodes we know of, ALPHA at a time, moving on to the
+    /// closer nodes they return until the K closest nodes seen have all been queried.
+    async fn lookup(self: &Arc<Self>, target: NodeId, query: DhtQuery) -> Lookup {
+        let mut candidates = self.table.lock().unwrap().closest(&target, K);
+        let mut queried: HashSet<SocketAddr> = HashSet::new();
+        let mut lookup = Lookup::default();
+        let mut seen_peers: HashSet<Vec<u8>> = HashSet::new();
+
+        loop {
+            candidates.sort_by_key(|contact| distance(&contact.id, &target));
+            let round: Vec<Contact> = candidates.iter()
+                .take(K)
+                .filter(|contact| !queried.contains(&contact.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if round.is_empty() {
+                break;
+            }
+
+            let mut queries = JoinSet::new();
+            for contact in round {
+                queried.insert(contact.addr);
+                let dht = self.clone();
+                queries.spawn(async move { (contact, dht.query(contact.addr, query, target.to_vec()).await) });
+            }
+
+            while let Some(answer) = queries.join_next().await {
+                let Ok((contact, answer)) = answer else { continue };
+                let Ok((id, response)) = answer else {
+                    candidates.retain(|candidate| candidate.addr != contact.addr);
+                    continue;
+                };
+                lookup.closest.push((Contact { id, ..contact }, response.token));
+
+                for peer in response.peers {
+                    if seen_peers.insert(peer.cert_fingerprint.clone()) {
+                        lookup.peers.push(peer);
+                    }
+                }
+                for node in response.nodes {
+                    let Some(found) = contact_of(&node) else { continue };
+                    if found.id != self.id && !candidates.iter().any(|candidate| candidate.addr == found.addr) {
+                        candidates.push(found);
+                    }
+                }
+            }
+        }
+
+        lookup.closest.sort_by_key(|(contact, _)| distance(&contact.id, &target));
+        lookup.closest.truncate(K);
+        lookup
+    }
+
+    ///query()
+    /// parameters:
+    ///     - addr: the node to ask
+    ///     - query: what to ask
+    ///     - target: the id or info hash it is about
+    ///
+    /// function:
+    /// Sends a request without a peer or token and returns the answering node's id with its answer.
+    async fn query(&self, addr: SocketAddr, query: DhtQuery, target: Vec<u8>) -> Result<(NodeId, DhtResponse), Box<dyn std::error::Error + Send + Sync>> {
+        self.request(addr, DhtRequest { query: query.into(), target, token: Vec::new(), peer: None }).await
+    }
+
+    ///request()
+    /// parameters:
+    ///     - addr: the node to send to
+    ///     - request: the request
+    ///
+    /// function:
+    /// Sends a request and waits QUERY_TIMEOUT for its answer. A node that does not answer is
+    /// dropped from the routing table, one that does is recorded as seen.
+    async fn request(&self, addr: SocketAddr, request: DhtRequest) -> Result<(NodeId, DhtResponse), Box<dyn std::error::Error + Send + Sync>> {
+        let transaction = rand::random::<[u8; 8]>().to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(transaction.clone(), (addr, tx));
+
+        let message = DhtMessage {
+            transaction: transaction.clone(),
+            sender: self.id.to_vec(),
+            body: Some(dht_message::Body::Request(request)),
+        };
+        let sent = self.socket.send_dht(addr, &message.encode_to_vec()).await;
+        let answer = match sent {
+            Ok(()) => timeout(QUERY_TIMEOUT, rx).await.ok().and_then(Result::ok),
+            Err(_) => None,
+        };
+        self.pending.lock().unwrap().remove(&transaction);
+
+        let answer = answer.and_then(|answer| {
+            let id: NodeId = answer.sender.try_into().ok()?;
+            match answer.body {
+                Some(dht_message::Body::Response(response)) => Some((id, response)),
+                _ => None,
+            }
+        });
+        match answer {
+            Some((id, response)) => {
+                self.table.lock().unwrap().insert(id, addr);
+                Ok((id, response))
+            }
+            None => {
+                self.table.lock().unwrap().remove(addr);
+                Err(format!("DHT node {} did not answer", addr).into())
+            }
+        }
+    }
+
+    ///learn()
+    /// parameters:
+    ///     - nodes: nodes another node told us about
+    ///
+    /// function:
+    /// Adds the nodes to the routing table, the first lookup through them drops those that do not answer.
+    fn learn(&self, nodes: &[DhtNode]) {
+        let mut table = self.table.lock().unwrap();
+        for contact in nodes.iter().filter_map(contact_of) {
+            table.insert(contact.id, contact.addr);
+        }
+    }
+
+    ///receive_loop()
+    /// parameters:
+    ///     - dht: the node, the loop ends once it is dropped
+    ///     - inbound: the DHT datagrams the socket receives
+    ///
+    /// function:
+    /// Answers the requests of other nodes and hands answers to the requests waiting for them.
+    /// Answers only count if they come from the node the request was sent to.
+    async fn receive_loop(dht: Weak<Dht>, mut inbound: mpsc::UnboundedReceiver<Datagram>) {
+        while let Some((from, datagram)) = inbound.recv().await {
+            let Some(dht) = dht.upgrade() else { return };
+            let Ok(message) = DhtMessage::decode(datagram.as_slice()) else { continue };
+
+            match message.body {
+                Some(dht_message::Body::Request(request)) => {
+                    let Ok(sender) = NodeId::try_from(message.sender.as_slice()) else { continue };
+                    let Some(response) = dht.answer(from, sender, request) else { continue };
+                    let answer = DhtMessage {
+                        transaction: message.transaction,
+                        sender: dht.id.to_vec(),
+                        body: Some(dht_message::Body::Response(response)),
+                    };
+                    if let Err(e) = dht.socket.send_dht(from, &answer.encode_to_vec()).await {
+                        eprintln!("Failed to answer DHT node {}: {}", from, e);
+                    }
+                }
+                Some(dht_message::Body::Response(_)) => {
+                    let mut pending = dht.pending.lock().unwrap();
+                    if pending.get(&message.transaction).is_some_and(|(addr, _)| *addr == from) {
+                        if let Some((_, tx)) = pending.remove(&message.transaction) {
+                            let _ = tx.send(message);
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
+    ///answer()
+    /// parameters:
+    ///     - from: where the request came from
+    ///     - sender: the id of the node that sent it
+    ///     - request: the request
+    ///
+    /// function:
+    /// Returns the answer to another node's request, or None if it should not be answered.
+    /// Announced peers are stored at the address the announce came from, so nobody can announce
+    /// someone else, and only with a token we handed out to that address.
+    fn answer(&self, from: SocketAddr, sender: NodeId, request: DhtRequest) -> Option<DhtResponse> {
+        self.table.lock().unwrap().insert(sender, from);
+        let target: Option<NodeId> = request.target.as_slice().try_into().ok();
+
+        match request.query() {
+            DhtQuery::Ping => Some(DhtResponse::default()),
+            DhtQuery::FindNode => Some(DhtResponse { nodes: self.closest_nodes(&target?), ..DhtResponse::default() }),
+            DhtQuery::GetPeers => {
+                let target = target?;
+                let peers = self.peers.lock().unwrap().get(&target)
+                    .map(|peers| peers.iter().map(|(peer, _)| peer.clone()).collect())
+                    .unwrap_or_default();
+                Some(DhtResponse { nodes: self.closest_nodes(&target), peers, token: self.token(from.ip(), false).to_vec() })
+            }
+            DhtQuery::AnnouncePeer => {
+                let target = target?;
+                if request.token != self.token(from.ip(), false) && request.token != self.token(from.ip(), true) {
+                    return None;
+                }
+                let (SocketAddr::V4(addr), Some(mut peer)) = (from, request.peer) else { return None };
+                let id = peer.id.as_mut()?;
+                id.ipaddr = u32::from_be_bytes(addr.ip().octets());
+                id.port = addr.port() as u32;
+
+                let mut stored = self.peers.lock().unwrap();
+                let peers = stored.entry(target).or_default();
+                peers.retain(|(known, _)| known.cert_fingerprint != peer.cert_fingerprint);
+                if peers.len() >= MAX_PEERS {
+                    peers.remove(0);
+                }
+                peers.push((peer, Instant::now()));
+                Some(DhtResponse::default())
+            }
+        }
+    }
+
+    ///closest_nodes()
+    /// parameters:
+    ///     - target: the id or info hash asked about
+    ///
+    /// function:
+    /// Returns the K nodes closest to the target we know, as sent to other nodes.
+    fn closest_nodes(&self, target: &NodeId) -> Vec<DhtNode> {
+        self.table.lock().unwrap().closest(target, K).into_iter()
+            .filter_map(|contact| match contact.addr {
+                SocketAddr::V4(addr) => Some(DhtNode {
+                    id: contact.id.to_vec(),
+                    ipaddr: u32::from_be_bytes(addr.ip().octets()),
+                    port: addr.port() as u32,
+                }),
+                SocketAddr::V6(_) => None,
+            })
+            .collect()
+    }
+
+    ///token()
+    /// parameters:
+    ///     - ip: the address the token is handed to
+    ///     - previous: whether to derive it from the previous secret
+    ///
+    /// function:
+    /// Returns the announce token of an address, only whoever receives datagrams at it learns it.
+    fn token(&self, ip: IpAddr, previous: bool) -> [u8; 20] {
+        let secrets = self.secrets.lock().unwrap();
+        let secret = if previous { secrets.1 } else { secrets.0 };
+        let ip = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        Sha1::new().chain_update(secret).chain_update(ip).finalize().into()
+    }
+
+    ///maintain()
+    /// parameters:
+    ///     - dht: the node, the task ends once it is dropped
+    ///
+    /// function:
+    /// Rotates the token secret and forgets expired peers every TOKEN_ROTATION, and refreshes
+    /// the routing table every REFRESH_INTERVAL.
+    async fn maintain(dht: Weak<Dht>) {
+        let mut rotation = interval(TOKEN_ROTATION);
+        let mut refresh = interval(REFRESH_INTERVAL);
+        //both first ticks complete immediately and the node was just created
+        rotation.tick().await;
+        refresh.tick().await;
+        loop {
+            tokio::select! {
+                _ = rotation.tick() => {
+                    let Some(dht) = dht.upgrade() else { return };
+                    {
+                        let mut secrets = dht.secrets.lock().unwrap();
+                        *secrets = (rand::random(), secrets.0);
+                    }
+                    let mut stored = dht.peers.lock().unwrap();
+                    stored.values_mut().for_each(|peers| peers.retain(|(_, at)| at.elapsed() < PEER_TTL));
+                    stored.retain(|_, peers| !peers.is_empty());
+                }
+                _ = refresh.tick() => {
+                    let Some(dht) = dht.upgrade() else { return };
+                    dht.lookup(dht.id, DhtQuery::FindNode).await;
+                }
+            }
+        }
+    }
+}
+
+/// node_id (
+///     fingerprint: the fingerprint of the client's persistent certificate
+/// )
+/// helper function deriving our node id, so it stays the same across runs like the certificate
+pub fn node_id(fingerprint: &[u8]) -> NodeId {
+    Sha1::digest(fingerprint).into()
+}
+
+/// distance (
+///     a: a node id or info hash
+///     b: another one
+/// )
+/// helper function returning Kademlia's XOR distance, which orders like a big endian number
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// bucket_index (
+///     own: our node id
+///     id: another node's id
+/// )
+/// helper function returning the bucket of a node, the number of leading bits it shares with
+/// us. Our own id has no bucket
+fn bucket_index(own: &NodeId, id: &NodeId) -> Option<usize> {
+    let distance = distance(own, id);
+    let byte = distance.iter().position(|byte| *byte != 0)?;
+    Some(byte * 8 + distance[byte].leading_zeros() as usize)
+}
+
+/// contact_of (
+///     node: a node as another node sent it
+/// )
+/// helper function to turn a node into a contact, None if it has no valid id
+fn contact_of(node: &DhtNode) -> Option<Contact> {
+    Some(Contact {
+        id: node.id.as_slice().try_into().ok()?,
+        addr: SocketAddr::from((Ipv4Addr::from(node.ipaddr), node.port as u16)),
+        last_seen: Instant::now(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::{Endpoint, EndpointConfig, TokioRuntime};
+    use crate::connection::connection::PeerId;
+
+    /// a node on its own loopback socket, with the endpoint that drives the socket's receiving
+    fn node(n: u8) -> (Arc<Dht>, Endpoint) {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let (socket, inbound) = DemuxSocket::new(socket).unwrap();
+        let mut config = EndpointConfig::default();
+        config.grease_quic_bit(false);
+        let endpoint = Endpoint::new_with_abstract_socket(config, None, socket.clone(), Arc::new(TokioRuntime)).unwrap();
+        (Dht::start(node_id(&[n]), socket, inbound), endpoint)
+    }
+
+    /// a peer announcing itself, its address is filled in from where the announce came from
+    fn peer(n: u8) -> DhtPeer {
+        DhtPeer { id: Some(PeerId::default()), cert_fingerprint: vec![n; 32], noise_key: Vec::new() }
+    }
+
+    #[tokio::test]
+    async fn peers_announced_by_one_node_are_found_by_another() {
+        let nodes: Vec<(Arc<Dht>, Endpoint)> = (0..16).map(node).collect();
+        let first = nodes[0].1.local_addr().unwrap();
+        for (dht, _) in &nodes[1..] {
+            assert!(dht.clone().bootstrap(vec![first]).await > 0);
+        }
+
+        let info_hash: NodeId = Sha1::digest(b"file").into();
+        let (announcer, announcer_endpoint) = &nodes[5];
+        assert!(announcer.announce(info_hash, peer(5)).await > 0);
+
+        let found = nodes[12].0.get_peers(info_hash).await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].cert_fingerprint, vec![5; 32]);
+        let id = found[0].id.unwrap();
+        assert_eq!(id.port as u16, announcer_endpoint.local_addr().unwrap().port());
+        assert_eq!(Ipv4Addr::from(id.ipaddr), Ipv4Addr::LOCALHOST);
+
+        assert!(nodes[12].0.get_peers(Sha1::digest(b"other").into()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn announces_need_a_token_handed_to_their_address() {
+        let (dht, _endpoint) = node(0);
+        let from: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let elsewhere: SocketAddr = "10.0.0.2:6000".parse().unwrap();
+        let target = vec![7; 20];
+        let get_peers = DhtRequest { query: DhtQuery::GetPeers.into(), target: target.clone(), token: Vec::new(), peer: None };
+        let token = dht.answer(from, [1; 20], get_peers).unwrap().token;
+        let announce = |token: &[u8]| DhtRequest {
+            query: DhtQuery::AnnouncePeer.into(),
+            target: target.clone(),
+            token: token.to_vec(),
+            peer: Some(peer(1)),
+        };
+
+        assert!(dht.answer(from, [1; 20], announce(&[0; 20])).is_none());
+        assert!(dht.answer(elsewhere, [2; 20], announce(&token)).is_none());
+        assert!(dht.answer(from, [1; 20], announce(&token)).is_some());
+
+        //tokens of the previous secret are still good, older ones are not
+        let rotate = || {
+            let mut secrets = dht.secrets.lock().unwrap();
+            *secrets = (rand::random(), secrets.0);
+        };
+        rotate();
+        assert!(dht.answer(from, [1; 20], announce(&token)).is_some());
+        rotate();
+        assert!(dht.answer(from, [1; 20], announce(&token)).is_none());
+
+        let stored = dht.peers.lock().unwrap();
+        assert_eq!(stored[&[7; 20]].len(), 1);
+        assert_eq!(stored[&[7; 20]][0].0.id.unwrap().port, 6000);
+    }
+
+    #[test]
+    fn full_buckets_only_replace_quiet_nodes() {
+        let own = [0; 20];
+        let mut table = RoutingTable::new(own);
+        let addr = |n: u8| SocketAddr::from((Ipv4Addr::LOCALHOST, 1000 + n as u16));
+        //every id with the top bit set lands in bucket 0
+        let id = |n: u8| { let mut id = [0; 20]; id[0] = 0x80; id[19] = n; id };
+
+        table.insert(own, addr(0));
+        assert_eq!(table.len(), 0);
+        for n in 0..K as u8 {
+            table.insert(id(n), addr(n));
+        }
+        table.insert(id(0), addr(0));
+        assert_eq!(table.buckets[0].len(), K);
+
+        table.insert(id(100), addr(100));
+        assert_eq!(table.buckets[0].len(), K);
+        assert!(table.buckets[0].iter().all(|contact| contact.id != id(100)));
+
+        let Some(quiet) = Instant::now().checked_sub(NODE_TIMEOUT + Duration::from_secs(1)) else { return };
+        table.buckets[0][3].last_seen = quiet;
+        table.insert(id(100), addr(100));
+        assert_eq!(table.buckets[0].len(), K);
+        assert!(table.buckets[0].iter().any(|contact| contact.id == id(100)));
+        assert!(table.buckets[0].iter().all(|contact| contact.id != id(3)));
+    }
+
+    #[tokio::test]
+    async fn lookups_end_and_drop_nodes_that_do_not_answer() {
+        let (dht, _endpoint) = node(0);
+        let (other, other_endpoint) = node(1);
+        let other_addr = other_endpoint.local_addr().unwrap();
+
+        //sockets nobody reads, requests to them time out
+        let silent: Vec<std::net::UdpSocket> = (0..4).map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap()).collect();
+        {
+            let mut table = dht.table.lock().unwrap();
+            for (n, socket) in silent.iter().enumerate() {
+                table.insert(node_id(&[100 + n as u8]), socket.local_addr().unwrap());
+            }
+            table.insert(other.id, other_addr);
+        }
+
+        let lookup = timeout(Duration::from_secs(10), dht.lookup(Sha1::digest(b"anything").into(), DhtQuery::FindNode))
+            .await
+            .expect("lookup did not end");
+
+        assert_eq!(lookup.closest.len(), 1);
+        assert_eq!(lookup.closest[0].0.addr, other_addr);
+        let table = dht.table.lock().unwrap();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.closest(&other.id, 1)[0].addr, other_addr);
+    }
+}
@@ -0,0 +1,315 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{Mutex, Notify, RwLock};
+use crate::connection::connection::InfoHash;
+use crate::file_assembler::FileAssembler;
+use crate::traffic::Traffic;
+
+/// where a download is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadState {
+    /// waiting for a free slot
+    Queued,
+    /// pieces are being requested
+    Active,
+    /// stopped until resumed, the pieces written so far stay
+    Paused,
+    /// the file was built, we seed it now
+    Completed,
+    /// no peer could provide the file, queuing it again retries
+    Failed,
+    /// stopped for good, forgotten once its connections closed
+    Cancelled,
+}
+
+/// a download as shown to the user
+#[derive(Debug, Clone)]
+pub struct DownloadStatus {
+    pub name: String,
+    pub hash: [u8; 20],
+    pub state: DownloadState,
+    pub priority: i32,
+    /// pieces written so far
+    pub pieces_done: usize,
+    /// pieces in the file
+    pub pieces: usize,
+    /// bytes per second downloaded since the previous status query
+    pub speed: u64,
+    /// live connections of the download
+    pub peers: usize,
+}
+
+/// one file the user asked for
+#[derive(Debug)]
+struct Download {
+    info_hash: InfoHash,
+    priority: i32,
+    state: DownloadState,
+    /// when it was queued, among downloads of equal priority the earliest starts first
+    queued: Instant,
+    /// whether a task is downloading it, a paused or cancelled one runs until its connections closed
+    running: bool,
+    /// the assembler of the running task, None until it found its peers
+    assembler: Option<Arc<RwLock<FileAssembler>>>,
+    /// pieces written when it was last looked at
+    pieces_done: usize,
+    /// bytes downloaded at the previous status query, and when
+    sample: (Instant, u64),
+}
+
+/// Downloads is the queue of files the user asked for. At most max_active of them download at
+/// the same time, a free slot goes to the queued download of highest priority. Stopped
+/// downloads keep the pieces they wrote, so resuming or queuing one again picks up from them.
+#[derive(Debug)]
+pub struct Downloads {
+    max_active: usize,
+    downloads: Mutex<HashMap<[u8; 20], Download>>,
+    /// wakes the scheduler when a download was queued or a slot may have freed
+    changed: Notify,
+}
+
+impl Downloads {
+
+    ///new()
+    /// parameters:
+    ///     - max_active: most downloads running at the same time
+    ///
+    /// function:
+    /// Creates an empty queue.
+    pub fn new(max_active: usize) -> Downloads {
+        Downloads {
+            max_active,
+            downloads: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    ///queue()
+    /// parameters:
+    ///     - info_hash: the file to download
+    ///     - priority: higher priorities get a slot first
+    ///
+    /// function:
+    /// Queues the file. A completed or failed download of it is replaced, one in progress is not.
+    pub async fn queue(&self, info_hash: InfoHash, priority: i32) -> Result<(), Box<dyn std::error::Error>> {
+        let hash = info_hash.get_hashed_info_hash();
+        let mut downloads = self.downloads.lock().await;
+        if downloads.get(&hash).is_some_and(|download| !matches!(download.state, DownloadState::Completed | DownloadState::Failed)) {
+            return Err(format!("{} is already queued", info_hash.name).into());
+        }
+
+        let now = Instant::now();
+        downloads.insert(hash, Download {
+            info_hash,
+            priority,
+            state: DownloadState::Queued,
+            queued: now,
+            running: false,
+            assembler: None,
+            pieces_done: 0,
+            sample: (now, 0),
+        });
+        self.changed.notify_one();
+
+        Ok(())
+    }
+
+    ///pause()
+    /// parameters:
+    ///     - hash: the download
+    ///
+    /// function:
+    /// Stops a queued or active download until it is resumed, freeing its slot.
+    pub async fn pause(&self, hash: &[u8; 20]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut downloads = self.downloads.lock().await;
+        let download = downloads.get_mut(hash).ok_or("no such download")?;
+        match download.state {
+            DownloadState::Queued => download.state = DownloadState::Paused,
+            DownloadState::Active => {
+                download.state = DownloadState::Paused;
+                download.stop().await;
+            }
+            _ => return Err("only queued and active downloads can be paused".into()),
+        }
+
+        Ok(())
+    }
+
+    ///resume()
+    /// parameters:
+    ///     - hash: the download
+    ///
+    /// function:
+    /// Queues a paused download again, it keeps its priority and its place in the queue.
+    pub async fn resume(&self, hash: &[u8; 20]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut downloads = self.downloads.lock().await;
+        let download = downloads.get_mut(hash).ok_or("no such download")?;
+        if download.state != DownloadState::Paused {
+            return Err("only paused downloads can be resumed".into());
+        }
+        download.state = DownloadState::Queued;
+        self.changed.notify_one();
+
+        Ok(())
+    }
+
+    ///cancel()
+    /// parameters:
+    ///     - hash: the download
+    ///
+    /// function:
+    /// Stops the download and forgets it. The pieces it wrote stay on disk.
+    pub async fn cancel(&self, hash: &[u8; 20]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut downloads = self.downloads.lock().await;
+        let download = downloads.get_mut(hash).ok_or("no such download")?;
+        if download.state == DownloadState::Cancelled {
+            return Err("download is already cancelled".into());
+        }
+        if !download.running {
+            downloads.remove(hash);
+            return Ok(());
+        }
+        download.state = DownloadState::Cancelled;
+        download.stop().await;
+
+        Ok(())
+    }
+
+    ///set_priority()
+    /// parameters:
+    ///     - hash: the download
+    ///     - priority: higher priorities get a slot first
+    ///
+    /// function:
+    /// Changes the priority of a download. Active downloads keep their slot, it only decides
+    /// which queued download starts next.
+    pub async fn set_priority(&self, hash: &[u8; 20], priority: i32) -> Result<(), Box<dyn std::error::Error>> {
+        let mut downloads = self.downloads.lock().await;
+        downloads.get_mut(hash).ok_or("no such download")?.priority = priority;
+
+        Ok(())
+    }
+
+    ///next()
+    ///
+    /// function:
+    /// Waits until a slot is free and a download queued, then marks the one of highest priority
+    /// active and returns its file. Downloads still closing their connections hold on to their slot.
+    pub async fn next(&self) -> InfoHash {
+        loop {
+            {
+                let mut downloads = self.downloads.lock().await;
+                let running = downloads.values().filter(|download| download.running).count();
+                let next = downloads.values_mut()
+                    .filter(|download| download.state == DownloadState::Queued && !download.running)
+                    .max_by_key(|download| (download.priority, Reverse(download.queued)));
+                if let Some(download) = next.filter(|_| running < self.max_active) {
+                    download.state = DownloadState::Active;
+                    download.running = true;
+                    return download.info_hash.clone();
+                }
+            }
+            self.changed.notified().await;
+        }
+    }
+
+    ///attach()
+    /// parameters:
+    ///     - hash: the download
+    ///     - assembler: the assembler building its file
+    ///
+    /// function:
+    /// Hands the download the assembler it is paused and reported through. A download paused
+    /// or cancelled before it got one is stopped right away.
+    pub async fn attach(&self, hash: &[u8; 20], assembler: Arc<RwLock<FileAssembler>>) {
+        let mut downloads = self.downloads.lock().await;
+        match downloads.get_mut(hash) {
+            Some(download) if download.state == DownloadState::Active => download.assembler = Some(assembler),
+            _ => assembler.read().await.stop(),
+        }
+    }
+
+    ///finish()
+    /// parameters:
+    ///     - hash: the download
+    ///     - completed: whether the file was built
+    ///
+    /// function:
+    /// Frees the download's slot once its task ended, forgetting it if it was cancelled.
+    pub async fn finish(&self, hash: &[u8; 20], completed: bool) {
+        let mut downloads = self.downloads.lock().await;
+        if let Some(download) = downloads.get_mut(hash) {
+            download.running = false;
+            if let Some(assembler) = download.assembler.take() {
+                download.pieces_done = download.info_hash.pieces.len() - assembler.read().await.pieces_left();
+            }
+            match download.state {
+                DownloadState::Active if completed => download.state = DownloadState::Completed,
+                DownloadState::Active => download.state = DownloadState::Failed,
+                DownloadState::Cancelled => { downloads.remove(hash); }
+                _ => {}
+            }
+        }
+        self.changed.notify_one();
+    }
+
+    ///status()
+    /// parameters:
+    ///     - traffic: the bytes downloaded per file
+    ///
+    /// function:
+    /// Returns every download, highest priority first.
+    pub async fn status(&self, traffic: &Traffic) -> Vec<DownloadStatus> {
+        let mut downloads = self.downloads.lock().await;
+        let mut statuses = Vec::new();
+        for (hash, download) in downloads.iter_mut() {
+            let mut peers = 0;
+            if let Some(assembler) = &download.assembler {
+                let assembler = assembler.read().await;
+                download.pieces_done = download.info_hash.pieces.len() - assembler.pieces_left();
+                peers = assembler.connections();
+            }
+            if download.state == DownloadState::Completed {
+                download.pieces_done = download.info_hash.pieces.len();
+            }
+
+            let (sampled_at, sampled) = download.sample;
+            let downloaded = traffic.get(hash).downloaded;
+            let elapsed = sampled_at.elapsed().as_secs_f64();
+            let speed = match download.running && elapsed > 0.0 {
+                true => (downloaded.saturating_sub(sampled) as f64 / elapsed) as u64,
+                false => 0,
+            };
+            download.sample = (Instant::now(), downloaded);
+
+            statuses.push(DownloadStatus {
+                name: download.info_hash.name.clone(),
+                hash: *hash,
+                state: download.state,
+                priority: download.priority,
+                pieces_done: download.pieces_done,
+                pieces: download.info_hash.pieces.len(),
+                speed,
+                peers,
+            });
+        }
+        statuses.sort_by_key(|status| Reverse(status.priority));
+
+        statuses
+    }
+}
+
+impl Download {
+
+    ///stop()
+    ///
+    /// function:
+    /// Has the download's assembler give up on the file, if it has one yet.
+    async fn stop(&self) {
+        if let Some(assembler) = &self.assembler {
+            assembler.read().await.stop();
+        }
+    }
+}
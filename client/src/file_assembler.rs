@@ -1,66 +1,198 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use crate::connection::connection::{InfoHash};
-use crate::message::Message;
-use tokio::sync::{mpsc, Notify, RwLock};
+use crate::message::{Message, PexPeer};
+use tokio::sync::{mpsc, oneshot, Notify, RwLock};
+use tokio::time::{interval, MissedTickBehavior};
 use crate::{file_handler};
-use crate::file_handler::{hash_piece_data, write_piece_to_part};
+use crate::file_handler::{get_piece_size, has_piece, hash_piece_data, write_piece_to_part};
+use crate::pex::diff;
+use crate::traffic::Traffic;
+
+/// how often we exchange peers with each peer we download from
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// most requests a connection has outstanding, the rest wait in pending for whichever
+/// connection has room first
+pub(crate) const REQUEST_WINDOW: usize = 16;
+
+/// how often the connections are checked even when no piece arrives, so one that failed
+/// before it got going is noticed
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how long nothing is requested from a peer after it cancelled a request
+const CHOKE_DURATION: Duration = Duration::from_secs(30);
+
+/// how often a peer may cancel our requests before we give up on it
+const MAX_CHOKES: u32 = 3;
+
+/// identifies a connection for the whole download, requests carry it as their seeder number
+pub type ConnectionId = u32;
+
+/// where a connection is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// subscribed, no piece came over it yet
+    Connecting,
+    /// pieces come over it
+    Active,
+    /// the peer cancelled a request, nothing is requested from it until the instant passes
+    Choked(Instant),
+    /// closed or choked too often, its outstanding pieces went back to pending
+    Failed,
+}
+
+/// PeerLink is one connection of the download and everything we track about it
+#[derive(Debug)]
+struct PeerLink {
+    /// sends requests across the connection, None once it failed
+    request_tx: Option<mpsc::Sender<Message>>,
+    state: ConnectionState,
+    /// the peer the connection goes to
+    peer: PexPeer,
+    /// the pieces the peer advertised, empty if it has the whole file
+    bitfield: Vec<u8>,
+    /// the pieces requested over the connection and not received yet
+    outstanding: HashSet<u32>,
+    /// the fingerprints shared with the peer over PEX
+    shared: HashSet<[u8; 32]>,
+    /// how often the peer cancelled our requests
+    chokes: u32,
+}
+
+impl PeerLink {
+
+    ///is_live()
+    ///
+    ///function:
+    ///Returns whether the connection may still deliver pieces.
+    fn is_live(&self) -> bool {
+        self.state != ConnectionState::Failed
+    }
+
+    ///is_requestable()
+    ///
+    ///function:
+    ///Returns whether pieces may be requested over the connection now.
+    fn is_requestable(&self) -> bool {
+        matches!(self.state, ConnectionState::Connecting | ConnectionState::Active)
+    }
+
+    ///choke()
+    ///parameters:
+    ///    - pending: where the connection's pieces go if we give up on it
+    ///
+    ///function:
+    ///Stops requesting from the peer for CHOKE_DURATION, or for good once it choked MAX_CHOKES
+    ///times. Pieces already requested may still come, the peer cancels those it will not send.
+    fn choke(&mut self, pending: &mut VecDeque<u32>) {
+        if !self.is_requestable() {
+            return;
+        }
+        self.chokes += 1;
+        if self.chokes >= MAX_CHOKES {
+            self.fail(pending);
+        } else {
+            self.state = ConnectionState::Choked(Instant::now() + CHOKE_DURATION);
+        }
+    }
+
+    ///fail()
+    ///parameters:
+    ///    - pending: where the connection's outstanding pieces go
+    ///
+    ///function:
+    ///Closes our end of the connection and requests its outstanding pieces from the others first.
+    fn fail(&mut self, pending: &mut VecDeque<u32>) {
+        self.state = ConnectionState::Failed;
+        self.request_tx = None;
+        for index in self.outstanding.drain() {
+            pending.push_front(index);
+        }
+    }
+}
 
 /// this represents a connection between 2 peers
 #[derive(Debug)]
 pub struct FileAssembler {
     ///file hash that is being requested
     file_hash: InfoHash,
-    ///notify handle used to tell send_requests to begin requesting
+    ///notify handle used to tell reassemble_loop to begin requesting
     start_requesting: Arc<Notify>,
-    ///number of active peer connections achieved
-    num_connections: usize,
+    ///notify handle used to tell reassemble_loop a connection was added
+    connection_added: Arc<Notify>,
+    ///notify handle used to tell reassemble_loop to give up on the file
+    stopped: Arc<Notify>,
     ///the sender used for LAN/P2P/QUIC to send data from
     conn_tx: mpsc::Sender<Message>,
-    /// sender used to send file requests across a connection
-    request_txs: Vec<mpsc::Sender<Message>>,
+    /// every connection of the download, failed ones included
+    connections: HashMap<ConnectionId, PeerLink>,
+    /// the id the next connection gets
+    next_id: ConnectionId,
+    /// the pieces not requested from any connection yet
+    pending: VecDeque<u32>,
+    /// fingerprints of every peer we connected to or were told about over PEX
+    heard: HashSet<[u8; 32]>,
+    /// seeders learned over PEX are sent here to be connected to
+    discovered_tx: mpsc::Sender<PexPeer>,
+    /// receiving end of discovered_tx, until someone takes it
+    discovered: Option<mpsc::Receiver<PexPeer>>,
+    ///counts the bytes of every piece written
+    traffic: Arc<Traffic>,
+    ///resolves to whether the file was built once reassembly ends, until someone takes it
+    outcome: Option<oneshot::Receiver<bool>>,
 }
 
 impl FileAssembler {
 
-    ///FileAssembler::new() 
+    ///FileAssembler::new()
     /// parameters:
     ///     - file_hash: the InfoHash object of the file requesting
-    ///     - num_connections: the number of successful p2p connections
-    /// 
+    ///     - traffic: counts the bytes of every piece written
+    ///
     /// function:
     /// This method creates a new FileAssembler object within Arc<RwLock<>>.
-    /// It spawns off both necessary file assembly processes, one for sending requests
-    /// and one for reassembling a file from pieces.
-    pub async fn new(file_hash: InfoHash, num_connection: usize) -> Arc<RwLock<FileAssembler>> {
+    /// It spawns off the process reassembling a file from pieces, which also hands out
+    /// the requests, and the one exchanging peers with our connections.
+    pub async fn new(file_hash: InfoHash, traffic: Arc<Traffic>) -> Arc<RwLock<FileAssembler>> {
+        //a download that was stopped picks up from the pieces it wrote
+        let written = file_handler::get_bitfield(&file_hash);
         let (conn_tx, conn_rx) = mpsc::channel::<Message>(150);
+        let (outcome_tx, outcome_rx) = oneshot::channel();
+        let (discovered_tx, discovered_rx) = mpsc::channel::<PexPeer>(16);
         let assembler = FileAssembler {
             file_hash: file_hash.clone(),
             start_requesting: Arc::new(Notify::new()),
-            num_connections: num_connection,
+            connection_added: Arc::new(Notify::new()),
+            stopped: Arc::new(Notify::new()),
             conn_tx,
-            request_txs: Vec::new(),
+            connections: HashMap::new(),
+            next_id: 0,
+            pending: (0..file_hash.pieces.len() as u32)
+                .filter(|&index| !written.as_ref().is_some_and(|bitfield| has_piece(bitfield, index)))
+                .collect(),
+            heard: HashSet::new(),
+            discovered_tx,
+            discovered: Some(discovered_rx),
+            traffic,
+            outcome: Some(outcome_rx),
         };
 
-        let (resend_tx, resend_rx) = mpsc::channel::<Message>(100);
-
         let assembler = Arc::new(RwLock::new(assembler));
 
         let assembler_clone = assembler.clone();
         tokio::spawn(async move {
-            let res = FileAssembler::reassemble_loop(conn_rx, assembler_clone, resend_tx).await;
+            let res = FileAssembler::reassemble_loop(conn_rx, assembler_clone.clone()).await;
             if res.is_err() {
                 eprintln!("Reassembly Loop Error: {:?}", res);
             }
+            //closing the connections ends the PEX loop too
+            assembler_clone.write().await.close_connections();
+            let _ = outcome_tx.send(res.is_ok());
         });
 
-        let assembler_clone = assembler.clone();
-        let hash = file_hash.get_hashed_info_hash();
-        tokio::spawn(async move {
-            let res = FileAssembler::send_requests(hash, assembler_clone, resend_rx).await;
-            if res.is_err() {
-                eprintln!("Send Request Error: {:?}", res);
-            }
-        });
+        tokio::spawn(FileAssembler::pex_loop(file_hash.get_hashed_info_hash(), assembler.clone()));
 
         assembler
     }
@@ -68,192 +200,344 @@ impl FileAssembler {
     ///get_conn_tx()
     /// parameters:
     ///     - self: to copy conn_tx
-    /// 
+    ///
     ///function:
-    ///Returns a clone of internal conn_tx which connections will 
+    ///Returns a clone of internal conn_tx which connections will
     ///use to communicate to the reassemble process.
     pub fn get_conn_tx(&self) -> mpsc::Sender<Message> {
         self.conn_tx.clone()
     }
-    
+
     ///subscribe_new_connection()
     ///parameters:
     ///    - mut self: self to add tx to array
+    ///    - peer: the peer the connection goes to, shared with our other peers over PEX
+    ///    - bitfield: the pieces the peer advertised, empty if it has the whole file
     ///
     ///function:
-    ///This method "subscribes" a new connection by adding a tx to an internal
-    ///vector of tx handles and returning the associated receiver
-    ///so that the send_requests method can send requests to connections. 
-    pub fn subscribe_new_connection(&mut self) -> mpsc::Receiver<Message> {
+    ///This method "subscribes" a new connection by giving it an id and a tx
+    ///and returning the associated receiver
+    ///so that the reassemble_loop can send requests to connections. Connections can be
+    ///subscribed at any time, once requesting started they get requests right away.
+    pub fn subscribe_new_connection(&mut self, peer: PexPeer, bitfield: Vec<u8>) -> mpsc::Receiver<Message> {
         let (request_tx, request_rx) = mpsc::channel::<Message>(150);
-        self.request_txs.push(request_tx);
+        self.heard.insert(peer.fingerprint);
+        self.connections.insert(self.next_id, PeerLink {
+            request_tx: Some(request_tx),
+            state: ConnectionState::Connecting,
+            peer,
+            bitfield,
+            outstanding: HashSet::new(),
+            shared: HashSet::new(),
+            chokes: 0,
+        });
+        self.next_id += 1;
+        self.connection_added.notify_one();
 
         request_rx
     }
 
-    
-    /// start_requesting begins the requesting process
-    /// this should only be called once connections have been
-    /// successfully established. 
-    pub fn start_requesting(&mut self) {
-        println!("Notifying waiters");
-        self.start_requesting.notify_waiters();
+    ///connected_to()
+    ///parameters:
+    ///    - fingerprint: a peer's certificate fingerprint
+    ///
+    ///function:
+    ///Returns whether one of our live connections goes to the peer.
+    pub fn connected_to(&self, fingerprint: &[u8]) -> bool {
+        self.connections.values().any(|link| link.is_live() && link.peer.fingerprint == fingerprint)
     }
 
-    ///send_requests
-    /// parameters:
-    ///     - hash: this is the 20 byte hash of the InfoHash for the requested file
-    ///     - assembler: this is a reference to the shared assembler object
-    ///     - resend_rx: receiving end of channel used to get resend requests from reassemble loop
-    /// 
-    /// function:
-    /// This method loops through all connections evenly splitting the number of file
-    /// pieces amongst connected peers. Once it sends out all initial requests, it listens
-    /// for resend requests which will be issued by reassemble_loop. Once reassemble_loop
-    /// gets the complete file, it will drop the resend_tx ending this process.
-    async fn send_requests(
-        hash: [u8; 20],
-        assembler: Arc<RwLock<FileAssembler>>,
-        mut resend_rx: mpsc::Receiver<Message>, //used to resend requests for pieces that didn't come or are incorrect
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    ///connections()
+    ///
+    ///function:
+    ///Returns the number of live connections.
+    pub fn connections(&self) -> usize {
+        self.connections.values().filter(|link| link.is_live()).count()
+    }
 
-        //get necessary fields in an efficient manner
-        let assembler_lock = assembler.read().await;
-        let num_pieces = assembler_lock.file_hash.pieces.len();
-        let num_connections = assembler_lock.num_connections;
-        let piece_length = assembler_lock.file_hash.piece_length;
-        drop(assembler_lock);
+    ///close_connections()
+    ///
+    ///function:
+    ///Drops every request sender, which ends the connections once they are done with what
+    ///they were sent.
+    fn close_connections(&mut self) {
+        for link in self.connections.values_mut() {
+            link.request_tx = None;
+        }
+    }
 
-        
-        //wait for connections to have been established to start requesting
-        let notify_handle = assembler.read().await.start_requesting.clone();
-        notify_handle.notified().await;
+    ///take_discovered()
+    ///parameters:
+    ///    - mut self: self to take the receiver from
+    ///
+    ///function:
+    ///Returns the receiver of the seeders learned over PEX. Only the first caller gets it.
+    pub fn take_discovered(&mut self) -> Option<mpsc::Receiver<PexPeer>> {
+        self.discovered.take()
+    }
 
-        //send initial requests
-        for i in 0..num_pieces {
+    ///take_outcome()
+    ///parameters:
+    ///    - mut self: self to take the receiver from
+    ///
+    ///function:
+    ///Returns the receiver that resolves to whether the file was built, once reassembly
+    ///is over. Only the first caller gets it.
+    pub fn take_outcome(&mut self) -> Option<oneshot::Receiver<bool>> {
+        self.outcome.take()
+    }
 
-            let request = Message::Request {
-                seeder: (i % num_connections) as u32,
-                index : i as u32,
-                begin: piece_length * i as u32,
-                length: piece_length,
-                hash,
-            };
+    ///stop()
+    ///
+    ///function:
+    ///Gives up on the file, closing its connections. The pieces written so far stay, a new
+    ///FileAssembler for the file only requests the others.
+    pub fn stop(&self) {
+        //the permit keeps reassemble_loop from missing us if it is not waiting yet
+        self.stopped.notify_one();
+    }
 
-            println!("Sending piece request {}", i);
-            assembler.read().await.request_txs.get(i % num_connections)
-                .ok_or(Box::<dyn std::error::Error + Send + Sync>::from("Could not retrieve connection rx"))?
-                .send(request).await?;
+    ///pieces_left()
+    ///
+    ///function:
+    ///Returns the number of pieces not written yet, requested or not.
+    pub fn pieces_left(&self) -> usize {
+        self.pending.len() + self.connections.values().map(|link| link.outstanding.len()).sum::<usize>()
+    }
 
+    /// start_requesting begins the requesting process
+    /// this should only be called once connections have been
+    /// successfully established.
+    pub fn start_requesting(&mut self) {
+        //reassemble_loop is the only waiter, the permit keeps it from missing us if it is not waiting yet
+        self.start_requesting.notify_one();
+    }
+
+    ///dispatch()
+    ///parameters:
+    ///    - hash: this is the 20 byte hash of the InfoHash for the requested file
+    ///
+    ///function:
+    ///Fails the connections that closed, unchokes those whose choke ran out and fills the window
+    ///of every connection we may request from with pending pieces its peer advertised. Pieces no
+    ///live connection's peer advertised go to any connection, a Cancel from it makes us give up on
+    ///the piece's peers one by one. Fails once no connection is live.
+    fn dispatch(&mut self, hash: [u8; 20]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let now = Instant::now();
+        let FileAssembler { connections, pending, .. } = self;
+        for link in connections.values_mut() {
+            if link.request_tx.as_ref().is_some_and(|request_tx| request_tx.is_closed()) {
+                link.fail(pending);
+            }
+            if matches!(link.state, ConnectionState::Choked(until) if until <= now) {
+                link.state = ConnectionState::Active;
+            }
+        }
+        if !self.connections.values().any(PeerLink::is_live) {
+            return Err("Failed to Retrieve File".into());
         }
 
-        let mut i = 0;
-        loop {
-            if let Some(msg) = resend_rx.recv().await {
-
-                println!("Sending request");
-
-                let new_seeder = (i % assembler.read().await.num_connections) as u32;
-
-                //make a new request message with the new seeder
-                let msg = match msg {
-                    Message::Cancel {index, begin, length, .. } =>
-                        Message::Request {seeder: new_seeder, index, begin, length, hash},
-                    Message::Piece { index, ..} =>
-                        Message::Request {
-                            seeder: new_seeder,
-                            index,
-                            begin: piece_length * index,
-                            length: piece_length,
-                            hash},
-                    _ => continue,
+        let piece_length = self.file_hash.piece_length;
+        let mut ids: Vec<ConnectionId> = self.connections.keys().copied().collect();
+        ids.sort();
+        for seeder in ids {
+            loop {
+                let link = &self.connections[&seeder];
+                if !link.is_requestable() || link.outstanding.len() >= REQUEST_WINDOW {
+                    break;
+                }
+                let Some(position) = self.pending.iter().position(|&index|
+                    has_piece(&link.bitfield, index)
+                        || !self.connections.values().any(|other| other.is_live() && has_piece(&other.bitfield, index))
+                ) else { break };
+                let index = self.pending[position];
+
+                let request = Message::Request {
+                    seeder,
+                    index,
+                    begin: piece_length * index,
+                    length: piece_length,
+                    hash,
                 };
+                //a full channel has enough queued, the piece stays pending
+                if link.request_tx.as_ref().is_none_or(|request_tx| request_tx.try_send(request).is_err()) {
+                    break;
+                }
+
+                self.pending.remove(position);
+                if let Some(link) = self.connections.get_mut(&seeder) {
+                    link.outstanding.insert(index);
+                }
+            }
+        }
 
-                assembler.read().await.request_txs.get(new_seeder as usize)
-                    .ok_or(Box::<dyn std::error::Error + Send + Sync>::from("Could not retrieve connection rx"))?
-                    .send(msg).await?;
+        Ok(())
+    }
 
-                i += 1;
+    ///received()
+    ///parameters:
+    ///    - index: a piece a connection delivered
+    ///
+    ///function:
+    ///Frees the piece's place in the window of the connection it was requested from, and marks
+    ///that connection active. Only one connection has a piece outstanding at a time.
+    fn received(&mut self, index: u32) -> Option<ConnectionId> {
+        let (id, link) = self.connections.iter_mut().find(|(_, link)| link.outstanding.contains(&index))?;
+        link.outstanding.remove(&index);
+        if link.state == ConnectionState::Connecting {
+            link.state = ConnectionState::Active;
+        }
+        Some(*id)
+    }
 
-            } else {
-                println!("Sending Loop finishing!");
-                return Ok(())
+    ///cancelled()
+    ///parameters:
+    ///    - seeder: the connection that cancelled the request
+    ///    - index: the piece it will not send
+    ///
+    ///function:
+    ///If we get a cancel notification, we are going to assume this means the seeder
+    ///does not or cannot provide the data right now. So we stop requesting from it
+    ///for a while and request the piece from the others. A connection that failed
+    ///cancels every piece it had outstanding, Cancels for pieces it no longer has are stale
+    ///and ignored.
+    fn cancelled(&mut self, seeder: ConnectionId, index: u32) {
+        let FileAssembler { connections, pending, .. } = self;
+        if let Some(link) = connections.get_mut(&seeder) {
+            if link.outstanding.remove(&index) {
+                pending.push_front(index);
+                link.choke(pending);
+            }
+        }
+    }
+
+    ///pex_loop
+    /// parameters:
+    ///     - hash: this is the 20 byte hash of the InfoHash for the requested file
+    ///     - assembler: this is a reference to the shared assembler object
+    ///
+    /// function:
+    /// Every PEX_INTERVAL this sends each connection's peer the peers of our other connections
+    /// that changed since the last time. Their answers come back to reassemble_loop. Ends
+    /// once the download has no connections left.
+    async fn pex_loop(hash: [u8; 20], assembler: Arc<RwLock<FileAssembler>>) {
+        let mut ticker = interval(PEX_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        //the first tick completes immediately, before any connection is up
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            let mut assembler = assembler.write().await;
+            if assembler.connections.values().all(|link| link.request_tx.is_none()) {
+                return;
+            }
+
+            let peers: Vec<PexPeer> = assembler.connections.values()
+                .filter(|link| link.is_live())
+                .map(|link| link.peer.clone())
+                .collect();
+            for link in assembler.connections.values_mut() {
+                let Some(request_tx) = &link.request_tx else { continue };
+                let others: Vec<PexPeer> = peers.iter()
+                    .filter(|peer| peer.fingerprint != link.peer.fingerprint)
+                    .cloned()
+                    .collect();
+                let (added, dropped) = diff(others, &mut link.shared);
+
+                //a full channel is busy with requests, this connection gets its Pex next time
+                let _ = request_tx.try_send(Message::Pex { hash, added, dropped });
             }
         }
     }
-    
 
     ///reassemble_loop
     /// parameters:
     ///    - conn_rx: receiving end to get piece messages back from connections
     ///    - assembler: this is a reference to the shared assembler object
-    ///    - resend_tx: sending end of resend channel to send resend requests to peer
-    /// 
+    ///
     /// function:
     /// This method waits until a file is completed or it fails to retrieve a file from underlying
-    /// connections. It takes each piece, checking it is valid with hash and constructs the file. 
+    /// connections. It takes each piece, checking it is valid with hash and constructs the file.
     /// If the hash does not line up, or the underlying connection fails, sending a cancel request
-    /// it will transmit a resend request to send_requests. 
+    /// the piece goes back to pending. After every message, connection added or DISPATCH_INTERVAL
+    /// the freed windows are filled again, spreading the pending pieces over the live connections.
     async fn reassemble_loop(
         mut conn_rx: mpsc::Receiver<Message>, //used to receive messages back from connection
         assembler: Arc<RwLock<FileAssembler>>,
-        resend_tx: mpsc::Sender<Message>, //used to send resend requests to send_requests loop
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
         let info_hash = assembler.read().await.file_hash.clone();
-        let piece_length = assembler.read().await.file_hash.piece_length;
+        let hash = info_hash.get_hashed_info_hash();
+        let traffic = assembler.read().await.traffic.clone();
+        let connection_added = assembler.read().await.connection_added.clone();
+        let stopped = assembler.read().await.stopped.clone();
 
+        //wait for connections to have been established to start requesting
+        let notify_handle = assembler.read().await.start_requesting.clone();
+        tokio::select! {
+            _ = notify_handle.notified() => {},
+            _ = stopped.notified() => return Err("download stopped".into()),
+        }
+
+        let mut dispatch_ticker = interval(DISPATCH_INTERVAL);
+        dispatch_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        //a download that was stopped may have written every piece before
+        let mut complete = file_handler::is_file_complete(info_hash.clone());
+        while !complete {
+           assembler.write().await.dispatch(hash)?;
+
+           let msg = tokio::select! {
+               msg = conn_rx.recv() => msg.ok_or("failed to get message")?,
+               _ = connection_added.notified() => continue,
+               _ = dispatch_ticker.tick() => continue,
+               _ = stopped.notified() => return Err("download stopped".into()),
+           };
 
-        loop {
-           let msg = conn_rx.recv().await.ok_or("failed to get message")?;
-           
            match msg {
                Message::Piece { index,  piece } => {
-                   println!("Received Piece: {}", index);
-
-                   //We want to verify the piece was not corrupted across transport.
-                   //If it was, we want to resend a request for a new piece.
-                   if  piece.len() == piece_length as usize {
-                       let recvd_piece_hash = hash_piece_data(piece.clone());
-
-                       let expected_hash: [u8; 20] = info_hash.pieces
-                           .get(index as usize).cloned()
-                           .ok_or(Box::<dyn std::error::Error + Send + Sync>::from("Could not retrieve piece hash"))?
-                           .hash.try_into().map_err(|_| Box::<dyn std::error::Error + Send + Sync>::from("Could not convert piece hash"))?;
-
-                       if recvd_piece_hash != expected_hash {
-                           println!("Piece corrupted sending resend request");
-                           resend_tx.send(Message::Piece { index, piece }).await?;
-                           continue;
+                   //We want to verify the piece was not corrupted across transport, or made up by
+                   //the peer. If it was, we want to request it again. Written pieces are served to
+                   //other leechers as verified, so no piece is written unchecked.
+                   let valid = info_hash.pieces.get(index as usize).is_some_and(|expected|
+                       piece.len() == get_piece_size(&info_hash, index)
+                           && expected.hash == hash_piece_data(piece.clone())
+                   );
+                   if !valid {
+                       println!("Piece corrupted sending resend request");
+                       let mut assembler = assembler.write().await;
+                       if assembler.received(index).is_some() {
+                           assembler.pending.push_front(index);
                        }
+                       continue;
                    }
 
+                   assembler.write().await.received(index);
+                   let piece_bytes = piece.len() as u64;
                    write_piece_to_part(info_hash.clone(), piece, index)?;
-                   println!("Successfully Wrote: {}", index);
+                   traffic.add_downloaded(hash, piece_bytes);
 
-                   if file_handler::is_file_complete(info_hash.clone()) {
+                   complete = file_handler::is_file_complete(info_hash.clone());
+                   if complete {
                        println!("File complete!");
-                       break;
                    }
                },
-               Message::Cancel {seeder,index, begin, length} => {
-                   println!("Failed to get piece removing seeder and trying again");
-
-                   //if we get a cancel notification, we are going to assume this means the seeder
-                   //does not or cannot provide the data. So we will remove it from seeder list
-                   //and resend a request.
-
-                   let bad_tx = assembler.write().await.request_txs.remove(seeder as usize);
-                   assembler.write().await.num_connections -= 1;
-                   drop(bad_tx);
-
-                   if assembler.read().await.num_connections == 0 {
-                       drop(resend_tx);
-                       return Err(Box::<dyn std::error::Error + Send + Sync>::from("Failed to Retrieve File"))
+               Message::Cancel { seeder, index, .. } => {
+                   assembler.write().await.cancelled(seeder, index);
+               },
+               Message::Pex { added, .. } => {
+                   //only seeders are worth a connection, and never more of them than there are pieces
+                   let mut assembler = assembler.write().await;
+                   for peer in added.into_iter().filter(|peer| peer.seed) {
+                       if assembler.heard.len() >= info_hash.pieces.len() {
+                           break;
+                       }
+                       if assembler.heard.insert(peer.fingerprint) {
+                           let _ = assembler.discovered_tx.try_send(peer);
+                       }
                    }
-
-                   resend_tx.send(Message::Cancel {seeder, index, begin, length}).await?;
-
                },
                _ => Err(Box::<dyn std::error::Error + Send + Sync>::from("wrong message type"))?,
            };
@@ -262,16 +546,153 @@ impl FileAssembler {
         }
 
         //drop all senders signaling end of connection
-        for request_tx in assembler.write().await.request_txs.drain(0..) {
-            drop(request_tx);
-        }
-        drop(resend_tx);
-        
+        assembler.write().await.close_connections();
+
         file_handler::build_file(info_hash)
             .map_err(|e| Box::<dyn std::error::Error + Send + Sync>::from(e.to_string()))?;
-        println!("piece built");
-        
+
         Ok(())
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::connection::{PeerId, PieceHash};
+
+    /// an assembler for a file of the given number of pieces, without its reassembly task
+    fn assembler(pieces: u32) -> FileAssembler {
+        let (conn_tx, _) = mpsc::channel(1);
+        let (discovered_tx, discovered) = mpsc::channel(1);
+        let file_hash = InfoHash {
+            name: "test".to_string(),
+            file_length: pieces as u64 * 4,
+            piece_length: 4,
+            pieces: vec![PieceHash::default(); pieces as usize],
+        };
+        FileAssembler {
+            file_hash,
+            start_requesting: Arc::new(Notify::new()),
+            connection_added: Arc::new(Notify::new()),
+            stopped: Arc::new(Notify::new()),
+            conn_tx,
+            connections: HashMap::new(),
+            next_id: 0,
+            pending: (0..pieces).collect(),
+            heard: HashSet::new(),
+            discovered_tx,
+            discovered: Some(discovered),
+            traffic: Arc::new(Traffic::default()),
+            outcome: None,
+        }
+    }
+
+    /// a seeder of the whole file
+    fn seeder(n: u8) -> PexPeer {
+        PexPeer { id: PeerId::default(), fingerprint: [n; 32], seed: true }
+    }
+
+    /// the pieces requested over a connection so far
+    fn requested(request_rx: &mut mpsc::Receiver<Message>) -> Vec<u32> {
+        let mut indexes = Vec::new();
+        while let Ok(message) = request_rx.try_recv() {
+            if let Message::Request { index, .. } = message {
+                indexes.push(index);
+            }
+        }
+        indexes
+    }
+
+    #[tokio::test]
+    async fn dropped_connection_pieces_move_to_the_others() {
+        let mut assembler = assembler(40);
+        let mut first = assembler.subscribe_new_connection(seeder(1), Vec::new());
+        let second = assembler.subscribe_new_connection(seeder(2), Vec::new());
+        let mut third = assembler.subscribe_new_connection(seeder(3), Vec::new());
+
+        assembler.dispatch([0; 20]).unwrap();
+        assert_eq!(requested(&mut first).len(), REQUEST_WINDOW);
+        assert_eq!(assembler.connections[&1].outstanding.len(), REQUEST_WINDOW);
+        assert_eq!(requested(&mut third).len(), 40 - 2 * REQUEST_WINDOW);
+        let dropped = assembler.connections[&1].outstanding.clone();
+
+        //the peer went away mid-transfer
+        drop(second);
+        assembler.dispatch([0; 20]).unwrap();
+
+        assert_eq!(assembler.connections[&1].state, ConnectionState::Failed);
+        assert!(assembler.connections[&1].outstanding.is_empty());
+        assert_eq!(assembler.connections(), 2);
+        let moved = requested(&mut third);
+        //the third connection only had room left for that many
+        assert_eq!(moved.len(), REQUEST_WINDOW - (40 - 2 * REQUEST_WINDOW));
+        assert!(moved.iter().all(|index| dropped.contains(index)));
+
+        //every piece is still either pending or requested from a live connection
+        let mut pieces: Vec<u32> = assembler.pending.iter().copied()
+            .chain(assembler.connections.values().flat_map(|link| link.outstanding.iter().copied()))
+            .collect();
+        pieces.sort();
+        assert_eq!(pieces, (0..40).collect::<Vec<u32>>());
+    }
+
+    #[tokio::test]
+    async fn stale_cancels_are_ignored() {
+        let mut assembler = assembler(4);
+        let _request_rx = assembler.subscribe_new_connection(seeder(1), Vec::new());
+        assembler.dispatch([0; 20]).unwrap();
+        assert!(assembler.pending.is_empty());
+
+        //an id that never existed, and a piece the connection was not asked for
+        assembler.cancelled(7, 0);
+        assembler.cancelled(0, 9);
+
+        assert!(assembler.pending.is_empty());
+        assert_eq!(assembler.connections[&0].outstanding.len(), 4);
+        assert_eq!(assembler.connections[&0].state, ConnectionState::Connecting);
+    }
+
+    #[tokio::test]
+    async fn choked_too_often_fails() {
+        let mut assembler = assembler(4);
+        let _request_rx = assembler.subscribe_new_connection(seeder(1), Vec::new());
+        assembler.dispatch([0; 20]).unwrap();
+
+        for chokes in 1..MAX_CHOKES {
+            assembler.cancelled(0, chokes);
+            assert!(matches!(assembler.connections[&0].state, ConnectionState::Choked(_)));
+            assert_eq!(assembler.pending, [chokes]);
+
+            //nothing is requested while choked, the piece waits for the choke to run out
+            assembler.dispatch([0; 20]).unwrap();
+            assert_eq!(assembler.pending, [chokes]);
+            assembler.connections.get_mut(&0).unwrap().state = ConnectionState::Choked(Instant::now());
+            assembler.dispatch([0; 20]).unwrap();
+            assert_eq!(assembler.connections[&0].state, ConnectionState::Active);
+            assert!(assembler.pending.is_empty());
+        }
+
+        assembler.cancelled(0, 0);
+        assert_eq!(assembler.connections[&0].state, ConnectionState::Failed);
+        assert!(assembler.connections[&0].request_tx.is_none());
+        assert_eq!(assembler.pending.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn fails_without_live_connections() {
+        let mut assembler = assembler(4);
+        assert!(assembler.dispatch([0; 20]).is_err());
+
+        let first = assembler.subscribe_new_connection(seeder(1), Vec::new());
+        let second = assembler.subscribe_new_connection(seeder(2), Vec::new());
+        assembler.dispatch([0; 20]).unwrap();
+
+        drop(first);
+        assembler.dispatch([0; 20]).unwrap();
+        drop(second);
+        assert!(assembler.dispatch([0; 20]).is_err());
+        assert_eq!(assembler.connections(), 0);
+        assert_eq!(assembler.pending.len(), 4);
+    }
+}
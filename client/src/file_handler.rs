@@ -51,45 +51,10 @@ impl connection::InfoHash {
         match is_new {
 
             // Generate the missing .fileinfo file
-            false =>{
-                // Byte length of the file
-                let file_length = file.path().metadata()?.len();
-                // Size of the pieces
-                let piece_length = Self::get_piece_length(file_length);
-                // Vector of piece hashes
-                let pieces = Self::get_piece_hashes(path, piece_length as usize)?;
-
-                // Create the new cache file to improve load time
-                let mut file = OpenOptions::new().write(true).open(file_cache)?;
-
-                // Write each field as newlines, this helps since we have 2 variable length fields
-                writeln!(file, "name: {}", name.clone())?;
-                writeln!(file, "file_length: {}", file_length.clone())?;
-                writeln!(file, "piece_length: {}", piece_length.clone())?;
-                writeln!(file, "pieces:")?;
-
-                // Write each piece on a newline
-                for piece in &pieces {
-                    let hex_hash = hex::encode(&piece.hash); // converts to hex string
-                    writeln!(file, "{}", hex_hash)?;
-                }
-
-                println!("File length: {}", file_length);
-                println!("Piece length: {}", piece_length);
-                println!("Pieces: {:x?}", pieces);
-                println!("File name: {}", name);
-
-                Ok(connection::InfoHash{
-                    name,
-                    file_length,
-                    piece_length,
-                    pieces
-                })
-
-            }
+            false => Self::generate(path, name, file_cache),
             // A cached file was identified, load it to save time
             true =>{
-                let mut file = OpenOptions::new().read(true).open(file_cache)?;
+                let mut file = OpenOptions::new().read(true).open(&file_cache)?;
 
                 // Load content from the cache file
                 let mut contents = String::new();
@@ -120,17 +85,67 @@ impl connection::InfoHash {
 
                 println!("Loaded {:?} from cache", name.clone());
 
-                Ok(connection::InfoHash{
+                let info_hash = connection::InfoHash{
                     name,
                     file_length,
                     piece_length,
                     pieces
-                })
+                };
+
+                // Caches written before last pieces were hashed without padding cannot verify them
+                let last_matches = match info_hash.pieces.last() {
+                    Some(last) => read_piece_from_file(info_hash.clone(), info_hash.pieces.len() as u32 - 1)
+                        .is_ok_and(|piece| last.hash == hash_piece_data(piece)),
+                    None => true,
+                };
+                if !last_matches {
+                    println!("Cache of {:?} is outdated, regenerating it", info_hash.name);
+                    return Self::generate(path, info_hash.name, file_cache);
+                }
+
+                Ok(info_hash)
             }
         }
 
     }
 
+    // Generate the info hash of a file and save it in its .fileinfo cache
+    fn generate(path: PathBuf, name: String, file_cache: PathBuf) -> std::io::Result<Self> {
+        // Byte length of the file
+        let file_length = path.metadata()?.len();
+        // Size of the pieces
+        let piece_length = Self::get_piece_length(file_length);
+        // Vector of piece hashes
+        let pieces = Self::get_piece_hashes(path, piece_length as usize)?;
+
+        // Create the new cache file to improve load time, replacing an outdated one
+        let mut file = OpenOptions::new().write(true).truncate(true).open(file_cache)?;
+
+        // Write each field as newlines, this helps since we have 2 variable length fields
+        writeln!(file, "name: {}", name.clone())?;
+        writeln!(file, "file_length: {}", file_length.clone())?;
+        writeln!(file, "piece_length: {}", piece_length.clone())?;
+        writeln!(file, "pieces:")?;
+
+        // Write each piece on a newline
+        for piece in &pieces {
+            let hex_hash = hex::encode(&piece.hash); // converts to hex string
+            writeln!(file, "{}", hex_hash)?;
+        }
+
+        println!("File length: {}", file_length);
+        println!("Piece length: {}", piece_length);
+        println!("Pieces: {:x?}", pieces);
+        println!("File name: {}", name);
+
+        Ok(connection::InfoHash{
+            name,
+            file_length,
+            piece_length,
+            pieces
+        })
+    }
+
     // Generates a vector containing 20-byte SHA1 hash of each piece from a file
     fn get_piece_hashes<P: AsRef<Path>>(path: P, piece_length: usize) -> std::io::Result<Vec<connection::PieceHash>>{
         let mut file_reader = BufReader::new(File::open(path)?);
@@ -156,8 +171,8 @@ impl connection::InfoHash {
                 break;
             }
 
-            // Hash the piece of data that was read
-            pieces.push(hash_piece_data(buf.to_vec()));
+            // Hash the piece of data that was read, the last one is usually shorter
+            pieces.push(hash_piece_data(buf[..bytes_read].to_vec()));
         }
         let piece_hashes = pieces.iter().map(|piece| connection::PieceHash{
             hash: piece.to_vec()
@@ -382,6 +397,43 @@ pub(crate) fn is_file_complete(info_hash: connection::InfoHash) -> bool {
     get_info_status(info_hash).has_all_pieces()
 }
 
+// Returns whether the file is complete in resources/files, as opposed to still being downloaded
+fn is_file_built(info_hash: &connection::InfoHash) -> bool {
+    Path::new(&format!("resources/files/{}", info_hash.name)).exists()
+}
+
+// Returns the pieces we have of a file being downloaded, one bit per piece starting with the
+// highest bit of the first byte, or None once the file is complete
+pub(crate) fn get_bitfield(info_hash: &connection::InfoHash) -> Option<Vec<u8>> {
+    if is_file_built(info_hash) {
+        return None;
+    }
+    let status = get_info_status(info_hash.clone());
+    let mut bitfield = vec![0u8; status.pieces_status.len().div_ceil(8)];
+    for (index, _) in status.pieces_status.iter().enumerate().filter(|(_, value)| **value == 1) {
+        bitfield[index / 8] |= 0x80 >> (index % 8);
+    }
+    Some(bitfield)
+}
+
+// Returns whether a bitfield includes a piece, an empty bitfield stands for the whole file
+pub(crate) fn has_piece(bitfield: &[u8], piece_index: u32) -> bool {
+    if bitfield.is_empty() {
+        return true;
+    }
+    bitfield.get(piece_index as usize / 8)
+        .is_some_and(|byte| byte & (0x80 >> (piece_index % 8)) != 0)
+}
+
+// Returns the length of a piece, every piece is piece_length long except for the last one
+pub(crate) fn get_piece_size(info_hash: &connection::InfoHash, piece_index: u32) -> usize {
+    if (piece_index as usize + 1) == info_hash.pieces.len() {
+        info_hash.file_length as usize - (info_hash.piece_length as usize * piece_index as usize)
+    } else {
+        info_hash.piece_length as usize
+    }
+}
+
 // This function writes a piece to a .part file
 pub(crate) fn write_piece_to_part(info_hash: connection::InfoHash, piece: Vec<u8>, piece_index: u32) -> std::io::Result<()> {
     // Verify cache directory exists
@@ -417,18 +469,22 @@ pub(crate) fn write_piece_to_part(info_hash: connection::InfoHash, piece: Vec<u8
 
 }
 
-// Reads piece data from a file given an index
+// Reads piece data from a file given an index. Files still being downloaded are read from their
+// .part file, as long as the piece was verified and written
 pub(crate) fn read_piece_from_file(info_hash: connection::InfoHash, piece_index: u32) -> std::io::Result<Vec<u8>>{
-    let file_path = get_file(info_hash.name.clone());
-    let mut file = OpenOptions::new().read(true).open(&file_path)?;
-
-    let piece_length = if (info_hash.pieces.len() - 1) == piece_index as usize {
-        info_hash.file_length as usize - (info_hash.piece_length as usize * (info_hash.pieces.len() - 1))
-    } else {
-        info_hash.piece_length as usize
+    let file_path = match is_file_built(&info_hash) {
+        true => get_file(info_hash.name.clone()),
+        false => {
+            let status = get_info_status(info_hash.clone());
+            if status.pieces_status.get(piece_index as usize) != Some(&1u8) {
+                return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "piece not downloaded yet"));
+            }
+            get_part_file(info_hash.name.clone())
+        }
     };
+    let mut file = OpenOptions::new().read(true).open(&file_path)?;
 
-    let mut buf= vec![0u8;piece_length];
+    let mut buf= vec![0u8;get_piece_size(&info_hash, piece_index)];
 
     file.seek(SeekFrom::Start((info_hash.piece_length * piece_index) as u64))?;
     file.read_exact(&mut buf)?;
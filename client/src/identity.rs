@@ -0,0 +1,295 @@
+use std::fs::{create_dir_all, read, write, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, DistinguishedName, Error, SignatureScheme};
+use sha2::{Digest, Sha256};
+use crate::noise_channel::NOISE_PARAMS;
+
+/// server name every peer certificate is issued for. Peers are authenticated by
+/// fingerprint, so the name only has to be a valid DNS name both sides agree on.
+pub const PEER_SERVER_NAME: &str = "helpful-serf";
+
+/// The persistent keypairs and self-signed certificate that identify this client to its peers.
+/// They are created on first start and reused afterwards so the published keys stay stable.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    /// DER encoded self-signed certificate
+    cert: Vec<u8>,
+    /// PKCS#8 DER encoded private key
+    key: Vec<u8>,
+    /// static X25519 private key used for Noise handshakes over the TURN relay
+    noise_private: Vec<u8>,
+    /// public half of the Noise key, published to the tracker
+    noise_public: Vec<u8>,
+}
+
+impl Identity {
+
+    ///load_or_create()
+    ///
+    /// function:
+    /// Loads the identity from resources/identity, generating and saving a new
+    /// certificate or Noise keypair if this client has never run before.
+    pub fn load_or_create() -> Result<Identity, Box<dyn std::error::Error>> {
+        let dir = get_identity_dir()?;
+        let cert_path = dir.join("cert.der");
+        let key_path = dir.join("key.der");
+        let noise_private_path = dir.join("noise.key");
+        let noise_public_path = dir.join("noise.pub");
+
+        let (cert, key) = if cert_path.exists() && key_path.exists() {
+            restrict(&key_path)?;
+            (read(&cert_path)?, read(&key_path)?)
+        } else {
+            println!("generating identity certificate");
+            let cert = rcgen::generate_simple_self_signed(vec![PEER_SERVER_NAME.to_string()])?;
+            let (cert, key) = (cert.cert.der().to_vec(), cert.key_pair.serialize_der());
+            write_private(&key_path, &key)?;
+            write(&cert_path, &cert)?;
+            (cert, key)
+        };
+
+        //identities created before relayed transfers were encrypted only need the noise keypair added
+        let (noise_private, noise_public) = if noise_private_path.exists() && noise_public_path.exists() {
+            restrict(&noise_private_path)?;
+            (read(&noise_private_path)?, read(&noise_public_path)?)
+        } else {
+            println!("generating noise keypair");
+            let keypair = snow::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+            write_private(&noise_private_path, &keypair.private)?;
+            write(&noise_public_path, &keypair.public)?;
+            (keypair.private, keypair.public)
+        };
+
+        let identity = Identity { cert, key, noise_private, noise_public };
+        println!("Using identity {}", hex::encode(identity.fingerprint()));
+
+        Ok(identity)
+    }
+
+    ///fingerprint()
+    ///
+    /// function:
+    /// Returns the SHA-256 fingerprint of the certificate, which is what peers pin.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        fingerprint(&self.cert)
+    }
+
+    ///certificate()
+    ///
+    /// function:
+    /// Returns the DER encoded certificate, whose SHA-256 is our fingerprint.
+    pub fn certificate(&self) -> &[u8] {
+        &self.cert
+    }
+
+    ///sign()
+    /// parameters:
+    ///     - message: what to sign
+    ///
+    /// function:
+    /// Signs the message with the certificate's key, which proves to a tracker that the
+    /// fingerprint we register is ours.
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let key = rustls::crypto::ring::sign::any_supported_type(&self.private_key())?;
+        let signer = key.choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256, SignatureScheme::ED25519])
+            .ok_or("the certificate's key cannot sign registrations")?;
+        Ok(signer.sign(message)?)
+    }
+
+    ///cert_chain()
+    ///
+    /// function:
+    /// Returns the certificate chain to present in a TLS handshake.
+    pub fn cert_chain(&self) -> Vec<CertificateDer<'static>> {
+        vec![CertificateDer::from(self.cert.clone())]
+    }
+
+    ///private_key()
+    ///
+    /// function:
+    /// Returns the private key matching cert_chain().
+    pub fn private_key(&self) -> PrivateKeyDer<'static> {
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key.clone()))
+    }
+
+    ///noise_private_key()
+    ///
+    /// function:
+    /// Returns the static key this client proves itself with in a Noise handshake.
+    pub fn noise_private_key(&self) -> &[u8] {
+        &self.noise_private
+    }
+
+    ///noise_public_key()
+    ///
+    /// function:
+    /// Returns the public Noise key peers expect to see in a relayed handshake.
+    pub fn noise_public_key(&self) -> &[u8] {
+        &self.noise_public
+    }
+}
+
+// Computes the SHA-256 fingerprint of a DER encoded certificate
+pub fn fingerprint(cert: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(cert);
+    hasher.finalize().into()
+}
+
+// Writes a private key so that only the user running the client can read it
+fn write_private(path: &Path, key: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    //mode only applies to files it creates
+    restrict(path)?;
+    file.write_all(key)
+}
+
+// Takes away everyone else's access to a private key, keys saved before they were written
+// privately may still be readable by other users
+fn restrict(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+// Create the identity directory holding the client keypair if it doesn't exist
+fn get_identity_dir() -> std::io::Result<PathBuf> {
+    let dir = Path::new("resources/identity");
+    if !dir.exists(){
+        create_dir_all(dir)?;
+    }
+    Ok(dir.to_path_buf())
+}
+
+/// PinnedCertVerifier accepts exactly one server certificate: the one whose fingerprint the
+/// peer published to the tracker. Handshake signatures are still checked against that
+/// certificate, so only the holder of the matching private key can complete the handshake.
+#[derive(Debug)]
+pub struct PinnedCertVerifier {
+    fingerprint: Vec<u8>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedCertVerifier {
+    pub fn new(fingerprint: Vec<u8>) -> PinnedCertVerifier {
+        PinnedCertVerifier {
+            fingerprint,
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if fingerprint(end_entity.as_ref()).as_slice() == self.fingerprint.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// PeerCertVerifier is the seeder side of mutual TLS. Any peer may connect, but it must present a
+/// certificate and prove it holds the matching private key. Who the certificate belongs to is
+/// decided after the handshake by looking its fingerprint up in the tracker's registry.
+#[derive(Debug)]
+pub struct PeerCertVerifier {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl PeerCertVerifier {
+    pub fn new() -> PeerCertVerifier {
+        PeerCertVerifier {
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ClientCertVerifier for PeerCertVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+///peer_fingerprint()
+/// parameters:
+///     - conn: an established QUIC connection
+///
+/// function:
+/// Returns the fingerprint of the certificate the remote side presented in the handshake.
+pub fn peer_fingerprint(conn: &quinn::Connection) -> Option<[u8; 32]> {
+    let certs = conn.peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>().ok()?;
+    certs.first().map(|cert| fingerprint(cert.as_ref()))
+}
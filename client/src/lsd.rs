@@ -0,0 +1,305 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::time::sleep;
+use crate::connection::connection::{Peer, PeerId};
+
+/// the multicast group and port of BitTorrent's local service discovery (BEP 14)
+const LSD_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const LSD_PORT: u16 = 6771;
+
+/// how long a peer is remembered after its last announce, a few of its announce intervals
+const PEER_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// how long a search waits for the peers on the LAN to answer
+const SEARCH_WAIT: Duration = Duration::from_secs(1);
+
+/// most info hashes in one announce, which keeps it well within an unfragmented datagram
+const HASHES_PER_ANNOUNCE: usize = 16;
+
+/// largest announce we read, anything longer is cut off
+const MAX_ANNOUNCE_LEN: usize = 1500;
+
+/// searched for instead of an info hash to have every file on the LAN announced
+const SEARCH_ALL: &str = "*";
+
+/// a client on the LAN seeding a file, and the certificate it has to present
+#[derive(Debug, Clone)]
+pub struct LocalPeer {
+    pub addr: SocketAddrV4,
+    pub fingerprint: [u8; 32],
+}
+
+impl LocalPeer {
+
+    ///peer()
+    ///
+    /// function:
+    /// Returns the peer to connect to. Its private address is all we know of it, so it stands in
+    /// for the public one too.
+    pub fn peer(&self) -> Peer {
+        let ipaddr = u32::from(*self.addr.ip());
+        let port = self.addr.port() as u32;
+        Peer {
+            id: Some(PeerId { ipaddr, port, priv_ipaddr: ipaddr, priv_port: port, relay_ipaddr: 0, relay_port: 0 }),
+            cert_fingerprint: self.fingerprint.to_vec(),
+            ..Default::default()
+        }
+    }
+}
+
+/// the seeders of one file, with when they last announced it
+type FilePeers = HashMap<[u8; 32], (SocketAddrV4, Instant)>;
+
+/// what a BT-SEARCH datagram told us
+#[derive(Debug, Default)]
+struct Announce {
+    port: u16,
+    fingerprint: [u8; 32],
+    /// files the sender seeds
+    hashes: Vec<[u8; 20]>,
+    /// files the sender wants announced, None for every file
+    searches: Vec<Option<[u8; 20]>>,
+}
+
+/// LocalDiscovery finds the clients on our LAN without a tracker, the way BitTorrent's local
+/// service discovery does. Seeders multicast BT-SEARCH announces of their files, on top of BEP 14
+/// they name the certificate they present and can be asked to announce right away with a Search
+/// header. Announces without a Fingerprint header come from other BitTorrent clients and are ignored.
+#[derive(Debug)]
+pub struct LocalDiscovery {
+    socket: UdpSocket,
+    /// the port of our QUIC endpoint, where peers connect to us
+    port: u16,
+    fingerprint: [u8; 32],
+    /// the files we announce and answer searches for
+    shared: Mutex<HashSet<[u8; 20]>>,
+    /// the seeders heard of for each file
+    peers: Mutex<HashMap<[u8; 20], FilePeers>>,
+}
+
+impl LocalDiscovery {
+
+    ///start()
+    /// parameters:
+    ///     - port: the port of our QUIC endpoint
+    ///     - fingerprint: the fingerprint of the certificate we present
+    ///
+    /// function:
+    /// Joins the discovery group and listens for announces in the background. Every client on the
+    /// machine shares the port, so several of ours or other BitTorrent clients can run side by side.
+    pub fn start(port: u16, fingerprint: [u8; 32]) -> io::Result<Arc<LocalDiscovery>> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+        //clients on this machine are on the LAN too
+        socket.set_multicast_loop_v4(true)?;
+        //without the group we can still search and announce, we just never hear back
+        if let Err(e) = socket.join_multicast_v4(&LSD_GROUP, &Ipv4Addr::UNSPECIFIED) {
+            eprintln!("Could not join the LAN discovery group: {}", e);
+        }
+
+        let lsd = Arc::new(LocalDiscovery {
+            socket: UdpSocket::from_std(socket.into())?,
+            port,
+            fingerprint,
+            shared: Mutex::new(HashSet::new()),
+            peers: Mutex::new(HashMap::new()),
+        });
+        tokio::spawn(lsd.clone().receive_loop());
+
+        Ok(lsd)
+    }
+
+    ///announce()
+    /// parameters:
+    ///     - hashes: files we seed
+    ///
+    /// function:
+    /// Tells the LAN we seed the files, and answers searches for them from now on.
+    pub async fn announce(&self, hashes: &[[u8; 20]]) {
+        self.shared.lock().unwrap().extend(hashes);
+        self.send_announces(hashes).await;
+    }
+
+    ///unshare()
+    /// parameters:
+    ///     - hash: a file we no longer seed
+    ///
+    /// function:
+    /// Stops announcing the file. Peers forget us once our last announce of it expires.
+    pub fn unshare(&self, hash: &[u8; 20]) {
+        self.shared.lock().unwrap().remove(hash);
+    }
+
+    ///search()
+    /// parameters:
+    ///     - hash: the file to find seeders of, None for every file on the LAN
+    ///
+    /// function:
+    /// Asks the LAN to announce the file now rather than at its next interval, and waits
+    /// SEARCH_WAIT for the answers.
+    pub async fn search(&self, hash: Option<[u8; 20]>) {
+        let search = hash.map(hex::encode).unwrap_or_else(|| SEARCH_ALL.to_string());
+        let datagram = self.datagram(&[("Search", search)]);
+        if let Err(e) = self.socket.send_to(&datagram, (LSD_GROUP, LSD_PORT)).await {
+            eprintln!("Could not search the LAN: {}", e);
+            return;
+        }
+        sleep(SEARCH_WAIT).await;
+    }
+
+    ///peers()
+    /// parameters:
+    ///     - hash: the file
+    ///
+    /// function:
+    /// Returns the seeders of the file that announced it within PEER_TTL.
+    pub fn peers(&self, hash: &[u8; 20]) -> Vec<LocalPeer> {
+        self.peers.lock().unwrap().get(hash)
+            .map(|peers| peers.iter()
+                .filter(|(_, (_, heard))| heard.elapsed() < PEER_TTL)
+                .map(|(fingerprint, (addr, _))| LocalPeer { addr: *addr, fingerprint: *fingerprint })
+                .collect())
+            .unwrap_or_default()
+    }
+
+    ///knows()
+    /// parameters:
+    ///     - fingerprint: the certificate the peer presented
+    ///     - addr: the address it connected from
+    ///
+    /// function:
+    /// Returns whether the peer announced a file from that address within PEER_TTL.
+    pub fn knows(&self, fingerprint: &[u8; 32], addr: SocketAddr) -> bool {
+        self.peers.lock().unwrap().values()
+            .filter_map(|peers| peers.get(fingerprint))
+            .any(|(announced, heard)| SocketAddr::V4(*announced) == addr && heard.elapsed() < PEER_TTL)
+    }
+
+    ///files()
+    ///
+    /// function:
+    /// Returns every file with a seeder on the LAN.
+    pub fn files(&self) -> Vec<[u8; 20]> {
+        self.peers.lock().unwrap().iter()
+            .filter(|(_, peers)| peers.values().any(|(_, heard)| heard.elapsed() < PEER_TTL))
+            .map(|(hash, _)| *hash)
+            .collect()
+    }
+
+    ///receive_loop()
+    ///
+    /// function:
+    /// Records the seeders the LAN announces and answers searches for files we share. Answers are
+    /// multicast as well, a unicast one could be delivered to any of the clients sharing the
+    /// searcher's port, and the rest of the LAN learns of us along the way.
+    async fn receive_loop(self: Arc<Self>) {
+        let mut buf = [0u8; MAX_ANNOUNCE_LEN];
+        loop {
+            let (len, source) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("LAN discovery socket failed: {}", e);
+                    return;
+                }
+            };
+            let SocketAddr::V4(source) = source else { continue };
+            let Some(announce) = parse(&buf[..len]) else { continue };
+            if announce.fingerprint == self.fingerprint {
+                continue;
+            }
+
+            if !announce.hashes.is_empty() {
+                let addr = SocketAddrV4::new(*source.ip(), announce.port);
+                let now = Instant::now();
+                let mut peers = self.peers.lock().unwrap();
+                for hash in announce.hashes {
+                    let file = peers.entry(hash).or_default();
+                    file.retain(|_, (_, heard)| heard.elapsed() < PEER_TTL);
+                    file.insert(announce.fingerprint, (addr, now));
+                }
+            }
+
+            if !announce.searches.is_empty() {
+                let answer: Vec<[u8; 20]> = self.shared.lock().unwrap().iter()
+                    .filter(|hash| announce.searches.iter().any(|search| search.is_none_or(|wanted| wanted == **hash)))
+                    .copied()
+                    .collect();
+                self.send_announces(&answer).await;
+            }
+        }
+    }
+
+    ///send_announces()
+    /// parameters:
+    ///     - hashes: the files to announce
+    ///
+    /// function:
+    /// Sends the announces of the files, HASHES_PER_ANNOUNCE to a datagram.
+    async fn send_announces(&self, hashes: &[[u8; 20]]) {
+        for chunk in hashes.chunks(HASHES_PER_ANNOUNCE) {
+            let headers: Vec<(&str, String)> = chunk.iter().map(|hash| ("Infohash", hex::encode(hash))).collect();
+            if let Err(e) = self.socket.send_to(&self.datagram(&headers), (LSD_GROUP, LSD_PORT)).await {
+                eprintln!("Could not announce on the LAN: {}", e);
+                return;
+            }
+        }
+    }
+
+    ///datagram()
+    /// parameters:
+    ///     - headers: the headers after the ones every datagram carries
+    ///
+    /// function:
+    /// Builds a BT-SEARCH datagram, which is laid out like an HTTP request.
+    fn datagram(&self, headers: &[(&str, String)]) -> Vec<u8> {
+        let mut datagram = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}:{}\r\nPort: {}\r\nFingerprint: {}\r\n",
+            LSD_GROUP, LSD_PORT, self.port, hex::encode(self.fingerprint),
+        );
+        for (name, value) in headers {
+            datagram.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        datagram.push_str("\r\n");
+        datagram.into_bytes()
+    }
+}
+
+/// parse (
+///     datagram: a datagram received on the discovery port
+/// )
+/// helper function reading a BT-SEARCH datagram, None if it is not one of ours. Header names are
+/// case insensitive and unknown headers are skipped, as in HTTP
+fn parse(datagram: &[u8]) -> Option<Announce> {
+    let text = std::str::from_utf8(datagram).ok()?;
+    let mut lines = text.split("\r\n");
+    if !lines.next()?.starts_with("BT-SEARCH ") {
+        return None;
+    }
+
+    let mut announce = Announce::default();
+    let mut fingerprint = None;
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => announce.port = value.parse().ok()?,
+            "fingerprint" => fingerprint = hex::decode(value).ok()?.try_into().ok(),
+            "infohash" => announce.hashes.push(hex::decode(value).ok()?.try_into().ok()?),
+            "search" if value == SEARCH_ALL => announce.searches.push(None),
+            "search" => announce.searches.push(Some(hex::decode(value).ok()?.try_into().ok()?)),
+            _ => {}
+        }
+    }
+
+    announce.fingerprint = fingerprint?;
+    (announce.port != 0).then_some(announce)
+}
@@ -7,9 +7,25 @@ mod file_handler;
 mod piece_assembler;
 mod file_assembler;
 mod message;
+mod config;
+mod identity;
+mod access;
+mod noise_channel;
+mod stun;
+mod relay_socket;
+mod auth;
+mod traffic;
+mod trackers;
+mod demux_socket;
+mod dht;
+mod pex;
+mod lsd;
+mod downloads;
 
 use std::collections::HashMap;
-use crate::connection::connection::InfoHash;
+use crate::config::ClientConfig;
+use crate::connection::connection::{CatalogQuery, FileSummary, InfoHash};
+use crate::downloads::DownloadStatus;
 use crate::torrent_client::TorrentClient;
 
 
@@ -18,7 +34,7 @@ use crate::torrent_client::TorrentClient;
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     rustls::crypto::CryptoProvider::install_default(rustls::crypto::ring::default_provider()).expect("cannot install default provider");
 
-    let mut torrent_client = TorrentClient::new().await?;
+    let torrent_client = TorrentClient::new(ClientConfig::from_env()).await?;
 
     // let server_conn_clone = server_conn.clone();
     loop {
@@ -26,85 +42,295 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::io::stdin().read_line(&mut input)?;
 
         let command = input.trim();
-        
-        match command {
-            "s" => {
-                println!("Seeding");
-
-                let mut client_clone = torrent_client.clone();
-                tokio::spawn(async move {
-                    client_clone.seeding().await.unwrap();
-                });
 
+        //a failed command, typos included, leaves the client and its downloads running
+        match run_command(&torrent_client, command).await {
+            Ok(true) => {
+                println!("Client successfully delisted. Exiting.....");
+                return Ok(());
             }
-            "r" => {
-                let mut input = String::new();
-                
-                println!("Requesting");
+            Ok(false) => {}
+            Err(e) => println!("Command {} failed: {}", command, e),
+        }
+    }
+
+}
+
+/// runs one command typed into the client, returning whether the client should exit
+async fn run_command(torrent_client: &TorrentClient, command: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    match command {
+        "s" => {
+            println!("Seeding");
+
+            let mut client_clone = torrent_client.clone();
+            tokio::spawn(async move {
+                client_clone.seeding().await.unwrap();
+            });
+
+        }
+        "r" => {
+            let mut input = String::new();
+            
+            println!("Requesting");
+
+            println!("\n\n type part of the file name to search for, or nothing to list every file:");
+            std::io::stdin().read_line(&mut input)?;
+
+            let mut query = CatalogQuery { name: input.trim().to_string(), ..Default::default() };
 
-                let files = torrent_client.get_server_files().await?;
+            //page through the catalog until a file is picked
+            let file_selected = loop {
+                let page = torrent_client.browse_catalog(query.clone()).await?;
 
-                let mut file_selection: HashMap<u16, InfoHash> = HashMap::new();
+                let mut file_selection: HashMap<u16, FileSummary> = HashMap::new();
 
                 let mut i: u16 = 0;
 
-                println!("Num of files: {}", files.len());
+                println!("Num of files: {}", page.files.len());
 
-                for file in files {
-                    println!("Option: {} -> File: {}", i, file.name);
+                for file in page.files {
+                    println!("Option: {} -> File: {} ({} bytes, {} seeders, {} leechers, {} downloads)",
+                        i, file.name, file.file_length, file.seeders, file.leechers, file.completed);
                     file_selection.insert(i, file);
                     i += 1;
                 }
 
-                println!("\n\n type a number for your selection:");
+                if page.next_cursor.is_empty() {
+                    println!("\n\n type a number for your selection:");
+                } else {
+                    println!("\n\n type a number for your selection, or n for the next page:");
+                }
 
+                input.clear();
                 std::io::stdin().read_line(&mut input)?;
-                
+
                 match input.trim() {
-                    "q" => continue,
+                    "q" => break None,
+                    "n" if !page.next_cursor.is_empty() => {
+                        query.cursor = page.next_cursor;
+                        continue;
+                    }
                     _ => {},
                 }
-                
-                let command: u16 = input.trim().parse().expect("Invalid number");
 
-                let file_requested = file_selection.remove(&command).unwrap();
-                println!("You Requested: {}", file_requested.name);
+                let command: u16 = input.trim().parse()?;
+                break Some(file_selection.remove(&command).ok_or("no such option")?);
+            };
 
-                torrent_client.file_request(file_requested).await?;
+            let Some(file_selected) = file_selected else { return Ok(false) };
+            let file_requested = torrent_client.get_info_hash(&file_selected).await?;
+            println!("You Requested: {}", file_requested.name);
+
+            println!("\n\n type a priority, higher downloads first, or nothing for 0:");
+            let priority = read_priority()?;
+
+            //downloads run in the background, "downloads" shows how they are going
+            match torrent_client.queue_download(file_requested, priority).await {
+                Ok(()) => println!("Queued for download"),
+                Err(e) => println!("Could not queue the download: {}", e),
             }
-            "d" => {
-                let mut input = String::new();
-                
-                let files = file_handler::get_info_hashes()?;
-                
-                let mut file_selection: HashMap<u16, InfoHash> = HashMap::new();
-                let mut i: u16 = 0;
-                
-                for file in files {
-                    println!("Option: {} -> File: {}", i, file.1.name);
-                    file_selection.insert(i, file.1);
-                    i += 1;
+        }
+        "downloads" => {
+            for status in torrent_client.download_status().await {
+                println!("{} -> {:?}, priority {}, {}/{} pieces, {} B/s, {} peers",
+                    status.name, status.state, status.priority, status.pieces_done, status.pieces, status.speed, status.peers);
+            }
+        }
+        "pause" | "resume" | "cancel" | "priority" => {
+            let Some(download) = select_download(torrent_client).await? else { return Ok(false) };
+
+            let result = match command {
+                "pause" => torrent_client.pause_download(&download.hash).await,
+                "resume" => torrent_client.resume_download(&download.hash).await,
+                "cancel" => torrent_client.cancel_download(&download.hash).await,
+                _ => {
+                    println!("\n\n type the new priority:");
+                    let priority = read_priority()?;
+                    torrent_client.set_download_priority(&download.hash, priority).await
                 }
+            };
+            match result {
+                Ok(()) => println!("Updated download of {}", download.name),
+                Err(e) => println!("Could not update download of {}: {}", download.name, e),
+            }
+        }
+        "d" => {
+            let files = file_handler::get_info_hashes()?;
+            
+            let mut file_selection: HashMap<u16, InfoHash> = HashMap::new();
+            let mut i: u16 = 0;
+            
+            for file in files {
+                println!("Option: {} -> File: {}", i, file.1.name);
+                file_selection.insert(i, file.1);
+                i += 1;
+            }
 
-                println!("\n\n type a number for your selection:");
+            println!("\n\n type a number for your selection:");
 
-                std::io::stdin().read_line(&mut input)?;
-                let command: u16 = input.trim().parse().expect("Invalid number");
+            let file_requested = read_selection(&mut file_selection)?;
+            println!("You Requested to Delete: {}", file_requested.name);
+            
+            torrent_client.delete_file(file_requested).await?; 
+        }
+        "block" => {
+            println!("\n\n type the certificate fingerprint of the peer to block:");
+
+            let fingerprint = read_fingerprint()?;
+            torrent_client.block_peer(fingerprint).await?;
+            println!("Blocked peer {}", hex::encode(fingerprint));
+        }
+        "allow" => {
+            let files = file_handler::get_info_hashes()?;
 
-                let file_requested = file_selection.remove(&command).unwrap();
-                println!("You Requested to Delete: {}", file_requested.name);
-                
-                torrent_client.delete_file(file_requested).await?; 
+            let mut file_selection: HashMap<u16, InfoHash> = HashMap::new();
+            let mut i: u16 = 0;
+
+            for file in files {
+                println!("Option: {} -> File: {}", i, file.1.name);
+                file_selection.insert(i, file.1);
+                i += 1;
             }
-            "exit" => {
-                torrent_client.remove_client().await?;
-                println!("Client successfully delisted. Exiting.....");
-                return Ok(());
+
+            println!("\n\n type a number for your selection:");
+
+            let file_selected = read_selection(&mut file_selection)?;
+
+            println!("\n\n type the certificate fingerprint of the peer to allow:");
+
+            let fingerprint = read_fingerprint()?;
+            println!("Only allowed peers may now download: {}", file_selected.name);
+            torrent_client.allow_peer(file_selected, fingerprint).await?;
+        }
+        "groups" => {
+            for group in torrent_client.list_groups().await? {
+                println!("Group: {} (owner {}, {} members)", group.name, hex::encode(&group.owner), group.members.len());
             }
-            _ => {
-                println!("Unknown command: {}", command);
+        }
+        "group" => {
+            println!("\n\n type the name of the group to create:");
+
+            let name = read_group()?;
+            torrent_client.create_group(name.clone()).await?;
+            println!("Created group {}", name);
+        }
+        "member" | "kick" => {
+            println!("\n\n type the name of the group:");
+            let group = read_group()?;
+
+            println!("\n\n type the certificate fingerprint of the peer:");
+            let fingerprint = read_fingerprint()?;
+
+            if command == "member" {
+                torrent_client.add_group_member(group, fingerprint).await?;
+                println!("Added {} to the group", hex::encode(fingerprint));
+            } else {
+                torrent_client.remove_group_member(group, fingerprint).await?;
+                println!("Removed {} from the group", hex::encode(fingerprint));
+            }
+        }
+        "share" => {
+            let files = file_handler::get_info_hashes()?;
+
+            let mut file_selection: HashMap<u16, InfoHash> = HashMap::new();
+            let mut i: u16 = 0;
+
+            for file in files {
+                println!("Option: {} -> File: {}", i, file.1.name);
+                file_selection.insert(i, file.1);
+                i += 1;
             }
+
+            println!("\n\n type a number for your selection:");
+
+            let file_selected = read_selection(&mut file_selection)?;
+
+            println!("\n\n type the name of the group to share it with:");
+
+            let group = read_group()?;
+            println!("Only members of {} now see: {}", group, file_selected.name);
+            torrent_client.share_with_group(file_selected, group).await?;
+        }
+        "exit" => {
+            torrent_client.remove_client().await?;
+            return Ok(true);
+        }
+        _ => {
+            println!("Unknown command: {}", command);
         }
     }
 
+    Ok(false)
+}
+
+/// lists the downloads and reads the one picked from stdin, None if there are none
+async fn select_download(torrent_client: &TorrentClient) -> Result<Option<DownloadStatus>, Box<dyn std::error::Error>> {
+    let mut downloads = torrent_client.download_status().await;
+    if downloads.is_empty() {
+        println!("No downloads");
+        return Ok(None);
+    }
+
+    for (i, status) in downloads.iter().enumerate() {
+        println!("Option: {} -> File: {} ({:?})", i, status.name, status.state);
+    }
+    println!("\n\n type a number for your selection:");
+
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    let command: usize = input.trim().parse()?;
+    if command >= downloads.len() {
+        return Err("no such download".into());
+    }
+
+    Ok(Some(downloads.swap_remove(command)))
+}
+
+/// reads a download priority from stdin, nothing stands for 0
+fn read_priority() -> Result<i32, Box<dyn std::error::Error>> {
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+
+    let priority = input.trim();
+    if priority.is_empty() {
+        return Ok(0);
+    }
+
+    Ok(priority.parse()?)
+}
+
+/// reads the number of one of the options from stdin and takes that option
+fn read_selection<T>(selection: &mut HashMap<u16, T>) -> Result<T, Box<dyn std::error::Error>> {
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+
+    let command: u16 = input.trim().parse()?;
+    let selected = selection.remove(&command).ok_or("no such option")?;
+
+    Ok(selected)
+}
+
+/// reads a group name from stdin, names cannot contain whitespace
+fn read_group() -> Result<String, Box<dyn std::error::Error>> {
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+
+    let name = input.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err("group names cannot be empty or contain whitespace".into());
+    }
+
+    Ok(name.to_string())
+}
+
+/// reads a hex encoded certificate fingerprint from stdin
+fn read_fingerprint() -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+
+    let fingerprint = hex::decode(input.trim())?
+        .try_into()
+        .map_err(|_| "fingerprint must be 32 bytes")?;
+
+    Ok(fingerprint)
 }
@@ -1,5 +1,6 @@
+use crate::connection::connection::PeerId;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 #[repr(u8)]
 // Using the message IDs and taking descriptions from the specification.
 pub enum Message{
@@ -27,6 +28,39 @@ pub enum Message{
         begin: u32, // Zero-based byte offset within the piece
         length: u32 // Requested length of the piece
     } = 8,
+
+    // Extension message sharing the peers of a torrent, like BitTorrent's ut_pex. A leecher sends
+    // its peers on a stream of their own and the seeder answers with its peers.
+    Pex{
+        hash: [u8; 20], // The info hash of the torrent
+        added: Vec<PexPeer>, // Peers the sender learned about since its last Pex on this connection
+        dropped: Vec<[u8; 32]> // Certificate fingerprints of peers gone since then
+    } = 20,
+
+    // Extension message asking a seeder for the info of a torrent, like BitTorrent's ut_metadata.
+    // Clients without a tracker to ask get it from the peers they found on the LAN.
+    Metadata{
+        hash: [u8; 20] // The info hash of the torrent
+    } = 21,
+
+    // Extension message answering Metadata.
+    Info{
+        info: Vec<u8> // The protobuf encoded InfoHash, empty if the seeder will not share it
+    } = 22,
+}
+
+// Encoded length of a PexPeer: six u32 addresses, a fingerprint and a flags byte
+const PEX_PEER_LEN: usize = 24 + 32 + 1;
+
+// Flag set on peers that have the whole file
+const PEX_SEED: u8 = 0x02;
+
+// A peer shared over PEX, with everything needed to connect to it directly
+#[derive(Debug, Clone, PartialEq)]
+pub struct PexPeer {
+    pub id: PeerId, // The peer's public, private and relayed addresses
+    pub fingerprint: [u8; 32], // The fingerprint of the certificate it has to present
+    pub seed: bool // Whether it has the whole file
 }
 
 impl Message{
@@ -59,6 +93,34 @@ impl Message{
                 buf.extend_from_slice(&begin.to_be_bytes());
                 buf.extend_from_slice(&length.to_be_bytes());
             }
+            Message::Pex{ hash, added, dropped } => {
+                let len = 1 + 20 + 2 + added.len() * PEX_PEER_LEN + 2 + dropped.len() * 32;
+                buf.extend_from_slice(&(len as u32).to_be_bytes());
+                buf.push(20);
+                buf.extend_from_slice(hash);
+                buf.extend_from_slice(&(added.len() as u16).to_be_bytes());
+                for peer in added {
+                    for addr in [peer.id.ipaddr, peer.id.port, peer.id.priv_ipaddr, peer.id.priv_port, peer.id.relay_ipaddr, peer.id.relay_port] {
+                        buf.extend_from_slice(&addr.to_be_bytes());
+                    }
+                    buf.extend_from_slice(&peer.fingerprint);
+                    buf.push(if peer.seed { PEX_SEED } else { 0 });
+                }
+                buf.extend_from_slice(&(dropped.len() as u16).to_be_bytes());
+                for fingerprint in dropped {
+                    buf.extend_from_slice(fingerprint);
+                }
+            }
+            Message::Metadata{ hash } => {
+                buf.extend_from_slice(&21u32.to_be_bytes());
+                buf.push(21);
+                buf.extend_from_slice(hash);
+            }
+            Message::Info{ info } => {
+                buf.extend_from_slice((1 + info.len() as u32).to_be_bytes().as_ref());
+                buf.push(22);
+                buf.extend_from_slice(info);
+            }
         }
 
         buf
@@ -66,13 +128,18 @@ impl Message{
     
     // Decodes the message
     pub fn decode(buf: Vec<u8>) -> Option<Message> {
-        if buf.len() < 4 {
+        if buf.len() < 5 {
             return None;
         }
 
         let message_id = buf[4];
         
         match message_id { 
+            // relayed messages are not framed by a QUIC stream, so check they are complete
+            6 if buf.len() < 41 => None,
+            7 if buf.len() < 9 => None,
+            8 if buf.len() < 21 => None,
+            21 if buf.len() < 25 => None,
             6 => {
                 let seeder = u32::from_be_bytes(buf[5..9].try_into().unwrap());
                 let index = u32::from_be_bytes(buf[9..13].try_into().unwrap());
@@ -93,10 +160,44 @@ impl Message{
                 let length = u32::from_be_bytes(buf[17..21].try_into().unwrap());
                 Some(Message::Cancel{ seeder, index, begin, length })
             }
+            20 => decode_pex(&buf[5..]),
+            21 => Some(Message::Metadata{ hash: buf[5..25].try_into().unwrap() }),
+            22 => Some(Message::Info{ info: buf[5..].to_vec() }),
             _ => {
                 // TODO Other messages types only as needed
                 None
             }
         }
     }
-}
\ No newline at end of file
+}
+
+// Decodes the body of a Pex message, None if it is cut short
+fn decode_pex(body: &[u8]) -> Option<Message> {
+    let hash = body.get(..20)?.try_into().ok()?;
+    let mut rest = &body[20..];
+
+    let count = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+    let (entries, tail) = rest.get(2..)?.split_at_checked(count * PEX_PEER_LEN)?;
+    let added = entries.chunks_exact(PEX_PEER_LEN).map(|entry| {
+        let addr = |i: usize| u32::from_be_bytes(entry[i * 4..i * 4 + 4].try_into().unwrap());
+        PexPeer {
+            id: PeerId {
+                ipaddr: addr(0),
+                port: addr(1),
+                priv_ipaddr: addr(2),
+                priv_port: addr(3),
+                relay_ipaddr: addr(4),
+                relay_port: addr(5),
+            },
+            fingerprint: entry[24..56].try_into().unwrap(),
+            seed: entry[56] & PEX_SEED != 0,
+        }
+    }).collect();
+    rest = tail;
+
+    let count = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+    let (fingerprints, _) = rest.get(2..)?.split_at_checked(count * 32)?;
+    let dropped = fingerprints.chunks_exact(32).map(|fingerprint| fingerprint.try_into().unwrap()).collect();
+
+    Some(Message::Pex{ hash, added, dropped })
+}
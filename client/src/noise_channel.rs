@@ -0,0 +1,233 @@
+use std::time::Duration;
+use snow::{Builder, HandshakeState, TransportState};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+use tonic::Streaming;
+use crate::connection::connection::{turn_packet::Body, SealedFrame, TurnPacket};
+use crate::identity::Identity;
+
+/// Noise protocol spoken between two peers relaying through TURN. The XX pattern transmits both
+/// static keys inside the handshake, so each side can check them against what the tracker holds.
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// largest message Noise will produce or accept
+const NOISE_MAX_MESSAGE: usize = 65535;
+
+/// the ChaChaPoly tag added to every transport message
+const NOISE_TAG_LEN: usize = 16;
+
+/// how long the peer has to complete the handshake once both sides are on the relay
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type NoiseResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// NoiseChannel is the encrypted session two peers share inside a TURN session.
+/// The relay only ever sees handshake messages and sealed frames, never the peer protocol.
+pub struct NoiseChannel {
+    session_id: String,
+    transport: TransportState,
+}
+
+impl NoiseChannel {
+
+    ///initiate()
+    /// parameters:
+    ///     - identity: our persistent keys
+    ///     - session_id: the TURN session the handshake runs over
+    ///     - expected_key: the Noise key the seeder published to the tracker
+    ///     - outbound: the stream of packets we send to the relay
+    ///     - inbound: the stream of packets the relay forwards to us
+    ///
+    /// function:
+    /// Runs the leecher side of the handshake. It is aborted before we reveal our own static key
+    /// if the seeder does not hold the key it registered.
+    pub async fn initiate(
+        identity: &Identity,
+        session_id: String,
+        expected_key: &[u8],
+        outbound: &mpsc::Sender<TurnPacket>,
+        inbound: &mut Streaming<TurnPacket>,
+    ) -> NoiseResult<NoiseChannel> {
+        let mut handshake = Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(identity.noise_private_key())
+            .build_initiator()?;
+
+        timeout(HANDSHAKE_TIMEOUT, async {
+            // -> e
+            send_handshake(&mut handshake, &session_id, outbound).await?;
+            // <- e, ee, s, es
+            recv_handshake(&mut handshake, inbound).await?;
+            if handshake.get_remote_static() != Some(expected_key) {
+                return Err("seeder did not present the noise key it registered".into());
+            }
+            // -> s, se
+            send_handshake(&mut handshake, &session_id, outbound).await
+        }).await.map_err(|_| "noise handshake timed out")??;
+
+        Ok(NoiseChannel { session_id, transport: handshake.into_transport_mode()? })
+    }
+
+    ///respond()
+    /// parameters:
+    ///     - identity: our persistent keys
+    ///     - session_id: the TURN session the handshake runs over
+    ///     - outbound: the stream of packets we send to the relay
+    ///     - inbound: the stream of packets the relay forwards to us
+    ///
+    /// function:
+    /// Runs the seeder side of the handshake. The leecher's static key is only authenticated
+    /// cryptographically, the caller still has to look up who it belongs to with remote_key().
+    pub async fn respond(
+        identity: &Identity,
+        session_id: String,
+        outbound: &mpsc::Sender<TurnPacket>,
+        inbound: &mut Streaming<TurnPacket>,
+    ) -> NoiseResult<NoiseChannel> {
+        // -> e
+        let first = timeout(HANDSHAKE_TIMEOUT, next_handshake(inbound)).await
+            .map_err(|_| "noise handshake timed out")??;
+        Self::respond_to(identity, session_id, &first, outbound, inbound).await
+    }
+
+    ///respond_to()
+    /// parameters:
+    ///     - identity: our persistent keys
+    ///     - session_id: the TURN session the handshake runs over
+    ///     - first: the leecher's opening handshake message, already taken off inbound
+    ///     - outbound: the stream of packets we send to the relay
+    ///     - inbound: the stream of packets the relay forwards to us
+    ///
+    /// function:
+    /// Finishes the seeder side of a handshake the leecher already opened. Used when a leecher
+    /// resumes a relayed session and starts over with fresh keys.
+    pub async fn respond_to(
+        identity: &Identity,
+        session_id: String,
+        first: &[u8],
+        outbound: &mpsc::Sender<TurnPacket>,
+        inbound: &mut Streaming<TurnPacket>,
+    ) -> NoiseResult<NoiseChannel> {
+        let mut handshake = Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(identity.noise_private_key())
+            .build_responder()?;
+
+        let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+        handshake.read_message(first, &mut buf)?;
+
+        timeout(HANDSHAKE_TIMEOUT, async {
+            // <- e, ee, s, es
+            send_handshake(&mut handshake, &session_id, outbound).await?;
+            // -> s, se
+            recv_handshake(&mut handshake, inbound).await
+        }).await.map_err(|_| "noise handshake timed out")??;
+
+        Ok(NoiseChannel { session_id, transport: handshake.into_transport_mode()? })
+    }
+
+    ///remote_key()
+    ///
+    /// function:
+    /// Returns the static Noise key the other peer proved it holds during the handshake.
+    pub fn remote_key(&self) -> Vec<u8> {
+        self.transport.get_remote_static().map(|key| key.to_vec()).unwrap_or_default()
+    }
+
+    ///seal()
+    /// parameters:
+    ///     - plaintext: an encoded peer-protocol message
+    ///
+    /// function:
+    /// Encrypts a message into a TurnPacket, splitting it into as many Noise messages as needed.
+    pub fn seal(&mut self, plaintext: &[u8]) -> NoiseResult<TurnPacket> {
+        let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+
+        let mut chunks = Vec::new();
+        for chunk in plaintext.chunks(NOISE_MAX_MESSAGE - NOISE_TAG_LEN) {
+            let len = self.transport.write_message(chunk, &mut buf)?;
+            chunks.push(buf[..len].to_vec());
+        }
+
+        Ok(TurnPacket {
+            session_id: self.session_id.clone(),
+            body: Some(Body::Sealed(SealedFrame { chunks })),
+        })
+    }
+
+    ///open()
+    /// parameters:
+    ///     - pkt: a packet relayed from the other peer
+    ///
+    /// function:
+    /// Decrypts and authenticates a sealed packet, returning the message it carries. Any failure means
+    /// the relay tampered with, dropped or reordered our traffic, so the session cannot be trusted afterwards.
+    pub fn open(&mut self, pkt: TurnPacket) -> NoiseResult<Vec<u8>> {
+        let sealed = match pkt.body {
+            Some(Body::Sealed(sealed)) => sealed,
+            _ => return Err("expected an encrypted frame from peer".into()),
+        };
+        let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+
+        let mut plaintext = Vec::new();
+        for chunk in sealed.chunks {
+            let len = self.transport.read_message(&chunk, &mut buf)?;
+            plaintext.extend_from_slice(&buf[..len]);
+        }
+
+        Ok(plaintext)
+    }
+}
+
+/// send_handshake (
+///     handshake: the handshake in progress
+///     session_id: the TURN session we are sending on
+///     outbound: the stream of packets we send to the relay
+/// )
+/// helper function to write our next handshake message and hand it to the relay
+async fn send_handshake(
+    handshake: &mut HandshakeState,
+    session_id: &str,
+    outbound: &mpsc::Sender<TurnPacket>,
+) -> NoiseResult<()> {
+    let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+    let len = handshake.write_message(&[], &mut buf)?;
+
+    outbound.send(TurnPacket {
+        session_id: session_id.to_string(),
+        body: Some(Body::Handshake(buf[..len].to_vec())),
+    }).await.map_err(|_| "relay stream closed during noise handshake")?;
+
+    Ok(())
+}
+
+/// recv_handshake (
+///     handshake: the handshake in progress
+///     inbound: the stream of packets the relay forwards to us
+/// )
+/// helper function to wait for the peer's next handshake message and process it
+async fn recv_handshake(
+    handshake: &mut HandshakeState,
+    inbound: &mut Streaming<TurnPacket>,
+) -> NoiseResult<()> {
+    let msg = next_handshake(inbound).await?;
+    let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+    handshake.read_message(&msg, &mut buf)?;
+    Ok(())
+}
+
+/// next_handshake (
+///     inbound: the stream of packets the relay forwards to us
+/// )
+/// helper function to wait for the peer's next handshake message, skipping the relay's notices
+/// about packets of the previous session that were lost
+async fn next_handshake(inbound: &mut Streaming<TurnPacket>) -> NoiseResult<Vec<u8>> {
+    loop {
+        match inbound.next().await {
+            Some(Ok(TurnPacket { body: Some(Body::Handshake(msg)), .. })) => return Ok(msg),
+            Some(Ok(TurnPacket { body: Some(Body::Undelivered(_)), .. })) => {}
+            Some(Ok(_)) => return Err("unexpected packet during noise handshake".into()),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err("relay stream closed during noise handshake".into()),
+        }
+    }
+}
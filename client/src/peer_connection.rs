@@ -1,156 +1,120 @@
-use std::io::{ErrorKind};
 use std::net::{Ipv4Addr, SocketAddr};
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::sync::{broadcast, mpsc};
 use std::sync::Arc;
-use std::time::Duration;
-use tonic::{Response};
+use std::time::{Duration, Instant};
 use crate::quic_p2p_sender::QuicP2PConn;
 use crate::torrent_client::TorrentClient;
-use crate::connection::connection::{PeerId, ConnectionIds};
-use tokio_util::sync::CancellationToken;
+use crate::connection::connection::{PeerId, ConnectionIds, Peer, PeerIdentity, SeedRequest};
 use tokio::sync::Mutex;
-use tokio::time::{sleep, timeout};
+use quinn::Connection;
+use tokio::time::{interval, sleep, timeout};
 use crate::message::Message;
 use crate::turn_fallback::TurnFallback;
 
+/// how long a leecher's transfer may stall before its upload counts as done. Its
+/// connection stays open in the leecher's pool, but the upload slot goes to the next leecher
+const UPLOAD_IDLE: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub struct PeerConnection {
     pub(crate) server: TorrentClient,
-    pub(crate) pub_socket: Option<UdpSocket>,
-    pub(crate) priv_socket: Option<UdpSocket>,
     pub(crate) self_addr: PeerId,
 }
 
 impl PeerConnection {
 
-    ///Hole punch initiates the hole punching procedure.
-    /// It concurrently sends udp packets while listening to receive a packet
-    /// containing string HELPFUL_SERF. Upon receipt of this String it returns the
-    /// socket used to hole punch so it can be consumed into a stable quic connection.
-    /// If it fails to receive that string in 5 seconds, it times out and returns an error.
-    async fn hole_punch(&mut self, peer_addr: SocketAddr ) -> Result<UdpSocket, Box<dyn std::error::Error + Send + Sync>> {
-
-        //todo maybe don't take this here
-        let socket_arc = Arc::new(self.pub_socket.take().unwrap());
-        let socket_clone = socket_arc.clone();
-        let cancel_token = CancellationToken::new();
-        let token_clone = cancel_token.clone();
-
-        let punch_string = b"HELPFUL_SERF";
-
-        println!("Starting Send to peer ip: {}, port: {}", peer_addr.ip(), peer_addr.port());
-
-        let send_task = tokio::spawn(async move {
-            for _ in 0..200 {
-                let res = socket_arc.send_to(punch_string, peer_addr).await;
-
-                if res.is_err() {
-                    println!("Send Failed: {}", res.err().unwrap());
-                }
-
-                if token_clone.is_cancelled() {
-                    println!("Send Cancelled");
-                    return Ok(socket_arc);
-                }
-
-                sleep(Duration::from_millis(10)).await;
-            }
-            Err(Box::<dyn std::error::Error + Send + Sync>::from("send task finished without succeeding"))
-        });
-
+    ///Creates a PeerConnection that brokers a connection through the client's shared endpoint.
+    pub fn new(server: TorrentClient) -> PeerConnection {
+        let self_addr = server.p2p.self_addr;
+        PeerConnection { server, self_addr }
+    }
 
-        let read_task = tokio::spawn( async move {
-            let mut recv_buf = [0u8; 1024];
-            
-            let result = timeout(Duration::from_secs(5), async {
-                loop {
-                    match socket_clone.recv_from(&mut recv_buf).await {
-                        Ok((n, src)) => {
-                            println!("Received from {}: {:?}", src, &recv_buf[..n]);
-                            if &recv_buf[..n] == punch_string {
-                                // println!("Punched SUCCESS {}", src);
-
-                                cancel_token.cancel();
-                                drop(socket_clone);
-                                return Ok(());
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Recv error: {}", e);
-                            return Err(Box::<dyn std::error::Error + Send + Sync>::from(e));
-                        },
+    ///This method waits until the leecher on a connection is done with its upload, either it closed
+    /// the connection or no stream data went either way for UPLOAD_IDLE. Leechers pool their
+    /// connections until they time out, so waiting on the close would hold an upload slot long
+    /// after the transfer.
+    async fn upload_finished(conn: &Connection) {
+        let stream_frames = || {
+            let stats = conn.stats();
+            stats.frame_rx.stream + stats.frame_tx.stream
+        };
+        let mut ticker = interval(Duration::from_secs(1));
+        let mut frames = stream_frames();
+        let mut last_active = Instant::now();
+        loop {
+            tokio::select! {
+                _ = conn.closed() => return,
+                _ = ticker.tick() => {
+                    let now = stream_frames();
+                    if now != frames {
+                        frames = now;
+                        last_active = Instant::now();
+                    } else if last_active.elapsed() >= UPLOAD_IDLE {
+                        println!("SEEDER: leecher {} went idle, freeing its upload slot", conn.remote_address());
+                        return;
                     }
                 }
-            }).await;
-            
-            match result {
-                Ok(res) => Ok(res),
-                Err(e) => Err(e),
-            } 
-        });
-
-        let socket_arc = match send_task.await {
-            Ok(Ok(socket)) => socket,
-            Ok(Err(e)) => return Err(e),
-            Err(join_err) => return Err(Box::new(join_err)),
-        };
-
-        let read_res = read_task.await?;
-        match read_res {
-            Ok(_) => {
-                let socket = Arc::try_unwrap(socket_arc).unwrap();
-                println!("Punch Success: {:?}", socket);
-                Ok(socket)
             }
-            _ => { Err(Box::new(std::io::Error::new(ErrorKind::TimedOut, "hole punch timed out"))) }
         }
-
     }
 
-
     ///This goes through the connection process for a seeder.
     /// It follows the ICE order of priorities, first attempting to make
-    /// a connection over LAN if possible, then attempting hole-punching, and then
-    /// falling back on our TURN server if all other methods fail.
-    pub async fn seeder_connection(&mut self, res: Response<PeerId>) -> Result<(), Box<dyn std::error::Error>> {
-
-        let mut server_connection = self.server.client.clone();
-        
-        
-        //I had to do some digging here because I wanted to call this immediately and await it later
-        //so that it could send a request to the server before the other peer does.
-        //This returns a future to the output known as a "hot future."
-        //So it sends a server request immediately like a want and blocking waits for the response when I call .await
-        //in case you were curious
-        let hole_punch_handle = server_connection.await_hole_punch_trigger(self.self_addr.clone());
-
-
-        let peer_id = res.into_inner();
+    /// a connection over LAN if possible, then attempting hole-punching, then
+    /// accepting the peer on our relayed address and finally
+    /// falling back on our gRPC TURN service if all other methods fail.
+    /// It returns once the leecher is done, so callers can bound the number of uploads.
+    /// `punch_triggers` carries the triggers pushed over our session and has to be subscribed
+    /// before the request is acknowledged, `credentials` is the leecher's identity if the tracker pushed it.
+    pub async fn seeder_connection(
+        &mut self,
+        request: SeedRequest,
+        credentials: Option<PeerIdentity>,
+        mut punch_triggers: broadcast::Receiver<PeerId>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+
+        let p2p = self.server.p2p.clone();
+
+        let peer_id = request.leecher.ok_or("seed request missing leecher")?;
+        let relay_token = request.relay_token.ok_or("seed request missing relay token")?;
         let pub_ip_addr = Ipv4Addr::from(peer_id.ipaddr);
         let pub_port = peer_id.port as u16;
         let peer_addr = SocketAddr::from((pub_ip_addr, pub_port));
-
-        // create a PeerConnection and get the receiver
+        let lan_peer_addr = SocketAddr::from((Ipv4Addr::from(peer_id.priv_ipaddr), peer_id.priv_port as u16));
 
         println!("peer to send {:?}", peer_id);
 
+        //subscribe before anything is sent so we cannot miss the peer connecting
+        let mut incoming = p2p.subscribe_incoming();
+
+        //let the leecher reach our relayed address straight away, it dials it as soon as hole punching fails
+        let relayed = match p2p.permit_relayed_peer(peer_addr.ip()).await {
+            Ok(relayed) => relayed,
+            Err(e) => {
+                println!("SEEDER: could not permit peer on the relay\n {:?}", e);
+                false
+            }
+        };
+
+        //the trigger may arrive while we wait on the LAN, so pick it up on its own task
+        let hole_punch_handle = tokio::spawn(async move {
+            loop {
+                match punch_triggers.recv().await {
+                    Ok(leecher) if leecher == peer_id => return Ok(()),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        });
 
         // 1. try connection over local NAT
         if self.self_addr.ipaddr == peer_id.ipaddr {
-            //start quick server
-            let socket = self.priv_socket.take().unwrap();
-            let mut p2p_sender = QuicP2PConn::create_quic_server(
-                socket,
-                peer_id,
-                self.server.clone(),
-                Ipv4Addr::from(self.self_addr.priv_ipaddr).to_string(),
-            ).await?;
-            // println!("P2P quic endpoint created successfully");
-            let res = p2p_sender.quic_listener(self.server.file_hashes.clone()).await;
-            match res {
-                Ok(()) => {
+            //the leecher dials our private address, the endpoint just has to accept it
+            match QuicP2PConn::wait_for_peer(&mut incoming, |addr| addr == lan_peer_addr, Duration::from_secs(4)).await {
+                Ok(conn) => {
                     println!("SEEDER: Quic connection within LAN success!");
-                    // conn_success = true
+                    //the upload lasts as long as the leecher keeps requesting
+                    PeerConnection::upload_finished(&conn).await;
                     return Ok(());
                 },
                 Err(e) => {
@@ -165,47 +129,55 @@ impl PeerConnection {
             let res = timeout(timeout_duration, hole_punch_handle).await;
 
             match res {
-                Ok(_) => {
+                Ok(Ok(Ok(_))) => {
                     println!("Seeder got hole punch notif");
-                    if let Ok(socket) = self.hole_punch(peer_addr).await {
-                        // println!("Returned value {:?}", socket);
-                        //start quick server
-
-
-                        let mut p2p_sender = QuicP2PConn::create_quic_server(
-                            socket,
-                            peer_id,
-                            self.server.clone(),
-                            Ipv4Addr::from(self.self_addr.ipaddr).to_string(),
-                        ).await?;
-                        // println!("SEEDER: P2P quic endpoint across NAT created successfully");
-                        match p2p_sender.quic_listener(self.server.file_hashes.clone()).await {
-                            Ok(()) => {
-                                println!("SEEDER: Quic connection across NAT successful!");
-                                return Ok(())
-                            },
-                            Err(_) => {
-                                println!("SEEDER: Connection across NAT after hole punch failed");
-                            }
+                    p2p.hole_punch(peer_addr)?;
+
+                    match QuicP2PConn::wait_for_peer(&mut incoming, |addr| addr == peer_addr, Duration::from_secs(4)).await {
+                        Ok(conn) => {
+                            println!("SEEDER: Quic connection across NAT successful!");
+                            PeerConnection::upload_finished(&conn).await;
+                            return Ok(())
+                        },
+                        Err(_) => {
+                            println!("SEEDER: Connection across NAT after hole punch failed");
                         }
                     }
                 },
-                Err(_) => {
+                _ => {
                     println!("SEEDER: Failed to receive hole punch trigger");
                 }
             }
         }
-            
+
+        //3. accept the leecher on our relayed address, its NAT may give it any port towards the relay
+        if relayed {
+            match QuicP2PConn::wait_for_peer(&mut incoming, |addr| addr.ip() == peer_addr.ip(), Duration::from_secs(6)).await {
+                Ok(conn) => {
+                    println!("SEEDER: Quic connection over UDP TURN relay successful!");
+                    PeerConnection::upload_finished(&conn).await;
+                    return Ok(())
+                },
+                Err(_) => {
+                    println!("SEEDER: Connection over UDP TURN relay failed");
+                }
+            }
+        }
+
         // Fall back connection on TURN
         {
             println!("Trying to seed over TURN...");
             // TURN for sending here
             TurnFallback::start_seeding(
-                self.server.turn.clone(), 
-                self.self_addr, 
-                peer_id, 
+                self.server.turn.clone(),
+                self.server.client.clone(),
+                credentials,
+                self.server.identity.clone(),
+                self.server.access.clone(),
+                self.server.traffic.clone(),
+                relay_token,
                 self.server.file_hashes.clone()
-            ).await?;
+            ).await.map_err(|e| e as Box<dyn std::error::Error>)?;
         }
 
         Ok(())
@@ -214,36 +186,47 @@ impl PeerConnection {
 
 
     ///This goes through the connection process for a leecher (requester)
-    /// It also follows the ICE priority order, starting with LAN,
-    /// then too hole punching across NATs and falling back on TURN
-    pub async fn requester_connection(&mut self, peer_id: PeerId, conn_tx: mpsc::Sender<Message>, request_rx:  mpsc::Receiver<Message> ) -> Result<(), Box<dyn std::error::Error>> {
-        
-        //init the map so cert can be retrieved
+    /// It reuses a pooled connection to the peer when one is still open. Otherwise it
+    /// also follows the ICE priority order, starting with LAN,
+    /// then too hole punching across NATs, then the peer's relayed address and falling back on gRPC TURN.
+    /// Direct connections only succeed if the peer presents the certificate it registered,
+    /// relayed ones only if it completes a Noise handshake with the key it registered.
+    pub async fn requester_connection(&mut self, peer: Peer, conn_tx: mpsc::Sender<Message>, request_rx:  mpsc::Receiver<Message> ) -> Result<(), Box<dyn std::error::Error>> {
+
+        let peer_id = peer.id.ok_or("peer missing connection details")?;
+        let fingerprint = peer.cert_fingerprint.clone();
+
+        let conn_rx = Arc::new(Mutex::new(request_rx));
+        let p2p = self.server.p2p.clone();
+
+        //hold the slot while brokering so a parallel download from this peer waits and shares
+        let slot = p2p.connection_slot(peer_id).await;
+        let mut pooled = slot.lock().await;
+
+        if let Some(conn) = pooled.as_ref().filter(|conn| conn.close_reason().is_none()) {
+            println!("REQUESTER: reusing pooled connection to {:?}", peer_id);
+            QuicP2PConn::start_requesting(conn.clone(), conn_tx, conn_rx);
+            return Ok(());
+        }
+
         let mut server_connection = self.server.client.clone();
-        server_connection.send_file_request(ConnectionIds {
-            connection_peer: Some(peer_id.clone()),
+        let connection_ids = ConnectionIds {
+            connection_peer: Some(peer_id),
             self_id: Some(self.self_addr)
-        }).await?;
+        };
+        let relay_token = server_connection.send_file_request(connection_ids).await?.into_inner();
         println!("peer to send {:?}", peer_id);
-        
-        let conn_rx = Arc::new(Mutex::new(request_rx));
 
         if self.self_addr.ipaddr == peer_id.ipaddr {
             let ip_addr = Ipv4Addr::from(peer_id.priv_ipaddr);
             let port = peer_id.priv_port as u16;
             let lan_peer_addr = SocketAddr::from((ip_addr, port));
-            let priv_socket = self.priv_socket.take().unwrap();
-
-            let mut p2p_conn = QuicP2PConn::create_quic_client(
-                priv_socket,
-                self.self_addr,
-                self.server.clone(),
-            ).await?;
 
-
-            match p2p_conn.connect_to_peer_server(lan_peer_addr, conn_tx.clone(), conn_rx.clone()).await {
-                Ok(()) => {
+            match p2p.connect_to_peer_server(lan_peer_addr, &fingerprint).await {
+                Ok(conn) => {
                     println!("REQUESTER: successful connection within LAN");
+                    *pooled = Some(conn.clone());
+                    QuicP2PConn::start_requesting(conn, conn_tx, conn_rx);
                     return Ok(())
                 },
                 Err(_) => {
@@ -258,59 +241,121 @@ impl PeerConnection {
             let port = peer_id.port as u16;
             let peer_addr = SocketAddr::from((ip_addr, port));
 
-            //add pause to give other peer time to wait on notify handle
-            sleep(Duration::from_millis(1000)).await;
-
-            //initiate hole punch routine with other peer
+            //initiate hole punch routine with other peer, the seeder already waits for the trigger
+            //since it accepted our request
             println!("PeerId {:?}", peer_id);
-            let res = server_connection.init_punch(peer_id).await;
+            let res = server_connection.init_punch(connection_ids).await;
 
             sleep(Duration::from_millis(250)).await;
             match res {
                 Ok(_) => {
-                    if let Ok(socket) = self.hole_punch(peer_addr).await {
-                        let ip_addr = Ipv4Addr::from(peer_id.ipaddr);
-                        let port = peer_id.port as u16;
-                        let peer_addr = SocketAddr::from((ip_addr, port));
-
-                        let mut p2p_conn = QuicP2PConn::create_quic_client(
-                            socket,
-                            self.self_addr,
-                            self.server.clone(),
-                        ).await?;
-
-                        match p2p_conn.connect_to_peer_server(peer_addr, conn_tx.clone(), conn_rx.clone()).await {
-                            Ok(()) => {
-                                println ! ("REQUESTER: successful connection across NAT");
-                                return Ok(())
-                            },
-                            Err(_) => {
-                                println ! ("REQUESTER: connect across NAT failed");
-                            }
+                    p2p.hole_punch(peer_addr)?;
+
+                    match p2p.connect_to_peer_server(peer_addr, &fingerprint).await {
+                        Ok(conn) => {
+                            println ! ("REQUESTER: successful connection across NAT");
+                            *pooled = Some(conn.clone());
+                            QuicP2PConn::start_requesting(conn, conn_tx, conn_rx);
+                            return Ok(())
+                        },
+                        Err(_) => {
+                            println ! ("REQUESTER: connect across NAT failed");
                         }
-                    } 
+                    }
                 },
                 Err(e) => {println!("REQUESTER: Connection across NAT failed\n {:?}", e);},
             }
         }
-            
-        
+
+        //the seeder already permitted us on its relay allocation, so QUIC runs over the relay unchanged
+        if peer_id.relay_port != 0 {
+            let relay_addr = SocketAddr::from((Ipv4Addr::from(peer_id.relay_ipaddr), peer_id.relay_port as u16));
+
+            match p2p.connect_to_peer_server(relay_addr, &fingerprint).await {
+                Ok(conn) => {
+                    println!("REQUESTER: successful connection over UDP TURN relay");
+                    *pooled = Some(conn.clone());
+                    QuicP2PConn::start_requesting(conn, conn_tx, conn_rx);
+                    return Ok(())
+                },
+                Err(_) => {
+                    println!("REQUESTER: connect over UDP TURN relay failed");
+                }
+            }
+        }
+
+        //nothing to share with other downloads over TURN
+        drop(pooled);
+
         {
             // TURN for receiving here
             println!("Trying to leech over TURN...");
             TurnFallback::start_leeching(
-                self.server.turn.clone(), 
-                self.self_addr, 
-                peer_id, 
-                conn_tx, 
+                self.server.turn.clone(),
+                self.server.identity.clone(),
+                relay_token,
+                peer.noise_key,
+                conn_tx,
                 conn_rx
-            ).await?;
+            ).await.map_err(|e| e as Box<dyn std::error::Error>)?;
         }
 
         Ok(())
     }
 
+    ///This connects to a peer found on the DHT, which no tracker of ours can broker a connection to,
+    /// and starts requesting pieces from it.
+    pub async fn direct_connection(&mut self, peer: Peer, conn_tx: mpsc::Sender<Message>, request_rx: mpsc::Receiver<Message>) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = self.direct_connect(&peer).await?;
+        QuicP2PConn::start_requesting(conn, conn_tx, Arc::new(Mutex::new(request_rx)));
+
+        Ok(())
+    }
+
+    ///This opens a connection to a peer without a tracker brokering it, as for peers found on the
+    /// DHT or over PEX. It reuses a pooled connection to the peer when one is still open, otherwise
+    /// it tries the peer's LAN address and then its public address. Pinging the peer's DHT node
+    /// first makes its answer open the peer's NAT towards us, our punch burst opens ours. There is
+    /// no relay to fall back on, so peers behind strict NATs cannot be reached this way.
+    pub async fn direct_connect(&mut self, peer: &Peer) -> Result<Connection, Box<dyn std::error::Error>> {
+
+        let peer_id = peer.id.ok_or("peer missing connection details")?;
+        let fingerprint = &peer.cert_fingerprint;
+        let p2p = self.server.p2p.clone();
+
+        let slot = p2p.connection_slot(peer_id).await;
+        let mut pooled = slot.lock().await;
+
+        if let Some(conn) = pooled.as_ref().filter(|conn| conn.close_reason().is_none()) {
+            println!("REQUESTER: reusing pooled connection to {:?}", peer_id);
+            return Ok(conn.clone());
+        }
+
+        if self.self_addr.ipaddr == peer_id.ipaddr {
+            let lan_peer_addr = SocketAddr::from((Ipv4Addr::from(peer_id.priv_ipaddr), peer_id.priv_port as u16));
 
-    
+            match p2p.connect_to_peer_server(lan_peer_addr, fingerprint).await {
+                Ok(conn) => {
+                    println!("REQUESTER: successful direct connection within LAN");
+                    *pooled = Some(conn.clone());
+                    return Ok(conn)
+                },
+                Err(_) => {
+                    println!("REQUESTER: direct connect over LAN failed");
+                }
+            }
+        }
+
+        let peer_addr = SocketAddr::from((Ipv4Addr::from(peer_id.ipaddr), peer_id.port as u16));
+        if let Err(e) = p2p.dht.ping(peer_addr).await {
+            println!("REQUESTER: peer did not answer our DHT ping\n {:?}", e);
+        }
+        p2p.hole_punch(peer_addr)?;
 
+        let conn = p2p.connect_to_peer_server(peer_addr, fingerprint).await?;
+        println!("REQUESTER: successful direct connection");
+        *pooled = Some(conn.clone());
+
+        Ok(conn)
+    }
 }
\ No newline at end of file
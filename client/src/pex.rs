@@ -0,0 +1,125 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use crate::connection::connection::Peer;
+use crate::message::PexPeer;
+
+/// most peers added in one Pex message, the rest are sent with the next one
+const MAX_PEX_PEERS: usize = 50;
+
+/// most peers remembered for one torrent
+const MAX_TORRENT_PEERS: usize = 200;
+
+/// a peer leechers told us about, with the connections it was heard on
+#[derive(Debug)]
+struct Heard {
+    peer: PexPeer,
+    sources: HashSet<usize>,
+}
+
+/// the peers heard about for one torrent, keyed by certificate fingerprint
+type TorrentPeers = HashMap<[u8; 32], Heard>;
+
+/// PexTable holds the peers the leechers connected to us share for each torrent, so we can pass
+/// them on to our other leechers. A peer is forgotten once every connection that shared it
+/// dropped it or closed. Connections are identified by their QUIC stable id.
+#[derive(Debug, Default)]
+pub struct PexTable {
+    torrents: Mutex<HashMap<[u8; 20], TorrentPeers>>,
+}
+
+impl PexTable {
+
+    ///record()
+    /// parameters:
+    ///     - hash: the torrent
+    ///     - source: the connection the peers were shared on
+    ///     - added: peers the leecher added
+    ///     - dropped: fingerprints of peers the leecher dropped
+    ///
+    /// function:
+    /// Applies a leecher's Pex message to the torrent's peers.
+    pub fn record(&self, hash: [u8; 20], source: usize, added: Vec<PexPeer>, dropped: &[[u8; 32]]) {
+        let mut torrents = self.torrents.lock().unwrap();
+        let peers = torrents.entry(hash).or_default();
+
+        for fingerprint in dropped {
+            if let Some(heard) = peers.get_mut(fingerprint) {
+                heard.sources.remove(&source);
+            }
+        }
+        peers.retain(|_, heard| !heard.sources.is_empty());
+
+        for peer in added {
+            if peers.len() >= MAX_TORRENT_PEERS && !peers.contains_key(&peer.fingerprint) {
+                break;
+            }
+            let heard = peers.entry(peer.fingerprint).or_insert_with(|| Heard { peer: peer.clone(), sources: HashSet::new() });
+            heard.peer = peer;
+            heard.sources.insert(source);
+        }
+    }
+
+    ///peers()
+    /// parameters:
+    ///     - hash: the torrent
+    ///     - exclude: the fingerprint of the leecher asking, it is not told about itself
+    ///
+    /// function:
+    /// Returns the peers shared with us for the torrent.
+    pub fn peers(&self, hash: &[u8; 20], exclude: &[u8; 32]) -> Vec<PexPeer> {
+        self.torrents.lock().unwrap().get(hash)
+            .map(|peers| peers.values()
+                .filter(|heard| heard.peer.fingerprint != *exclude)
+                .map(|heard| heard.peer.clone())
+                .collect())
+            .unwrap_or_default()
+    }
+
+    ///forget()
+    /// parameters:
+    ///     - source: a connection that closed
+    ///
+    /// function:
+    /// Drops everything only that connection shared.
+    pub fn forget(&self, source: usize) {
+        let mut torrents = self.torrents.lock().unwrap();
+        for peers in torrents.values_mut() {
+            peers.values_mut().for_each(|heard| { heard.sources.remove(&source); });
+            peers.retain(|_, heard| !heard.sources.is_empty());
+        }
+        torrents.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+/// diff (
+///     current: the peers to share on a connection now
+///     shared: the fingerprints shared on the connection so far, updated to what this diff shares
+/// )
+/// helper function returning the peers added and dropped since the last Pex on a connection.
+/// At most MAX_PEX_PEERS are added at once, the others are left for the next Pex
+pub fn diff(current: Vec<PexPeer>, shared: &mut HashSet<[u8; 32]>) -> (Vec<PexPeer>, Vec<[u8; 32]>) {
+    let fingerprints: HashSet<[u8; 32]> = current.iter().map(|peer| peer.fingerprint).collect();
+    let dropped: Vec<[u8; 32]> = shared.iter().filter(|fingerprint| !fingerprints.contains(*fingerprint)).copied().collect();
+    let added: Vec<PexPeer> = current.into_iter()
+        .filter(|peer| !shared.contains(&peer.fingerprint))
+        .take(MAX_PEX_PEERS)
+        .collect();
+
+    for fingerprint in &dropped {
+        shared.remove(fingerprint);
+    }
+    shared.extend(added.iter().map(|peer| peer.fingerprint));
+    (added, dropped)
+}
+
+/// pex_peer (
+///     peer: a peer a tracker or the DHT listed
+/// )
+/// helper function to turn a listed peer into the one we share, None if it cannot be connected to
+pub fn pex_peer(peer: &Peer) -> Option<PexPeer> {
+    Some(PexPeer {
+        id: peer.id?,
+        fingerprint: peer.cert_fingerprint.as_slice().try_into().ok()?,
+        seed: peer.bitfield.is_empty(),
+    })
+}
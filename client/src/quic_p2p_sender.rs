@@ -1,196 +1,546 @@
-use std::collections::HashMap;
-use std::net::{ SocketAddr};
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use tokio::sync::mpsc::Receiver;
 use std::time::Duration;
+use local_ip_address::local_ip;
 use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
 use quinn::{Connection, Endpoint, TokioRuntime};
-use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
-use crate::torrent_client::TorrentClient;
-use crate::connection::connection::{PeerId, CertMessage, Cert, InfoHash};
+use tonic::Code;
+use stunclient::StunClient;
+use crate::trackers::TrackerLinks;
+use crate::access::{AccessPolicy, AuthenticatedPeer};
+use crate::connection::connection::{PeerId, InfoHash, PeerFingerprint, RelayCredentials};
+use crate::demux_socket::DemuxSocket;
+use crate::dht::{node_id, Dht};
+use crate::lsd::LocalDiscovery;
+use crate::identity::{peer_fingerprint, Identity, PeerCertVerifier, PinnedCertVerifier, PEER_SERVER_NAME};
 use crate::message::Message;
-use tokio::{net::UdpSocket as TokioUdpSocket};
+use crate::pex::{diff, PexTable};
+use crate::relay_socket::RelaySocket;
+use crate::traffic::Traffic;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::{Mutex, RwLock};
-use tokio::task::JoinHandle;
-use tokio::time::timeout;
-use tonic::Request;
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+use quinn::RecvStream;
 use crate::file_handler::{read_piece_from_file};
+use crate::file_assembler::REQUEST_WINDOW;
+
+/// the longest message a peer may send us on a stream, requests are 41 bytes and Pex messages
+/// a few kilobytes
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// the longest Info answer we accept, enough for the piece hashes of files of several hundred gigabytes
+const MAX_INFO_LEN: usize = 16 * 1024 * 1024;
+
+/// A pooled connection slot. The inner lock is held while a connection to that peer is
+/// being brokered so concurrent downloads from the same seeder wait for (and then share)
+/// a single QUIC connection instead of hole punching twice.
+pub type ConnectionSlot = Arc<Mutex<Option<Connection>>>;
+
+/// Discovery is where a peer no tracker knows has to have found us to be served: our DHT node
+/// or the LAN. Relayed connections are always brokered by a tracker and never get one.
+#[derive(Debug, Clone)]
+struct Discovery {
+    dht: Arc<Dht>,
+    lan: Arc<LocalDiscovery>,
+}
+
+impl Discovery {
+
+    ///knows
+    ///
+    /// parameters:
+    ///    - fingerprint: the certificate the peer presented
+    ///    - addr: the address the peer connected from
+    ///
+    /// function:
+    /// Returns whether the peer is a DHT node or a LAN seeder at the address it connected from.
+    /// DHT nodes share the socket of their QUIC endpoint, so their node id and address match.
+    fn knows(&self, fingerprint: &[u8; 32], addr: SocketAddr) -> bool {
+        self.dht.knows(&node_id(fingerprint), addr) || self.lan.knows(fingerprint, addr)
+    }
+}
 
+/// QuicP2PConn is the long-lived QUIC endpoint of this client.
+/// Every peer connection, incoming or outgoing, is multiplexed over its single UDP socket.
+#[derive(Debug)]
 pub struct QuicP2PConn {
     endpoint: Endpoint,
+    ///a clone of the endpoint socket used to send raw hole punching datagrams
+    punch_socket: std::net::UdpSocket,
+    ///public, private and relayed address of the endpoint as registered with the server
+    pub(crate) self_addr: PeerId,
+    ///allocation on the server's UDP TURN relay, peers we cannot reach directly connect to it
+    relay: Option<Arc<RelaySocket>>,
+    ///our node of the DHT, it shares the endpoint socket
+    pub(crate) dht: Arc<Dht>,
+    ///discovery of the seeders on the LAN, announcing the endpoint's port
+    pub(crate) lan: Arc<LocalDiscovery>,
+    ///the certificate we authenticate with when connecting to other peers
+    identity: Identity,
+    ///outgoing connections keyed by the peer they were made to
+    connections: Mutex<HashMap<PeerId, ConnectionSlot>>,
+    ///every accepted connection is broadcast so seeders can wait for the peer they brokered
+    incoming: broadcast::Sender<Connection>,
 }
 
 impl QuicP2PConn {
 
-    ///create_quic_server
+    ///new
     ///
     /// parameters:
-    ///    - TokioUdpSocket: resembles the socket to consume in the connection
-    ///    - peer_id: resembles the peer to accept connections from
-    ///    - server: the connection to introducer server to send certificate
-    ///    - cert_ip: the ip to build the certificate off (self ip)
+    ///    - file_map: the map used to get file information when it is requested by peer
+    ///    - identity: the persistent certificate peers pin when connecting to us
+    ///    - trackers: the trackers used to look up who a connecting peer is
+    ///    - access: the rules deciding which peers may download which files
+    ///    - traffic: counts the bytes of every piece we serve
+    ///    - relay_credentials: credentials for the server's UDP TURN relay, if it runs one
+    ///    - allow_unregistered: whether peers no tracker knows are served when they found us on the DHT or the LAN
     ///
     /// function:
-    /// This creates a quinn server endpoint to accept a quic connection.
-    pub(crate) async fn create_quic_server(
-        socket: TokioUdpSocket,
-        peer_id: PeerId,
-        server: TorrentClient,
-        cert_ip: String,
+    /// This binds the client's UDP socket, discovers its public address over STUN and creates
+    /// a quinn endpoint that acts as both client and server. Without a STUN server to reach, as on
+    /// a LAN without internet access, the private address stands in for the public one. It spawns an accept loop which
+    /// serves file pieces to every peer that connects for the lifetime of the endpoint.
+    /// If a relay is available a second endpoint accepts peers on a relayed address as well.
+    /// The socket is shared with our DHT node, which has to be bootstrapped before it finds anyone.
+    /// LAN discovery announces the same port.
+    pub(crate) async fn new(
+        file_map: Arc<RwLock<HashMap<[u8; 20], InfoHash>>>,
+        identity: &Identity,
+        trackers: TrackerLinks,
+        access: Arc<RwLock<AccessPolicy>>,
+        traffic: Arc<Traffic>,
+        relay_credentials: Option<RelayCredentials>,
+        allow_unregistered: bool,
     ) -> Result<QuicP2PConn, Box<dyn std::error::Error>> {
+        //bind port
+        let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
 
-        let (certs, key) = {
-            println!("generating self-signed certificate");
-            // let cert_ip = Ipv4Addr::from(torrent_client.self_addr.ipaddr).to_string();
-            let cert = rcgen::generate_simple_self_signed(vec![cert_ip])?;
-            let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
-            let cert: CertificateDer = cert.cert.into();
-
-            (vec![cert], key.secret_pkcs8_der().to_vec())
+        // Get the local IP address of this machine (defaults to Ipv4)
+        let priv_ipaddr = match local_ip()? {
+            IpAddr::V4(v4) => v4,
+            IpAddr::V6(_) => return Err(Box::new(std::io::Error::new(ErrorKind::Other, "Cannot convert IPv6 to u32"))),
         };
 
-        //send certificate to client here
-        let cert_bytes = certs[0].to_vec();
-        let mut server_connection = server.client.clone();
-        let request = Request::new( CertMessage{
-            peer_id: Some(peer_id),
-            cert: Some(Cert { certificate: cert_bytes }),
-        });
-        println!("Server sent client certificate!");
+        //the socket is bound on all interfaces so LAN peers reach the same port
+        let priv_port = socket.local_addr()?.port();
 
+        println!("My private IP {:?}", priv_ipaddr);
+        println!("My private port is {}", priv_port);
 
-        server_connection.send_cert(request).await?;
+        //get public facing id
+        let external_addr = match QuicP2PConn::query_external_address(&socket) {
+            Ok(external_addr) => external_addr,
+            Err(e) => {
+                eprintln!("Could not discover our public address, only peers on the LAN can reach us: {}", e);
+                SocketAddr::from((priv_ipaddr, priv_port))
+            }
+        };
+
+        let pub_ipaddr = match external_addr.ip() {
+            IpAddr::V4(v4) => v4,
+            IpAddr::V6(_) => return Err(Box::new(std::io::Error::new(ErrorKind::Other, "Cannot convert IPv6 to u32"))),
+        };
+
+        println!("My public IP {}", external_addr.ip());
+        println!("My public PORT {}", external_addr.port());
 
+        let mut self_addr = PeerId {
+            ipaddr: u32::from_be_bytes(pub_ipaddr.octets()),
+            port: external_addr.port() as u32,
+            priv_ipaddr: u32::from_be_bytes(priv_ipaddr.octets()),
+            priv_port: priv_port as u32,
+            relay_ipaddr: 0,
+            relay_port: 0,
+        };
 
+        //peers pin the fingerprint of this certificate, so it is the same for every address and run.
+        //connecting peers must present their own certificate so we know who we are serving
         let mut server_crypto = rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, PrivateKeyDer::try_from(key.clone())?)?;
+            .with_client_cert_verifier(Arc::new(PeerCertVerifier::new()))
+            .with_single_cert(identity.cert_chain(), identity.private_key())?;
         //set my custom expected ALPN (Application-Layer Protocol Negotiation)
         server_crypto.alpn_protocols = vec![b"helpful-serf-p2p".to_vec()];
 
-        //todo consider adding keylogging
-
         let server_config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_crypto)?));
 
-        //todo consider setting max-uni-streams if that's necessary for us (control security)
-        // let socket = socket_arc.take().into();
-
-        let endpoint = Endpoint::new(
-            quinn::EndpointConfig::default(),
-            Some(server_config),
-            socket.into_std().unwrap(),
+        let punch_socket = socket.try_clone()?;
+        socket.set_nonblocking(true)?;
+        let (socket, dht_datagrams) = DemuxSocket::new(socket)?;
+
+        //DHT datagrams are told apart by the fixed bit of QUIC packets, so it must never be greased
+        let mut endpoint_config = quinn::EndpointConfig::default();
+        endpoint_config.grease_quic_bit(false);
+        let endpoint = Endpoint::new_with_abstract_socket(
+            endpoint_config,
+            Some(server_config.clone()),
+            socket.clone(),
             Arc::new(TokioRuntime),
         )?;
+        let dht = Dht::start(node_id(&identity.fingerprint()), socket, dht_datagrams);
+        let lan = LocalDiscovery::start(priv_port, identity.fingerprint())?;
+
+        let (incoming, _) = broadcast::channel(16);
+        let pex = Arc::new(PexTable::default());
+
+        //the relay is a last resort, a client without one still works for every other path
+        let relay = match relay_credentials {
+            Some(credentials) => match RelaySocket::allocate(credentials, trackers.clone()).await {
+                Ok(relay) => Some(relay),
+                Err(e) => {
+                    eprintln!("Could not allocate a relayed address: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        if let Some(relay) = &relay {
+            if let SocketAddr::V4(relayed_addr) = relay.relayed_addr() {
+                self_addr.relay_ipaddr = u32::from_be_bytes(relayed_addr.ip().octets());
+                self_addr.relay_port = relayed_addr.port() as u32;
+            }
+
+            //connections accepted on the relay are served exactly like direct ones
+            let relay_endpoint = Endpoint::new_with_abstract_socket(
+                quinn::EndpointConfig::default(),
+                Some(server_config),
+                relay.clone(),
+                Arc::new(TokioRuntime),
+            )?;
+            tokio::spawn(QuicP2PConn::accept_loop(relay_endpoint, incoming.clone(), file_map.clone(), trackers.clone(), access.clone(), traffic.clone(), pex.clone(), None));
+        }
+
+        let discovery = allow_unregistered.then(|| Discovery { dht: dht.clone(), lan: lan.clone() });
+        tokio::spawn(QuicP2PConn::accept_loop(endpoint.clone(), incoming.clone(), file_map, trackers, access, traffic, pex, discovery));
 
         Ok(
             QuicP2PConn {
                 endpoint,
+                punch_socket,
+                self_addr,
+                relay,
+                dht,
+                lan,
+                identity: identity.clone(),
+                connections: Mutex::new(HashMap::new()),
+                incoming,
             }
         )
     }
 
-    ///create_quic_client
+    ///query_external_address
     ///
     /// parameters:
-    ///    - TokioUdpSocket: resembles the socket to consume in the connection
-    ///    - self_addr: holds data of own connection ips
-    ///    - server: the connection to introducer server to get certificate from
+    ///    - socket: the socket whose public address we want
     ///
     /// function:
-    /// This creates a quinn client endpoint to create a quic connection.
-    pub (crate) async fn create_quic_client (
-        socket: TokioUdpSocket,
-        self_addr: PeerId,
-        server: TorrentClient,
-    ) -> Result<QuicP2PConn, Box<dyn std::error::Error>> {
-        let mut server_connection = server.client.clone();
+    /// Asks a public STUN server which address our socket's packets come from.
+    fn query_external_address(socket: &std::net::UdpSocket) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+        let stun_server = "stun.l.google.com:19302".to_socket_addrs()?.find(|x| x.is_ipv4())
+            .ok_or("could not resolve stun server")?;
+        let client = StunClient::new(stun_server);
+        Ok(client.query_external_address(socket)?)
+    }
+
+    ///subscribe_incoming
+    ///
+    /// function:
+    /// Returns a receiver of every connection accepted from now on. Subscribe before
+    /// brokering a connection so the peer cannot connect before we start listening.
+    pub(crate) fn subscribe_incoming(&self) -> broadcast::Receiver<Connection> {
+        self.incoming.subscribe()
+    }
+
+    ///connection_slot
+    ///
+    /// parameters:
+    ///    - peer_id: the peer the connection is made to
+    ///
+    /// function:
+    /// Returns the pool slot for a peer, creating an empty one if this is the first connection.
+    pub(crate) async fn connection_slot(&self, peer_id: PeerId) -> ConnectionSlot {
+        self.connections.lock().await
+            .entry(peer_id)
+            .or_insert_with(|| Arc::new(Mutex::new(None)))
+            .clone()
+    }
+
+    ///accept_loop
+    ///
+    /// parameters:
+    ///    - endpoint: the endpoint to accept connections on
+    ///    - incoming: broadcasts every successfully accepted connection
+    ///    - file_map: this is the map used to get file information when it is requested by peer
+    ///    - trackers: the trackers used to authenticate peers
+    ///    - access: the rules applied to every piece request
+    ///    - traffic: counts the bytes of every piece we serve
+    ///    - pex: the peers our leechers shared, a closed connection's are forgotten
+    ///    - discovery: where peers no tracker knows may have found us, None to refuse them all
+    ///
+    /// function:
+    /// Accepts connections until the endpoint is closed. Each handshake runs on its own task so
+    /// one slow peer does not hold up the others. Peers that cannot be authenticated are
+    /// disconnected, every other connection is served by send_data.
+    #[allow(clippy::too_many_arguments)]
+    async fn accept_loop(
+        endpoint: Endpoint,
+        incoming: broadcast::Sender<Connection>,
+        file_map: Arc<RwLock<HashMap<[u8; 20], InfoHash>>>,
+        trackers: TrackerLinks,
+        access: Arc<RwLock<AccessPolicy>>,
+        traffic: Arc<Traffic>,
+        pex: Arc<PexTable>,
+        discovery: Option<Discovery>,
+    ) {
+        println!("Listening on {:?}", endpoint.local_addr());
+        while let Some(conn_listener) = endpoint.accept().await {
+            let incoming = incoming.clone();
+            let file_map = file_map.clone();
+            let trackers = trackers.clone();
+            let access = access.clone();
+            let traffic = traffic.clone();
+            let pex = pex.clone();
+            let discovery = discovery.clone();
+            tokio::spawn(async move {
+                //establish timeout duration to drop handshakes that never complete
+                let timeout_duration = Duration::from_secs(4);
+                let conn = match timeout(timeout_duration, conn_listener).await {
+                    Ok(Ok(conn)) => conn,
+                    Ok(Err(e)) => {
+                        eprintln!("Failed to accept connection: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        eprintln!("Timed out accepting connection");
+                        return;
+                    }
+                };
+
+                let peer = match QuicP2PConn::authenticate(&conn, &trackers, discovery.as_ref()).await {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        eprintln!("Rejecting connection from {}: {}", conn.remote_address(), e);
+                        conn.close(1u32.into(), b"peer not authenticated");
+                        return;
+                    }
+                };
+                println!("Peer {} authenticated as {} ({})", conn.remote_address(), peer.describe(), hex::encode(peer.fingerprint));
+
+                //nobody waiting on this connection is not an error
+                let _ = incoming.send(conn.clone());
 
-        let request = Request::new(self_addr);
-        let cert_res = server_connection.get_cert(request).await?.into_inner();
-        let cert_bytes = cert_res.certificate;
+                let source = conn.stable_id();
+                let res = QuicP2PConn::send_data(conn, peer, file_map, access, traffic, &pex).await;
+                if res.is_err() {
+                    eprintln!("Failed to get connection request Listener: {:?}", res);
+                }
+                pex.forget(source);
+            });
+        }
+        println!("Endpoint closed, accept loop finishing");
+    }
 
-        println!("Client received certificate {:?}", cert_bytes);
+    ///authenticate
+    ///
+    /// parameters:
+    ///    - conn: a freshly accepted connection
+    ///    - trackers: the trackers holding the client registries
+    ///    - discovery: where peers no tracker knows may have found us, None to refuse them all
+    ///
+    /// function:
+    /// Looks up the certificate the peer presented in our trackers' registries, in order, and
+    /// returns the identity it is registered under with the first tracker that knows it. The peer
+    /// may have reached us through any of them. A peer is only served unregistered when every
+    /// tracker answered that it does not know it and the peer found us on the DHT or the LAN, a
+    /// tracker we could not ask might have known it under an identity our rules apply to.
+    async fn authenticate(
+        conn: &Connection,
+        trackers: &TrackerLinks,
+        discovery: Option<&Discovery>,
+    ) -> Result<AuthenticatedPeer, Box<dyn std::error::Error + Send + Sync>> {
+        let fingerprint = peer_fingerprint(conn).ok_or("peer presented no certificate")?;
+
+        let links = trackers.read().await.clone();
+        for link in links {
+            let identity = link.client.clone().verify_peer(PeerFingerprint {
+                cert_fingerprint: fingerprint.to_vec(),
+                noise_key: Vec::new(),
+            }).await;
+            match identity {
+                Ok(identity) => return Ok(AuthenticatedPeer::from_identity(identity.into_inner())?),
+                Err(status) if status.code() == Code::NotFound => {}
+                Err(status) => return Err(format!("could not look the peer up with {}: {}", link.url, status.message()).into()),
+            }
+        }
 
-        let mut roots = rustls::RootCertStore::empty();
+        match discovery {
+            Some(discovery) if discovery.knows(&fingerprint, conn.remote_address()) => Ok(AuthenticatedPeer::unregistered(fingerprint)),
+            Some(_) => Err("peer is not registered and did not find us on the DHT or the LAN".into()),
+            None => Err("peer is not registered with any of our trackers".into()),
+        }
+    }
 
-        roots.add(CertificateDer::try_from(&cert_bytes[..])?)?;
+    ///wait_for_peer
+    ///
+    /// parameters:
+    ///    - incoming: receiver obtained from subscribe_incoming()
+    ///    - is_peer: whether a connection's remote address belongs to the expected peer
+    ///    - timeout_duration: how long to wait for the peer
+    ///
+    /// function:
+    /// Waits until a connection from the expected peer has been accepted.
+    pub(crate) async fn wait_for_peer(
+        incoming: &mut broadcast::Receiver<Connection>,
+        is_peer: impl Fn(SocketAddr) -> bool,
+        timeout_duration: Duration,
+    ) -> Result<Connection, Box<dyn std::error::Error + Send + Sync>> {
+        let conn = timeout(timeout_duration, async {
+            loop {
+                match incoming.recv().await {
+                    Ok(conn) if is_peer(conn.remote_address()) => return Ok(conn),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }).await??;
 
+        Ok(conn)
+    }
+
+    ///permit_relayed_peer
+    ///
+    /// parameters:
+    ///    - peer_ip: the public IP of a peer that may connect to our relayed address
+    ///
+    /// function:
+    /// Lets the peer reach us through the relay. Returns false if we have no relayed address.
+    pub(crate) async fn permit_relayed_peer(&self, peer_ip: IpAddr) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        match &self.relay {
+            Some(relay) => {
+                relay.permit(peer_ip).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    ///hole_punch
+    ///
+    /// parameters:
+    ///    - peer_addr: the public address of the peer to punch towards
+    ///
+    /// function:
+    /// Sends a burst of udp packets containing HELPFUL_SERF from the endpoint socket so our NAT
+    /// opens a mapping for the peer. The burst runs in the background; the QUIC handshake that
+    /// follows on the same socket is what confirms the hole was punched.
+    pub(crate) fn hole_punch(&self, peer_addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+        let socket = self.punch_socket.try_clone()?;
+        let punch_string = b"HELPFUL_SERF";
+
+        println!("Starting Send to peer ip: {}, port: {}", peer_addr.ip(), peer_addr.port());
+
+        tokio::spawn(async move {
+            for _ in 0..200 {
+                //the socket is non-blocking, a full send buffer just skips this packet
+                if let Err(e) = socket.send_to(punch_string, peer_addr) {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        println!("Send Failed: {}", e);
+                    }
+                }
+
+                sleep(Duration::from_millis(10)).await;
+            }
+        });
+
+        Ok(())
+    }
+
+    ///connect_to_peer_server
+    ///
+    /// parameter:
+    ///     - peer_addr: the is the address of the peer to connect to
+    ///     - fingerprint: the certificate fingerprint the peer published to the server
+    ///
+    /// function:
+    /// This method tries to connect to the peer quic server from the shared endpoint, only accepting
+    /// the certificate matching the pinned fingerprint. It will timeout and return a failure
+    /// in 4 seconds if it does not succeed in making a connection.
+    pub(crate) async fn connect_to_peer_server(
+        &self,
+        peer_addr: SocketAddr,
+        fingerprint: &[u8],
+    ) -> Result<Connection, Box<dyn std::error::Error>> {
         let mut client_crypto = rustls::ClientConfig::builder()
-            .with_root_certificates(roots)
-            .with_no_client_auth();
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier::new(fingerprint.to_vec())))
+            .with_client_auth_cert(self.identity.cert_chain(), self.identity.private_key())?;
 
         //set crypto with custom protocol type
         client_crypto.alpn_protocols = vec![b"helpful-serf-p2p".to_vec()];
 
         let client_config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_crypto)?));
 
-        let mut endpoint = Endpoint::new(
-            quinn::EndpointConfig::default(),
-            None,
-            socket.into_std().unwrap(),
-            Arc::new(TokioRuntime),
-        )?;
+        let timeout_duration = Duration::from_secs(4);
 
-        endpoint.set_default_client_config(client_config);
+        let connecting = self.endpoint.connect_with(client_config, peer_addr, PEER_SERVER_NAME)?;
+        let conn = timeout(timeout_duration, connecting).await??;
 
-        Ok( QuicP2PConn {
-            endpoint,
-        })
+        Ok(conn)
     }
-    
-    ///quic_listener
+
+    ///start_requesting
     ///
     /// parameters:
-    ///    - file_map: this is the map used to get file information when it is requested by peer
+    ///    - conn: the (possibly shared) connection to send requests over
+    ///    - conn_tx: this is the sending end of the file assembler channel from which to send responses
+    ///    - conn_rx: this is the receiving end of the file assembler channel from which to get requests from
     ///
     /// function:
-    /// This method listens for a connection request, spawning off the send_data task when a connection
-    /// is successfully made. it times out after 4 seconds if no connection request is made.
-    pub(crate) async fn quic_listener(
-        &mut self,
-        file_map: Arc<RwLock<HashMap<[u8; 20], InfoHash>>>
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let conn_listener = self.endpoint.accept().await.ok_or("failed to accept")?;
-        
-        //establish timeout duration to exit if connection request is not received
-        let timeout_duration = Duration::from_secs(4);
-
-        println!("Listening on {:?}", self.endpoint.local_addr());
-        let res = timeout(timeout_duration,conn_listener).await?;
-        
-        match res {
-            Ok(conn) => {
-                tokio::spawn(async move {
-                    let res = QuicP2PConn::send_data(conn, file_map).await;
-                    if res.is_err() {
-                        eprintln!("Failed to get connection request Listener: {:?}", res);
-                    }
-                });
-                
-                Ok(())
-            },
-            Err(e) => {
-                Err(Box::from(e.to_string()))
+    /// Spins off the recv_data task which sends file piece requests over the connection.
+    pub(crate) fn start_requesting(
+        conn: Connection,
+        conn_tx: Sender<Message>,
+        conn_rx: Arc<Mutex<Receiver<Message>>>,
+    ) {
+        tokio::spawn(async move {
+            let res = QuicP2PConn::recv_data(conn, conn_tx, conn_rx).await;
+            if res.is_err() {
+                eprintln!("Connect to Peer Server Error{:?}", res);
             }
-        }
+        });
     }
-    
+
     ///send_data()
     ///
     /// parameters:
+    ///    - peer: the authenticated identity of the peer on the other end
     ///    - file_map: this is the file map from which file information is acquired when file
     ///                is requested.
+    ///    - access: the rules deciding whether this peer may download the requested file
+    ///    - traffic: counts the bytes of every piece we serve
+    ///    - pex: the peers our leechers shared with us
     ///
     /// function:
     /// This method waits for incoming streams. It then takes the requests from the peer and then send
-    /// the requested piece. If the piece is not available, it will respond with a Cancel request indicating
-    /// the peer should ask another peer for the data.
+    /// the requested piece. If the piece is not available, or the peer is not allowed to have it,
+    /// it will respond with a Cancel request indicating the peer should ask another peer for the data.
+    /// Pex messages are answered with the peers other leechers shared for the same file.
     async fn send_data(
         conn: Connection,
+        peer: AuthenticatedPeer,
         file_map: Arc<RwLock<HashMap<[u8; 20], InfoHash>>>,
+        access: Arc<RwLock<AccessPolicy>>,
+        traffic: Arc<Traffic>,
+        pex: &PexTable,
     ) -> Result<(), Box<dyn std::error::Error>> {
         println!("Seeder accepted quic connection");
+        //the fingerprints we shared with this leecher so far, per file
+        let mut shared: HashMap<[u8; 20], HashSet<[u8; 32]>> = HashMap::new();
         loop {
             tokio::select! {
                 _ = conn.closed() => {
@@ -202,29 +552,23 @@ impl QuicP2PConn {
                         Ok((mut send, mut recv)) => {
                            println!("Seeder accepted bi stream!");
 
-                            let mut req_buf : [u8; 41] = [0; 41];
-                            recv.read_exact(&mut req_buf).await?;
-                            println!("Client received req {:?}", req_buf);
-
-                            let mut request = None;
-                            if let Some(msg) = Message::decode(Vec::from(req_buf)) {
-                                request = match msg {
-                                    Message::Request { seeder, index, begin, length, hash } => Some((seeder, index, begin, length, hash)),
-                                    _ => None,
-                                };
-                            }
-                            let (seeder, index, begin, length, hash) = request.ok_or("failed to decode request")?;
-
-                            //if no message found, we send a Cancel message back indicating we do not have the piece
-                            //the client will then re-issue this request to another peer.
-                            let msg = match file_map.read().await.get(&hash).cloned(){
-                                Some(info_hash) => {
-                                    match read_piece_from_file(info_hash, index) {
-                                        Ok(piece) => Message::Piece { index, piece },
-                                        Err(_) => Message::Cancel {seeder, index, begin, length},
-                                    }
-                                },
-                                None => Message::Cancel {seeder, index, begin, length},
+                            let request = QuicP2PConn::read_message(&mut recv).await?;
+                            println!("Client received req {:?}", request);
+
+                            let msg = match request {
+                                Message::Pex { hash, added, dropped } => {
+                                    let peers = match file_map.read().await.contains_key(&hash) && access.read().await.allows(&peer, &hash) {
+                                        true => {
+                                            pex.record(hash, conn.stable_id(), added, &dropped);
+                                            pex.peers(&hash, &peer.fingerprint)
+                                        }
+                                        false => Vec::new(),
+                                    };
+                                    let (added, dropped) = diff(peers, shared.entry(hash).or_default());
+                                    Message::Pex { hash, added, dropped }
+                                }
+                                request => QuicP2PConn::answer_request(request, &peer, &file_map, &access, &traffic).await
+                                    .ok_or("peer sent something other than a request")?,
                             };
 
                             send.write_all(&msg.encode()).await?;
@@ -246,45 +590,81 @@ impl QuicP2PConn {
 
     }
 
-    ///connect_to_peer_server
+    ///read_message()
     ///
-    /// parameter:
-    ///     - peer_addr: the is the address of the peer to connect to
-    ///     - conn_rx: this is the receiving end of the file assembler channel from which to get requests from
-    ///     - conn_tx: this is the sending end of the file assembler channel from which to send responses
+    /// parameters:
+    ///    - recv: a stream a peer opened
     ///
     /// function:
-    /// This method tries to connect to the peer quic server. If it succeeds, it spins off the recv_data task
-    /// which sends file piece requests. It will timeout and return a failure in 4 seconds if it does not
-    /// succeed in making a connection.
-    pub(crate) async fn connect_to_peer_server(
-        &mut self,
-        peer_addr: SocketAddr,
-        conn_tx: Sender<Message>,
-        conn_rx: Arc<Mutex<Receiver<Message>>>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    /// Reads one length prefixed message from the stream, refusing anything over MAX_MESSAGE_LEN.
+    async fn read_message(recv: &mut RecvStream) -> Result<Message, Box<dyn std::error::Error>> {
+        let mut len_buf = [0u8; 4];
+        recv.read_exact(&mut len_buf).await?;
+        let len = u32::from_be_bytes(len_buf) as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(format!("peer sent a {} byte message", len).into());
+        }
 
-        let timeout_duration = Duration::from_secs(4);
-        
-        let res = timeout(timeout_duration,self.endpoint.connect(peer_addr, &*peer_addr.ip().to_string())?).await?;
-        
-        match res {
-            Ok(conn) => {
-                tokio::spawn(async move {
-                    let res = QuicP2PConn::recv_data(conn, conn_tx, conn_rx).await;
-                    if res.is_err() {
-                        eprintln!("Connect to Peer Server Error{:?}", res);
-                    }
-                });
-            
-                Ok(()) 
-            }
-            Err(e) => {
-                Err(Box::new(e))
+        let mut buf = vec![0u8; 4 + len];
+        buf[..4].copy_from_slice(&len_buf);
+        recv.read_exact(&mut buf[4..]).await?;
+
+        Ok(Message::decode(buf).ok_or("failed to decode request")?)
+    }
+
+    ///answer_request()
+    ///
+    /// parameters:
+    ///    - msg: a message received from a leecher
+    ///    - peer: the authenticated identity of the leecher
+    ///    - file_map: the files we are able to serve
+    ///    - access: the rules deciding whether this peer may download the requested file
+    ///    - traffic: counts the piece if we send it
+    ///
+    /// function:
+    /// Returns the answer to a Request, which is the piece or a Cancel if we cannot or will not
+    /// send it, and to Metadata, which is the file's info or nothing under the same rules.
+    /// The same rules apply whether the leecher reached us directly or over a relay.
+    /// Messages that need no answer return None.
+    pub(crate) async fn answer_request(
+        msg: Message,
+        peer: &AuthenticatedPeer,
+        file_map: &RwLock<HashMap<[u8; 20], InfoHash>>,
+        access: &RwLock<AccessPolicy>,
+        traffic: &Traffic,
+    ) -> Option<Message> {
+        let (seeder, index, begin, length, hash) = match msg {
+            Message::Request { seeder, index, begin, length, hash } => (seeder, index, begin, length, hash),
+            Message::Metadata { hash } => {
+                let info = match access.read().await.allows(peer, &hash) {
+                    true => file_map.read().await.get(&hash).map(prost::Message::encode_to_vec).unwrap_or_default(),
+                    false => Vec::new(),
+                };
+                return Some(Message::Info { info });
             }
-            
+            _ => return None,
+        };
+
+        if !access.read().await.allows(peer, &hash) {
+            println!("Refusing piece {} to {}", index, hex::encode(peer.fingerprint));
+            return Some(Message::Cancel {seeder, index, begin, length});
         }
-        
+
+        //if no message found, we send a Cancel message back indicating we do not have the piece
+        //the client will then re-issue this request to another peer.
+        let msg = match file_map.read().await.get(&hash).cloned(){
+            Some(info_hash) => {
+                match read_piece_from_file(info_hash, index) {
+                    Ok(piece) => {
+                        traffic.add_uploaded(hash, piece.len() as u64);
+                        Message::Piece { index, piece }
+                    },
+                    Err(_) => Message::Cancel {seeder, index, begin, length},
+                }
+            },
+            None => Message::Cancel {seeder, index, begin, length},
+        };
+        Some(msg)
     }
 
     ///recv_data
@@ -294,69 +674,142 @@ impl QuicP2PConn {
     ///    - conn_rx: the receiving end of the channel to receive requests from request sender.
     ///
     /// function:
-    /// This method loops through all the requests delegated to it by the receiver. It sends those
-    /// to the peer and passes the response back up to the requester. If the connection fails,
-    /// it loops back all the responses as a cancel request so they may be re-requested by another peer.
+    /// This method loops through all the requests delegated to it by the receiver. Each is sent to
+    /// the peer on a stream of its own, up to REQUEST_WINDOW at a time, and the response is passed
+    /// back up to the requester. A request that fails is looped back as a cancel request so it may
+    /// be re-requested by another peer, the others carry on.
+    /// Pex messages are exchanged for the peer's own, which go back to the requester as well.
     async fn recv_data(
         conn: Connection,
         conn_tx: Sender<Message>,
         conn_rx: Arc<Mutex<Receiver<Message>>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut requests = JoinSet::new();
         loop {
-            if let Some(msg) = conn_rx.lock().await.recv().await {
-
-                match conn.open_bi().await {
-                    Ok((mut send, mut recv)) => {
-                        println!("requester opened bi stream!");
-                        let conn_tx_clone = conn_tx.clone();
+            //the assembler keeps no more than REQUEST_WINDOW requests out with a peer, so this
+            //only waits when another download shares the connection
+            if requests.len() >= REQUEST_WINDOW {
+                requests.join_next().await;
+            }
 
+            let msg = conn_rx.lock().await.recv().await;
+            let Some(msg) = msg else { break };
 
-                        let (index, length) = match msg {
-                            Message::Request { index,length,.. } => (index, length),
-                            _ => Err("length not found")?,
-                        };
+            if let Message::Pex { .. } = msg {
+                if let Err(e) = QuicP2PConn::exchange_pex(&conn, &msg, &conn_tx).await {
+                    eprintln!("PEX exchange failed: {}", e);
+                }
+                continue;
+            }
+            requests.spawn(QuicP2PConn::request_piece(conn.clone(), msg, conn_tx.clone()));
+        }
 
-                        let ret: JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>> =
-                            tokio::spawn(async move {
-                                println!("requester waiting for length {:?}", length + 9);
-                                let buf = recv.read_to_end(length as usize + 9).await?;
-                                println!("received piece from peer");
+        //the connection stays in the pool for other downloads from this peer,
+        //quinn's idle timeout closes it once nobody is using it
+        while requests.join_next().await.is_some() {}
+        println!("requests finished");
+        Ok(())
+    }
 
-                                let msg = Message::decode(buf).ok_or("failed to decode message")?;
+    ///request_piece
+    ///
+    /// parameters:
+    ///    - conn: the connection to the peer
+    ///    - msg: the Request to send
+    ///    - conn_tx: where the peer's answer goes
+    ///
+    /// function:
+    /// Sends one request on a stream of its own and passes the answer back up to the requester.
+    /// If the stream or the connection fails the request goes back as a Cancel instead, so the
+    /// file_assembler asks another peer for it.
+    async fn request_piece(conn: Connection, msg: Message, conn_tx: Sender<Message>) {
+        let Message::Request { seeder, index, begin, length, .. } = msg else { return };
+
+        let answer = match QuicP2PConn::exchange_request(&conn, &msg, length).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                eprintln!("Request for piece {} failed, sending Cancel: {}", index, e);
+                Message::Cancel { seeder, index, begin, length }
+            }
+        };
+        let _ = conn_tx.send(answer).await;
+    }
 
-                                conn_tx_clone.send(msg).await?;
+    ///exchange_request
+    ///
+    /// parameters:
+    ///    - conn: the connection to the peer
+    ///    - msg: the Request to send
+    ///    - length: how long the requested piece is
+    ///
+    /// function:
+    /// Opens a stream, sends the request on it and returns the peer's answer.
+    async fn exchange_request(
+        conn: &Connection,
+        msg: &Message,
+        length: u32,
+    ) -> Result<Message, Box<dyn std::error::Error + Send + Sync>> {
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&msg.encode()).await?;
+        send.finish()?;
+
+        let buf = recv.read_to_end(length as usize + 9).await?;
+        Ok(Message::decode(buf).ok_or("failed to decode message")?)
+    }
 
-                                Ok(())
-                            });
+    ///fetch_info
+    ///
+    /// parameters:
+    ///    - conn: a connection to a peer seeding the file
+    ///    - hash: the info hash of the file
+    ///
+    /// function:
+    /// Asks the peer for the info of a file, checking it hashes to what we asked for so a peer
+    /// cannot hand us the pieces of another file.
+    pub(crate) async fn fetch_info(conn: &Connection, hash: [u8; 20]) -> Result<InfoHash, Box<dyn std::error::Error>> {
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&Message::Metadata { hash }.encode()).await?;
+        send.finish()?;
+
+        let buf = recv.read_to_end(MAX_INFO_LEN).await?;
+        let Some(Message::Info { info }) = Message::decode(buf) else {
+            return Err("peer did not answer with the file info".into());
+        };
+        if info.is_empty() {
+            return Err("peer does not share the file with us".into());
+        }
 
-                        send.write_all(&msg.encode()).await?;
-                        send.finish()?;
-                        println!("sent message requesting: {}", index);
+        let info_hash: InfoHash = prost::Message::decode(info.as_slice())?;
+        if info_hash.get_hashed_info_hash() != hash {
+            return Err("peer sent the info of another file".into());
+        }
+        Ok(info_hash)
+    }
 
-                        let res = ret.await?;
-                        if res.is_err() {
-                            eprintln!("{:?}", res);
-                        }
-                    },
-                    //if connection errors, we want to resend the requests to another connection
-                    //this loops it back to file_assembler so it can handle removing this connection
-                    //and resend a new request to a viable connection
-                    Err(_) => {
-                        println!("Connection Error sending Cancel Request");
-                        let cancel_req = match msg {
-                            Message::Request { seeder, index, begin, length, .. } =>
-                                Message::Cancel { seeder, index, begin, length },
-                            _ => continue,
-                        };
-                        conn_tx.send(cancel_req).await?;
-                    }
-                }
+    ///exchange_pex
+    ///
+    /// parameters:
+    ///    - conn: the connection to the peer
+    ///    - msg: our Pex message
+    ///    - conn_tx: where the peer's answer goes
+    ///
+    /// function:
+    /// Sends our peers on a stream of their own and hands the peers the seeder answers with to
+    /// the requester. A seeder that does not know PEX just closes the stream.
+    async fn exchange_pex(
+        conn: &Connection,
+        msg: &Message,
+        conn_tx: &Sender<Message>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&msg.encode()).await?;
+        send.finish()?;
 
-            } else {
-                println!("connection closed");
-                conn.close(0u32.into(), b"closing connection gracefully");
-                return Ok(())
-            }
+        let buf = recv.read_to_end(MAX_MESSAGE_LEN).await?;
+        if let Some(answer @ Message::Pex { .. }) = Message::decode(buf) {
+            conn_tx.send(answer).await?;
         }
+
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,472 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::io::IoSliceMut;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use quinn::udp::{RecvMeta, Transmit};
+use quinn::{AsyncUdpSocket, UdpPoller};
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{interval, timeout};
+use crate::connection::connection::RelayCredentials;
+use crate::trackers::TrackerLinks;
+use crate::stun::{attr, decode_channel_data, encode_channel_data, is_channel_data, long_term_key,
+                  method, Class, StunMessage};
+
+/// how long we wait for the relay to answer a request before retransmitting it
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_RETRANSMITS: usize = 5;
+
+/// lifetime we ask for, refreshed well before it runs out along with permissions and channels
+const ALLOCATION_LIFETIME: u32 = 600;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(4 * 60);
+
+/// credentials running out sooner than this are replaced by fresh ones from the tracker
+const RENEW_AHEAD: Duration = Duration::from_secs(2 * 4 * 60);
+
+/// channel numbers we bind (RFC 8656 section 12)
+const CHANNELS: std::ops::RangeInclusive<u16> = 0x4000..=0x4FFF;
+
+/// REQUESTED-TRANSPORT value for UDP, padded to the attribute's 4 bytes
+const TRANSPORT_UDP: [u8; 4] = [17, 0, 0, 0];
+
+/// RelaySocket is a UDP socket whose datagrams travel through an allocation on the server's
+/// TURN relay (RFC 8656). It implements quinn's AsyncUdpSocket, so a regular QUIC endpoint can
+/// run over it and peers that cannot reach us directly connect to the relayed address instead.
+pub struct RelaySocket {
+    /// socket connected to the relay server
+    socket: UdpSocket,
+    /// the address peers send to, allocated on the relay
+    relayed_addr: SocketAddr,
+    realm: String,
+    /// the tracker that issued our credentials is asked for new ones before they expire
+    trackers: TrackerLinks,
+    state: Mutex<RelayState>,
+    /// receive buffer for poll_recv, datagrams are unwrapped before quinn sees them
+    recv_buf: Mutex<Vec<u8>>,
+    /// peers that sent us data without a channel, the maintenance task binds one for them
+    new_peers: mpsc::UnboundedSender<SocketAddr>,
+}
+
+#[derive(Default)]
+struct RelayState {
+    username: String,
+    /// long-term credential key every request is signed with
+    key: Vec<u8>,
+    /// when the credentials expire, in seconds since the unix epoch
+    expires: u64,
+    nonce: String,
+    /// peer IPs we installed a permission for
+    permissions: HashSet<IpAddr>,
+    /// channels bound on the relay, by peer
+    channels: HashMap<SocketAddr, u16>,
+    /// peers a channel is being bound for
+    binding: HashSet<SocketAddr>,
+    /// requests waiting for their response, by transaction id
+    pending: HashMap<[u8; 12], oneshot::Sender<StunMessage>>,
+}
+
+impl fmt::Debug for RelaySocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelaySocket")
+            .field("relay", &self.socket.peer_addr().ok())
+            .field("relayed_addr", &self.relayed_addr)
+            .finish()
+    }
+}
+
+impl RelaySocket {
+
+    ///allocate()
+    /// parameters:
+    ///     - credentials: the relay's address and the long-term credentials issued by the tracker
+    ///     - trackers: our trackers, the first one issued the credentials and renews them
+    ///
+    /// function:
+    /// Allocates a relayed address on the relay and starts the task that keeps it, its
+    /// permissions and its channels alive for as long as the socket exists.
+    pub async fn allocate(credentials: RelayCredentials, trackers: TrackerLinks) -> Result<Arc<RelaySocket>, Box<dyn std::error::Error>> {
+        let server = SocketAddr::from((Ipv4Addr::from(credentials.ipaddr), credentials.port as u16));
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(server).await?;
+
+        //the first attempt is rejected with the realm and nonce we have to sign the real one with
+        let mut request = StunMessage::new(method::ALLOCATE, Class::Request, rand::random());
+        request.add(attr::REQUESTED_TRANSPORT, TRANSPORT_UDP.to_vec());
+        let challenge = exchange(&socket, &request, None).await?;
+        let nonce = challenge.get_str(attr::NONCE).ok_or("relay did not send a nonce")?.to_string();
+        let realm = challenge.get_str(attr::REALM).unwrap_or(&credentials.realm).to_string();
+
+        let key = long_term_key(&credentials.username, &realm, &credentials.password);
+        let mut request = StunMessage::new(method::ALLOCATE, Class::Request, rand::random());
+        request.add(attr::REQUESTED_TRANSPORT, TRANSPORT_UDP.to_vec())
+            .add(attr::LIFETIME, ALLOCATION_LIFETIME.to_be_bytes().to_vec())
+            .add(attr::USERNAME, credentials.username.clone().into_bytes())
+            .add(attr::REALM, realm.clone().into_bytes())
+            .add(attr::NONCE, nonce.clone().into_bytes());
+        let response = exchange(&socket, &request, Some(&key)).await?;
+        if response.class != Class::Success {
+            return Err(format!("relay refused allocation: {:?}", response.error_code()).into());
+        }
+        let relayed_addr = response.get_xor_addr(attr::XOR_RELAYED_ADDRESS).ok_or("relay sent no relayed address")?;
+        println!("Relayed address {}", relayed_addr);
+
+        let (new_peers, new_peers_rx) = mpsc::unbounded_channel();
+        let relay = Arc::new(RelaySocket {
+            socket,
+            relayed_addr,
+            realm,
+            trackers,
+            state: Mutex::new(RelayState {
+                expires: credentials_expiry(&credentials.username),
+                username: credentials.username,
+                key,
+                nonce,
+                ..RelayState::default()
+            }),
+            recv_buf: Mutex::new(vec![0u8; 65536]),
+            new_peers,
+        });
+
+        tokio::spawn(RelaySocket::maintain(Arc::downgrade(&relay), new_peers_rx));
+        Ok(relay)
+    }
+
+    ///relayed_addr()
+    ///
+    /// function:
+    /// Returns the address on the relay peers reach this socket at.
+    pub fn relayed_addr(&self) -> SocketAddr {
+        self.relayed_addr
+    }
+
+    ///permit()
+    /// parameters:
+    ///     - ip: the public IP of a peer that is about to connect to our relayed address
+    ///
+    /// function:
+    /// Installs a permission for the peer, the relay drops datagrams from anyone else.
+    pub async fn permit(&self, ip: IpAddr) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut request = StunMessage::new(method::CREATE_PERMISSION, Class::Request, rand::random());
+        request.add_xor_addr(attr::XOR_PEER_ADDRESS, SocketAddr::new(ip, 0));
+        self.transact(request).await?;
+
+        self.state.lock().unwrap().permissions.insert(ip);
+        Ok(())
+    }
+
+    ///maintain()
+    /// parameters:
+    ///     - relay: the socket to maintain, the task ends once it is dropped
+    ///     - new_peers: peers poll_recv saw sending without a channel
+    ///
+    /// function:
+    /// Refreshes the allocation, permissions and channels before the relay expires them, and binds
+    /// a channel for every new peer so its datagrams carry 4 bytes of overhead instead of 36.
+    async fn maintain(relay: Weak<RelaySocket>, mut new_peers: mpsc::UnboundedReceiver<SocketAddr>) {
+        let mut refresh = interval(REFRESH_INTERVAL);
+        //the first tick completes immediately and the allocation is brand new
+        refresh.tick().await;
+        loop {
+            tokio::select! {
+                _ = refresh.tick() => {
+                    let Some(relay) = relay.upgrade() else { return };
+                    if let Err(e) = relay.refresh().await {
+                        eprintln!("failed to refresh relay allocation: {}", e);
+                    }
+                }
+                peer = new_peers.recv() => {
+                    let (Some(peer), Some(relay)) = (peer, relay.upgrade()) else { return };
+                    //the lowest number no peer holds, without one the peer keeps using Send indications
+                    let channel = {
+                        let state = relay.state.lock().unwrap();
+                        CHANNELS.clone().find(|channel| !state.channels.values().any(|bound| bound == channel))
+                    };
+                    if let Some(channel) = channel {
+                        if let Err(e) = relay.bind_channel(peer, channel).await {
+                            eprintln!("failed to bind relay channel for {}: {}", peer, e);
+                        }
+                    }
+                    relay.state.lock().unwrap().binding.remove(&peer);
+                }
+            }
+        }
+    }
+
+    ///refresh()
+    ///
+    /// function:
+    /// Extends the allocation and re-installs every permission and channel binding. Credentials
+    /// about to expire are renewed first, so the allocation outlives them.
+    async fn refresh(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let expires = self.state.lock().unwrap().expires;
+        if expires < unix_now() + RENEW_AHEAD.as_secs() {
+            if let Err(e) = self.renew().await {
+                eprintln!("failed to renew relay credentials: {}", e);
+            }
+        }
+
+        let mut request = StunMessage::new(method::REFRESH, Class::Request, rand::random());
+        request.add(attr::LIFETIME, ALLOCATION_LIFETIME.to_be_bytes().to_vec());
+        self.transact(request).await?;
+
+        let (permissions, channels) = {
+            let state = self.state.lock().unwrap();
+            (state.permissions.clone(), state.channels.clone())
+        };
+        if !permissions.is_empty() {
+            let mut request = StunMessage::new(method::CREATE_PERMISSION, Class::Request, rand::random());
+            for ip in permissions {
+                request.add_xor_addr(attr::XOR_PEER_ADDRESS, SocketAddr::new(ip, 0));
+            }
+            self.transact(request).await?;
+        }
+        for (peer, channel) in channels {
+            self.bind_channel(peer, channel).await?;
+        }
+        Ok(())
+    }
+
+    ///renew()
+    ///
+    /// function:
+    /// Fetches new credentials from the tracker that issued ours, the relay keeps the allocation
+    /// since they are issued to the same client.
+    async fn renew(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let link = self.trackers.read().await.first().cloned().ok_or("no tracker to renew relay credentials with")?;
+        let credentials = link.client.clone().get_relay_credentials(link.uid.clone()).await?.into_inner();
+
+        let mut state = self.state.lock().unwrap();
+        state.key = long_term_key(&credentials.username, &self.realm, &credentials.password);
+        state.expires = credentials_expiry(&credentials.username);
+        state.username = credentials.username;
+        Ok(())
+    }
+
+    ///bind_channel()
+    /// parameters:
+    ///     - peer: the peer to bind the channel to
+    ///     - channel: the channel number
+    async fn bind_channel(&self, peer: SocketAddr, channel: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut request = StunMessage::new(method::CHANNEL_BIND, Class::Request, rand::random());
+        request.add(attr::CHANNEL_NUMBER, [channel.to_be_bytes(), [0, 0]].concat())
+            .add_xor_addr(attr::XOR_PEER_ADDRESS, peer);
+        self.transact(request).await?;
+
+        self.state.lock().unwrap().channels.insert(peer, channel);
+        Ok(())
+    }
+
+    ///transact()
+    /// parameters:
+    ///     - request: the request to send, credentials are added here
+    ///
+    /// function:
+    /// Sends an authenticated request and waits for its response, which poll_recv hands over since
+    /// the endpoint driver owns the socket. A stale nonce is replaced and expired credentials are
+    /// renewed, retrying the request after each.
+    async fn transact(&self, request: StunMessage) -> Result<StunMessage, Box<dyn std::error::Error + Send + Sync>> {
+        let mut request = request;
+        let mut renewed = false;
+        for _ in 0..3 {
+            let encoded = {
+                let state = self.state.lock().unwrap();
+                let mut signed = request.clone();
+                signed.add(attr::USERNAME, state.username.clone().into_bytes())
+                    .add(attr::REALM, self.realm.clone().into_bytes())
+                    .add(attr::NONCE, state.nonce.clone().into_bytes());
+                signed.encode(Some(&state.key))
+            };
+
+            let (tx, mut rx) = oneshot::channel();
+            self.state.lock().unwrap().pending.insert(request.transaction_id, tx);
+
+            let mut response = None;
+            for _ in 0..MAX_RETRANSMITS {
+                self.socket.send(&encoded).await?;
+                if let Ok(res) = timeout(RETRANSMIT_INTERVAL, &mut rx).await {
+                    response = res.ok();
+                    break;
+                }
+            }
+            self.state.lock().unwrap().pending.remove(&request.transaction_id);
+
+            let response = response.ok_or("relay did not answer")?;
+            match response.class {
+                Class::Success => return Ok(response),
+                _ if response.error_code() == Some(438) => {
+                    let nonce = response.get_str(attr::NONCE).ok_or("relay did not send a nonce")?;
+                    self.state.lock().unwrap().nonce = nonce.to_string();
+                    request.transaction_id = rand::random();
+                }
+                //the relay no longer accepts our credentials, which happens once they expired
+                _ if response.error_code() == Some(401) && !renewed => {
+                    if let Some(nonce) = response.get_str(attr::NONCE) {
+                        self.state.lock().unwrap().nonce = nonce.to_string();
+                    }
+                    self.renew().await?;
+                    renewed = true;
+                    request.transaction_id = rand::random();
+                }
+                _ => return Err(format!("relay rejected request: {:?}", response.error_code()).into()),
+            }
+        }
+        Err("relay kept rejecting our credentials".into())
+    }
+
+    ///unwrap_datagram()
+    /// parameters:
+    ///     - datagram: a datagram received from the relay
+    ///
+    /// function:
+    /// Returns the peer and payload of relayed data. Responses to our own requests are handed
+    /// to the waiting transact() and anything else is dropped.
+    fn unwrap_datagram<'a>(&self, datagram: &'a [u8]) -> Option<(SocketAddr, &'a [u8])> {
+        if is_channel_data(datagram) {
+            let (channel, data) = decode_channel_data(datagram)?;
+            let state = self.state.lock().unwrap();
+            let peer = state.channels.iter().find(|(_, c)| **c == channel).map(|(peer, _)| *peer)?;
+            return Some((peer, data));
+        }
+
+        let msg = StunMessage::decode(datagram)?;
+        match msg.class {
+            Class::Indication if msg.method == method::DATA => {
+                let peer = msg.get_xor_addr(attr::XOR_PEER_ADDRESS)?;
+                let len = msg.get(attr::DATA)?.len();
+                let offset = data_offset(datagram, len)?;
+
+                let mut state = self.state.lock().unwrap();
+                if !state.channels.contains_key(&peer) && state.binding.insert(peer) {
+                    let _ = self.new_peers.send(peer);
+                }
+                Some((peer, &datagram[offset..offset + len]))
+            }
+            Class::Success | Class::Error => {
+                if let Some(tx) = self.state.lock().unwrap().pending.remove(&msg.transaction_id) {
+                    let _ = tx.send(msg);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+impl AsyncUdpSocket for RelaySocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(RelayPoller { relay: self })
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        let channel = self.state.lock().unwrap().channels.get(&transmit.destination).copied();
+        let datagram = match channel {
+            Some(channel) => encode_channel_data(channel, transmit.contents),
+            None => {
+                let mut indication = StunMessage::new(method::SEND, Class::Indication, rand::random());
+                indication.add_xor_addr(attr::XOR_PEER_ADDRESS, transmit.destination)
+                    .add(attr::DATA, transmit.contents.to_vec());
+                indication.encode(None)
+            }
+        };
+        self.socket.try_send(&datagram).map(|_| ())
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut recv_buf = self.recv_buf.lock().unwrap();
+        loop {
+            let mut read_buf = ReadBuf::new(&mut recv_buf);
+            ready!(self.socket.poll_recv(cx, &mut read_buf))?;
+
+            if let Some((peer, data)) = self.unwrap_datagram(read_buf.filled()) {
+                let len = data.len().min(bufs[0].len());
+                bufs[0][..len].copy_from_slice(&data[..len]);
+                meta[0] = RecvMeta { addr: peer, len, stride: len, ecn: None, dst_ip: None };
+                return Poll::Ready(Ok(1));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.relayed_addr)
+    }
+}
+
+/// RelayPoller tells quinn when the socket to the relay can take another datagram.
+#[derive(Debug)]
+struct RelayPoller {
+    relay: Arc<RelaySocket>,
+}
+
+impl UdpPoller for RelayPoller {
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.relay.socket.poll_send_ready(cx)
+    }
+}
+
+/// exchange (
+///     socket: socket connected to the relay
+///     request: the request to send
+///     key: long-term credential key to sign the request with, if any
+/// )
+/// helper function to run a single transaction before the endpoint has taken over the socket
+async fn exchange(socket: &UdpSocket, request: &StunMessage, key: Option<&[u8]>) -> Result<StunMessage, Box<dyn std::error::Error>> {
+    let encoded = request.encode(key);
+    let mut buf = vec![0u8; 2048];
+
+    for _ in 0..MAX_RETRANSMITS {
+        socket.send(&encoded).await?;
+        let Ok(len) = timeout(RETRANSMIT_INTERVAL, socket.recv(&mut buf)).await else {
+            continue;
+        };
+        match StunMessage::decode(&buf[..len?]) {
+            Some(response) if response.transaction_id == request.transaction_id => return Ok(response),
+            _ => continue,
+        }
+    }
+    Err("relay did not answer".into())
+}
+
+/// data_offset (
+///     datagram: a Data indication
+///     len: length of its DATA attribute
+/// )
+/// helper function locating the DATA attribute's value in the raw datagram, so it can be handed
+/// to quinn without copying it out first
+fn data_offset(datagram: &[u8], len: usize) -> Option<usize> {
+    let mut offset = 20;
+    while offset + 4 <= datagram.len() {
+        let attr_type = u16::from_be_bytes([datagram[offset], datagram[offset + 1]]);
+        let attr_len = u16::from_be_bytes([datagram[offset + 2], datagram[offset + 3]]) as usize;
+        if attr_type == attr::DATA && attr_len == len {
+            return Some(offset + 4).filter(|start| start + len <= datagram.len());
+        }
+        offset += 4 + ((attr_len + 3) & !3);
+    }
+    None
+}
+
+/// credentials_expiry (
+///     username: a username issued by the tracker, its expiry followed by our client id
+/// )
+/// helper function returning when credentials expire, in seconds since the unix epoch
+fn credentials_expiry(username: &str) -> u64 {
+    username.split_once(':')
+        .and_then(|(expiry, _)| expiry.parse().ok())
+        .unwrap_or_default()
+}
+
+// Seconds since the unix epoch, the form credential expiries are given in
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
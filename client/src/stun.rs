@@ -0,0 +1,298 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+/// fixed value every STUN message carries after its length (RFC 8489 section 5)
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+/// size of the STUN header and of a MESSAGE-INTEGRITY attribute including its header
+const HEADER_LEN: usize = 20;
+const INTEGRITY_LEN: usize = 24;
+
+/// STUN and TURN methods (RFC 8489, RFC 8656)
+pub mod method {
+    pub const ALLOCATE: u16 = 0x003;
+    pub const REFRESH: u16 = 0x004;
+    pub const SEND: u16 = 0x006;
+    pub const DATA: u16 = 0x007;
+    pub const CREATE_PERMISSION: u16 = 0x008;
+    pub const CHANNEL_BIND: u16 = 0x009;
+}
+
+/// STUN and TURN attribute types (RFC 8489, RFC 8656)
+pub mod attr {
+    pub const USERNAME: u16 = 0x0006;
+    pub const MESSAGE_INTEGRITY: u16 = 0x0008;
+    pub const ERROR_CODE: u16 = 0x0009;
+    pub const CHANNEL_NUMBER: u16 = 0x000C;
+    pub const LIFETIME: u16 = 0x000D;
+    pub const XOR_PEER_ADDRESS: u16 = 0x0012;
+    pub const DATA: u16 = 0x0013;
+    pub const REALM: u16 = 0x0014;
+    pub const NONCE: u16 = 0x0015;
+    pub const XOR_RELAYED_ADDRESS: u16 = 0x0016;
+    pub const REQUESTED_TRANSPORT: u16 = 0x0019;
+}
+
+/// the class bits of a STUN message type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    Request,
+    Indication,
+    Success,
+    Error,
+}
+
+/// A decoded STUN message. Attributes are kept raw and in order, typed accessors decode them on demand.
+#[derive(Clone, Debug)]
+pub struct StunMessage {
+    pub method: u16,
+    pub class: Class,
+    pub transaction_id: [u8; 12],
+    attributes: Vec<(u16, Vec<u8>)>,
+    /// offset of the MESSAGE-INTEGRITY attribute in the raw message it was decoded from
+    integrity_offset: Option<usize>,
+}
+
+impl StunMessage {
+
+    ///new()
+    /// parameters:
+    ///     - method: the STUN method
+    ///     - class: request, indication or response
+    ///     - transaction_id: identifies the transaction a response belongs to
+    pub fn new(method: u16, class: Class, transaction_id: [u8; 12]) -> StunMessage {
+        StunMessage { method, class, transaction_id, attributes: Vec::new(), integrity_offset: None }
+    }
+
+    ///add()
+    /// parameters:
+    ///     - attr_type: the attribute to append
+    ///     - value: its raw value, without padding
+    pub fn add(&mut self, attr_type: u16, value: Vec<u8>) -> &mut StunMessage {
+        self.attributes.push((attr_type, value));
+        self
+    }
+
+    ///add_xor_addr()
+    /// parameters:
+    ///     - attr_type: one of the XOR-*-ADDRESS attributes
+    ///     - addr: the address to encode
+    pub fn add_xor_addr(&mut self, attr_type: u16, addr: SocketAddr) -> &mut StunMessage {
+        let value = xor_addr(addr, &self.transaction_id);
+        self.add(attr_type, value)
+    }
+
+    ///get()
+    /// parameters:
+    ///     - attr_type: the attribute to look up
+    ///
+    /// function:
+    /// Returns the value of the first attribute of that type.
+    pub fn get(&self, attr_type: u16) -> Option<&[u8]> {
+        self.attributes.iter()
+            .find(|(t, _)| *t == attr_type)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    ///get_str()
+    pub fn get_str(&self, attr_type: u16) -> Option<&str> {
+        std::str::from_utf8(self.get(attr_type)?).ok()
+    }
+
+    ///get_xor_addr()
+    pub fn get_xor_addr(&self, attr_type: u16) -> Option<SocketAddr> {
+        unxor_addr(self.get(attr_type)?, &self.transaction_id)
+    }
+
+    ///error_code()
+    pub fn error_code(&self) -> Option<u16> {
+        let value = self.get(attr::ERROR_CODE)?;
+        Some((value.get(2)? & 0x07) as u16 * 100 + *value.get(3)? as u16)
+    }
+
+    ///decode()
+    /// parameters:
+    ///     - buf: one UDP datagram
+    ///
+    /// function:
+    /// Parses a STUN message, returning None for anything that is not one.
+    pub fn decode(buf: &[u8]) -> Option<StunMessage> {
+        if buf.len() < HEADER_LEN || buf[0] & 0xC0 != 0 {
+            return None;
+        }
+        let msg_type = u16::from_be_bytes([buf[0], buf[1]]);
+        let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if u32::from_be_bytes(buf[4..8].try_into().ok()?) != MAGIC_COOKIE || buf.len() < HEADER_LEN + length {
+            return None;
+        }
+
+        let class = match ((msg_type >> 7) & 0x2) | ((msg_type >> 4) & 0x1) {
+            0 => Class::Request,
+            1 => Class::Indication,
+            2 => Class::Success,
+            _ => Class::Error,
+        };
+        let method = (msg_type & 0x000F) | ((msg_type >> 1) & 0x0070) | ((msg_type >> 2) & 0x0F80);
+        let mut msg = StunMessage::new(method, class, buf[8..20].try_into().ok()?);
+
+        let mut offset = HEADER_LEN;
+        while offset + 4 <= HEADER_LEN + length {
+            let attr_type = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+            let attr_len = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+            let value = buf.get(offset + 4..offset + 4 + attr_len)?;
+
+            //attributes after MESSAGE-INTEGRITY are not covered by it, so they are ignored
+            if msg.integrity_offset.is_none() {
+                if attr_type == attr::MESSAGE_INTEGRITY {
+                    msg.integrity_offset = Some(offset);
+                }
+                msg.attributes.push((attr_type, value.to_vec()));
+            }
+            offset += 4 + padded(attr_len);
+        }
+
+        Some(msg)
+    }
+
+    ///encode()
+    /// parameters:
+    ///     - key: long-term credential key, when given a MESSAGE-INTEGRITY attribute is appended
+    pub fn encode(&self, key: Option<&[u8]>) -> Vec<u8> {
+        let msg_type = (self.method & 0x000F) | ((self.method & 0x0070) << 1) | ((self.method & 0x0F80) << 2)
+            | match self.class {
+                Class::Request => 0x0000,
+                Class::Indication => 0x0010,
+                Class::Success => 0x0100,
+                Class::Error => 0x0110,
+            };
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + 64);
+        buf.extend_from_slice(&msg_type.to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id);
+
+        for (attr_type, value) in self.attributes.iter().filter(|(t, _)| *t != attr::MESSAGE_INTEGRITY) {
+            buf.extend_from_slice(&attr_type.to_be_bytes());
+            buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            buf.extend_from_slice(value);
+            buf.resize(HEADER_LEN + padded(buf.len() - HEADER_LEN), 0);
+        }
+
+        if let Some(key) = key {
+            let mac = integrity(&buf, key);
+            buf.extend_from_slice(&attr::MESSAGE_INTEGRITY.to_be_bytes());
+            buf.extend_from_slice(&20u16.to_be_bytes());
+            buf.extend_from_slice(&mac);
+        }
+
+        let length = (buf.len() - HEADER_LEN) as u16;
+        buf[2..4].copy_from_slice(&length.to_be_bytes());
+        buf
+    }
+}
+
+///long_term_key()
+/// parameters:
+///     - username, realm, password: the long-term credentials
+///
+/// function:
+/// Derives the key MESSAGE-INTEGRITY is computed with, MD5(username ":" realm ":" password).
+pub fn long_term_key(username: &str, realm: &str, password: &str) -> Vec<u8> {
+    Md5::digest(format!("{}:{}:{}", username, realm, password)).to_vec()
+}
+
+///is_channel_data()
+///
+/// function:
+/// ChannelData messages start with a channel number in 0x4000..=0x7FFF, STUN messages with 0b00.
+pub fn is_channel_data(buf: &[u8]) -> bool {
+    buf.first().is_some_and(|b| b & 0xC0 == 0x40)
+}
+
+///encode_channel_data()
+pub fn encode_channel_data(channel: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + data.len());
+    buf.extend_from_slice(&channel.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+///decode_channel_data()
+///
+/// function:
+/// Returns the channel number and application data of a ChannelData message.
+pub fn decode_channel_data(buf: &[u8]) -> Option<(u16, &[u8])> {
+    let channel = u16::from_be_bytes([*buf.first()?, *buf.get(1)?]);
+    let len = u16::from_be_bytes([*buf.get(2)?, *buf.get(3)?]) as usize;
+    Some((channel, buf.get(4..4 + len)?))
+}
+
+// Computes the MESSAGE-INTEGRITY value over a message encoded up to where the attribute goes
+fn integrity(prefix: &[u8], key: &[u8]) -> [u8; 20] {
+    let mut header = prefix[..HEADER_LEN].to_vec();
+    let length = (prefix.len() - HEADER_LEN + INTEGRITY_LEN) as u16;
+    header[2..4].copy_from_slice(&length.to_be_bytes());
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(&header);
+    mac.update(&prefix[HEADER_LEN..]);
+    mac.finalize().into_bytes().into()
+}
+
+// Rounds an attribute length up to the 4 byte boundary attributes are aligned on
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// Encodes an address XORed with the magic cookie and transaction id
+fn xor_addr(addr: SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let mut value = vec![0];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value.push(0x01);
+            value.extend_from_slice(&port.to_be_bytes());
+            value.extend_from_slice(&(u32::from(ip) ^ MAGIC_COOKIE).to_be_bytes());
+        }
+        IpAddr::V6(ip) => {
+            value.push(0x02);
+            value.extend_from_slice(&port.to_be_bytes());
+            let mask = xor_mask(transaction_id);
+            value.extend(ip.octets().iter().zip(mask.iter()).map(|(a, b)| a ^ b));
+        }
+    }
+    value
+}
+
+// Decodes an address encoded by xor_addr
+fn unxor_addr(value: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match value.get(1)? {
+        0x01 => {
+            let raw = u32::from_be_bytes(value.get(4..8)?.try_into().ok()?);
+            IpAddr::V4(Ipv4Addr::from(raw ^ MAGIC_COOKIE))
+        }
+        0x02 => {
+            let mask = xor_mask(transaction_id);
+            let mut octets = [0u8; 16];
+            for (i, byte) in value.get(4..20)?.iter().enumerate() {
+                octets[i] = byte ^ mask[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+// IPv6 addresses are XORed with the magic cookie followed by the transaction id
+fn xor_mask(transaction_id: &[u8; 12]) -> [u8; 16] {
+    let mut mask = [0u8; 16];
+    mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    mask[4..].copy_from_slice(transaction_id);
+    mask
+}
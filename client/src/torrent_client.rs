@@ -1,124 +1,309 @@
 use std::cmp::min;
 use std::collections::HashMap;
-use std::io::ErrorKind;
-use std::net::{IpAddr, SocketAddrV4, ToSocketAddrs};
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
-use local_ip_address::local_ip;
-use stunclient::StunClient;
-use tokio::net::UdpSocket;
-use tokio::sync::{Notify, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+use tokio::sync::{broadcast, Mutex, Notify, RwLock, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::interval;
 use tonic::Request;
-use tonic::transport::{Channel, ClientTlsConfig};
+use crate::access::AccessPolicy;
+use crate::auth::{Relay, Tracker};
+use crate::config::ClientConfig;
 use crate::connection::connection::*;
+use crate::downloads::{DownloadStatus, Downloads};
 use crate::file_assembler::FileAssembler;
 use crate::file_handler;
 use crate::file_handler::{get_info_hashes};
+use crate::identity::Identity;
+use crate::lsd::{LocalDiscovery, LocalPeer};
+use crate::message::PexPeer;
 use crate::peer_connection::PeerConnection;
+use crate::pex;
+use crate::quic_p2p_sender::QuicP2PConn;
+use crate::traffic::Traffic;
+use crate::trackers::{reconnecting, serve_session, TrackerLink, TrackerLinks, SESSION_DEPTH};
 
 #[derive(Debug, Clone)]
 pub struct TorrentClient {
-    pub(crate) client: connector_client::ConnectorClient<Channel>,
-    pub(crate) turn: turn_client::TurnClient<Channel>,
+    pub(crate) client: Tracker,
+    pub(crate) turn: Relay,
     pub(crate) uid: ClientId,
+    /// the tracker client, turn and uid belong to, each view of the client talks to one tracker
+    pub(crate) tracker_url: String,
+    /// every tracker we are registered with, announces go to all of them
+    trackers: TrackerLinks,
+    /// the key we register with trackers we learn about later
+    api_key: String,
     pub(crate) file_hashes: Arc<RwLock<HashMap<[u8;20], InfoHash>>>,
+    /// the single QUIC endpoint all peer connections are multiplexed over
+    pub(crate) p2p: Arc<QuicP2PConn>,
+    /// the persistent keys peers authenticate us by
+    pub(crate) identity: Identity,
+    /// finds the clients on our LAN without a tracker
+    lan: Arc<LocalDiscovery>,
+    /// the info of files seeded on the LAN, fetched from their seeders when no tracker answers
+    lan_files: Arc<RwLock<HashMap<[u8; 20], InfoHash>>>,
+    /// rules deciding which peers may download which of our files
+    pub(crate) access: Arc<RwLock<AccessPolicy>>,
+    /// bytes downloaded and uploaded per file, reported to the tracker
+    pub(crate) traffic: Arc<Traffic>,
+    /// one permit per leecher we are allowed to upload to at the same time
+    upload_slots: Arc<Semaphore>,
+    /// the files the user asked for, a few of them downloading at a time
+    downloads: Arc<Downloads>,
     close_down: Arc<Notify>,
 }
 
-const GCLOUD_URL: &str = "https://helpful-serf-server-1016068426296.us-south1.run.app:";
+/// how long credentials the tracker pushed wait for the connection request they come ahead of
+const CREDENTIALS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// how often we tell the tracker how a download is going and how much we uploaded
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// how often the tracker learns which pieces of a file we are downloading we can serve
+const PIECES_INTERVAL: Duration = Duration::from_secs(5);
+
+/// how often we store ourselves on the DHT again, DHT nodes forget peers after half an hour
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// how often a download looks for seeders it is not connected to yet
+const PEERS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// how often we announce our files on the LAN, as BEP 14 suggests
+const LAN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 impl TorrentClient {
-    ///This method creates a new torrent client, establishing a connection to our underlying gRPC server
-    /// used both as an introducer and relay.
-    pub (crate) async fn new() -> Result<TorrentClient, Box<dyn std::error::Error>> {
-        //tls config
-        //webki roots uses Mozilla's certificate store
-        let tls = ClientTlsConfig::new()
-            .with_webpki_roots()
-            .domain_name("helpful-serf-server-1016068426296.us-south1.run.app");
-
-        let endpoint = Channel::from_static(GCLOUD_URL).tls_config(tls)?
-            .connect().await?;
-
-        let mut client = connector_client::ConnectorClient::new(endpoint.clone());
-        let turn = turn_client::TurnClient::new(endpoint);
-        
-        let uid = client.register_client(ClientRegistry { peer_id: None} ).await?;
-        let uid = uid.into_inner();
+    ///This method creates a new torrent client, registering with every configured tracker that can be
+    /// reached. The trackers are used both as introducers and relays, the first one that answers
+    /// is preferred. If none of them can be reached the client still starts, and finds its peers
+    /// on the LAN and the DHT only.
+    pub (crate) async fn new(config: ClientConfig) -> Result<TorrentClient, Box<dyn std::error::Error>> {
+        //the persistent identity is what peers pin, so publish its keys with the registration
+        let identity = Identity::load_or_create()?;
+
+        let mut links = Vec::new();
+        for url in &config.trackers {
+            match TrackerLink::connect(url, &identity, &config.api_key).await {
+                Ok(link) => links.push(link),
+                Err(e) => eprintln!("Could not register with tracker {}: {}", url, e),
+            }
+        }
+        let primary = match links.first() {
+            Some(link) => link.clone(),
+            None => {
+                eprintln!("Could not reach any tracker, only peers on the LAN and the DHT can be found");
+                TrackerLink::unregistered(config.trackers.first().ok_or("no tracker configured")?)?
+            }
+        };
+        let registered = !links.is_empty();
+        let trackers = Arc::new(RwLock::new(links));
 
         let file_hashes = match get_info_hashes(){
             Ok(file_hashes) => file_hashes,
             Err(err) => return Err(Box::new(err)),
         };
+        let file_hashes = Arc::new(RwLock::new(file_hashes));
+
+        let access = Arc::new(RwLock::new(AccessPolicy::load()?));
+
+        //servers without a UDP TURN relay refuse, we then fall back on the gRPC relay only
+        let relay_credentials = primary.client.clone().get_relay_credentials(primary.uid.clone()).await
+            .map(|res| res.into_inner())
+            .ok();
+
+        //the endpoint lives as long as the client so its address only has to be registered once
+        let traffic = Arc::new(Traffic::default());
+        let p2p = Arc::new(QuicP2PConn::new(file_hashes.clone(), &identity, trackers.clone(), access.clone(), traffic.clone(), relay_credentials, config.allow_unregistered_peers).await?);
+        let lan = p2p.lan.clone();
+
+        let torrent_client = TorrentClient {
+            client: primary.client,
+            turn: primary.turn,
+            uid: primary.uid,
+            tracker_url: primary.url,
+            trackers,
+            api_key: config.api_key.clone(),
+            file_hashes,
+            p2p,
+            identity,
+            lan,
+            lan_files: Arc::new(RwLock::new(HashMap::new())),
+            access,
+            traffic,
+            upload_slots: Arc::new(Semaphore::new(config.max_uploads)),
+            downloads: Arc::new(Downloads::new(config.max_downloads)),
+            close_down: Arc::new(Notify::new()),
+        };
+
+        let self_addr = torrent_client.p2p.self_addr;
+        if registered {
+            torrent_client.on_every_tracker(|mut tracker| async move {
+                tracker.update_registered_peer_id(self_addr).await
+            }).await?;
+        }
+        torrent_client.bootstrap_dht(&config.dht_nodes).await;
+        tokio::spawn(torrent_client.clone().download_loop());
 
-        Ok(
-            TorrentClient {
-                client,
-                turn,
-                uid,
-                file_hashes: Arc::new(RwLock::new(file_hashes)),
-                close_down: Arc::new(Notify::new()),
+        Ok(torrent_client)
+    }
+
+    ///This method starts the queued downloads as slots free up, each in its own task, for as long
+    /// as the client runs.
+    async fn download_loop(self) {
+        loop {
+            let info_hash = self.downloads.next().await;
+            let mut client = self.clone();
+            tokio::spawn(async move {
+                let hash = info_hash.get_hashed_info_hash();
+                let name = info_hash.name.clone();
+                let completed = match client.file_request(info_hash).await {
+                    Ok(completed) => completed,
+                    Err(e) => {
+                        eprintln!("Download of {} failed: {}", name, e);
+                        false
+                    }
+                };
+                client.downloads.finish(&hash, completed).await;
+            });
+        }
+    }
+
+    ///This method joins the DHT through the configured nodes and the clients our trackers hand
+    /// out. The lookups run in the background, the DHT is only needed once a tracker fails us.
+    async fn bootstrap_dht(&self, configured: &[String]) {
+        let mut nodes: Vec<SocketAddr> = Vec::new();
+        for node in configured {
+            match lookup_host(node.as_str()).await {
+                Ok(addrs) => nodes.extend(addrs.filter(SocketAddr::is_ipv4)),
+                Err(e) => eprintln!("Could not resolve DHT node {}: {}", node, e),
             }
-        )
-    }
-
-    ///This method registers a new peer connection by sending the public and private ip and port numbers
-    /// so that other peers can attempt to make a peer-to-peer connection with this client.
-    async fn register_new_connection(&mut self) -> Result<PeerConnection, Box<dyn std::error::Error>> {
-        //bind port and get public facing id
-        let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
-        let stun_server = "stun.l.google.com:19302".to_socket_addrs().unwrap().filter(|x|x.is_ipv4()).next().unwrap();
-        let client = StunClient::new(stun_server);
-        let external_addr = client.query_external_address(&socket)?;
-
-        let ipaddr =  match external_addr.ip() {
-            IpAddr::V4(v4) => Ok(u32::from_be_bytes(v4.octets())),
-            IpAddr::V6(_) => Err("Cannot convert IPv6 to u32"),
-        }?;
-
-        println!("My public IP {}", external_addr.ip());
-        println!("My public PORT {}", external_addr.port());
-
-        // Get the local IP address of this machine (defaults to Ipv4)
-        let priv_ipaddr = match local_ip() {
-            Ok(ip) => {
-                match ip {
-                    IpAddr::V4(v4) => v4,
-                    IpAddr::V6(_) => return Err(Box::new(std::io::Error::new(ErrorKind::Other, "Cannot convert IPv6 to u32"))),
-                }
+        }
+        for mut tracker in self.trackers().await {
+            match tracker.client.get_dht_nodes(Request::new(())).await {
+                Ok(list) => nodes.extend(list.into_inner().nodes.iter()
+                    .map(|node| SocketAddr::from((Ipv4Addr::from(node.ipaddr), node.port as u16)))),
+                Err(e) => eprintln!("Could not get DHT nodes from {}: {}", tracker.tracker_url, e),
             }
-            Err(err) => return Err(Box::new(err)),
-        };
+        }
 
+        let dht = self.p2p.dht.clone();
+        tokio::spawn(async move {
+            let known = dht.bootstrap(nodes).await;
+            println!("Joined the DHT, {} nodes known", known);
+        });
+    }
 
-        let priv_addr= SocketAddrV4::new(priv_ipaddr, 0);
-        let priv_socket = UdpSocket::bind(priv_addr).await?;
+    ///This method tells whether we seed the whole of a file to everyone. Only those files are
+    /// announced on the DHT and the LAN, which cannot keep a file from anyone.
+    async fn shared_publicly(&self, info_hash: &InfoHash) -> bool {
+        file_handler::get_bitfield(info_hash).is_none() && self.access.read().await.is_public(&info_hash.get_hashed_info_hash())
+    }
 
-        let priv_port = priv_socket.local_addr()?.port();
+    ///This method stores us on the DHT as a seeder of a file, in the background.
+    async fn announce_to_dht(&self, info_hash: &InfoHash) {
+        let hash = info_hash.get_hashed_info_hash();
+        if !self.shared_publicly(info_hash).await {
+            return;
+        }
 
-        println!("My private IP {:?}", priv_ipaddr);
-        println!("My private port is {}", priv_port);
+        let dht = self.p2p.dht.clone();
+        let peer = DhtPeer {
+            id: Some(self.p2p.self_addr),
+            cert_fingerprint: self.identity.fingerprint().to_vec(),
+            noise_key: self.identity.noise_public_key().to_vec(),
+        };
+        let name = info_hash.name.clone();
+        tokio::spawn(async move {
+            if dht.announce(hash, peer).await == 0 {
+                eprintln!("No DHT node stored us as a seeder of {}", name);
+            }
+        });
+    }
 
+    ///This method announces the files we seed on the LAN, and stops announcing those no longer shared with everyone.
+    async fn announce_to_lan(&self, info_hashes: &[InfoHash]) {
+        let mut public = Vec::new();
+        for info_hash in info_hashes {
+            match self.shared_publicly(info_hash).await {
+                true => public.push(info_hash.get_hashed_info_hash()),
+                false => self.lan.unshare(&info_hash.get_hashed_info_hash()),
+            }
+        }
+        self.lan.announce(&public).await;
+    }
 
-        let self_addr = PeerId {
-            ipaddr,
-            port: external_addr.port() as u32,
-            priv_ipaddr: u32::from_be_bytes(priv_ipaddr.octets()),
-            priv_port: priv_port as u32,
-        };
-        socket.set_nonblocking(true)?;
+    ///This method returns a view of the client that talks to the tracker of the given link.
+    fn via(&self, link: &TrackerLink) -> TorrentClient {
+        TorrentClient {
+            client: link.client.clone(),
+            turn: link.turn.clone(),
+            uid: link.uid.clone(),
+            tracker_url: link.url.clone(),
+            ..self.clone()
+        }
+    }
+
+    ///This method returns a view of the client for every tracker we are registered with, in order of preference.
+    async fn trackers(&self) -> Vec<TorrentClient> {
+        self.trackers.read().await.iter().map(|link| self.via(link)).collect()
+    }
+
+    ///This method returns a view of the client that talks to the tracker at url, registering with it
+    /// first if a federated tracker pointed us at one we do not know yet. An empty url stands for
+    /// the tracker this view talks to.
+    async fn tracker_at(&self, url: &str) -> Result<TorrentClient, Box<dyn std::error::Error>> {
+        if url.is_empty() || url == self.tracker_url {
+            return Ok(self.clone());
+        }
+        if let Some(link) = self.trackers.read().await.iter().find(|link| link.url == url) {
+            return Ok(self.via(link));
+        }
 
-        self.update_registered_peer_id(self_addr.clone()).await?;
+        let link = TrackerLink::connect(url, &self.identity, &self.api_key).await?;
+        let mut tracker = self.via(&link);
+        tracker.update_registered_peer_id(self.p2p.self_addr).await?;
+        self.trackers.write().await.push(link);
 
-        let server = self.clone();
+        Ok(tracker)
+    }
 
-        Ok(
-            PeerConnection {
-                server,
-                pub_socket: Some(UdpSocket::try_from(socket)?) ,
-                priv_socket: Some(priv_socket),
-                self_addr,
-            },
-        ) 
+    ///This method runs an operation against every tracker we are registered with, so each of them
+    /// can point peers at us should another one go down. It fails only if every tracker failed,
+    /// and returns the answer of the first tracker that succeeded.
+    async fn on_every_tracker<T, F, Fut>(&self, op: F) -> Result<T, Box<dyn std::error::Error>>
+    where
+        F: Fn(TorrentClient) -> Fut,
+        Fut: Future<Output = Result<T, Box<dyn std::error::Error>>>,
+    {
+        let mut answer = None;
+        let mut failure = String::from("not registered with any tracker");
+        for tracker in self.trackers().await {
+            match op(tracker).await {
+                Ok(value) => { answer.get_or_insert(value); }
+                Err(e) => failure = e.to_string(),
+            }
+        }
+        answer.ok_or_else(|| failure.into())
+    }
+
+    ///This method runs an operation against our trackers in order until one of them answers, so a
+    /// tracker that is down only costs the time it takes to notice.
+    async fn on_any_tracker<T, F, Fut>(&self, op: F) -> Result<T, Box<dyn std::error::Error>>
+    where
+        F: Fn(TorrentClient) -> Fut,
+        Fut: Future<Output = Result<T, Box<dyn std::error::Error>>>,
+    {
+        let mut failure = String::from("not registered with any tracker");
+        for tracker in self.trackers().await {
+            match op(tracker).await {
+                Ok(value) => return Ok(value),
+                Err(e) => failure = e.to_string(),
+            }
+        }
+        Err(failure.into())
     }
 
     ///This method gives the server the latest peer-id (ip and port numbers) so the server can give valid
@@ -144,39 +329,56 @@ impl TorrentClient {
     }
 
     ///seeding is used as a listening process to begin sending data upon request
-    /// it simply awaits a server request for it to send data
+    /// it opens a session with every tracker we are registered with, so leechers can be brokered
+    /// to us through any of them. Every ANNOUNCE_INTERVAL it reports the files we uploaded to
+    /// since the last report, every DHT_ANNOUNCE_INTERVAL it stores us on the DHT again and every
+    /// LAN_ANNOUNCE_INTERVAL it announces our files on the LAN again.
     pub async fn seeding(&mut self) -> Result<(), Box<dyn std::error::Error>> {
 
-        loop {
-            let mut peer_connection = self.register_new_connection().await?;
-
-            let mut server_client = self.client.clone();
+        //update every tracker's list of seeder files, peers on the LAN and the DHT find us without them
+        if let Err(e) = self.advertise_all().await {
+            eprintln!("Failed to advertise our files: {}", e);
+        }
 
-            //update server's list of seeder files
-            self.advertise_all().await?;
+        println!("Seeding with {:?}", self.p2p.self_addr);
+        for tracker in self.trackers().await {
+            tokio::spawn(async move { tracker.session().await });
+        }
 
-            // calls get_peer
-            println!("Seeding with {:?}", peer_connection.self_addr);
+        let mut announce_ticker = interval(ANNOUNCE_INTERVAL);
+        let mut announced: HashMap<[u8; 20], u64> = HashMap::new();
+        //advertising just announced every file on the DHT
+        let mut dht_ticker = interval(DHT_ANNOUNCE_INTERVAL);
+        dht_ticker.tick().await;
+        let mut lan_ticker = interval(LAN_ANNOUNCE_INTERVAL);
+        lan_ticker.tick().await;
 
+        loop {
             tokio::select! {
                 _ = self.close_down.notified() => {
                     println!("Shutting down");
                     return Ok(());
                 }
-                response = server_client.seed(peer_connection.self_addr.clone()) => {
-                    // waits for response from get_peer
-                    match response {
-                        Ok(res) => {
-                            tokio::spawn(async move {
-                                let res = peer_connection.seeder_connection(res).await;
-                                if res.is_err() {
-                                    println!("Connect Failed: {}", res.err().unwrap());
-                                }
-                            });
-
+                _ = dht_ticker.tick() => {
+                    let seeding = self.file_hashes.read().await.clone();
+                    for info_hash in seeding.values() {
+                        self.announce_to_dht(info_hash).await;
+                    }
+                }
+                _ = lan_ticker.tick() => {
+                    let seeding: Vec<InfoHash> = self.file_hashes.read().await.values().cloned().collect();
+                    self.announce_to_lan(&seeding).await;
+                }
+                _ = announce_ticker.tick() => {
+                    let seeding = self.file_hashes.read().await.clone();
+                    for (hash, transferred) in self.traffic.all() {
+                        let Some(info_hash) = seeding.get(&hash) else { continue };
+                        if announced.get(&hash) == Some(&transferred.uploaded) {
+                            continue;
                         }
-                        Err(e) => {
-                            eprintln!("Failed to get peer: {:?}", e);
+                        match self.announce(info_hash, AnnounceEvent::Progress).await {
+                            Ok(()) => { announced.insert(hash, transferred.uploaded); }
+                            Err(e) => eprintln!("Failed to announce {}: {}", info_hash.name, e),
                         }
                     }
                 }
@@ -184,77 +386,485 @@ impl TorrentClient {
         }
     }
 
+    ///This method holds our session with the tracker this view talks to until the client shuts
+    /// down, opening it again whenever it drops.
+    async fn session(&self) {
+        reconnecting(&self.close_down, &self.tracker_url, || self.hold_session()).await
+    }
+
+    ///This method opens a session with the tracker this view talks to and holds it until it ends.
+    /// The tracker pushes the leechers requesting data over it along with their credentials and
+    /// hole punch triggers. Every leecher is served on its own task, with at most `max_uploads`
+    /// leechers being served at the same time over all our trackers.
+    async fn hold_session(&self) -> Result<(), Box<dyn std::error::Error>> {
+        //every connection in progress listens for the trigger meant for it
+        let (punch_tx, _) = broadcast::channel(SESSION_DEPTH);
+        let mut credentials: HashMap<PeerId, (PeerIdentity, Instant)> = HashMap::new();
+
+        serve_session(self.client.clone(), self.p2p.self_addr, |event| {
+            match event {
+                server_event::Event::PeerCredentials(PeerCredentials { peer: Some(peer), identity: Some(identity) }) => {
+                    //credentials whose connection request never came are not kept around
+                    credentials.retain(|_, (_, received)| received.elapsed() < CREDENTIALS_TIMEOUT);
+                    credentials.insert(peer, (identity, Instant::now()));
+                }
+                server_event::Event::ConnectionRequest(request) => {
+                    let mut peer_connection = PeerConnection::new(self.clone());
+                    let upload_slots = self.upload_slots.clone();
+                    let leecher_credentials = request.leecher
+                        .and_then(|leecher| credentials.remove(&leecher))
+                        .map(|(identity, _)| identity);
+                    //subscribed before we accept, so the leecher cannot trigger the punch too early
+                    let punch_triggers = punch_tx.subscribe();
+
+                    tokio::spawn(async move {
+                        //requests beyond max_uploads wait here until an upload finishes
+                        let _permit = match upload_slots.acquire_owned().await {
+                            Ok(permit) => permit,
+                            Err(_) => return,
+                        };
+                        let res = peer_connection.seeder_connection(request, leecher_credentials, punch_triggers).await;
+                        if res.is_err() {
+                            println!("Connect Failed: {}", res.err().unwrap());
+                        }
+                    });
+                }
+                server_event::Event::PunchTrigger(leecher) => {
+                    //nobody waiting on the trigger is not an error
+                    let _ = punch_tx.send(leecher);
+                }
+                _ => return false,
+            }
+            true
+        }).await
+    }
+
+    ///This method queues a file for download. It starts once fewer than the configured number of
+    /// downloads are active and no queued download has a higher priority.
+    pub async fn queue_download(&self, info_hash: InfoHash, priority: i32) -> Result<(), Box<dyn std::error::Error>> {
+        self.downloads.queue(info_hash, priority).await
+    }
+
+    ///This method pauses a queued or active download, closing its connections. Resuming it only
+    /// requests the pieces it does not have yet.
+    pub async fn pause_download(&self, hash: &[u8; 20]) -> Result<(), Box<dyn std::error::Error>> {
+        self.downloads.pause(hash).await
+    }
+
+    ///This method queues a paused download again.
+    pub async fn resume_download(&self, hash: &[u8; 20]) -> Result<(), Box<dyn std::error::Error>> {
+        self.downloads.resume(hash).await
+    }
+
+    ///This method stops a download and removes it from the queue, keeping the pieces it wrote.
+    pub async fn cancel_download(&self, hash: &[u8; 20]) -> Result<(), Box<dyn std::error::Error>> {
+        self.downloads.cancel(hash).await
+    }
+
+    ///This method changes which queued downloads start first, higher priorities go before lower ones.
+    pub async fn set_download_priority(&self, hash: &[u8; 20], priority: i32) -> Result<(), Box<dyn std::error::Error>> {
+        self.downloads.set_priority(hash, priority).await
+    }
+
+    ///This method returns the progress, speed and number of peers of every download.
+    pub async fn download_status(&self) -> Vec<DownloadStatus> {
+        self.downloads.status(&self.traffic).await
+    }
+
     ///this method is used to request a file from the peer.
     /// it spins off as many connections as possible and begins the FileAssembler processes
-    /// which piece together a file from various peers.
-    pub async fn file_request(
+    /// which piece together a file from various peers. The download is announced to the tracker
+    /// when it starts, every ANNOUNCE_INTERVAL while it runs, and once it completes or stops.
+    /// Pieces are shared with other leechers as soon as we have them, and the file is advertised
+    /// as a whole once it is built. Every PEERS_INTERVAL the seeders are looked up again and the
+    /// ones we are not connected to are added to the download, the pieces still to request are
+    /// spread over every live connection. Peers exchange the peers they know over PEX while
+    /// downloading, and seeders learned that way are connected to directly as well. Returns
+    /// whether the file was built, pausing or cancelling the download stops it early.
+    async fn file_request(
         &mut self,
         file_hash: InfoHash
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut client = self.client.clone();
-
-        let peer_list = client.get_file_peer_list(
-            FileHash {hash: Vec::from(file_hash.get_hashed_info_hash())}
-        ).await?.into_inner().list;
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let peers = self.find_peers(&file_hash).await;
 
         //we want to maximize connection which means either one connection per piece
         // or one connection per peer, whichever is less.
-        let num_connections = min(peer_list.len(), file_hash.pieces.len());
-        let assembler =FileAssembler::new(file_hash.clone(), num_connections).await;
+        let num_connections = min(peers.len(), file_hash.pieces.len());
+        if num_connections == 0 {
+            return Err("no peer is sharing this file with us".into());
+        }
+
+        //stats are only informational, a download does not fail because the tracker missed one
+        if let Err(e) = self.announce(&file_hash, AnnounceEvent::Started).await {
+            eprintln!("Failed to announce download start: {}", e);
+        }
+
+        let assembler =FileAssembler::new(file_hash.clone(), self.traffic.clone()).await;
+        self.downloads.attach(&file_hash.get_hashed_info_hash(), assembler.clone()).await;
+        let mut outcome = assembler.write().await.take_outcome().ok_or("assembler outcome already taken")?;
+        let mut discovered = assembler.write().await.take_discovered().ok_or("assembler discovered peers already taken")?;
 
         let mut connection_handles = Vec::new();
 
 
         //spawn the correct number of connections
-        for i in 0..num_connections {
-            let mut peer_connection = self.register_new_connection().await?;
-
-            let conn_tx = assembler.read().await.get_conn_tx();
-            let request_rx = assembler.write().await.subscribe_new_connection();
-            let peer_id = peer_list[i];
-            let handle = tokio::spawn(async move {
-                
-                let res = peer_connection.requester_connection(peer_id,conn_tx, request_rx).await;
-                if res.is_err() {
-                    eprintln!("connection error: {}", res.err().unwrap());
-                }
-            });
-            connection_handles.push(handle);
+        for (server, peer) in peers.into_iter().take(num_connections) {
+            connection_handles.push(self.connect_peer(server, peer, assembler.clone()).await?);
         }
 
 
         //begin assemble task
         assembler.write().await.start_requesting();
 
+        //report progress and share our pieces until the file is built or reassembly gives up
+        let mut announce_ticker = interval(ANNOUNCE_INTERVAL);
+        announce_ticker.tick().await;
+        let mut pieces_ticker = interval(PIECES_INTERVAL);
+        let mut peers_ticker = interval(PEERS_INTERVAL);
+        peers_ticker.tick().await;
+        let mut shared = None;
+        let completed = loop {
+            tokio::select! {
+                built = &mut outcome => break built.unwrap_or(false),
+                _ = announce_ticker.tick() => {
+                    if let Err(e) = self.announce(&file_hash, AnnounceEvent::Progress).await {
+                        eprintln!("Failed to announce download progress: {}", e);
+                    }
+                }
+                _ = pieces_ticker.tick() => {
+                    match self.share_pieces(&file_hash, shared.clone()).await {
+                        Ok(now_shared) => shared = now_shared,
+                        Err(e) => eprintln!("Failed to share downloaded pieces: {}", e),
+                    }
+                }
+                _ = peers_ticker.tick() => {
+                    //seeders that came online since we started, or that we lost and are back
+                    for (server, peer) in self.find_peers(&file_hash).await {
+                        let assembler_lock = assembler.read().await;
+                        if assembler_lock.connections() >= file_hash.pieces.len() {
+                            break;
+                        }
+                        if assembler_lock.connected_to(&peer.cert_fingerprint) || peer.cert_fingerprint == self.identity.fingerprint() {
+                            continue;
+                        }
+                        drop(assembler_lock);
+
+                        println!("Adding a connection to a seeder found mid-download");
+                        match self.connect_peer(server, peer, assembler.clone()).await {
+                            Ok(handle) => connection_handles.push(handle),
+                            Err(e) => eprintln!("Could not add a connection: {}", e),
+                        }
+                    }
+                }
+                Some(seeder) = discovered.recv() => {
+                    if seeder.fingerprint.as_slice() == self.identity.fingerprint() {
+                        continue;
+                    }
+                    connection_handles.push(self.connect_discovered(seeder, assembler.clone()));
+                }
+            }
+        };
+
+        //we are a full seeder of the file from now on
+        if completed {
+            self.file_hashes.write().await.insert(file_hash.get_hashed_info_hash(), file_hash.clone());
+            if let Err(e) = self.advertise(file_hash.clone()).await {
+                eprintln!("Failed to advertise downloaded file: {}", e);
+            }
+        }
+
         //wait on connections to finish
         for handle in connection_handles {
             handle.await?;
         }
 
-        Ok(())
+        let event = if completed { AnnounceEvent::Completed } else { AnnounceEvent::Stopped };
+        if let Err(e) = self.announce(&file_hash, event).await {
+            eprintln!("Failed to announce download end: {}", e);
+        }
+
+        Ok(completed)
+    }
+
+    ///This method finds the peers sharing a file with us, each with the tracker brokering our
+    /// connection to it. The first tracker that answers lists them, except for peers a federated
+    /// tracker told it about, which are brokered through their own tracker. Seeders on our LAN come
+    /// first, and if there are none of either the seeders are looked up on the DHT.
+    async fn find_peers(&self, file_hash: &InfoHash) -> Vec<(Option<TorrentClient>, Peer)> {
+        let hash = FileHash {hash: Vec::from(file_hash.get_hashed_info_hash())};
+        let found = self.on_any_tracker(|mut tracker| {
+            let hash = hash.clone();
+            async move {
+                let peer_list = tracker.client.get_file_peer_list(hash).await?.into_inner().list;
+                Ok((tracker, peer_list))
+            }
+        }).await.map_err(|e| e.to_string());
+
+        //each peer comes with the tracker brokering our connection to it, None for LAN and DHT peers
+        let mut peers: Vec<(Option<TorrentClient>, Peer)> = Vec::new();
+        match found {
+            Ok((tracker, peer_list)) => {
+                for peer in peer_list {
+                    match tracker.tracker_at(&peer.tracker).await {
+                        Ok(server) => peers.push((Some(server), peer)),
+                        Err(e) => eprintln!("Could not reach tracker {}: {}", peer.tracker, e),
+                    }
+                }
+            }
+            Err(e) => eprintln!("No tracker could list the seeders: {}", e),
+        }
+        let lan_peers: Vec<(Option<TorrentClient>, Peer)> = self.lan_peers(file_hash).await.into_iter()
+            .filter(|lan_peer| peers.iter().all(|(_, peer)| peer.cert_fingerprint != lan_peer.cert_fingerprint))
+            .map(|peer| (None, peer))
+            .collect();
+        peers.splice(0..0, lan_peers);
+        if peers.is_empty() {
+            peers = self.dht_peers(file_hash).await.into_iter().map(|peer| (None, peer)).collect();
+        }
+        //peers we could not share over PEX could not be connected to either
+        peers.retain(|(_, peer)| pex::pex_peer(peer).is_some());
+
+        peers
     }
 
-    ///This method advertises a specific file to the server.
-    /// Essentially, it tells the server that this client has this file and it can
-    /// be requested by other peers.
+    ///This method subscribes a connection to a peer with the download's assembler and connects to
+    /// it in the background, through the tracker that brokers it or directly if none does.
+    async fn connect_peer(
+        &self,
+        server: Option<TorrentClient>,
+        peer: Peer,
+        assembler: Arc<RwLock<FileAssembler>>,
+    ) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+        let brokered = server.is_some();
+        let mut peer_connection = PeerConnection::new(server.unwrap_or_else(|| self.clone()));
+
+        let conn_tx = assembler.read().await.get_conn_tx();
+        let pex_peer = pex::pex_peer(&peer).ok_or("peer missing connection details")?;
+        let request_rx = assembler.write().await.subscribe_new_connection(pex_peer, peer.bitfield.clone());
+        Ok(tokio::spawn(async move {
+
+            let res = match brokered {
+                true => peer_connection.requester_connection(peer, conn_tx, request_rx).await,
+                false => peer_connection.direct_connection(peer, conn_tx, request_rx).await,
+            };
+            if res.is_err() {
+                eprintln!("connection error: {}", res.err().unwrap());
+            }
+        }))
+    }
+
+    ///This method connects to a seeder learned over PEX in the background and, once connected,
+    /// adds it to the download's connections.
+    fn connect_discovered(&self, seeder: PexPeer, assembler: Arc<RwLock<FileAssembler>>) -> JoinHandle<()> {
+        let mut peer_connection = PeerConnection::new(self.clone());
+        tokio::spawn(async move {
+            let peer = Peer {
+                id: Some(seeder.id),
+                cert_fingerprint: seeder.fingerprint.to_vec(),
+                noise_key: Vec::new(),
+                bitfield: Vec::new(),
+                tracker: String::new(),
+            };
+            let conn = match peer_connection.direct_connect(&peer).await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    eprintln!("Could not connect to a seeder learned over PEX: {}", e);
+                    return;
+                }
+            };
+
+            let mut assembler = assembler.write().await;
+            let conn_tx = assembler.get_conn_tx();
+            let request_rx = assembler.subscribe_new_connection(seeder, Vec::new());
+            QuicP2PConn::start_requesting(conn, conn_tx, Arc::new(Mutex::new(request_rx)));
+        })
+    }
+
+    ///This method finds the seeders of a file on our LAN, searching for them if none announced it yet.
+    async fn lan_peers(&self, info_hash: &InfoHash) -> Vec<Peer> {
+        let hash = info_hash.get_hashed_info_hash();
+        if self.lan.peers(&hash).is_empty() {
+            self.lan.search(Some(hash)).await;
+        }
+
+        self.lan.peers(&hash).iter().map(LocalPeer::peer).collect()
+    }
+
+    ///This method fetches the info of a file seeded on our LAN from the first of its seeders that
+    /// hands it over, and keeps it for get_info_hash.
+    async fn lan_info(&self, hash: [u8; 20]) -> Result<InfoHash, Box<dyn std::error::Error>> {
+        if let Some(info_hash) = self.lan_files.read().await.get(&hash) {
+            return Ok(info_hash.clone());
+        }
+
+        let mut failure = String::from("no seeder on the LAN");
+        for peer in self.lan.peers(&hash) {
+            let info = match PeerConnection::new(self.clone()).direct_connect(&peer.peer()).await {
+                Ok(conn) => QuicP2PConn::fetch_info(&conn, hash).await,
+                Err(e) => Err(e),
+            };
+            match info {
+                Ok(info_hash) => {
+                    self.lan_files.write().await.insert(hash, info_hash.clone());
+                    return Ok(info_hash);
+                }
+                Err(e) => failure = e.to_string(),
+            }
+        }
+        Err(failure.into())
+    }
+
+    ///This method lists the files seeded on our LAN the way the tracker's catalog does, for when no
+    /// tracker answers. All of them come on one page, sorted by name.
+    async fn lan_catalog(&self, query: &CatalogQuery) -> CatalogPage {
+        self.lan.search(None).await;
+
+        let mut files = Vec::new();
+        for hash in self.lan.files() {
+            let info_hash = match self.lan_info(hash).await {
+                Ok(info_hash) => info_hash,
+                Err(e) => {
+                    eprintln!("Could not get the info of {} from the LAN: {}", hex::encode(hash), e);
+                    continue;
+                }
+            };
+            if !info_hash.name.to_lowercase().contains(&query.name.to_lowercase())
+                || info_hash.file_length < query.min_size
+                || (query.max_size != 0 && info_hash.file_length > query.max_size) {
+                continue;
+            }
+            files.push(FileSummary {
+                hash: Some(FileHash { hash: hash.to_vec() }),
+                name: info_hash.name.clone(),
+                file_length: info_hash.file_length,
+                piece_length: info_hash.piece_length,
+                piece_count: info_hash.pieces.len() as u32,
+                seeders: self.lan.peers(&hash).len() as u32,
+                ..Default::default()
+            });
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        CatalogPage { files, next_cursor: String::new() }
+    }
+
+    ///This method looks up the seeders of a file on the DHT, leaving us out.
+    async fn dht_peers(&self, info_hash: &InfoHash) -> Vec<Peer> {
+        let own = self.identity.fingerprint();
+        self.p2p.dht.get_peers(info_hash.get_hashed_info_hash()).await.into_iter()
+            .filter(|peer| peer.cert_fingerprint != own)
+            .map(|peer| Peer {
+                id: peer.id,
+                cert_fingerprint: peer.cert_fingerprint,
+                noise_key: peer.noise_key,
+                bitfield: Vec::new(),
+                tracker: String::new(),
+            })
+            .collect()
+    }
+
+    ///This method reports to our trackers how far we got with a file and how many of its bytes we
+    /// moved, counted since the client started.
+    async fn announce(&self, info_hash: &InfoHash, event: AnnounceEvent) -> Result<(), Box<dyn std::error::Error>> {
+        let hash = info_hash.get_hashed_info_hash();
+        let transferred = self.traffic.get(&hash);
+        let left = match (event, file_handler::get_bitfield(info_hash)) {
+            (AnnounceEvent::Completed, _) | (_, None) => 0,
+            (_, Some(bitfield)) => {
+                let have = bitfield.iter().map(|byte| byte.count_ones() as u64).sum::<u64>();
+                let missing = info_hash.pieces.len() as u64 - have;
+                min(missing * info_hash.piece_length as u64, info_hash.file_length)
+            }
+        };
+
+        let announce = Announce {
+            id: None,
+            hash: Some(FileHash { hash: hash.to_vec() }),
+            event: event.into(),
+            downloaded: transferred.downloaded,
+            uploaded: transferred.uploaded,
+            left,
+        };
+        self.on_every_tracker(|mut tracker| {
+            let announce = Announce { id: Some(tracker.uid.clone()), ..announce.clone() };
+            async move {
+                tracker.client.announce(announce).await?;
+                Ok(())
+            }
+        }).await
+    }
+
+    ///This method tells our trackers which pieces of a file we are downloading we can serve. The file
+    /// is advertised once we have a piece of it, from then on its pieces are served from the .part
+    /// file and the tracker is updated whenever we got more. Returns the bitfield the tracker has.
+    async fn share_pieces(
+        &self,
+        info_hash: &InfoHash,
+        shared: Option<Vec<u8>>
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let Some(bitfield) = file_handler::get_bitfield(info_hash) else {
+            return Ok(shared);
+        };
+        if shared.as_ref() == Some(&bitfield) || bitfield.iter().all(|byte| *byte == 0) {
+            return Ok(shared);
+        }
+
+        let hash = info_hash.get_hashed_info_hash();
+        match shared {
+            None => {
+                self.file_hashes.write().await.insert(hash, info_hash.clone());
+                self.advertise(info_hash.clone()).await?;
+            }
+            Some(_) => {
+                let update = PieceUpdate {
+                    id: None,
+                    hash: Some(FileHash { hash: hash.to_vec() }),
+                    bitfield: bitfield.clone(),
+                };
+                self.on_every_tracker(|mut tracker| {
+                    let update = PieceUpdate { id: Some(tracker.uid.clone()), ..update.clone() };
+                    async move {
+                        tracker.client.update_pieces(update).await?;
+                        Ok(())
+                    }
+                }).await?;
+            }
+        }
+
+        Ok(Some(bitfield))
+    }
+
+    ///This method advertises a specific file to every tracker we are registered with.
+    /// Essentially, it tells the trackers that this client has this file and it can
+    /// be requested by other peers, limited to the peers our access rules share it with.
+    /// Files we are still downloading are advertised with the pieces we have of them.
+    /// Public files we have as a whole are announced on the DHT as well.
     pub async fn advertise(
         &self,
         info_hash: InfoHash
-    ) -> Result<ClientId, Box<dyn std::error::Error>> {
-        let mut client = self.client.clone();
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.announce_to_dht(&info_hash).await;
+        self.announce_to_lan(std::slice::from_ref(&info_hash)).await;
 
         let file_hash = FileHash { hash: Vec::from(info_hash.get_hashed_info_hash())};
+        let scope = self.access.read().await.scope(&info_hash.get_hashed_info_hash());
 
         //todo make hash active
-        let request = Request::new(FileMessage {
-            id: Some(self.uid.clone()),
+        let message = FileMessage {
+            id: None,
             hash: Some(file_hash),
+            bitfield: file_handler::get_bitfield(&info_hash).unwrap_or_default(),
             info_hash: Some(info_hash),
-        });
-
-        let resp = client.advertise(request).await?;
+            scope: Some(scope),
+        };
 
-        Ok(resp.into_inner())
+        self.on_every_tracker(|mut tracker| {
+            let request = Request::new(FileMessage { id: Some(tracker.uid.clone()), ..message.clone() });
+            async move {
+                tracker.client.advertise(request).await?;
+                Ok(())
+            }
+        }).await
     }
 
     ///This method loops through all files stored locally and advertises them to the server to be requested.
@@ -268,42 +878,137 @@ impl TorrentClient {
         Ok(())
     }
 
-    ///This methods gets all the files currently being advertised by connected peers to the server.
-    pub async fn get_server_files(&self) -> Result<Vec<InfoHash>, Box<dyn std::error::Error>> {
-        let mut server_connection = self.client.clone();
-
-        let res = server_connection.get_all_files(Request::new(())).await?;
-        let info_hashes = res.into_inner().info_hashes;
-
-        Ok(info_hashes)
+    ///This method gets one page of the files advertised to us, following the query's search,
+    /// filters, sort order and cursor. Files come without their piece hashes. If no tracker
+    /// answers, the files seeded on our LAN are listed instead.
+    pub async fn browse_catalog(&self, query: CatalogQuery) -> Result<CatalogPage, Box<dyn std::error::Error>> {
+        let page = self.on_any_tracker(|mut tracker| {
+            let query = query.clone();
+            async move { Ok(tracker.client.browse_catalog(query).await?.into_inner()) }
+        }).await;
+
+        match page {
+            Ok(page) => Ok(page),
+            Err(e) => {
+                eprintln!("No tracker could list the catalog, listing the files on the LAN: {}", e);
+                Ok(self.lan_catalog(&query).await)
+            }
+        }
+    }
 
+    ///This method fetches everything needed to download one file from the catalog, from a seeder
+    /// on our LAN if no tracker answers.
+    pub async fn get_info_hash(&self, file: &FileSummary) -> Result<InfoHash, Box<dyn std::error::Error>> {
+        let file_hash = file.hash.clone().ok_or("catalog entry missing file hash")?;
+
+        let info_hash = self.on_any_tracker(|mut tracker| {
+            let file_hash = file_hash.clone();
+            async move { Ok(tracker.client.get_info_hash(file_hash).await?.into_inner()) }
+        }).await;
+
+        match info_hash {
+            Ok(info_hash) => Ok(info_hash),
+            Err(e) => {
+                let hash: [u8; 20] = file_hash.hash.as_slice().try_into().map_err(|_| e)?;
+                self.lan_info(hash).await
+            }
+        }
     }
 
-    ///This method deletes a file from the local system and delists it from the server so peers do not
-    /// request to receive a file from this peer.
+    ///This method deletes a file from the local system and delists it from our trackers so peers do not
+    /// request to receive a file from this peer. It is no longer announced on the LAN either.
     pub async fn delete_file(
         &self,
         file_hash: InfoHash
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut server_connection = self.client.clone();
+        self.lan.unshare(&file_hash.get_hashed_info_hash());
         let hash = FileHash { hash: Vec::from(file_hash.get_hashed_info_hash())};
-        let file_delete = FileDelete {
-            id: Some(self.uid.clone()),
-            hash: Some(hash),
-        };
 
-        server_connection.delete_file(file_delete).await?;
+        self.on_every_tracker(|mut tracker| {
+            let file_delete = FileDelete {
+                id: Some(tracker.uid.clone()),
+                hash: Some(hash.clone()),
+            };
+            async move {
+                tracker.client.delete_file(file_delete).await?;
+                Ok(())
+            }
+        }).await?;
 
         file_handler::delete_file(file_hash.name)?;
 
         Ok(())
     }
 
-    ///This method delists a client entirely from the server so that no peer may try making a request to this client.
-    pub async fn remove_client(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut server_connection = self.client.clone();
+    ///This method blocks a peer, identified by its certificate fingerprint, from downloading any of our files.
+    pub async fn block_peer(&self, fingerprint: [u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        self.access.write().await.block(fingerprint)?;
+        Ok(())
+    }
+
+    ///This method restricts one of our files to an explicit list of peers, adding the given peer to it.
+    /// The file is advertised again so only those peers see it on the tracker.
+    pub async fn allow_peer(&self, info_hash: InfoHash, fingerprint: [u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        self.access.write().await.allow(info_hash.get_hashed_info_hash(), fingerprint)?;
+        self.advertise(info_hash).await?;
+        Ok(())
+    }
+
+    ///This method shares one of our files with the members of a tracker group only and advertises it again.
+    pub async fn share_with_group(&self, info_hash: InfoHash, group: String) -> Result<(), Box<dyn std::error::Error>> {
+        self.access.write().await.share_with_group(info_hash.get_hashed_info_hash(), group)?;
+        self.advertise(info_hash).await?;
+        Ok(())
+    }
 
-        server_connection.delist_client(self.uid.clone()).await?;
+    ///This method creates a group that we own. Groups are kept by each tracker, so it is created on all of them.
+    pub async fn create_group(&self, name: String) -> Result<(), Box<dyn std::error::Error>> {
+        self.on_every_tracker(|mut tracker| {
+            let name = name.clone();
+            async move {
+                tracker.client.create_group(GroupName { name }).await?;
+                Ok(())
+            }
+        }).await
+    }
+
+    ///This method adds a peer, by certificate fingerprint, to a group we own.
+    pub async fn add_group_member(&self, group: String, fingerprint: [u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        let member = GroupMember { group, cert_fingerprint: fingerprint.to_vec() };
+        self.on_every_tracker(|mut tracker| {
+            let member = member.clone();
+            async move {
+                tracker.client.add_group_member(member).await?;
+                Ok(())
+            }
+        }).await
+    }
+
+    ///This method removes a peer from a group we own, or removes us from a group when given our own fingerprint.
+    pub async fn remove_group_member(&self, group: String, fingerprint: [u8; 32]) -> Result<(), Box<dyn std::error::Error>> {
+        let member = GroupMember { group, cert_fingerprint: fingerprint.to_vec() };
+        self.on_every_tracker(|mut tracker| {
+            let member = member.clone();
+            async move {
+                tracker.client.remove_group_member(member).await?;
+                Ok(())
+            }
+        }).await
+    }
+
+    ///This method lists the groups we are in on the first tracker that answers.
+    pub async fn list_groups(&self) -> Result<Vec<Group>, Box<dyn std::error::Error>> {
+        self.on_any_tracker(|mut tracker| async move {
+            Ok(tracker.client.list_groups(Request::new(())).await?.into_inner().groups)
+        }).await
+    }
+
+    ///This method delists a client entirely from every tracker so that no peer may try making a request to this client.
+    pub async fn remove_client(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.on_every_tracker(|mut tracker| async move {
+            tracker.client.delist_client(tracker.uid.clone()).await?;
+            Ok(())
+        }).await?;
         self.close_down.notify_waiters();
 
         Ok(())
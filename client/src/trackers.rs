@@ -0,0 +1,285 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify, RwLock};
+use tokio::time::{interval, sleep};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{ClientTlsConfig, Endpoint};
+use crate::auth::{Relay, SessionAuth, Tracker};
+use crate::connection::connection::{client_event, connector_client, server_event, turn_client, ClientEvent, ClientId, ClientRegistry, PeerId, ServerEvent};
+use crate::identity::Identity;
+
+/// every tracker we registered with, the first one is the one we prefer
+pub type TrackerLinks = Arc<RwLock<Vec<TrackerLink>>>;
+
+/// what we sign ahead of a tracker's nonce to prove our certificate is ours, as trackers expect it
+const REGISTRATION_CONTEXT: &[u8] = b"tracker registration\0";
+
+/// how often we swap our session token for a fresh one, well within the day a token lasts
+const TOKEN_RENEWAL: Duration = Duration::from_secs(60 * 60);
+
+/// events buffered in either direction of our session with a tracker
+pub const SESSION_DEPTH: usize = 32;
+
+/// how long we wait to open a session again after it dropped, doubled every time it drops again
+/// soon after up to SESSION_RETRY_MAX
+const SESSION_RETRY_MIN: Duration = Duration::from_secs(1);
+const SESSION_RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// TrackerLink is our registration with one tracker. Trackers do not share registrations, so we
+/// have a different client id and session token with each of them.
+#[derive(Debug, Clone)]
+pub struct TrackerLink {
+    /// where the tracker is reached, as configured or as a federated tracker named it
+    pub url: String,
+    pub client: Tracker,
+    pub turn: Relay,
+    pub uid: ClientId,
+}
+
+impl TrackerLink {
+
+    ///connect()
+    /// parameters:
+    ///     - url: the tracker's address, https urls are reached over TLS
+    ///     - identity: the persistent keys we register with
+    ///     - api_key: the key trackers that require one admit us with
+    ///
+    /// function:
+    /// Connects to a tracker and registers with it, signing the nonce it hands out with our
+    /// certificate's key, and returns clients that carry the session token it handed out. The token
+    /// is renewed in the background for as long as the client runs.
+    pub async fn connect(url: &str, identity: &Identity, api_key: &str) -> Result<TrackerLink, Box<dyn std::error::Error>> {
+        let channel = endpoint(url)?.connect().await?;
+        let mut registrar = connector_client::ConnectorClient::new(channel.clone());
+
+        let nonce = registrar.get_registration_challenge(()).await?.into_inner().nonce;
+        let signature = identity.sign(&[REGISTRATION_CONTEXT, &nonce].concat())?;
+        let registration = registrar.register_client(ClientRegistry {
+            peer_id: None,
+            cert_fingerprint: identity.fingerprint().to_vec(),
+            noise_key: identity.noise_public_key().to_vec(),
+            api_key: api_key.to_string(),
+            certificate: identity.certificate().to_vec(),
+            nonce,
+            signature,
+        }).await?.into_inner();
+        let uid = registration.client_id.ok_or("tracker returned no client id")?;
+
+        //every call from here on carries the session token the tracker handed out
+        let auth = SessionAuth::new(&registration.session_token)?;
+        let client = connector_client::ConnectorClient::with_interceptor(channel.clone(), auth.clone());
+        tokio::spawn(renew_forever(client.clone(), auth.clone(), url.to_string()));
+        Ok(TrackerLink {
+            url: url.to_string(),
+            client,
+            turn: turn_client::TurnClient::with_interceptor(channel, auth),
+            uid,
+        })
+    }
+
+    ///unregistered()
+    /// parameters:
+    ///     - url: the tracker's address
+    ///
+    /// function:
+    /// Returns a link to a tracker we could not register with, so a client without any tracker
+    /// still has one to point its calls at. The channel only connects once used and the tracker
+    /// refuses calls without a session token, so every call through it fails.
+    pub fn unregistered(url: &str) -> Result<TrackerLink, Box<dyn std::error::Error>> {
+        let channel = endpoint(url)?.connect_lazy();
+        let auth = SessionAuth::new("")?;
+
+        Ok(TrackerLink {
+            url: url.to_string(),
+            client: connector_client::ConnectorClient::with_interceptor(channel.clone(), auth.clone()),
+            turn: turn_client::TurnClient::with_interceptor(channel, auth),
+            uid: ClientId::default(),
+        })
+    }
+}
+
+/// renew_forever (
+///     tracker: the tracker the token was issued by
+///     auth: where the token is kept
+///     url: the tracker's address, for the log
+/// )
+/// helper function that swaps our session token for a fresh one every TOKEN_RENEWAL, so our calls
+/// keep being admitted whether or not we hold a session with the tracker
+async fn renew_forever(mut tracker: Tracker, auth: SessionAuth, url: String) {
+    let mut renewal = interval(TOKEN_RENEWAL);
+    //the first tick completes immediately and the token is fresh
+    renewal.tick().await;
+    loop {
+        renewal.tick().await;
+        let token = match tracker.renew_session_token(()).await {
+            Ok(registration) => registration.into_inner().session_token,
+            Err(e) => {
+                eprintln!("Could not renew our session token with {}: {}", url, e.message());
+                continue;
+            }
+        };
+        if let Err(e) = auth.renew(&token) {
+            eprintln!("{} handed out a session token we cannot send: {}", url, e);
+        }
+    }
+}
+
+/// serve_session (
+///     tracker: the tracker to open the session with
+///     hello: the connection details we registered
+///     handle: called with every event the tracker pushes, returns whether we accept it
+/// )
+/// helper function that opens a session with a tracker and hands it the events it pushes until it
+/// ends, acknowledging those that were accepted. The tracker closing the session is not an error.
+pub async fn serve_session(
+    mut tracker: Tracker,
+    hello: PeerId,
+    mut handle: impl FnMut(server_event::Event) -> bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (event_tx, event_rx) = mpsc::channel(SESSION_DEPTH);
+    event_tx.send(ClientEvent { event: Some(client_event::Event::Hello(hello)) }).await?;
+    let mut events = tracker.session(ReceiverStream::new(event_rx)).await?.into_inner();
+
+    // waits for the next event the server pushes
+    while let Some(ServerEvent { event_id, event }) = events.message().await? {
+        if event.is_some_and(&mut handle) {
+            event_tx.send(ClientEvent { event: Some(client_event::Event::Ack(event_id)) }).await?;
+        }
+    }
+    Ok(())
+}
+
+/// reconnecting (
+///     close_down: notified once the client shuts down
+///     url: the tracker the session is held with, for the log
+///     open: opens the session and holds it until it ends
+/// )
+/// helper function that holds a session with a tracker until the client shuts down, opening it
+/// again whenever it ends. A session that drops soon after it was opened waits twice as long as
+/// the one before it, up to SESSION_RETRY_MAX.
+pub async fn reconnecting<F, Fut>(close_down: &Notify, url: &str, mut open: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), Box<dyn std::error::Error>>>,
+{
+    //created up front, so shutting down while we wait between attempts is not missed
+    let closed = close_down.notified();
+    tokio::pin!(closed);
+
+    let mut backoff = SESSION_RETRY_MIN;
+    loop {
+        let opened = Instant::now();
+        tokio::select! {
+            _ = &mut closed => return,
+            ended = open() => match ended {
+                Ok(()) => eprintln!("Session with {} closed by the tracker", url),
+                Err(e) => eprintln!("Session with {} dropped: {}", url, e),
+            },
+        }
+
+        //a session that held for a while was not refused, the next one starts over
+        if opened.elapsed() > SESSION_RETRY_MAX {
+            backoff = SESSION_RETRY_MIN;
+        }
+        tokio::select! {
+            _ = &mut closed => return,
+            _ = sleep(backoff) => {}
+        }
+        backoff = (backoff * 2).min(SESSION_RETRY_MAX);
+    }
+}
+
+/// endpoint (
+///     url: the tracker's address, https urls are reached over TLS
+/// )
+/// helper function returning the endpoint a tracker is reached through. Its error is Send, so
+/// connecting can be awaited from the download tasks
+fn endpoint(url: &str) -> Result<Endpoint, tonic::transport::Error> {
+    let mut endpoint = Endpoint::from_shared(url.to_string())?;
+    if endpoint.uri().scheme_str() == Some("https") {
+        //webki roots uses Mozilla's certificate store, the domain is the url's host
+        endpoint = endpoint.tls_config(ClientTlsConfig::new().with_webpki_roots())?;
+    }
+    Ok(endpoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::time::timeout;
+    use tonic::codegen::BoxStream;
+    use tonic::transport::Server;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::{Request, Response, Status, Streaming};
+    use crate::connection::connection::connector_server::{Connector, ConnectorServer};
+
+    /// a tracker that pushes every session an event and drops the first `drops` of them right after
+    struct FlakyTracker {
+        opened: AtomicU64,
+        drops: u64,
+        /// the events the sessions that were kept acknowledged
+        acks: mpsc::UnboundedSender<u64>,
+    }
+
+    #[tonic::async_trait]
+    impl Connector for FlakyTracker {
+        async fn session(&self, request: Request<Streaming<ClientEvent>>) -> Result<Response<BoxStream<ServerEvent>>, Status> {
+            let event_id = self.opened.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut inbound = request.into_inner();
+            let Some(ClientEvent { event: Some(client_event::Event::Hello(hello)) }) = inbound.message().await? else {
+                return Err(Status::invalid_argument("session must start with hello"));
+            };
+
+            let (tx, rx) = mpsc::channel(SESSION_DEPTH);
+            let _ = tx.send(Ok(ServerEvent { event_id, event: Some(server_event::Event::PunchTrigger(hello)) })).await;
+            if event_id <= self.drops {
+                let _ = tx.send(Err(Status::unavailable("instance going away"))).await;
+            } else {
+                let acks = self.acks.clone();
+                tokio::spawn(async move {
+                    while let Ok(Some(ClientEvent { event: Some(client_event::Event::Ack(event_id)) })) = inbound.message().await {
+                        let _ = acks.send(event_id);
+                    }
+                    drop(tx);
+                });
+            }
+            Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+        }
+    }
+
+    #[tokio::test]
+    async fn a_dropped_session_is_opened_again_until_the_client_shuts_down() {
+        let (acks_tx, mut acks) = mpsc::unbounded_channel();
+        let tracker = FlakyTracker { opened: AtomicU64::new(0), drops: 1, acks: acks_tx };
+        let incoming = TcpIncoming::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = format!("http://{}", incoming.local_addr().unwrap());
+        tokio::spawn(Server::builder().add_service(ConnectorServer::new(tracker)).serve_with_incoming(incoming));
+
+        let channel = endpoint(&url).unwrap().connect().await.unwrap();
+        let client = connector_client::ConnectorClient::with_interceptor(channel, SessionAuth::new("token").unwrap());
+        let hello = PeerId { port: 7, ..PeerId::default() };
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let close_down = Arc::new(Notify::new());
+        let held = {
+            let (seen, close_down) = (seen.clone(), close_down.clone());
+            tokio::spawn(async move {
+                reconnecting(&close_down, &url, || {
+                    let seen = seen.clone();
+                    serve_session(client.clone(), hello, move |event| {
+                        seen.lock().unwrap().push(event);
+                        true
+                    })
+                }).await
+            })
+        };
+
+        //the first session was dropped, the one opened after it is kept and acknowledges its event
+        assert_eq!(timeout(Duration::from_secs(10), acks.recv()).await.unwrap(), Some(2));
+        assert_eq!(*seen.lock().unwrap(), vec![server_event::Event::PunchTrigger(hello); 2]);
+
+        close_down.notify_waiters();
+        timeout(Duration::from_secs(5), held).await.unwrap().unwrap();
+    }
+}
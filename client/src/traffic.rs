@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// bytes moved for one file since the client started
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Transferred {
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+/// Traffic counts the piece bytes we download and upload per file, they are what we announce to
+/// the tracker. Every connection, direct or relayed, counts into the same Traffic.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    files: Mutex<HashMap<[u8; 20], Transferred>>,
+}
+
+impl Traffic {
+
+    ///add_downloaded()
+    /// parameters:
+    ///     - hash: the file the piece belongs to
+    ///     - bytes: length of the piece
+    ///
+    /// function:
+    /// Counts a piece we received and verified.
+    pub fn add_downloaded(&self, hash: [u8; 20], bytes: u64) {
+        self.files.lock().unwrap().entry(hash).or_default().downloaded += bytes;
+    }
+
+    ///add_uploaded()
+    /// parameters:
+    ///     - hash: the file the piece belongs to
+    ///     - bytes: length of the piece
+    ///
+    /// function:
+    /// Counts a piece we sent to a peer.
+    pub fn add_uploaded(&self, hash: [u8; 20], bytes: u64) {
+        self.files.lock().unwrap().entry(hash).or_default().uploaded += bytes;
+    }
+
+    ///get()
+    /// parameters:
+    ///     - hash: the file
+    ///
+    /// function:
+    /// Returns the bytes moved for the file so far.
+    pub fn get(&self, hash: &[u8; 20]) -> Transferred {
+        self.files.lock().unwrap().get(hash).copied().unwrap_or_default()
+    }
+
+    ///all()
+    ///
+    /// function:
+    /// Returns the bytes moved for every file we moved any for.
+    pub fn all(&self) -> HashMap<[u8; 20], Transferred> {
+        self.files.lock().unwrap().clone()
+    }
+}
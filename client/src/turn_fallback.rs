@@ -1,14 +1,21 @@
-use crate::connection::connection::{turn_client::TurnClient, PeerId, RegisterRequest, TurnPacket,
-                                    turn_packet::Body, TurnPiece, TurnPieceRequest, InfoHash};
+use crate::connection::connection::{RelayToken,
+                                    RegisterRequest, TurnPacket, InfoHash, PeerFingerprint, PeerIdentity, turn_packet::Body};
 use crate::message::Message;
 use tokio::sync::mpsc;
 use tokio_stream::{StreamExt, wrappers::ReceiverStream};
-use tonic::Status;
+use tonic::{Code, Status, Streaming};
 use std::sync::Arc;
 use tokio::{sync::{Mutex, RwLock}};
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
-use crate::file_handler::{read_piece_from_file };
+use crate::access::{AccessPolicy, AuthenticatedPeer};
+use crate::auth::{Relay, Tracker};
+use crate::identity::Identity;
+use crate::noise_channel::{NoiseChannel, NoiseResult};
+use crate::quic_p2p_sender::QuicP2PConn;
+use crate::traffic::Traffic;
+
+/// how many times one relayed session is resumed after its relay stream drops before giving up
+const MAX_RESUME_ATTEMPTS: u32 = 3;
 
 pub struct TurnFallback {
 }
@@ -17,239 +24,303 @@ impl TurnFallback {
 
     /// start_seeding(
     ///     turn_client: a client's way to access the turn service on the server
-    ///     seeder_id: their peer_id
-    ///     leecher_id: the peer_id of the leecher they are registering for the TURN service with
+    ///     tracker: connection to the server used to look up who the leecher is
+    ///     credentials: the leecher's identity the tracker pushed with its request, if any
+    ///     identity: our persistent keys, used to authenticate the relayed session
+    ///     access: the rules applied to every piece request
+    ///     traffic: counts the bytes of every piece we serve
+    ///     relay_token: the token the tracker issued us for the leecher's TURN session
     ///     file_map: the map used to identify files
     /// )
     ///
-    /// function to start seeding via our TURN service on the server
+    /// function to seed via our TURN service on the server, returns once the leecher disconnects.
+    /// Peer-protocol messages are encrypted end to end with a Noise session and answered exactly
+    /// like on a direct connection, so the relay never sees file data. If our relay stream drops
+    /// we register again and wait for the leecher to start a new handshake. Returns the relay's
+    /// Status once its quota for us or the session is used up, or the leecher does not come back.
+    #[allow(clippy::too_many_arguments)]
     pub async fn start_seeding(
-        mut turn_client: TurnClient<tonic::transport::Channel>,
-        seeder_id: PeerId,
-        leecher_id: PeerId,
+        mut turn_client: Relay,
+        mut tracker: Tracker,
+        credentials: Option<PeerIdentity>,
+        identity: Identity,
+        access: Arc<RwLock<AccessPolicy>>,
+        traffic: Arc<Traffic>,
+        relay_token: RelayToken,
         file_map: Arc<RwLock<HashMap<[u8; 20], InfoHash>>>,
-    ) -> Result<(), Status> {
-        let session_id = make_session_id(&seeder_id, &leecher_id);
-
-        // register this client as a seeder for the turn service and gets the mpsc::Receiver back
-        // to receive data from the TURN service
-        let inbound = turn_client
-            .register(RegisterRequest {
-                session_id: session_id.clone(),
-                is_seeder: true,
-            })
-            .await?
-            .into_inner();
-
-        // create our channels and wrap the rx in a ReceiverStream to send to the turn service
-        let (tx, rx) = mpsc::channel::<TurnPacket>(128);
-        let outbound = ReceiverStream::new(rx);
-
-        // build the request and insert metadata
-        let mut req = tonic::Request::new(outbound);
-        println!("made it past sending req");
-        let md = req.metadata_mut();
-        md.insert("x-session-id", session_id.parse().unwrap());
-        md.insert("x-role", "seeder".parse().unwrap());
-
-        // signal for the turn service to start relaying our data
-        let mut client_clone = turn_client.clone();
-        tokio::spawn(async move {
-            match client_clone.send(req).await {
-                Ok(_) => eprintln!("TURN send RPC ended normally"),
-                Err(e) => eprintln!("TURN send stream ended unexpectedly: {}", e),
-            }
-        });
-
-        // this is the main seeding loop we will use to read requests we receive from the leecher (via turn),
-        // grab the corresponding piece, and send it back to the leecher (via turn)
-        tokio::spawn({
-            let mut inbound = inbound;
-            let file_map = file_map.clone();
-            let session_id = session_id.clone();
-            let tx = tx.clone();
-
-            async move {
-                loop {
-                    match inbound.next().await {
-                        Some(Ok(pkt)) => {
-                            if let Some(Body::Request(req)) = pkt.body {
-                                let index: u32 = req.index;
-
-                                // turn bytes into a fixed [u8;20] hash that we need
-                                let hash: [u8; 20] = req
-                                    .hash
-                                    .as_slice()
-                                    .try_into()
-                                    .expect("hash was not 20 bytes");
-
-                                // lookup the file
-                                let info_hash = match file_map.read().await.get(&hash).cloned() {
-                                    Some(h) => h,
-                                    None => {
-                                        eprintln!("missing file info for hash {:?}", hash);
-                                        return;
-                                    }
-                                };
+    ) -> NoiseResult<()> {
+        let session_id = relay_token.session_id.clone();
+        let (mut inbound, mut tx) = open_relay(&mut turn_client, &relay_token).await?;
 
-                                // grab the piece from the file
-                                let piece = match read_piece_from_file(info_hash, index) {
-                                    Ok(vec) => vec,
-                                    Err(e) => {
-                                        eprintln!("failed to read piece: {}", e);
-                                        return;
-                                    }
-                                };
+        // the leecher starts the handshake as soon as both of us are on the relay
+        let mut channel = NoiseChannel::respond(&identity, session_id.clone(), &tx, &mut inbound).await?;
+        let leecher_key = channel.remote_key();
 
-                                // load the packet and send it via turn
-                                let reply = TurnPacket {
-                                    session_id: session_id.clone(),
-                                    body: Some(Body::Piece(TurnPiece { payload: piece, index })),
-                                };
-                                if let Err(e) = tx.send(reply).await {
-                                    eprintln!("failed to send piece over TURN: {}", e);
-                                }
-                            }
-                        }
+        // the handshake only proves the leecher holds its key, the tracker tells us who it is,
+        // we only have to ask if it did not push the leecher's credentials or they do not match
+        let peer = match credentials.filter(|credentials| credentials.noise_key == leecher_key) {
+            Some(credentials) => credentials,
+            None => tracker.verify_peer(PeerFingerprint {
+                cert_fingerprint: Vec::new(),
+                noise_key: leecher_key.clone(),
+            }).await?.into_inner(),
+        };
+        let peer = AuthenticatedPeer::from_identity(peer)?;
+        println!("TURN peer authenticated as {} ({})", peer.describe(), hex::encode(peer.fingerprint));
 
-                        Some(Err(e)) => {
-                            eprintln!("error reading inbound TURN packet: {:?}", e);
-                            continue;
-                        }
+        // this is the main seeding loop we will use to read messages we receive from the leecher (via turn),
+        // answer them the same way a direct connection does, and send the answer back (via turn).
+        // it runs until the leecher is done so the caller's upload slot is held for the whole transfer
+        let mut resumes = 0;
+        loop {
+            match inbound.next().await {
+                // the leecher resumed the session and starts over with a new handshake, it has to be
+                // the same leecher we authenticated before
+                Some(Ok(TurnPacket { body: Some(Body::Handshake(first)), .. })) => {
+                    if first.is_empty() {
+                        continue;
+                    }
+                    channel = NoiseChannel::respond_to(&identity, session_id.clone(), &first, &tx, &mut inbound).await?;
+                    if channel.remote_key() != leecher_key {
+                        return Err("a different peer tried to resume the TURN session".into());
+                    }
+                    println!("TURN session {} resumed by leecher", session_id);
+                }
 
-                        None => {
-                            println!("inbound stream closed, exiting Seeder loop");
+                // the leecher is resuming and lost our answers, it asks for them again once it is back
+                Some(Ok(TurnPacket { body: Some(Body::Undelivered(_)), .. })) => {
+                    eprintln!("TURN relay could not deliver our answers, waiting for the leecher to resume");
+                }
+
+                Some(Ok(pkt)) => {
+                    // a packet that fails to decrypt means the relay tampered with the session
+                    let plaintext = channel.open(pkt)?;
+                    let Some(msg) = Message::decode(plaintext) else {
+                        eprintln!("ignoring undecodable message from TURN peer");
+                        continue;
+                    };
+
+                    if let Some(reply) = QuicP2PConn::answer_request(msg, &peer, &file_map, &access, &traffic).await {
+                        // seal the answer and send it via turn
+                        let reply = channel.seal(&reply.encode())?;
+                        if let Err(e) = tx.send(reply).await {
+                            eprintln!("failed to send answer over TURN: {}", e);
                             break;
                         }
                     }
                 }
+
+                // the relay used up its quota for the session or the leecher left it, resuming would not help
+                Some(Err(e)) if session_over(&e) => {
+                    eprintln!("TURN session over: {}", e.message());
+                    return Err(e.into());
+                }
+
+                // our relay stream dropped, register again and ask the leecher for a new handshake
+                Some(Err(e)) => {
+                    eprintln!("error reading inbound TURN packet: {:?}", e);
+                    resumes += 1;
+                    if resumes > MAX_RESUME_ATTEMPTS {
+                        return Err("TURN session dropped too many times".into());
+                    }
+                    (inbound, tx) = open_relay(&mut turn_client, &relay_token).await?;
+                    tx.send(TurnPacket { session_id: session_id.clone(), body: Some(Body::Handshake(Vec::new())) })
+                        .await.map_err(|_| "relay stream closed while resuming")?;
+                }
+
+                None => {
+                    println!("inbound stream closed, exiting Seeder loop");
+                    break;
+                }
             }
-        });
+        }
 
         Ok(())
     }
 
 
-    /// start_seeding(
+    /// start_leeching(
     ///     turn_client: a client's way to access the turn service on the server
-    ///     leecher_id: their peer_id
-    ///     seeder_id: the peer_id of the seeder they are registering for the TURN service with
-    ///     conn_tx: the Sender used to send pieces to our file assembly system
-    ///     conn_rx: the Receiver used to get Requests from
+    ///     identity: our persistent keys, used to authenticate the relayed session
+    ///     relay_token: the token the tracker issued us for the seeder's TURN session
+    ///     seeder_key: the Noise key the seeder registered with the tracker
+    ///     conn_tx: the Sender used to send the seeder's messages to our file assembly system
+    ///     conn_rx: the Receiver used to get messages for the seeder from
     /// )
-    /// function to start leeching via TURN
+    /// function to start leeching via TURN. Every peer-protocol message is relayed unchanged in both
+    /// directions, encrypted end to end with a Noise session which is only established if the
+    /// seeder proves it holds seeder_key. When either side's relay stream drops the session is
+    /// resumed with a new handshake and the unanswered requests are sent again. If the relay's
+    /// quota is used up or the seeder does not come back the session ends with the relay's Status
+    /// and the unanswered requests are cancelled, so they are asked of another seeder.
     pub async fn start_leeching(
-        mut turn_client: TurnClient<tonic::transport::Channel>,
-        leecher_id: PeerId,
-        seeder_id: PeerId,
+        mut turn_client: Relay,
+        identity: Identity,
+        relay_token: RelayToken,
+        seeder_key: Vec<u8>,
         conn_tx: mpsc::Sender<Message>,
         conn_rx: Arc<Mutex<mpsc::Receiver<Message>>>,
-    ) -> Result<(), Status> {
-        let session_id = make_session_id(&seeder_id, &leecher_id);
-
-        // register for turn as the leecher
-        let mut inbound = turn_client
-            .register(RegisterRequest {
-                session_id: session_id.clone(),
-                is_seeder: false,
-            })
-            .await?
-            .into_inner();
-
-        // create a channel to send requests through and wrap rx in a ReceiverStream
-        let (tx, rx) = mpsc::channel::<TurnPacket>(128);
-        let outbound = ReceiverStream::new(rx);
-
-        let session_id_clone = session_id.clone();
-
-        // spawns the actual sending task
-        let mut req = tonic::Request::new(outbound);
-        println!("made it past leeching req");
-
-        // attach metadata to the stream for registering with the turn service
-        let md = req.metadata_mut();
-        md.insert("x-session-id", session_id_clone.parse().unwrap());
-        md.insert("x-role", "leecher".parse().unwrap());
-
-        // signal for the turn service to relay our data
-        let mut client_clone = turn_client.clone();
-        tokio::spawn(async move {
-            match client_clone.send(req).await {
-                Ok(_) => eprintln!("TURN send RPC ended normally"),
-                Err(e) => eprintln!("TURN send stream ended unexpectedly: {}", e),
-            }
-        });
+    ) -> NoiseResult<()> {
+        let session_id = relay_token.session_id.clone();
+        let (mut inbound, mut tx) = open_relay(&mut turn_client, &relay_token).await?;
+
+        let mut channel = NoiseChannel::initiate(&identity, session_id.clone(), &seeder_key, &tx, &mut inbound).await?;
+
+        // requests the seeder has not answered yet, sent again on resume and handed back as
+        // Cancels if the session ends
+        let mut outstanding: HashMap<u32, Message> = HashMap::new();
 
         // spawn a task to both receive pieces and requests and process them
         let conn_rx = Arc::clone(&conn_rx);
         println!("made it to Leecher loop");
-        loop {
-            tokio::select! {
-                // if we receive a piece from the seeder (via turn), process it and sent it off to
-                // our file assembly system
-                turn_packet = inbound.next() => {
-                    match turn_packet {
-                        Some(Ok(pkt)) => {
-                            if let Some(Body::Piece(tp)) = pkt.body {
-
-                                let piece_msg = Message::Piece {
-                                    index: tp.index,
-                                    piece: tp.payload,
+        let mut resumes = 0;
+        let res: NoiseResult<()> = async {
+            loop {
+                tokio::select! {
+                    // if we receive a message from the seeder (via turn), send it off to
+                    // our file assembly system just like one received over QUIC
+                    turn_packet = inbound.next() => {
+                        // the seeder's relay stream dropped and it asks us for a new handshake, or ours did
+                        let resume = match turn_packet {
+                            Some(Ok(TurnPacket { body: Some(Body::Handshake(_)), .. })) => true,
+                            // the seeder is resuming and lost our requests, they are sent again once it is back
+                            Some(Ok(TurnPacket { body: Some(Body::Undelivered(_)), .. })) => {
+                                eprintln!("TURN relay could not deliver our requests, waiting for the seeder to resume");
+                                false
+                            }
+                            Some(Ok(pkt)) => {
+                                // a packet that fails to decrypt means the relay tampered with the session
+                                let plaintext = channel.open(pkt)?;
+                                let Some(msg) = Message::decode(plaintext) else {
+                                    eprintln!("ignoring undecodable message from TURN peer");
+                                    continue;
                                 };
 
-                                let res = conn_tx.send(piece_msg).await;
-                                if res.is_err() {
-                                    eprintln!("failed to send piece");
+                                if let Message::Piece { index, .. } | Message::Cancel { index, .. } = &msg {
+                                    outstanding.remove(index);
                                 }
+
+                                if conn_tx.send(msg).await.is_err() {
+                                    eprintln!("failed to send message to file assembler");
+                                }
+                                false
+                            }
+                            // the relay used up its quota for the session or the seeder left it,
+                            // the requests go back to be asked of another seeder
+                            Some(Err(e)) if session_over(&e) => {
+                                eprintln!("TURN session over: {}", e.message());
+                                return Err(e.into());
+                            }
+                            Some(Err(e)) => {
+                                eprintln!("error reading inbound TURN packet: {:?}", e);
+                                (inbound, tx) = open_relay(&mut turn_client, &relay_token).await?;
+                                true
+                            }
+                            None => {
+                                println!("inbound stream closed, exiting Leecher loop");
+                                return Ok(());
+                            }
+                        };
+
+                        if resume {
+                            resumes += 1;
+                            if resumes > MAX_RESUME_ATTEMPTS {
+                                return Err("TURN session dropped too many times".into());
+                            }
+                            channel = NoiseChannel::initiate(&identity, session_id.clone(), &seeder_key, &tx, &mut inbound).await?;
+                            println!("TURN session {} resumed", session_id);
+
+                            // anything in flight when the stream dropped is lost, ask for it again
+                            for msg in outstanding.values() {
+                                let packet = channel.seal(&msg.encode())?;
+                                tx.send(packet).await.map_err(|_| "relay stream closed while resuming")?;
                             }
                         }
-                        _ => continue,
                     }
-                }
 
-                // if we receive a request from our piece-requesting system, process it and send it via turn
-                request_message = async {
-                    let mut rx = conn_rx.lock().await;
-                    rx.recv().await
-                } => {
-                    if let Some(Message::Request { index, hash, .. }) = request_message {
-                        let request_packet = TurnPacket {
-                            session_id: session_id.clone(),
-                            body: Some(Body::Request(TurnPieceRequest {
-                                hash: hash.to_vec(),
-                                index,
-                            })),
+                    // if our piece-requesting system has a message for the seeder, send it via turn as is
+                    request_message = async {
+                        let mut rx = conn_rx.lock().await;
+                        rx.recv().await
+                    } => {
+                        let Some(msg) = request_message else {
+                            println!("requests finished");
+                            return Ok(());
                         };
 
-                        if let Err(e) = tx.send(request_packet).await {
-                            eprintln!("failed to queue request: {}", e);
+                        //seeders do not answer PEX over TURN, so there is no point spending relay quota on it
+                        if let Message::Pex { .. } = msg {
+                            continue;
+                        }
+
+                        if let Message::Request { index, .. } = &msg {
+                            outstanding.insert(*index, msg.clone());
+                        }
+
+                        let packet = channel.seal(&msg.encode())?;
+                        if let Err(e) = tx.send(packet).await {
+                            eprintln!("failed to queue message: {}", e);
+                            return Ok(());
                         }
                     }
                 }
             }
+        }.await;
+
+        //like a failed QUIC connection, unanswered requests go back to be re-requested from another peer
+        for msg in outstanding.into_values() {
+            if let Message::Request { seeder, index, begin, length, .. } = msg {
+                let _ = conn_tx.send(Message::Cancel { seeder, index, begin, length }).await;
+            }
         }
 
+        res
     }
 }
-/// peer_to_string(
-///     peer: PeerId we are converting to a string
+
+/// session_over (
+///     status: an error the relay sent down our stream
 /// )
-/// helper function for creating a string from a peerid (for use in make_session_id)
-fn peer_to_string(peer: &PeerId) -> String {
-    let public_ip  = Ipv4Addr::from(peer.ipaddr);
-    let private_ip = Ipv4Addr::from(peer.priv_ipaddr);
-    format!("{}:{}-{}:{}", public_ip, peer.port, private_ip, peer.priv_port)
+/// helper function telling whether the relay ended the session for good, either because its quota
+/// is used up or because the other side did not come back in time
+fn session_over(status: &Status) -> bool {
+    matches!(status.code(), Code::ResourceExhausted | Code::Aborted)
 }
 
-/// make_session_id (
-///     a: PeerId a that we are converting to session_id
-///     b: PeerId a that we are converting to session_id
+/// open_relay (
+///     turn_client: a client's way to access the turn service on the server
+///     relay_token: the token admitting us to our side of the session
 /// )
-/// fucntion for creating a session_id as a string for properly identifying the turn session that
-/// the seeder/leecher is a part of
-fn make_session_id(a: &PeerId, b: &PeerId) -> String {
-    let key_a = (a.ipaddr, a.port, a.priv_ipaddr, a.priv_port);
-    let key_b = (b.ipaddr, b.port, b.priv_ipaddr, b.priv_port);
-    let (first, second) = if key_a <= key_b { (a, b) } else { (b, a) };
-    format!("{}|{}", peer_to_string(first), peer_to_string(second))
+/// registers with the turn service and starts the stream we send through, returning the stream of
+/// packets relayed to us and the Sender for packets we relay. Also used to resume a dropped session.
+async fn open_relay(
+    turn_client: &mut Relay,
+    relay_token: &RelayToken,
+) -> NoiseResult<(Streaming<TurnPacket>, mpsc::Sender<TurnPacket>)> {
+    // register this client for the turn service and get the stream the other side's packets arrive on
+    let inbound = turn_client
+        .register(RegisterRequest {
+            relay_token: relay_token.token.clone(),
+        })
+        .await?
+        .into_inner();
+
+    // create our channels and wrap the rx in a ReceiverStream to send to the turn service
+    let (tx, rx) = mpsc::channel::<TurnPacket>(128);
+    let outbound = ReceiverStream::new(rx);
+
+    // build the request, the token tells the turn service which session and side we send for
+    let mut req = tonic::Request::new(outbound);
+    req.metadata_mut().insert("x-session-id", relay_token.token.parse()?);
+
+    // signal for the turn service to start relaying our data
+    let mut client_clone = turn_client.clone();
+    tokio::spawn(async move {
+        match client_clone.send(req).await {
+            Ok(_) => eprintln!("TURN send RPC ended normally"),
+            Err(e) => eprintln!("TURN send stream ended unexpectedly: {}", e),
+        }
+    });
+
+    Ok((inbound, tx))
 }
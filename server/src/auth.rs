@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use rustls_pki_types::{CertificateDer, SignatureVerificationAlgorithm};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use webpki::EndEntityCert;
+use tonic::{service::Interceptor, Request, Status};
+use crate::connection::connection::ClientId;
+
+/// how long a session token is accepted after it was issued, clients renew theirs well before
+pub const SESSION_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// what a client signs ahead of the tracker's nonce to prove it holds its certificate's key
+pub const REGISTRATION_CONTEXT: &[u8] = b"tracker registration\0";
+
+/// the algorithms a registration may be signed with, those peer certificates are made with
+static REGISTRATION_ALGORITHMS: &[&dyn SignatureVerificationAlgorithm] = &[
+    webpki::ring::ECDSA_P256_SHA256,
+    webpki::ring::ED25519,
+];
+
+///verify_possession()
+/// parameters:
+///     - certificate: the DER certificate a client registers with
+///     - fingerprint: the SHA-256 fingerprint the client registers
+///     - nonce: the challenge the tracker handed it
+///     - signature: what it signed REGISTRATION_CONTEXT and the nonce with
+///
+/// function:
+/// Fails with unauthenticated unless the fingerprint is the certificate's and the signature was
+/// made with the certificate's key, so nobody can register with another client's fingerprint.
+#[allow(clippy::result_large_err)]
+pub fn verify_possession(certificate: &[u8], fingerprint: &[u8], nonce: &[u8], signature: &[u8]) -> Result<(), Status> {
+    if Sha256::digest(certificate).as_slice() != fingerprint {
+        return Err(Status::unauthenticated("certificate does not match the fingerprint"));
+    }
+    let certificate = CertificateDer::from(certificate);
+    let certificate = EndEntityCert::try_from(&certificate)
+        .map_err(|_| Status::invalid_argument("malformed certificate"))?;
+
+    let signed = [REGISTRATION_CONTEXT, nonce].concat();
+    if REGISTRATION_ALGORITHMS.iter().any(|alg| certificate.verify_signature(*alg, &signed, signature).is_ok()) {
+        Ok(())
+    } else {
+        Err(Status::unauthenticated("registration was not signed with the certificate's key"))
+    }
+}
+
+/// ApiKeys decides who may register with the tracker. The keys are handed out to operators of
+/// clients, without any every process that can reach the server may register.
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    /// TRACKER_API_KEYS, comma separated, None leaves registration open
+    keys: Option<HashSet<String>>,
+}
+
+impl ApiKeys {
+
+    ///from_env()
+    ///
+    /// function:
+    /// Reads the accepted keys, leaving registration open if TRACKER_API_KEYS is not set.
+    pub fn from_env() -> ApiKeys {
+        let keys = env::var("TRACKER_API_KEYS").ok().map(|keys| {
+            keys.split(',')
+                .map(|key| key.trim().to_string())
+                .filter(|key| !key.is_empty())
+                .collect::<HashSet<_>>()
+        });
+        if keys.is_none() {
+            println!("TRACKER_API_KEYS is not set, any client may register");
+        }
+        ApiKeys { keys }
+    }
+
+    ///check()
+    /// parameters:
+    ///     - key: the key a client registered with
+    ///
+    /// function:
+    /// Fails with unauthenticated unless the key is accepted.
+    #[allow(clippy::result_large_err)]
+    pub fn check(&self, key: &str) -> Result<(), Status> {
+        match &self.keys {
+            Some(keys) if !keys.iter().any(|accepted| secret_matches(key, accepted)) => {
+                Err(Status::unauthenticated("invalid API key"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// SessionTokens issues and checks the token a registered client attaches to every call.
+/// A token names the client it was issued to and when, and is signed, so it cannot be made up for
+/// another client or kept alive past SESSION_TOKEN_LIFETIME.
+#[derive(Debug)]
+pub struct SessionTokens {
+    /// key tokens are signed with, from TRACKER_TOKEN_SECRET so several instances can share it,
+    /// otherwise regenerated every run
+    secret: Vec<u8>,
+}
+
+impl Default for SessionTokens {
+    fn default() -> Self {
+        SessionTokens { secret: rand::random::<[u8; 32]>().to_vec() }
+    }
+}
+
+impl SessionTokens {
+
+    ///from_env()
+    ///
+    /// function:
+    /// Reads the token secret, generating one if TRACKER_TOKEN_SECRET is not set.
+    pub fn from_env() -> Result<SessionTokens, Box<dyn std::error::Error>> {
+        let secret = match env::var("TRACKER_TOKEN_SECRET") {
+            Ok(secret) => hex::decode(secret)?,
+            Err(_) => return Ok(SessionTokens::default()),
+        };
+        Ok(SessionTokens { secret })
+    }
+
+    ///issue()
+    /// parameters:
+    ///     - client_id: the client that just registered, or whose token is renewed
+    ///
+    /// function:
+    /// Returns the token of the form uid.issued.signature the client authenticates with from now on.
+    pub fn issue(&self, client_id: &ClientId) -> String {
+        let claims = format!("{}.{}", client_id.uid, unix_now());
+        format!("{}.{}", claims, hex::encode(self.sign(&claims)))
+    }
+
+    ///verify()
+    /// parameters:
+    ///     - token: the token a client presented
+    ///
+    /// function:
+    /// Checks the token's signature and age, and returns the client it was issued to.
+    #[allow(clippy::result_large_err)]
+    pub fn verify(&self, token: &str) -> Result<ClientId, Status> {
+        let invalid = || Status::unauthenticated("invalid session token");
+
+        let (claims, mac) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let mac = hex::decode(mac).map_err(|_| invalid())?;
+        let mut expected = Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        expected.update(claims.as_bytes());
+        expected.verify_slice(&mac).map_err(|_| invalid())?;
+
+        let (uid, issued) = claims.rsplit_once('.').ok_or_else(invalid)?;
+        let issued: u64 = issued.parse().map_err(|_| invalid())?;
+        if issued + SESSION_TOKEN_LIFETIME.as_secs() < unix_now() {
+            return Err(Status::unauthenticated("session token expired"));
+        }
+
+        Ok(ClientId { uid: uid.to_string() })
+    }
+
+    ///sign()
+    /// parameters:
+    ///     - claims: the client id and issue time to sign
+    ///
+    /// function:
+    /// Returns the HMAC of the claims.
+    fn sign(&self, claims: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(claims.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// the client a call was authenticated as, added to the request's extensions by SessionAuth
+#[derive(Debug, Clone)]
+pub struct Authenticated(pub ClientId);
+
+/// SessionAuth checks the session token in the authorization header of every Connector and Turn
+/// call. Calls without one pass through unauthenticated, so register_client can be reached, and
+/// every other call asks for the client with authenticated().
+#[derive(Debug, Clone)]
+pub struct SessionAuth {
+    tokens: Arc<SessionTokens>,
+}
+
+impl SessionAuth {
+
+    ///new()
+    /// parameters:
+    ///     - tokens: the signer shared with the tracker that issues session tokens
+    ///
+    /// function:
+    /// Creates the interceptor.
+    pub fn new(tokens: Arc<SessionTokens>) -> Self {
+        SessionAuth { tokens }
+    }
+}
+
+impl Interceptor for SessionAuth {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        let Some(header) = req.metadata().get("authorization") else {
+            return Ok(req);
+        };
+        let token = header.to_str().ok()
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("malformed authorization header"))?;
+
+        let client_id = self.tokens.verify(token)?;
+        req.extensions_mut().insert(Authenticated(client_id));
+        Ok(req)
+    }
+}
+
+/// authenticated (
+///     req: a call that passed through SessionAuth
+/// )
+/// helper function returning the client a call was made by, unauthenticated if it carried no session token
+#[allow(clippy::result_large_err)]
+pub fn authenticated<T>(req: &Request<T>) -> Result<ClientId, Status> {
+    req.extensions()
+        .get::<Authenticated>()
+        .map(|Authenticated(client_id)| client_id.clone())
+        .ok_or_else(|| Status::unauthenticated("missing session token"))
+}
+
+/// secret_matches (
+///     presented: the secret a caller sent
+///     expected: the secret it has to match
+/// )
+/// helper function comparing secrets in constant time, so how long the comparison takes does not
+/// tell a caller how much of its guess was right
+pub fn secret_matches(presented: &str, expected: &str) -> bool {
+    presented.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// unix_now ()
+/// helper function for the current unix time in seconds
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    #[test]
+    fn possession_is_proven_with_the_key_clients_make_their_certificate_with() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["peer".to_string()]).unwrap();
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &certificate.key_pair.serialize_der(), &rng).unwrap();
+        let der = certificate.cert.der().to_vec();
+        let fingerprint = Sha256::digest(&der).to_vec();
+        let signature = key.sign(&rng, &[REGISTRATION_CONTEXT, b"nonce"].concat()).unwrap().as_ref().to_vec();
+
+        assert!(verify_possession(&der, &fingerprint, b"nonce", &signature).is_ok());
+        assert!(verify_possession(&der, &fingerprint, b"other nonce", &signature).is_err());
+        assert!(verify_possession(&der, &[0; 32], b"nonce", &signature).is_err());
+        assert!(verify_possession(b"not a certificate", &Sha256::digest(b"not a certificate"), b"nonce", &signature).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::cmp::Ordering;
+use tonic::Status;
+use crate::connection::connection::{CatalogPage, CatalogQuery, CatalogSort, FileSummary};
+
+/// files on a page when the query does not ask for a number
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+/// the most files one page can hold
+const MAX_PAGE_SIZE: usize = 200;
+
+/// sort_key (
+///     file: a file in the catalog
+///     sort: what the catalog is sorted by
+/// )
+/// helper function returning the value a file is sorted by
+fn sort_key(file: &FileSummary, sort: CatalogSort) -> u64 {
+    match sort {
+        CatalogSort::Seeders => file.seeders as u64,
+        CatalogSort::Size => file.file_length,
+        CatalogSort::Recent => file.advertised_at,
+    }
+}
+
+/// position (
+///     file: a file in the catalog
+///     sort: what the catalog is sorted by
+/// )
+/// helper function returning where a file goes in the catalog. Files with the same sort key are
+/// ordered by their hash, so every file has exactly one place and a cursor never skips or repeats one.
+fn position(file: &FileSummary, sort: CatalogSort) -> (u64, Vec<u8>) {
+    (sort_key(file, sort), file.hash.as_ref().map(|hash| hash.hash.clone()).unwrap_or_default())
+}
+
+/// encode_cursor (
+///     position: the position of the last file on a page
+/// )
+/// helper function for the cursor handed to the client, of the form key.hash
+fn encode_cursor((key, hash): &(u64, Vec<u8>)) -> String {
+    format!("{}.{}", key, hex::encode(hash))
+}
+
+/// decode_cursor (
+///     cursor: a cursor returned by encode_cursor
+/// )
+/// helper function reading a cursor back, invalid_argument if it was not ours
+#[allow(clippy::result_large_err)]
+fn decode_cursor(cursor: &str) -> Result<(u64, Vec<u8>), Status> {
+    let invalid = || Status::invalid_argument("invalid catalog cursor");
+    let (key, hash) = cursor.split_once('.').ok_or_else(invalid)?;
+    Ok((key.parse().map_err(|_| invalid())?, hex::decode(hash).map_err(|_| invalid())?))
+}
+
+/// page (
+///     files: every file visible to the caller
+///     query: what the caller is looking for
+/// )
+/// filters and sorts the catalog and cuts out the page following the query's cursor
+#[allow(clippy::result_large_err)]
+pub fn page(files: Vec<FileSummary>, query: &CatalogQuery) -> Result<CatalogPage, Status> {
+    let sort = query.sort();
+    let name = query.name.to_lowercase();
+    let limit = match query.limit as usize {
+        0 => DEFAULT_PAGE_SIZE,
+        limit => limit.min(MAX_PAGE_SIZE),
+    };
+    let after = (!query.cursor.is_empty()).then(|| decode_cursor(&query.cursor)).transpose()?;
+
+    // largest first unless the caller asks otherwise
+    let order = |a: &(u64, Vec<u8>), b: &(u64, Vec<u8>)| -> Ordering {
+        if query.ascending { a.cmp(b) } else { b.cmp(a) }
+    };
+
+    let mut files: Vec<_> = files.into_iter()
+        .filter(|file| file.name.to_lowercase().contains(&name))
+        .filter(|file| file.file_length >= query.min_size)
+        .filter(|file| query.max_size == 0 || file.file_length <= query.max_size)
+        .map(|file| (position(&file, sort), file))
+        .filter(|(position, _)| after.as_ref().is_none_or(|after| order(position, after) == Ordering::Greater))
+        .collect();
+    files.sort_by(|(a, _), (b, _)| order(a, b));
+
+    let next_cursor = match files.get(limit) {
+        Some(_) => encode_cursor(&files[limit - 1].0),
+        None => String::new(),
+    };
+    files.truncate(limit);
+
+    Ok(CatalogPage {
+        files: files.into_iter().map(|(_, file)| file).collect(),
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::connection::FileHash;
+
+    /// a file of the given size whose hash is n, sizes may repeat so ties are covered
+    fn file(n: u8, size: u64) -> FileSummary {
+        FileSummary {
+            hash: Some(FileHash { hash: vec![n; 20] }),
+            name: format!("file {}", n),
+            file_length: size,
+            ..FileSummary::default()
+        }
+    }
+
+    fn by_size(cursor: &str) -> CatalogQuery {
+        let mut query = CatalogQuery { cursor: cursor.to_string(), limit: 2, ..CatalogQuery::default() };
+        query.set_sort(CatalogSort::Size);
+        query
+    }
+
+    fn hashes(page: &CatalogPage) -> Vec<u8> {
+        page.files.iter().map(|file| file.hash.as_ref().unwrap().hash[0]).collect()
+    }
+
+    #[test]
+    fn pages_cover_every_file_once_in_order() {
+        let files: Vec<_> = (1..=5).map(|n| file(n, 100 * (n as u64 % 3))).collect();
+
+        let mut seen = Vec::new();
+        let mut cursor = String::new();
+        loop {
+            let page = page(files.clone(), &by_size(&cursor)).unwrap();
+            seen.extend(hashes(&page));
+            if page.next_cursor.is_empty() {
+                break;
+            }
+            cursor = page.next_cursor;
+        }
+
+        //largest first, ties broken by hash
+        assert_eq!(seen, vec![5, 2, 4, 1, 3]);
+    }
+
+    #[test]
+    fn cursors_stay_put_across_inserts_and_deletes() {
+        let mut files: Vec<_> = (1..=6).map(|n| file(n, 100 * n as u64)).collect();
+        let first = page(files.clone(), &by_size("")).unwrap();
+        assert_eq!(hashes(&first), vec![6, 5]);
+
+        //a file lands before the cursor, one already shown and one not shown yet are removed
+        files.push(file(7, 1000));
+        files.retain(|file| file.file_length != 600 && file.file_length != 300);
+        let second = page(files.clone(), &by_size(&first.next_cursor)).unwrap();
+        assert_eq!(hashes(&second), vec![4, 2]);
+
+        files.push(file(8, 150));
+        let third = page(files, &by_size(&second.next_cursor)).unwrap();
+        assert_eq!(hashes(&third), vec![8, 1]);
+        assert!(third.next_cursor.is_empty());
+    }
+
+    #[test]
+    fn filters_and_bad_cursors() {
+        let files = vec![file(1, 10), file(2, 20), file(3, 30)];
+        let query = CatalogQuery { name: "FILE 2".to_string(), ..CatalogQuery::default() };
+        assert_eq!(hashes(&page(files.clone(), &query).unwrap()), vec![2]);
+
+        let query = CatalogQuery { min_size: 15, max_size: 25, ..CatalogQuery::default() };
+        assert_eq!(hashes(&page(files.clone(), &query).unwrap()), vec![2]);
+
+        assert_eq!(page(files, &by_size("nonsense")).unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -1,3 +1,5 @@
+//the DHT messages only travel between clients, the tracker shares the proto but never builds them
+#[allow(dead_code)]
 pub mod connection {
     tonic::include_proto!("connection");
 }
\ No newline at end of file
@@ -0,0 +1,738 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use redis::AsyncCommands;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tonic::{async_trait, Status};
+
+/// messages a subscription buffers, once it is that far behind it misses what is published
+const SUBSCRIPTION_DEPTH: usize = 128;
+
+/// the messages published on a topic, in order, for as long as the receiver is kept
+pub type Subscription = mpsc::Receiver<Vec<u8>>;
+
+/// Coordinator is the backend server instances rendezvous through. Two clients brokered by the
+/// tracker may be connected to different instances, so everything one instance has to tell another
+/// goes through publish/subscribe on a topic, and everything it has to look up is an entry here.
+/// Entries, hash fields and messages are opaque bytes, callers encode them with prost.
+/// Every instance keeps all of its state here, the client registry, advertised files, swarms,
+/// groups and federated replicas included, so each call can land on any instance.
+#[async_trait]
+pub trait Coordinator: fmt::Debug + Send + Sync {
+
+    ///publish()
+    /// parameters:
+    ///     - topic: the topic to publish on
+    ///     - payload: the message
+    ///
+    /// function:
+    /// Hands the message to every current subscriber of the topic and returns how many it reached.
+    /// Publishing never waits for a subscriber that fell behind, that one misses the message.
+    async fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<usize, Status>;
+
+    ///subscribe()
+    /// parameters:
+    ///     - topic: the topic to listen on
+    ///
+    /// function:
+    /// Returns once the subscription is in place, so nothing published afterwards is missed.
+    /// Dropping the receiver unsubscribes.
+    async fn subscribe(&self, topic: &str) -> Result<Subscription, Status>;
+
+    ///set()
+    /// parameters:
+    ///     - key: the entry to write
+    ///     - value: its value
+    ///     - ttl: how long it lives, None to keep it until it is deleted
+    ///
+    /// function:
+    /// Writes an entry, replacing any previous value.
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), Status>;
+
+    ///get()
+    /// parameters:
+    ///     - key: the entry to read
+    ///
+    /// function:
+    /// Reads an entry, None if it does not exist or has expired.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Status>;
+
+    ///delete()
+    /// parameters:
+    ///     - key: the entry to remove
+    ///
+    /// function:
+    /// Removes an entry if it exists.
+    async fn delete(&self, key: &str) -> Result<(), Status>;
+
+    ///add()
+    /// parameters:
+    ///     - key: the counter to add to
+    ///     - amount: what to add
+    ///     - ttl: how long a counter lives from when it is created, adding does not extend it
+    ///
+    /// function:
+    /// Adds to a counter in one step, so instances adding at the same time do not lose each
+    /// other's amounts, and returns its new value. A missing counter starts at 0.
+    async fn add(&self, key: &str, amount: u64, ttl: Duration) -> Result<u64, Status>;
+
+    ///hash_set()
+    /// parameters:
+    ///     - key: the hash
+    ///     - field: the field to write
+    ///     - value: its value
+    ///
+    /// function:
+    /// Writes a field of a hash, replacing any previous value. A missing hash is created.
+    async fn hash_set(&self, key: &str, field: &str, value: Vec<u8>) -> Result<(), Status>;
+
+    ///hash_set_new()
+    /// parameters:
+    ///     - key: the hash
+    ///     - field: the field to write
+    ///     - value: its value
+    ///
+    /// function:
+    /// Writes a field only if the hash does not have it yet, and returns whether it was written.
+    /// Instances racing for the same field cannot both win.
+    async fn hash_set_new(&self, key: &str, field: &str, value: Vec<u8>) -> Result<bool, Status>;
+
+    ///hash_get()
+    /// parameters:
+    ///     - key: the hash
+    ///     - field: the field to read
+    ///
+    /// function:
+    /// Reads a field, None if the hash or the field does not exist.
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<Vec<u8>>, Status>;
+
+    ///hash_delete()
+    /// parameters:
+    ///     - key: the hash
+    ///     - field: the field to remove
+    ///
+    /// function:
+    /// Removes a field and returns whether it existed. A hash without fields no longer exists.
+    async fn hash_delete(&self, key: &str, field: &str) -> Result<bool, Status>;
+
+    ///hash_entries()
+    /// parameters:
+    ///     - key: the hash
+    ///
+    /// function:
+    /// Returns every field of the hash with its value, in no particular order.
+    async fn hash_entries(&self, key: &str) -> Result<Vec<(String, Vec<u8>)>, Status>;
+
+    ///hash_add()
+    /// parameters:
+    ///     - key: the hash
+    ///     - field: the counter to add to
+    ///     - amount: what to add
+    ///
+    /// function:
+    /// Adds to a counter field in one step and returns its new value. A missing field starts at 0.
+    async fn hash_add(&self, key: &str, field: &str, amount: u64) -> Result<u64, Status>;
+}
+
+///from_env()
+///
+/// function:
+/// Connects to the Redis server in REDIS_URL when it is set, so several instances share their
+/// state. Without it everything stays inside this process. Instances sharing state have to check
+/// each other's tokens, so they refuse to start unless TRACKER_TOKEN_SECRET and TURN_TOKEN_SECRET
+/// are set as well.
+pub async fn from_env() -> Result<Arc<dyn Coordinator>, Box<dyn std::error::Error>> {
+    let Ok(url) = env::var("REDIS_URL") else {
+        return Ok(Arc::new(InMemoryCoordinator::default()));
+    };
+    for secret in ["TRACKER_TOKEN_SECRET", "TURN_TOKEN_SECRET"] {
+        if env::var(secret).is_err() {
+            return Err(format!("REDIS_URL is set but {} is not, every instance would reject the tokens the others issue", secret).into());
+        }
+    }
+    Ok(Arc::new(RedisCoordinator::connect(&url).await?))
+}
+
+/// InMemoryCoordinator keeps topics and entries in this process, for a single instance
+#[derive(Debug, Default)]
+pub struct InMemoryCoordinator {
+    topics: DashMap<String, Vec<mpsc::Sender<Vec<u8>>>>,
+    entries: DashMap<String, (Vec<u8>, Option<Instant>)>,
+    hashes: DashMap<String, HashMap<String, Vec<u8>>>,
+}
+
+#[async_trait]
+impl Coordinator for InMemoryCoordinator {
+    async fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<usize, Status> {
+        // forget receivers that were dropped, and clone the rest so the map is not locked while we send
+        let subscribers = match self.topics.get_mut(topic) {
+            Some(mut subscribers) => {
+                subscribers.retain(|tx| !tx.is_closed());
+                subscribers.clone()
+            }
+            None => return Ok(0),
+        };
+        self.topics.remove_if(topic, |_, subscribers| subscribers.is_empty());
+
+        //a subscriber that fell SUBSCRIPTION_DEPTH messages behind misses this one rather than
+        //holding up every other subscriber, and is not counted as reached
+        let reached = subscribers.iter()
+            .filter(|tx| tx.try_send(payload.clone()).is_ok())
+            .count();
+        Ok(reached)
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<Subscription, Status> {
+        let (tx, rx) = mpsc::channel(SUBSCRIPTION_DEPTH);
+        self.topics.entry(topic.to_string()).or_default().push(tx);
+        Ok(rx)
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), Status> {
+        self.entries.insert(key.to_string(), (value, ttl.map(|ttl| Instant::now() + ttl)));
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Status> {
+        let now = Instant::now();
+        self.entries.remove_if(key, |_, (_, expires)| expires.is_some_and(|expires| expires <= now));
+        Ok(self.entries.get(key).map(|entry| entry.0.clone()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Status> {
+        self.entries.remove(key);
+        self.hashes.remove(key);
+        Ok(())
+    }
+
+    async fn add(&self, key: &str, amount: u64, ttl: Duration) -> Result<u64, Status> {
+        let now = Instant::now();
+        self.entries.remove_if(key, |_, (_, expires)| expires.is_some_and(|expires| expires <= now));
+        let mut entry = self.entries.entry(key.to_string()).or_insert_with(|| (b"0".to_vec(), Some(now + ttl)));
+        let total = counter_value(&entry.0)? + amount;
+        entry.0 = total.to_string().into_bytes();
+        Ok(total)
+    }
+
+    async fn hash_set(&self, key: &str, field: &str, value: Vec<u8>) -> Result<(), Status> {
+        self.hashes.entry(key.to_string()).or_default().insert(field.to_string(), value);
+        Ok(())
+    }
+
+    async fn hash_set_new(&self, key: &str, field: &str, value: Vec<u8>) -> Result<bool, Status> {
+        let mut hash = self.hashes.entry(key.to_string()).or_default();
+        if hash.contains_key(field) {
+            return Ok(false);
+        }
+        hash.insert(field.to_string(), value);
+        Ok(true)
+    }
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<Vec<u8>>, Status> {
+        Ok(self.hashes.get(key).and_then(|hash| hash.get(field).cloned()))
+    }
+
+    async fn hash_delete(&self, key: &str, field: &str) -> Result<bool, Status> {
+        let removed = self.hashes.get_mut(key).is_some_and(|mut hash| hash.remove(field).is_some());
+        self.hashes.remove_if(key, |_, hash| hash.is_empty());
+        Ok(removed)
+    }
+
+    async fn hash_entries(&self, key: &str) -> Result<Vec<(String, Vec<u8>)>, Status> {
+        Ok(self.hashes.get(key)
+            .map(|hash| hash.iter().map(|(field, value)| (field.clone(), value.clone())).collect())
+            .unwrap_or_default())
+    }
+
+    async fn hash_add(&self, key: &str, field: &str, amount: u64) -> Result<u64, Status> {
+        let mut hash = self.hashes.entry(key.to_string()).or_default();
+        let counter = hash.entry(field.to_string()).or_insert_with(|| b"0".to_vec());
+        let total = counter_value(counter)? + amount;
+        *counter = total.to_string().into_bytes();
+        Ok(total)
+    }
+}
+
+/// RedisCoordinator shares topics and entries between instances through a Redis server,
+/// or anything that speaks its protocol
+pub struct RedisCoordinator {
+    client: redis::Client,
+    /// shared by every command, subscriptions each get their own connection
+    conn: redis::aio::MultiplexedConnection,
+}
+
+impl fmt::Debug for RedisCoordinator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisCoordinator").field("server", self.client.get_connection_info()).finish()
+    }
+}
+
+impl RedisCoordinator {
+
+    ///connect()
+    /// parameters:
+    ///     - url: where the Redis server is, e.g. redis://127.0.0.1:6379
+    ///
+    /// function:
+    /// Opens the connection commands are sent over.
+    pub async fn connect(url: &str) -> Result<RedisCoordinator, redis::RedisError> {
+        let client = redis::Client::open(url)?;
+        let conn = client.get_multiplexed_async_connection().await?;
+        Ok(RedisCoordinator { client, conn })
+    }
+}
+
+#[async_trait]
+impl Coordinator for RedisCoordinator {
+    async fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<usize, Status> {
+        let mut conn = self.conn.clone();
+        conn.publish(topic, payload).await.map_err(redis_status)
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<Subscription, Status> {
+        let mut pubsub = self.client.get_async_pubsub().await.map_err(redis_status)?;
+        pubsub.subscribe(topic).await.map_err(redis_status)?;
+
+        // the subscription's connection is closed as soon as its receiver is dropped
+        let (tx, rx) = mpsc::channel(SUBSCRIPTION_DEPTH);
+        tokio::spawn(async move {
+            let mut messages = pubsub.into_on_message();
+            loop {
+                tokio::select! {
+                    _ = tx.closed() => break,
+                    msg = messages.next() => {
+                        let Some(msg) = msg else { break };
+                        if let Err(mpsc::error::TrySendError::Closed(_)) = tx.try_send(msg.get_payload_bytes().to_vec()) {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(rx)
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), Status> {
+        let mut conn = self.conn.clone();
+        match ttl {
+            Some(ttl) => conn.pset_ex(key, value, ttl.as_millis() as u64).await.map_err(redis_status),
+            None => conn.set(key, value).await.map_err(redis_status),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Status> {
+        let mut conn = self.conn.clone();
+        conn.get(key).await.map_err(redis_status)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Status> {
+        let mut conn = self.conn.clone();
+        conn.del(key).await.map_err(redis_status)
+    }
+
+    async fn add(&self, key: &str, amount: u64, ttl: Duration) -> Result<u64, Status> {
+        let mut conn = self.conn.clone();
+        //the counter is created with its ttl only if it does not exist yet, both in one transaction
+        let (total,): (u64,) = redis::pipe()
+            .atomic()
+            .cmd("SET").arg(key).arg(0).arg("PX").arg(ttl.as_millis() as u64).arg("NX").ignore()
+            .cmd("INCRBY").arg(key).arg(amount)
+            .query_async(&mut conn)
+            .await
+            .map_err(redis_status)?;
+        Ok(total)
+    }
+
+    async fn hash_set(&self, key: &str, field: &str, value: Vec<u8>) -> Result<(), Status> {
+        let mut conn = self.conn.clone();
+        conn.hset(key, field, value).await.map_err(redis_status)
+    }
+
+    async fn hash_set_new(&self, key: &str, field: &str, value: Vec<u8>) -> Result<bool, Status> {
+        let mut conn = self.conn.clone();
+        conn.hset_nx(key, field, value).await.map_err(redis_status)
+    }
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<Vec<u8>>, Status> {
+        let mut conn = self.conn.clone();
+        conn.hget(key, field).await.map_err(redis_status)
+    }
+
+    async fn hash_delete(&self, key: &str, field: &str) -> Result<bool, Status> {
+        let mut conn = self.conn.clone();
+        conn.hdel(key, field).await.map_err(redis_status)
+    }
+
+    async fn hash_entries(&self, key: &str) -> Result<Vec<(String, Vec<u8>)>, Status> {
+        let mut conn = self.conn.clone();
+        conn.hgetall(key).await.map_err(redis_status)
+    }
+
+    async fn hash_add(&self, key: &str, field: &str, amount: u64) -> Result<u64, Status> {
+        let mut conn = self.conn.clone();
+        conn.hincr(key, field, amount).await.map_err(redis_status)
+    }
+}
+
+/// counter_value (
+///     value: an entry written by add()
+/// )
+/// helper function reading a counter, stored as decimal text like Redis does
+#[allow(clippy::result_large_err)]
+fn counter_value(value: &[u8]) -> Result<u64, Status> {
+    std::str::from_utf8(value).ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| Status::failed_precondition("entry is not a counter"))
+}
+
+/// redis_status (
+///     e: what the Redis client failed with
+/// )
+/// helper function to report a backend failure to the caller of an RPC
+fn redis_status(e: redis::RedisError) -> Status {
+    Status::unavailable(format!("coordination backend failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::net::tcp::OwnedReadHalf;
+
+    #[tokio::test]
+    async fn publish_reaches_every_live_subscriber() {
+        let coordinator = InMemoryCoordinator::default();
+        let mut first = coordinator.subscribe("topic").await.unwrap();
+        let mut second = coordinator.subscribe("topic").await.unwrap();
+        let dropped = coordinator.subscribe("topic").await.unwrap();
+        drop(dropped);
+
+        assert_eq!(coordinator.publish("topic", b"hello".to_vec()).await.unwrap(), 2);
+        assert_eq!(first.recv().await.unwrap(), b"hello");
+        assert_eq!(second.recv().await.unwrap(), b"hello");
+        assert_eq!(coordinator.publish("other", b"hello".to_vec()).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn a_stalled_subscriber_does_not_hold_up_the_others() {
+        let coordinator = InMemoryCoordinator::default();
+        let _stalled = coordinator.subscribe("topic").await.unwrap();
+        for _ in 0..SUBSCRIPTION_DEPTH {
+            coordinator.publish("topic", b"backlog".to_vec()).await.unwrap();
+        }
+
+        let mut live = coordinator.subscribe("topic").await.unwrap();
+        let reached = tokio::time::timeout(Duration::from_secs(1), coordinator.publish("topic", b"hello".to_vec()))
+            .await
+            .expect("publish waited for the stalled subscriber")
+            .unwrap();
+        assert_eq!(reached, 1);
+        assert_eq!(live.recv().await.unwrap(), b"hello");
+    }
+
+    /// entries_expire (
+    ///     coordinator: the implementation under test
+    /// )
+    /// helper function checking set, get and delete, with and without a ttl
+    async fn entries_expire(coordinator: &dyn Coordinator) {
+        coordinator.set("kept", b"a".to_vec(), None).await.unwrap();
+        coordinator.set("expiring", b"b".to_vec(), Some(Duration::from_millis(50))).await.unwrap();
+        assert_eq!(coordinator.get("expiring").await.unwrap(), Some(b"b".to_vec()));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(coordinator.get("expiring").await.unwrap(), None);
+        assert_eq!(coordinator.get("kept").await.unwrap(), Some(b"a".to_vec()));
+
+        coordinator.delete("kept").await.unwrap();
+        assert_eq!(coordinator.get("kept").await.unwrap(), None);
+    }
+
+    /// counters_add_up (
+    ///     coordinator: the implementation under test
+    /// )
+    /// helper function checking add() keeps the ttl a counter was created with
+    async fn counters_add_up(coordinator: &dyn Coordinator) {
+        let ttl = Duration::from_millis(200);
+        assert_eq!(coordinator.add("counter", 5, ttl).await.unwrap(), 5);
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert_eq!(coordinator.add("counter", 3, ttl).await.unwrap(), 8);
+
+        //the second add did not extend the counter
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert_eq!(coordinator.add("counter", 1, ttl).await.unwrap(), 1);
+    }
+
+    /// hashes_hold_fields (
+    ///     coordinator: the implementation under test
+    /// )
+    /// helper function checking every hash operation
+    async fn hashes_hold_fields(coordinator: &dyn Coordinator) {
+        coordinator.hash_set("hash", "a", b"1".to_vec()).await.unwrap();
+        assert!(coordinator.hash_set_new("hash", "b", b"2".to_vec()).await.unwrap());
+        assert!(!coordinator.hash_set_new("hash", "b", b"3".to_vec()).await.unwrap());
+        assert_eq!(coordinator.hash_get("hash", "b").await.unwrap(), Some(b"2".to_vec()));
+        assert_eq!(coordinator.hash_get("hash", "c").await.unwrap(), None);
+
+        let mut entries = coordinator.hash_entries("hash").await.unwrap();
+        entries.sort();
+        assert_eq!(entries, vec![("a".to_string(), b"1".to_vec()), ("b".to_string(), b"2".to_vec())]);
+
+        assert!(coordinator.hash_delete("hash", "a").await.unwrap());
+        assert!(!coordinator.hash_delete("hash", "a").await.unwrap());
+        assert_eq!(coordinator.hash_add("hash", "counter", 4).await.unwrap(), 4);
+        assert_eq!(coordinator.hash_add("hash", "counter", 2).await.unwrap(), 6);
+
+        coordinator.delete("hash").await.unwrap();
+        assert!(coordinator.hash_entries("hash").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn entries_expire_after_their_ttl() {
+        entries_expire(&InMemoryCoordinator::default()).await;
+    }
+
+    #[tokio::test]
+    async fn counters_keep_the_ttl_they_were_created_with() {
+        counters_add_up(&InMemoryCoordinator::default()).await;
+    }
+
+    #[tokio::test]
+    async fn hashes_hold_their_fields() {
+        hashes_hold_fields(&InMemoryCoordinator::default()).await;
+    }
+
+    #[tokio::test]
+    async fn redis_publish_reaches_subscribers() {
+        let coordinator = RedisCoordinator::connect(&fake_redis().await).await.unwrap();
+        let mut subscription = coordinator.subscribe("topic").await.unwrap();
+
+        assert_eq!(coordinator.publish("topic", b"hello".to_vec()).await.unwrap(), 1);
+        assert_eq!(subscription.recv().await.unwrap(), b"hello");
+        assert_eq!(coordinator.publish("other", b"hello".to_vec()).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn redis_entries_expire_after_their_ttl() {
+        entries_expire(&RedisCoordinator::connect(&fake_redis().await).await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn redis_counters_keep_the_ttl_they_were_created_with() {
+        counters_add_up(&RedisCoordinator::connect(&fake_redis().await).await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn redis_hashes_hold_their_fields() {
+        hashes_hold_fields(&RedisCoordinator::connect(&fake_redis().await).await.unwrap()).await;
+    }
+
+    /// what the fake server answers a command with
+    enum Reply {
+        Status(&'static str),
+        Integer(i64),
+        Bulk(Option<Vec<u8>>),
+        Array(Vec<Reply>),
+        Error(String),
+    }
+
+    impl Reply {
+        fn bulk(value: &[u8]) -> Reply {
+            Reply::Bulk(Some(value.to_vec()))
+        }
+
+        fn encode(&self, out: &mut Vec<u8>) {
+            match self {
+                Reply::Status(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+                Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+                Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+                Reply::Bulk(Some(value)) => {
+                    out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                    out.extend_from_slice(value);
+                    out.extend_from_slice(b"\r\n");
+                }
+                Reply::Array(replies) => {
+                    out.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                    replies.iter().for_each(|reply| reply.encode(out));
+                }
+                Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            }
+        }
+    }
+
+    /// FakeRedis speaks just enough of the Redis protocol for RedisCoordinator, so it is tested
+    /// without a Redis server
+    #[derive(Default)]
+    struct FakeRedis {
+        entries: HashMap<String, (Vec<u8>, Option<Instant>)>,
+        hashes: HashMap<String, HashMap<String, Vec<u8>>>,
+        channels: HashMap<String, Vec<mpsc::UnboundedSender<Reply>>>,
+    }
+
+    impl FakeRedis {
+        fn run(&mut self, command: &[Vec<u8>], connection: &mpsc::UnboundedSender<Reply>) -> Reply {
+            let now = Instant::now();
+            self.entries.retain(|_, (_, expires)| expires.is_none_or(|expires| expires > now));
+            let arg = |i: usize| String::from_utf8_lossy(&command[i]).to_string();
+            let millis = |i: usize| Duration::from_millis(arg(i).parse().unwrap());
+
+            match arg(0).to_uppercase().as_str() {
+                "CLIENT" => Reply::Status("OK"),
+                "SET" => {
+                    let options: Vec<String> = (3..command.len()).map(|i| arg(i).to_uppercase()).collect();
+                    if options.iter().any(|option| option == "NX") && self.entries.contains_key(&arg(1)) {
+                        return Reply::Bulk(None);
+                    }
+                    let expires = options.iter().position(|option| option == "PX").map(|i| now + millis(i + 4));
+                    self.entries.insert(arg(1), (command[2].clone(), expires));
+                    Reply::Status("OK")
+                }
+                "PSETEX" => {
+                    self.entries.insert(arg(1), (command[3].clone(), Some(now + millis(2))));
+                    Reply::Status("OK")
+                }
+                "GET" => Reply::Bulk(self.entries.get(&arg(1)).map(|entry| entry.0.clone())),
+                "DEL" => {
+                    let removed = self.entries.remove(&arg(1)).is_some() | self.hashes.remove(&arg(1)).is_some();
+                    Reply::Integer(removed as i64)
+                }
+                "HSET" | "HSETNX" => {
+                    let hash = self.hashes.entry(arg(1)).or_default();
+                    let new = !hash.contains_key(&arg(2));
+                    if new || arg(0).eq_ignore_ascii_case("HSET") {
+                        hash.insert(arg(2), command[3].clone());
+                    }
+                    Reply::Integer(new as i64)
+                }
+                "HGET" => Reply::Bulk(self.hashes.get(&arg(1)).and_then(|hash| hash.get(&arg(2)).cloned())),
+                "HDEL" => {
+                    let removed = self.hashes.get_mut(&arg(1)).is_some_and(|hash| hash.remove(&arg(2)).is_some());
+                    self.hashes.retain(|_, hash| !hash.is_empty());
+                    Reply::Integer(removed as i64)
+                }
+                "HGETALL" => Reply::Array(self.hashes.get(&arg(1))
+                    .map(|hash| hash.iter().flat_map(|(field, value)| [Reply::bulk(field.as_bytes()), Reply::bulk(value)]).collect())
+                    .unwrap_or_default()),
+                "HINCRBY" => {
+                    let counter = self.hashes.entry(arg(1)).or_default().entry(arg(2)).or_insert_with(|| b"0".to_vec());
+                    let total = counter_value(counter).unwrap() as i64 + arg(3).parse::<i64>().unwrap();
+                    *counter = total.to_string().into_bytes();
+                    Reply::Integer(total)
+                }
+                "INCRBY" => {
+                    let entry = self.entries.entry(arg(1)).or_insert((b"0".to_vec(), None));
+                    let total = counter_value(&entry.0).unwrap() as i64 + arg(2).parse::<i64>().unwrap();
+                    entry.0 = total.to_string().into_bytes();
+                    Reply::Integer(total)
+                }
+                "PUBLISH" => {
+                    let subscribers = self.channels.entry(arg(1)).or_default();
+                    let message = || Reply::Array(vec![Reply::bulk(b"message"), Reply::bulk(&command[1]), Reply::bulk(&command[2])]);
+                    let reached = subscribers.iter().filter(|subscriber| subscriber.send(message()).is_ok()).count();
+                    Reply::Integer(reached as i64)
+                }
+                "SUBSCRIBE" => {
+                    self.channels.entry(arg(1)).or_default().push(connection.clone());
+                    Reply::Array(vec![Reply::bulk(b"subscribe"), Reply::bulk(&command[1]), Reply::Integer(1)])
+                }
+                command => Reply::Error(format!("ERR unknown command '{}'", command)),
+            }
+        }
+    }
+
+    /// fake_redis()
+    ///
+    /// function:
+    /// Starts a FakeRedis on a free port and returns the url to connect to it with.
+    async fn fake_redis() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(FakeRedis::default()));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                //like Redis, replies go out as soon as they are written
+                stream.set_nodelay(true).unwrap();
+                tokio::spawn(serve(stream, state.clone()));
+            }
+        });
+        url
+    }
+
+    /// serve (
+    ///     stream: a connection to the fake server
+    ///     state: what every connection shares
+    /// )
+    /// helper function answering the commands sent over one connection, MULTI to EXEC included.
+    /// Replies and published messages go out through the same queue so they stay in order.
+    async fn serve(stream: TcpStream, state: Arc<Mutex<FakeRedis>>) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let (tx, mut rx) = mpsc::unbounded_channel::<Reply>();
+        let writing = tokio::spawn(async move {
+            while let Some(reply) = rx.recv().await {
+                let mut out = Vec::new();
+                reply.encode(&mut out);
+                if writer.write_all(&out).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut transaction: Option<Vec<Vec<Vec<u8>>>> = None;
+        while let Some(command) = read_command(&mut reader).await {
+            let mut state = state.lock().unwrap();
+            let reply = match (String::from_utf8_lossy(&command[0]).to_uppercase().as_str(), &mut transaction) {
+                ("MULTI", None) => {
+                    transaction = Some(Vec::new());
+                    Reply::Status("OK")
+                }
+                ("EXEC", Some(queued)) => {
+                    let replies = queued.iter().map(|command| state.run(command, &tx)).collect();
+                    transaction = None;
+                    Reply::Array(replies)
+                }
+                (_, Some(queued)) => {
+                    queued.push(command);
+                    Reply::Status("QUEUED")
+                }
+                _ => state.run(&command, &tx),
+            };
+            let _ = tx.send(reply);
+        }
+        //closes the connection's subscriptions too
+        writing.abort();
+    }
+
+    /// read_command (
+    ///     reader: a connection to the fake server
+    /// )
+    /// helper function reading one command, an array of bulk strings. None once the connection closed.
+    async fn read_command(reader: &mut BufReader<OwnedReadHalf>) -> Option<Vec<Vec<u8>>> {
+        let count = read_length(reader, '*').await?;
+        let mut command = Vec::with_capacity(count);
+        for _ in 0..count {
+            let length = read_length(reader, '$').await?;
+            let mut arg = vec![0; length + 2];
+            reader.read_exact(&mut arg).await.ok()?;
+            arg.truncate(length);
+            command.push(arg);
+        }
+        Some(command)
+    }
+
+    /// read_length (
+    ///     reader: a connection to the fake server
+    ///     prefix: '*' for an array, '$' for a bulk string
+    /// )
+    /// helper function reading the header line of an array or bulk string
+    async fn read_length(reader: &mut BufReader<OwnedReadHalf>, prefix: char) -> Option<usize> {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.ok()?;
+        line.strip_prefix(prefix)?.trim_end().parse().ok()
+    }
+}
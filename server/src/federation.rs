@@ -0,0 +1,243 @@
+use std::env;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use prost::Message;
+use tokio::time::interval;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::{Request, Response, Status};
+use crate::auth::{secret_matches, unix_now};
+use crate::connection::connection::federation_client::FederationClient;
+use crate::connection::connection::{FederatedFile, FederatedSeeder, FileHash, InfoHash, Replica};
+use crate::coordinator::Coordinator;
+
+/// how often a tracker pushes its replica to the trackers it federates with
+const REPLICATION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// a replica that was not replaced for this long belongs to a tracker that is down, its seeders
+/// could not be brokered through it anymore
+const REPLICA_TTL: Duration = Duration::from_secs(90);
+
+/// the metadata key federated trackers authenticate each other with
+const FEDERATION_KEY_HEADER: &str = "x-federation-key";
+
+/// the hash holding the latest replica of every tracker, keyed by its url
+const REPLICAS: &str = "replicas";
+
+/// the latest replica received from one tracker
+#[derive(Clone, PartialEq, Message)]
+struct Received {
+    /// unix time it was received
+    #[prost(uint64, tag = "1")]
+    at: u64,
+    #[prost(message, repeated, tag = "2")]
+    files: Vec<FederatedFile>,
+}
+
+/// Federation holds the files other trackers replicated to us and knows who to replicate ours to.
+/// Without TRACKER_FEDERATION_KEY the tracker neither sends nor accepts replicas.
+#[derive(Debug)]
+pub struct Federation {
+    /// the url clients reach us at, sent along with our replica (TRACKER_PUBLIC_URL)
+    url: String,
+    /// the trackers we replicate to (TRACKER_FEDERATION_PEERS, comma separated). They are reached
+    /// over plain HTTP/2, so they should be addresses on a private network
+    peers: Vec<String>,
+    /// shared by every federated tracker (TRACKER_FEDERATION_KEY)
+    key: Option<String>,
+    /// where the replicas are kept, whichever instance received them
+    coordinator: Arc<dyn Coordinator>,
+}
+
+impl Federation {
+
+    ///from_env()
+    /// parameters:
+    ///     - coordinator: the backend shared with the other instances
+    ///
+    /// function:
+    /// Reads the federation settings, leaving federation off if TRACKER_FEDERATION_KEY is not set.
+    pub fn from_env(coordinator: Arc<dyn Coordinator>) -> Federation {
+        let key = env::var("TRACKER_FEDERATION_KEY").ok().filter(|key| !key.is_empty());
+        let peers = env::var("TRACKER_FEDERATION_PEERS").ok()
+            .map(|peers| {
+                peers.split(',')
+                    .map(|peer| peer.trim().to_string())
+                    .filter(|peer| !peer.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let url = env::var("TRACKER_PUBLIC_URL").unwrap_or_default();
+
+        if key.is_some() && url.is_empty() {
+            println!("TRACKER_PUBLIC_URL is not set, federated trackers cannot point clients at us");
+        }
+        Federation { url, peers, key, coordinator }
+    }
+
+    ///store()
+    /// parameters:
+    ///     - replica: what another tracker's clients advertise
+    ///
+    /// function:
+    /// Replaces the previous replica of the tracker it came from.
+    async fn store(&self, replica: Replica) -> Result<(), Status> {
+        let received = Received { at: unix_now(), files: replica.files };
+        self.coordinator.hash_set(REPLICAS, &replica.tracker, received.encode_to_vec()).await
+    }
+
+    ///replicas()
+    ///
+    /// function:
+    /// Returns the replicas that are not older than REPLICA_TTL, with the url of the tracker each
+    /// came from. Older ones are dropped on the way.
+    async fn replicas(&self) -> Result<Vec<(String, Received)>, Status> {
+        let mut replicas = Vec::new();
+        for (tracker, payload) in self.coordinator.hash_entries(REPLICAS).await? {
+            match Received::decode(&payload[..]) {
+                Ok(received) if unix_now().saturating_sub(received.at) < REPLICA_TTL.as_secs() => replicas.push((tracker, received)),
+                _ => {
+                    self.coordinator.hash_delete(REPLICAS, &tracker).await?;
+                }
+            }
+        }
+        Ok(replicas)
+    }
+
+    ///seeders()
+    /// parameters:
+    ///     - file_hash: the file
+    ///
+    /// function:
+    /// Returns the seeders other trackers replicated for the file, each with the url of the tracker
+    /// to broker connections to it through.
+    pub async fn seeders(&self, file_hash: &FileHash) -> Result<Vec<(String, FederatedSeeder)>, Status> {
+        Ok(self.replicas().await?.into_iter()
+            .flat_map(|(tracker, replica)| {
+                replica.files.into_iter()
+                    .filter(|file| file.hash.as_ref() == Some(file_hash))
+                    .flat_map(|file| file.seeders)
+                    .map(|seeder| (tracker.clone(), seeder))
+                    .collect::<Vec<_>>()
+            })
+            .collect())
+    }
+
+    ///files()
+    ///
+    /// function:
+    /// Returns every file other trackers replicated, once each.
+    pub async fn files(&self) -> Result<Vec<(FileHash, InfoHash)>, Status> {
+        let mut files: Vec<(FileHash, InfoHash)> = Vec::new();
+        for (_, replica) in self.replicas().await? {
+            for file in replica.files {
+                let (Some(hash), Some(info_hash)) = (file.hash, file.info_hash) else { continue };
+                if !files.iter().any(|(known, _)| *known == hash) {
+                    files.push((hash, info_hash));
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    ///info_hash()
+    /// parameters:
+    ///     - file_hash: the file
+    ///
+    /// function:
+    /// Returns the info hash another tracker replicated for the file.
+    pub async fn info_hash(&self, file_hash: &FileHash) -> Result<Option<InfoHash>, Status> {
+        Ok(self.files().await?.into_iter()
+            .find(|(hash, _)| hash == file_hash)
+            .map(|(_, info_hash)| info_hash))
+    }
+
+    ///replicate_forever()
+    /// parameters:
+    ///     - replica: builds what our own clients advertise right now
+    ///
+    /// function:
+    /// Pushes our replica to every federated tracker each REPLICATION_INTERVAL. A tracker that
+    /// cannot be reached is tried again with the next replica. Every instance pushes the same
+    /// replica since they share what their clients advertise.
+    pub async fn replicate_forever<F>(self: Arc<Self>, replica: impl Fn() -> F)
+    where
+        F: Future<Output = Result<Vec<FederatedFile>, Status>>,
+    {
+        let Some(key) = &self.key else { return };
+        let Ok(key) = key.parse::<MetadataValue<Ascii>>() else {
+            eprintln!("TRACKER_FEDERATION_KEY cannot be sent as metadata");
+            return;
+        };
+        let mut ticker = interval(REPLICATION_INTERVAL);
+
+        loop {
+            ticker.tick().await;
+            let files = match replica().await {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("Failed to build our replica: {}", e);
+                    continue;
+                }
+            };
+
+            for peer in &self.peers {
+                let mut request = Request::new(Replica { tracker: self.url.clone(), files: files.clone() });
+                request.metadata_mut().insert(FEDERATION_KEY_HEADER, key.clone());
+
+                let pushed = match FederationClient::connect(peer.clone()).await {
+                    Ok(mut client) => client.replicate(request).await.map(|_| ()).map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                if let Err(e) = pushed {
+                    eprintln!("Failed to replicate to {}: {}", peer, e);
+                }
+            }
+        }
+    }
+}
+
+/// FederationService accepts the replicas other trackers push
+#[derive(Debug)]
+pub struct FederationService {
+    federation: Arc<Federation>,
+}
+
+impl FederationService {
+
+    ///new()
+    /// parameters:
+    ///     - federation: the replicas shared with the tracker
+    ///
+    /// function:
+    /// Creates the service.
+    pub fn new(federation: Arc<Federation>) -> Self {
+        FederationService { federation }
+    }
+}
+
+#[tonic::async_trait]
+impl crate::connection::connection::federation_server::Federation for FederationService {
+
+    /// replicate() replaces what the sending tracker's clients advertise, if it presents our key
+    async fn replicate(
+        &self,
+        request: Request<Replica>
+    ) -> Result<Response<()>, Status> {
+        let Some(key) = &self.federation.key else {
+            return Err(Status::unimplemented("federation is not enabled on this tracker"));
+        };
+        let presented = request.metadata().get(FEDERATION_KEY_HEADER).and_then(|value| value.to_str().ok());
+        if !presented.is_some_and(|presented| secret_matches(presented, key)) {
+            return Err(Status::unauthenticated("invalid federation key"));
+        }
+
+        let replica = request.into_inner();
+        if replica.tracker.is_empty() {
+            return Err(Status::invalid_argument("replica does not name its tracker"));
+        }
+        self.federation.store(replica).await?;
+
+        Ok(Response::new(()))
+    }
+}
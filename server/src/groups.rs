@@ -0,0 +1,219 @@
+use std::sync::Arc;
+use tonic::Status;
+use crate::connection::connection::{Group, Scope, Visibility};
+use crate::coordinator::Coordinator;
+
+/// the hash mapping the name of every group to the fingerprint of its owner
+const GROUPS: &str = "groups";
+
+/// Groups holds the private groups files can be advertised to. They are kept in the coordinator,
+/// each one as its owner in GROUPS and a hash of its members. Members are certificate fingerprints
+/// since those stay the same across runs while client ids do not.
+#[derive(Debug)]
+pub struct Groups {
+    coordinator: Arc<dyn Coordinator>,
+}
+
+impl Groups {
+
+    ///new()
+    /// parameters:
+    ///     - coordinator: where the groups are kept
+    ///
+    /// function:
+    /// Creates the groups of every instance sharing the coordinator.
+    pub fn new(coordinator: Arc<dyn Coordinator>) -> Groups {
+        Groups { coordinator }
+    }
+
+    ///create()
+    /// parameters:
+    ///     - name: the name of the new group
+    ///     - owner: fingerprint of the client creating it
+    ///
+    /// function:
+    /// Creates a group with its owner as the only member.
+    pub async fn create(&self, name: &str, owner: &[u8]) -> Result<(), Status> {
+        if name.is_empty() {
+            return Err(Status::invalid_argument("group name must not be empty"));
+        }
+        if !self.coordinator.hash_set_new(GROUPS, name, owner.to_vec()).await? {
+            return Err(Status::already_exists("group already exists"));
+        }
+        //members added while an earlier group of the same name was being removed do not carry over
+        self.coordinator.delete(&members_key(name)).await?;
+        self.coordinator.hash_set(&members_key(name), &hex::encode(owner), Vec::new()).await
+    }
+
+    ///owner()
+    /// parameters:
+    ///     - name: the group
+    ///
+    /// function:
+    /// Returns the fingerprint of the group's owner.
+    async fn owner(&self, name: &str) -> Result<Vec<u8>, Status> {
+        self.coordinator.hash_get(GROUPS, name).await?
+            .ok_or_else(|| Status::not_found("no such group"))
+    }
+
+    ///add_member()
+    /// parameters:
+    ///     - name: the group
+    ///     - caller: fingerprint of the client adding the member, has to be the owner
+    ///     - member: fingerprint of the client to add
+    ///
+    /// function:
+    /// Adds a member to a group.
+    pub async fn add_member(&self, name: &str, caller: &[u8], member: &[u8]) -> Result<(), Status> {
+        if self.owner(name).await? != caller {
+            return Err(Status::permission_denied("only the owner can add members"));
+        }
+        self.coordinator.hash_set(&members_key(name), &hex::encode(member), Vec::new()).await
+    }
+
+    ///remove_member()
+    /// parameters:
+    ///     - name: the group
+    ///     - caller: fingerprint of the client removing the member, the owner or the member itself
+    ///     - member: fingerprint of the client to remove
+    ///
+    /// function:
+    /// Removes a member from a group. The owner can only leave once it is the last member, which
+    /// removes the group.
+    pub async fn remove_member(&self, name: &str, caller: &[u8], member: &[u8]) -> Result<(), Status> {
+        let owner = self.owner(name).await?;
+        if owner != caller && caller != member {
+            return Err(Status::permission_denied("only the owner can remove other members"));
+        }
+
+        if owner == member {
+            if self.coordinator.hash_entries(&members_key(name)).await?.len() > 1 {
+                return Err(Status::failed_precondition("the owner can only leave an empty group"));
+            }
+            self.coordinator.delete(&members_key(name)).await?;
+            self.coordinator.hash_delete(GROUPS, name).await?;
+        } else {
+            self.coordinator.hash_delete(&members_key(name), &hex::encode(member)).await?;
+        }
+        Ok(())
+    }
+
+    ///is_member()
+    /// parameters:
+    ///     - name: the group
+    ///     - fingerprint: the client's certificate fingerprint
+    ///
+    /// function:
+    /// Returns whether the client is in the group.
+    pub async fn is_member(&self, name: &str, fingerprint: &[u8]) -> Result<bool, Status> {
+        Ok(self.coordinator.hash_get(&members_key(name), &hex::encode(fingerprint)).await?.is_some())
+    }
+
+    ///list_for()
+    /// parameters:
+    ///     - fingerprint: the client's certificate fingerprint
+    ///
+    /// function:
+    /// Returns every group the client is in.
+    pub async fn list_for(&self, fingerprint: &[u8]) -> Result<Vec<Group>, Status> {
+        let mut groups = Vec::new();
+        for (name, owner) in self.coordinator.hash_entries(GROUPS).await? {
+            let members: Vec<Vec<u8>> = self.coordinator.hash_entries(&members_key(&name)).await?
+                .into_iter()
+                .filter_map(|(member, _)| hex::decode(member).ok())
+                .collect();
+            if members.iter().any(|member| member == fingerprint) {
+                groups.push(Group { name, owner, members });
+            }
+        }
+        Ok(groups)
+    }
+
+    ///can_see()
+    /// parameters:
+    ///     - scope: the scope a file was advertised with
+    ///     - fingerprint: the certificate fingerprint of the client asking
+    ///
+    /// function:
+    /// Returns whether the scope includes the client.
+    pub async fn can_see(&self, scope: &Scope, fingerprint: &[u8]) -> Result<bool, Status> {
+        match scope.visibility() {
+            Visibility::Public => Ok(true),
+            Visibility::Group => self.is_member(&scope.group, fingerprint).await,
+            Visibility::InviteOnly => Ok(scope.invited.iter().any(|invited| invited == fingerprint)),
+        }
+    }
+}
+
+/// members_key (
+///     name: a group
+/// )
+/// helper function for the hash the group's members are kept in
+fn members_key(name: &str) -> String {
+    format!("group:{}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinator::InMemoryCoordinator;
+
+    const OWNER: &[u8] = &[1; 32];
+    const MEMBER: &[u8] = &[2; 32];
+    const OUTSIDER: &[u8] = &[3; 32];
+
+    fn scope(visibility: Visibility, group: &str, invited: &[&[u8]]) -> Scope {
+        let mut scope = Scope {
+            group: group.to_string(),
+            invited: invited.iter().map(|invited| invited.to_vec()).collect(),
+            ..Scope::default()
+        };
+        scope.set_visibility(visibility);
+        scope
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_manages_membership() {
+        let groups = Groups::new(Arc::new(InMemoryCoordinator::default()));
+        groups.create("team", OWNER).await.unwrap();
+        assert_eq!(groups.create("team", MEMBER).await.unwrap_err().code(), tonic::Code::AlreadyExists);
+
+        assert_eq!(groups.add_member("team", MEMBER, MEMBER).await.unwrap_err().code(), tonic::Code::PermissionDenied);
+        groups.add_member("team", OWNER, MEMBER).await.unwrap();
+        assert!(groups.is_member("team", MEMBER).await.unwrap());
+        assert_eq!(groups.remove_member("team", OUTSIDER, MEMBER).await.unwrap_err().code(), tonic::Code::PermissionDenied);
+
+        //the owner stays until it is the last one, members can leave on their own
+        assert_eq!(groups.remove_member("team", OWNER, OWNER).await.unwrap_err().code(), tonic::Code::FailedPrecondition);
+        groups.remove_member("team", MEMBER, MEMBER).await.unwrap();
+        assert!(!groups.is_member("team", MEMBER).await.unwrap());
+        groups.remove_member("team", OWNER, OWNER).await.unwrap();
+        assert!(groups.list_for(OWNER).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn group_scopes_include_only_members() {
+        let groups = Groups::new(Arc::new(InMemoryCoordinator::default()));
+        groups.create("team", OWNER).await.unwrap();
+        groups.add_member("team", OWNER, MEMBER).await.unwrap();
+        let team = scope(Visibility::Group, "team", &[]);
+
+        assert!(groups.can_see(&team, OWNER).await.unwrap());
+        assert!(groups.can_see(&team, MEMBER).await.unwrap());
+        assert!(!groups.can_see(&team, OUTSIDER).await.unwrap());
+        assert!(!groups.can_see(&scope(Visibility::Group, "missing", &[]), OWNER).await.unwrap());
+
+        groups.remove_member("team", OWNER, MEMBER).await.unwrap();
+        assert!(!groups.can_see(&team, MEMBER).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn invite_only_scopes_include_only_the_invited() {
+        let groups = Groups::new(Arc::new(InMemoryCoordinator::default()));
+        let invite = scope(Visibility::InviteOnly, "", &[MEMBER]);
+
+        assert!(groups.can_see(&invite, MEMBER).await.unwrap());
+        assert!(!groups.can_see(&invite, OWNER).await.unwrap());
+        assert!(groups.can_see(&scope(Visibility::Public, "", &[]), OUTSIDER).await.unwrap());
+    }
+}
@@ -1,161 +1,790 @@
 mod turn;
 mod connection;
+mod stun;
+mod turn_relay;
+mod rate_limit;
+mod relay_token;
+mod coordinator;
+mod auth;
+mod groups;
+mod catalog;
+mod swarm;
+mod federation;
 
 use std::{env, sync::Arc};
+use std::future::Future;
 use std::time::Duration;
-use dashmap::DashMap;
-use tonic::{transport::Server, Code, Request, Response, Status};
+use prost::Message;
+use rand::seq::IteratorRandom;
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+use tonic::service::interceptor::InterceptedService;
 use connection::connection::*;
 use crate::connector_server::{Connector, ConnectorServer};
 use crate::turn_server::TurnServer;
-use tokio::sync::{mpsc, watch};
-use tokio::time::{sleep};
+use tokio::sync::mpsc;
+use tokio::time::{interval, timeout};
+use tokio_stream::wrappers::ReceiverStream;
 use uuid::Uuid;
 use crate::turn::TurnService;
+use crate::turn_relay::{RelayConfig, TurnRelay};
+use crate::rate_limit::RateLimits;
+use crate::relay_token::RelayTokens;
+use crate::coordinator::Coordinator;
+use crate::auth::{authenticated, unix_now, verify_possession, ApiKeys, SessionAuth, SessionTokens};
+use crate::groups::Groups;
+use crate::swarm::Swarms;
+use crate::federation::{Federation, FederationService};
+use crate::federation_server::FederationServer;
 
 
-#[derive(Debug, Default)]
+/// number of events that can be waiting on one client before new ones are dropped
+const EVENT_QUEUE_DEPTH: usize = 32;
+
+/// how long a client has to accept an event pushed over its session
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how many registered clients get_dht_nodes hands out
+const DHT_BOOTSTRAP_NODES: usize = 16;
+
+/// a client's listener entry in the coordinator expires this long after its session closed, so
+/// every instance knows not to push to a client that went away without delisting
+const SESSION_ENTRY_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// how often an open session writes its entry again
+const SESSION_ENTRY_REFRESH: Duration = Duration::from_secs(3 * 60);
+
+/// how long a nonce handed out by get_registration_challenge can be signed and registered with
+const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+
+/// the hash of every registered client's ClientRecord, keyed by client id
+const CLIENTS: &str = "clients";
+
+/// the hash of the InfoHash of every advertised file, keyed by the file's hash in hex
+const FILES: &str = "files";
+
+/// everything the tracker knows about a registered client
+#[derive(Clone, PartialEq, Message)]
+pub struct ClientRecord {
+    /// the client's current connection details, None until it has bound its endpoint
+    #[prost(message, optional, tag = "1")]
+    peer_id: Option<PeerId>,
+    /// fingerprint of the client's persistent certificate that peers pin
+    #[prost(bytes = "vec", tag = "2")]
+    cert_fingerprint: Vec<u8>,
+    /// static Noise public key used to authenticate relayed transfers
+    #[prost(bytes = "vec", tag = "3")]
+    noise_key: Vec<u8>,
+}
+
+/// a client seeding a file, and who it shares the file with. Each file has a hash of these,
+/// keyed by the id of the client that advertised it
+#[derive(Clone, PartialEq, Message)]
+pub struct Advertisement {
+    #[prost(message, optional, tag = "1")]
+    scope: Option<Scope>,
+    /// unix time the client last advertised the file
+    #[prost(uint64, tag = "2")]
+    advertised_at: u64,
+    /// the pieces the client has while it is still downloading the file, empty once it has all of them
+    #[prost(bytes = "vec", tag = "3")]
+    bitfield: Vec<u8>,
+}
+
+/// ConnectionService is the tracker. Everything clients registered and advertised is kept in the
+/// coordinator, so a client can call any instance and find clients that registered with any other.
+#[derive(Debug)]
 pub struct ConnectionService {
-    client_registry: Arc<DashMap<ClientId, Option<PeerId>>>,
-    file_tracker: Arc<DashMap<FileHash, InfoHash>>,
-    seeder_list: Arc<DashMap<FileHash, Vec<ClientId>>>,
-    seed_notifier: Arc<DashMap<PeerId, mpsc::Sender<PeerId>>>,
-    cert_sender: Arc<DashMap<PeerId, mpsc::Sender<Cert>>>,
-    init_hole_punch: Arc<DashMap<PeerId, watch::Sender<bool>>>,
+    /// private groups files can be advertised to
+    groups: Groups,
+    /// progress and traffic announced for each file
+    swarms: Swarms,
+    /// the files and seeders federated trackers replicated to us
+    federation: Arc<Federation>,
+    /// shared with the other instances, holds the registry, advertised files, session events and
+    /// peer credentials since the two clients of a connection may be connected to different instances
+    coordinator: Arc<dyn Coordinator>,
+    /// settings of the UDP TURN relay, None when this server does not run one
+    relay: Option<Arc<RelayConfig>>,
+    /// signs the tokens that admit both peers of a brokered connection to their TURN session
+    relay_tokens: Arc<RelayTokens>,
+    /// keys a client has to present to register
+    api_keys: ApiKeys,
+    /// signs the token a registered client authenticates every other call with
+    session_tokens: Arc<SessionTokens>,
 }
 
 impl ConnectionService {
+
+    ///new()
+    /// parameters:
+    ///     - relay: settings of the UDP TURN relay, None when this server does not run one
+    ///     - relay_tokens: the signer shared with the TURN service
+    ///     - coordinator: the backend shared with the other instances
+    ///     - api_keys: the keys clients register with
+    ///     - session_tokens: the signer shared with the SessionAuth interceptor
+    ///     - federation: the replicas shared with the Federation service
+    ///
+    /// function:
+    /// Creates a tracker on top of what the coordinator already holds.
+    pub fn new(
+        relay: Option<Arc<RelayConfig>>,
+        relay_tokens: Arc<RelayTokens>,
+        coordinator: Arc<dyn Coordinator>,
+        api_keys: ApiKeys,
+        session_tokens: Arc<SessionTokens>,
+        federation: Arc<Federation>,
+    ) -> Self {
+        ConnectionService {
+            groups: Groups::new(coordinator.clone()),
+            swarms: Swarms::new(coordinator.clone()),
+            federation,
+            coordinator,
+            relay,
+            relay_tokens,
+            api_keys,
+            session_tokens,
+        }
+    }
+
+    ///caller()
+    /// parameters:
+    ///     - request: a call that passed through SessionAuth
+    ///
+    /// function:
+    /// Returns the client the call was authenticated as. A token outlives a client that delisted
+    /// itself, so the client also has to still be registered. The request is only read up front,
+    /// so it can be taken apart while the registration is looked up.
+    fn caller<T>(&self, request: &Request<T>) -> impl Future<Output = Result<ClientId, Status>> + Send + '_ {
+        let caller = authenticated(request);
+        async move {
+            let caller = caller?;
+            if self.record_of(&caller).await?.is_none() {
+                return Err(Status::unauthenticated("client not registered"));
+            }
+            Ok(caller)
+        }
+    }
+
+    ///record_of()
+    /// parameters:
+    ///     - client_id: a client
+    ///
+    /// function:
+    /// Returns what the client registered with, None if it is not registered.
+    async fn record_of(&self, client_id: &ClientId) -> Result<Option<ClientRecord>, Status> {
+        Ok(self.coordinator.hash_get(CLIENTS, &client_id.uid).await?
+            .and_then(|payload| ClientRecord::decode(&payload[..]).ok()))
+    }
+
+    ///records()
+    ///
+    /// function:
+    /// Returns every registered client with what it registered with.
+    async fn records(&self) -> Result<Vec<(ClientId, ClientRecord)>, Status> {
+        Ok(self.coordinator.hash_entries(CLIENTS).await?
+            .into_iter()
+            .filter_map(|(uid, payload)| Some((ClientId { uid }, ClientRecord::decode(&payload[..]).ok()?)))
+            .collect())
+    }
+
+    ///fingerprint_of()
+    /// parameters:
+    ///     - caller: the client the call was authenticated as
+    ///
+    /// function:
+    /// Returns the certificate fingerprint the client registered, which groups and invites refer to.
+    async fn fingerprint_of(&self, caller: &ClientId) -> Result<Vec<u8>, Status> {
+        self.record_of(caller).await?
+            .map(|record| record.cert_fingerprint)
+            .ok_or_else(|| Status::not_found("client not registered"))
+    }
+
+    ///info_hash_of()
+    /// parameters:
+    ///     - file_hash: the file
+    ///
+    /// function:
+    /// Returns the info hash the file was advertised with, None if nobody advertised it to us.
+    async fn info_hash_of(&self, file_hash: &FileHash) -> Result<Option<InfoHash>, Status> {
+        Ok(self.coordinator.hash_get(FILES, &file_field(file_hash)).await?
+            .and_then(|payload| InfoHash::decode(&payload[..]).ok()))
+    }
+
+    ///advertised_files()
+    ///
+    /// function:
+    /// Returns every file advertised to us.
+    async fn advertised_files(&self) -> Result<Vec<(FileHash, InfoHash)>, Status> {
+        Ok(self.coordinator.hash_entries(FILES).await?
+            .into_iter()
+            .filter_map(|(field, payload)| {
+                Some((FileHash { hash: hex::decode(field).ok()? }, InfoHash::decode(&payload[..]).ok()?))
+            })
+            .collect())
+    }
+
+    ///advertisements()
+    /// parameters:
+    ///     - file_hash: the file
+    ///
+    /// function:
+    /// Returns every advertisement of the file with the client that made it.
+    async fn advertisements(&self, file_hash: &FileHash) -> Result<Vec<(ClientId, Advertisement)>, Status> {
+        Ok(self.coordinator.hash_entries(&seeders_key(file_hash)).await?
+            .into_iter()
+            .filter_map(|(uid, payload)| Some((ClientId { uid }, Advertisement::decode(&payload[..]).ok()?)))
+            .collect())
+    }
+
+    ///visible()
+    /// parameters:
+    ///     - owner: the client that made the advertisement
+    ///     - advertisement: a client seeding a file
+    ///     - caller: the client asking
+    ///     - fingerprint: the certificate fingerprint of the client asking
+    ///
+    /// function:
+    /// Returns whether the advertisement's scope includes the client, clients always see their own.
+    async fn visible(&self, owner: &ClientId, advertisement: &Advertisement, caller: &ClientId, fingerprint: &[u8]) -> Result<bool, Status> {
+        if owner == caller {
+            return Ok(true);
+        }
+        self.groups.can_see(&advertisement.scope.clone().unwrap_or_default(), fingerprint).await
+    }
+
+    ///visible_seeders()
+    /// parameters:
+    ///     - file_hash: the file
+    ///     - caller: the client asking
+    ///     - fingerprint: the certificate fingerprint of the client asking
+    ///
+    /// function:
+    /// Returns the advertisements of a file the client can see, with the clients that made them.
+    async fn visible_seeders(&self, file_hash: &FileHash, caller: &ClientId, fingerprint: &[u8]) -> Result<Vec<(ClientId, Advertisement)>, Status> {
+        let mut visible = Vec::new();
+        for (owner, advertisement) in self.advertisements(file_hash).await? {
+            if self.visible(&owner, &advertisement, caller, fingerprint).await? {
+                visible.push((owner, advertisement));
+            }
+        }
+        Ok(visible)
+    }
+
+    ///federated_seeders()
+    /// parameters:
+    ///     - file_hash: the file
+    ///     - fingerprint: the certificate fingerprint of the client asking
+    ///
+    /// function:
+    /// Returns the seeders federated trackers replicated for a file that share it with the client,
+    /// along with when they advertised it. Clients that also advertised the file to us are left
+    /// out, as is the client asking, since connecting through us is the shorter way.
+    async fn federated_seeders(&self, file_hash: &FileHash, fingerprint: &[u8]) -> Result<Vec<(Peer, u64)>, Status> {
+        let mut local = Vec::new();
+        for (owner, _) in self.advertisements(file_hash).await? {
+            local.extend(self.record_of(&owner).await?.map(|record| record.cert_fingerprint));
+        }
+
+        let mut seeders = Vec::new();
+        for (tracker, seeder) in self.federation.seeders(file_hash).await? {
+            let Some(peer) = seeder.peer else { continue };
+            let elsewhere = peer.cert_fingerprint == fingerprint || local.contains(&peer.cert_fingerprint);
+            if !elsewhere && self.groups.can_see(&seeder.scope.unwrap_or_default(), fingerprint).await? {
+                seeders.push((Peer { tracker, ..peer }, seeder.advertised_at));
+            }
+        }
+        Ok(seeders)
+    }
+
+    ///can_see_file()
+    /// parameters:
+    ///     - file_hash: the file
+    ///     - caller: the client asking
+    ///     - fingerprint: the certificate fingerprint of the client asking
+    ///
+    /// function:
+    /// Returns whether at least one seeder, ours or a federated tracker's, shares the file with the client.
+    async fn can_see_file(&self, file_hash: &FileHash, caller: &ClientId, fingerprint: &[u8]) -> Result<bool, Status> {
+        Ok(!self.visible_seeders(file_hash, caller, fingerprint).await?.is_empty()
+            || !self.federated_seeders(file_hash, fingerprint).await?.is_empty())
+    }
+
+    ///known_files()
+    ///
+    /// function:
+    /// Returns every file advertised to us or replicated by a federated tracker, once each.
+    async fn known_files(&self) -> Result<Vec<(FileHash, InfoHash)>, Status> {
+        let mut files = self.advertised_files().await?;
+        for (file_hash, info_hash) in self.federation.files().await? {
+            if !files.iter().any(|(known, _)| *known == file_hash) {
+                files.push((file_hash, info_hash));
+            }
+        }
+        Ok(files)
+    }
+
+    ///forget_file()
+    /// parameters:
+    ///     - file_hash: a file whose last seeder went away
+    ///
+    /// function:
+    /// Stops listing the file and drops its swarm. A client advertising the file meanwhile writes
+    /// its advertisement before the file, so the file is put back once one shows up.
+    async fn forget_file(&self, file_hash: &FileHash) -> Result<(), Status> {
+        let Some(info_hash) = self.info_hash_of(file_hash).await? else { return Ok(()) };
+        self.coordinator.hash_delete(FILES, &file_field(file_hash)).await?;
+        if !self.advertisements(file_hash).await?.is_empty() {
+            return self.coordinator.hash_set(FILES, &file_field(file_hash), info_hash.encode_to_vec()).await;
+        }
+        self.swarms.remove(file_hash).await
+    }
+
+    ///replica()
+    ///
+    /// function:
+    /// Returns what our own clients advertise for federated trackers. Files shared with a group are
+    /// left out since the group only exists here.
+    pub async fn replica(&self) -> Result<Vec<FederatedFile>, Status> {
+        let mut replica = Vec::new();
+        for (file_hash, info_hash) in self.advertised_files().await? {
+            let mut seeders = Vec::new();
+            for (owner, advertisement) in self.advertisements(&file_hash).await? {
+                let scope = advertisement.scope.unwrap_or_default();
+                if scope.visibility() == Visibility::Group {
+                    continue;
+                }
+                let Some(record) = self.record_of(&owner).await? else { continue };
+                let Some(peer_id) = record.peer_id else { continue };
+                seeders.push(FederatedSeeder {
+                    peer: Some(Peer {
+                        id: Some(peer_id),
+                        cert_fingerprint: record.cert_fingerprint,
+                        noise_key: record.noise_key,
+                        bitfield: advertisement.bitfield,
+                        tracker: String::new(),
+                    }),
+                    scope: Some(scope),
+                    advertised_at: advertisement.advertised_at,
+                });
+            }
+
+            if !seeders.is_empty() {
+                replica.push(FederatedFile { hash: Some(file_hash), info_hash: Some(info_hash), seeders });
+            }
+        }
+        Ok(replica)
+    }
+
+    ///owned_peer()
+    /// parameters:
+    ///     - caller: the client the call was authenticated as
+    ///     - peer: connection details the caller claims as its own
+    ///
+    /// function:
+    /// Returns the caller's identity if it registered those connection details, so one client
+    /// cannot broker connections in the name of another.
+    async fn owned_peer(&self, caller: &ClientId, peer: &PeerId) -> Result<PeerIdentity, Status> {
+        self.identity_of(peer).await?
+            .filter(|identity| identity.client_id.as_ref() == Some(caller))
+            .ok_or_else(|| Status::permission_denied("connection details belong to another client"))
+    }
+
+    ///push()
+    /// parameters:
+    ///     - peer: the client the event is for
+    ///     - event: what to tell it
+    ///
+    /// function:
+    /// Pushes an event over the client's session, on whichever instance it is open, and waits
+    /// for the client to accept it. Fails straight away if the client has no session open.
+    async fn push(&self, peer: &PeerId, event: server_event::Event) -> Result<(), Status> {
+        let event_id = rand::random::<u64>();
+        let mut ack = self.coordinator.subscribe(&ack_topic(event_id)).await?;
+
+        let payload = ServerEvent { event_id, event: Some(event) }.encode_to_vec();
+        if self.coordinator.publish(&session_topic(peer), payload).await? == 0 {
+            return Err(Status::not_found("peer has no open session"));
+        }
+
+        match timeout(ACK_TIMEOUT, ack.recv()).await {
+            Ok(Some(_)) => Ok(()),
+            _ => Err(Status::deadline_exceeded("peer did not accept the event")),
+        }
+    }
+
+    ///identity_of()
+    /// parameters:
+    ///     - peer: the connection details of a client
+    ///
+    /// function:
+    /// Looks up what the client registered with.
+    async fn identity_of(&self, peer: &PeerId) -> Result<Option<PeerIdentity>, Status> {
+        Ok(self.coordinator.get(&identity_key("peer", &peer.encode_to_vec())).await?
+            .and_then(|payload| PeerIdentity::decode(&payload[..]).ok()))
+    }
+
+    ///bound_to()
+    /// parameters:
+    ///     - key: an identity entry in the coordinator
+    ///
+    /// function:
+    /// Returns the client the key is bound to, None if nobody holds it.
+    async fn bound_to(&self, key: &str) -> Result<Option<ClientId>, Status> {
+        Ok(self.coordinator.get(key).await?
+            .and_then(|payload| PeerIdentity::decode(&payload[..]).ok())
+            .and_then(|identity| identity.client_id))
+    }
+
+    ///claim()
+    /// parameters:
+    ///     - client_id: the client claiming the keys
+    ///     - keys: identity entries in the coordinator
+    ///
+    /// function:
+    /// Fails unless every key is free or already bound to the client. A key stays bound for as long
+    /// as its client is registered, so nobody can register with another client's Noise key or
+    /// connection details.
+    async fn claim(&self, client_id: &ClientId, keys: &[String]) -> Result<(), Status> {
+        for key in keys {
+            if self.bound_to(key).await?.is_some_and(|owner| owner != *client_id) {
+                return Err(Status::already_exists("identity is registered to another client"));
+            }
+        }
+        Ok(())
+    }
+
+    ///release()
+    /// parameters:
+    ///     - client_id: a client that delisted itself
+    ///     - key: an identity entry in the coordinator
+    ///
+    /// function:
+    /// Deletes the entry, unless another client holds it by now.
+    async fn release(&self, client_id: &ClientId, key: &str) -> Result<(), Status> {
+        if self.bound_to(key).await? == Some(client_id.clone()) {
+            self.coordinator.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    ///redeem_challenge()
+    /// parameters:
+    ///     - nonce: a nonce a client signed to register
+    ///
+    /// function:
+    /// Fails unless an instance handed the nonce out within CHALLENGE_TTL and nobody registered
+    /// with it yet.
+    async fn redeem_challenge(&self, nonce: &[u8]) -> Result<(), Status> {
+        let unknown = || Status::unauthenticated("unknown or expired registration challenge");
+        let key = challenge_key(nonce);
+        if self.coordinator.get(&key).await?.is_none() {
+            return Err(unknown());
+        }
+        //instances redeeming the same nonce at once all count, only the first one sees 1
+        if self.coordinator.add(&format!("{}:used", key), 1, CHALLENGE_TTL).await? != 1 {
+            return Err(unknown());
+        }
+        self.coordinator.delete(&key).await
+    }
+
+    ///forget()
+    /// parameters:
+    ///     - client_id: a client that delisted itself, or whose certificate registered again
+    ///
+    /// function:
+    /// Removes the client's registration and frees its keys, and takes it out of the files it
+    /// advertised and the swarms it was in. Its session ends with the next event.
+    async fn forget(&self, client_id: &ClientId) -> Result<(), Status> {
+        let Some(record) = self.record_of(client_id).await? else { return Ok(()) };
+        self.coordinator.hash_delete(CLIENTS, &client_id.uid).await?;
+
+        let _ = self.release(client_id, &identity_key("fp", &record.cert_fingerprint)).await;
+        let _ = self.release(client_id, &identity_key("noise", &record.noise_key)).await;
+
+        //if peer_id is found anywhere remove it
+        if let Some(peer_id) = record.peer_id {
+            let peer_key_entry = identity_key("peer", &peer_id.encode_to_vec());
+            if self.bound_to(&peer_key_entry).await.ok().flatten() == Some(client_id.clone()) {
+                let _ = self.coordinator.delete(&peer_key_entry).await;
+                let _ = self.coordinator.delete(&format!("listener:{}", peer_key(&peer_id))).await;
+            }
+        }
+
+        //remove from seeding list and from the swarms it was downloading in
+        for (file_hash, _) in self.advertised_files().await? {
+            if self.coordinator.hash_delete(&seeders_key(&file_hash), &client_id.uid).await?
+                && self.advertisements(&file_hash).await?.is_empty() {
+                self.forget_file(&file_hash).await?;
+            }
+        }
+        self.swarms.forget(client_id).await
+    }
+}
+
+/// share_identity (
+///     coordinator: the backend shared with the other instances
+///     identity: what a client registered with
+///     peer_id: its connection details, if it has bound its endpoint
+/// )
+/// helper function letting every instance look the client up by either of its keys in verify_peer(),
+/// and by its connection details when pushing its credentials to a seeder. The entries are kept
+/// for as long as the client is registered.
+async fn share_identity(coordinator: &dyn Coordinator, identity: &PeerIdentity, peer_id: Option<&PeerId>) -> Result<(), Status> {
+    let payload = identity.encode_to_vec();
+    coordinator.set(&identity_key("fp", &identity.cert_fingerprint), payload.clone(), None).await?;
+    coordinator.set(&identity_key("noise", &identity.noise_key), payload.clone(), None).await?;
+    if let Some(peer_id) = peer_id {
+        coordinator.set(&identity_key("peer", &peer_id.encode_to_vec()), payload, None).await?;
+    }
+    Ok(())
+}
+
+/// check_owner (
+///     caller: the client the call was authenticated as
+///     client_id: the client the call acts on
+/// )
+/// helper function so clients can only change their own registration and advertisements
+#[allow(clippy::result_large_err)]
+fn check_owner(caller: &ClientId, client_id: &ClientId) -> Result<(), Status> {
+    if caller != client_id {
+        return Err(Status::permission_denied("clients can only act on their own registration"));
+    }
+    Ok(())
+}
+
+/// check_bitfield (
+///     bitfield: the pieces a client says it has
+///     info_hash: the file they belong to
+/// )
+/// helper function failing with invalid_argument unless the bitfield is empty or has a bit for every piece
+#[allow(clippy::result_large_err)]
+fn check_bitfield(bitfield: &[u8], info_hash: &InfoHash) -> Result<(), Status> {
+    if !bitfield.is_empty() && bitfield.len() != info_hash.pieces.len().div_ceil(8) {
+        return Err(Status::invalid_argument("bitfield does not match the file's piece count"));
+    }
+    Ok(())
+}
+
+/// peer_key (
+///     peer: the connection details of a client
+/// )
+/// helper function to name a client's topics and entries in the coordinator
+fn peer_key(peer: &PeerId) -> String {
+    hex::encode(peer.encode_to_vec())
+}
+
+/// file_field (
+///     file_hash: a file
+/// )
+/// helper function for the field of FILES the file is listed under
+fn file_field(file_hash: &FileHash) -> String {
+    hex::encode(&file_hash.hash)
+}
+
+/// seeders_key (
+///     file_hash: a file
+/// )
+/// helper function for the hash of the file's advertisements
+fn seeders_key(file_hash: &FileHash) -> String {
+    format!("seeders:{}", file_field(file_hash))
+}
+
+/// session_topic (
+///     peer: the connection details of a client
+/// )
+/// helper function for the topic events for a client's session are published on
+fn session_topic(peer: &PeerId) -> String {
+    format!("session:{}", peer_key(peer))
+}
+
+/// ack_topic (
+///     event_id: an event pushed to a client
+/// )
+/// helper function for the topic the client's acknowledgement of an event is published on
+fn ack_topic(event_id: u64) -> String {
+    format!("ack:{}", event_id)
+}
+
+/// identity_key (
+///     kind: "fp" for a certificate fingerprint, "noise" for a Noise key, "peer" for connection details
+///     key: the key itself
+/// )
+/// helper function for the coordinator entry a client's identity is shared under
+fn identity_key(kind: &str, key: &[u8]) -> String {
+    format!("identity:{}:{}", kind, hex::encode(key))
+}
+
+/// challenge_key (
+///     nonce: a nonce handed out by get_registration_challenge
+/// )
+/// helper function for the coordinator entry that exists while the nonce can be registered with
+fn challenge_key(nonce: &[u8]) -> String {
+    format!("challenge:{}", hex::encode(nonce))
 }
 
 #[tonic::async_trait]
 impl Connector for ConnectionService {
+    type sessionStream = ReceiverStream<Result<ServerEvent, Status>>;
 
     /// this function is used for a client to request a file from the server
-    /// it returns the list of peers that have the file, from the tracker map
+    /// it returns the list of peers that have the file, from the tracker map, leaving out those that
+    /// did not share it with the caller
     async fn get_file_peer_list(
         &self,
         request: Request<FileHash>,
     ) -> Result<Response<PeerList>, Status> {
+        let caller = self.caller(&request).await?;
+        let fingerprint = self.fingerprint_of(&caller).await?;
         let info_hash = request.into_inner();
 
-        if let Some(clients) = self.seeder_list.get(&info_hash) {
-
-            let client_map = self.client_registry.clone();
-            // todo maybe resolve unwarp here
-            let peer_list = clients.iter().filter_map( |s| client_map.get(&s)?.to_owned()).collect();
-
-            Ok(Response::new(PeerList { list: peer_list }))
-        } else {
-            // just returns an empty list
-            Ok(Response::new(PeerList { list: vec![] }))
+        let mut peer_list = Vec::new();
+        for (owner, advertisement) in self.visible_seeders(&info_hash, &caller, &fingerprint).await? {
+            if owner == caller {
+                continue;
+            }
+            let Some(record) = self.record_of(&owner).await? else { continue };
+            let Some(peer_id) = record.peer_id else { continue };
+            peer_list.push(Peer {
+                id: Some(peer_id),
+                cert_fingerprint: record.cert_fingerprint,
+                noise_key: record.noise_key,
+                bitfield: advertisement.bitfield,
+                tracker: String::new(),
+            });
         }
+        //seeders federated trackers told us about come after our own
+        peer_list.extend(self.federated_seeders(&info_hash, &fingerprint).await?.into_iter().map(|(peer, _)| peer));
+
+        Ok(Response::new(PeerList { list: peer_list }))
     }
 
+    /// send_file_request() pushes a leecher's connection request to a seeder, preceded by the
+    /// leecher's credentials, and returns once the seeder accepted it. Both of them get a token for
+    /// the TURN session they fall back on, the seeder along with the request and the leecher in the reply.
     async fn send_file_request(
         &self,
         request: Request<ConnectionIds>
-    ) -> Result<Response<()>, Status> {
+    ) -> Result<Response<RelayToken>, Status> {
+        let caller = self.caller(&request).await?;
         let r = request.into_inner();
         //this is the connection id retrieved from get_file_peer_list() of the peer seeding
         let seeder_peer_id = r.connection_peer.ok_or(Status::invalid_argument("missing peer id"))?;
 
         //this is your own client_id so they can find your connection id
         let self_id = r.self_id.ok_or(Status::invalid_argument("missing self"))?;
+        let identity = self.owned_peer(&caller, &self_id).await?;
 
-        //loop through 3 times after delay.
-        //this just gives multiple attempts in case of network delay or seeding at bad times
-        for i in 0 ..5 {
-            if let Some(entry) = self.seed_notifier.remove(&seeder_peer_id) {
-                let tx = entry.1;
-                tx.send(self_id).await.map_err(|_| Status::internal("Failed to send self_id"))?;
-                break;
-            } else {
-                sleep(Duration::from_millis(250)).await;
-                if i == 4 {
-                    //todo check for this error and initiate a new connection if it occurs
-                    return Err(Status::not_found("missing seeding peer"));
-                }
-                continue;
-            }
+        let (session_id, seeder_token, leecher_token) = self.relay_tokens.issue();
+        let seed_request = SeedRequest {
+            leecher: Some(self_id),
+            relay_token: Some(RelayToken { session_id: session_id.clone(), token: seeder_token }),
+        };
 
-        }
+        //the seeder checks the leecher against these instead of asking us once it connects
+        self.push(&seeder_peer_id, server_event::Event::PeerCredentials(PeerCredentials {
+            peer: Some(self_id),
+            identity: Some(identity),
+        })).await?;
+        //a seeder without a session or that does not accept in time fails the call, the leecher moves on to another seeder
+        self.push(&seeder_peer_id, server_event::Event::ConnectionRequest(seed_request)).await?;
 
-        Ok(Response::new(()))
+        Ok(Response::new(RelayToken { session_id, token: leecher_token }))
     }
 
-    ///this function is used by clients willing to share data to get peers who request data.
-    /// clients should listen to this service at all times they are willing to send.
-    async fn seed(
+    /// session() is opened by every client willing to share data and kept open for as long as it is.
+    /// The client says hello with its connection details first, then the server pushes connection
+    /// requests, hole punch triggers and peer credentials over it in order, and the client
+    /// acknowledges each one it accepts. Opening a new session replaces the previous one, on
+    /// whichever instance it was opened. Events arriving while the client is behind are dropped.
+    async fn session(
         &self,
-        request: Request<PeerId>
-    ) -> Result<Response<PeerId>, Status> {
-        let self_peer_id = request.into_inner();
+        request: Request<Streaming<ClientEvent>>
+    ) -> Result<Response<Self::sessionStream>, Status> {
+        let caller = self.caller(&request).await?;
+        let mut inbound = request.into_inner();
+        let self_peer_id = match inbound.message().await? {
+            Some(ClientEvent { event: Some(client_event::Event::Hello(peer_id)) }) => peer_id,
+            _ => return Err(Status::invalid_argument("session must start with hello")),
+        };
+        self.owned_peer(&caller, &self_peer_id).await?;
 
-        let (peer_id_tx, mut peer_id_rx) = mpsc::channel::<PeerId>(1);
+        let mut events = self.coordinator.subscribe(&session_topic(&self_peer_id)).await?;
 
-        self.seed_notifier.insert(self_peer_id, peer_id_tx);
+        //the latest session claims the client, older ones stop once they see they were replaced
+        let listener_key = format!("listener:{}", peer_key(&self_peer_id));
+        let listener = Uuid::new_v4().to_string().into_bytes();
+        self.coordinator.set(&listener_key, listener.clone(), Some(SESSION_ENTRY_TTL)).await?;
 
-        //get id of client requesting file
-        let peer_id= peer_id_rx.recv().await
-            .ok_or(Status::new(Code::Internal, "Failed to receive client id"))?;
+        let (event_tx, event_rx) = mpsc::channel::<Result<ServerEvent, Status>>(EVENT_QUEUE_DEPTH);
+        let coordinator = self.coordinator.clone();
+        tokio::spawn(async move {
+            let mut refresh = interval(SESSION_ENTRY_REFRESH);
+            //the first tick completes immediately and the client's entries are fresh
+            refresh.tick().await;
+            loop {
+                tokio::select! {
+                    _ = event_tx.closed() => break,
+                    _ = refresh.tick() => {
+                        if coordinator.get(&listener_key).await.ok().flatten().as_ref() != Some(&listener) {
+                            break;
+                        }
+                        let _ = coordinator.set(&listener_key, listener.clone(), Some(SESSION_ENTRY_TTL)).await;
+                    }
+                    payload = events.recv() => {
+                        let Some(payload) = payload else { break };
+                        if coordinator.get(&listener_key).await.ok().flatten().as_ref() != Some(&listener) {
+                            break;
+                        }
+                        let Ok(event) = ServerEvent::decode(&payload[..]) else { continue };
+                        if let Err(mpsc::error::TrySendError::Full(_)) = event_tx.try_send(Ok(event)) {
+                            eprintln!("client has too many pending events, dropping one");
+                        }
+                    }
+                    message = inbound.message() => match message {
+                        Ok(Some(ClientEvent { event: Some(client_event::Event::Ack(event_id)) })) => {
+                            let _ = coordinator.publish(&ack_topic(event_id), Vec::new()).await;
+                        }
+                        Ok(Some(_)) => continue,
+                        Ok(None) | Err(_) => break,
+                    },
+                }
+            }
+            //only forget the client's session if it has not opened a new one since
+            if coordinator.get(&listener_key).await.ok().flatten() == Some(listener) {
+                let _ = coordinator.delete(&listener_key).await;
+            }
+        });
 
-        Ok(Response::new(peer_id))
+        Ok(Response::new(ReceiverStream::new(event_rx)))
     }
-    
-    /// await_trigger_hole_punch() should be used by the sending peer (ie the peer with the data)
-    /// and awaited to initiate its hole punch sequence. 
-    /// When this method returns, it indicates the requesting client is beginning its hole punch sequence. 
-    async fn await_hole_punch_trigger(
-        &self,
-        request: Request<PeerId>,
-    ) -> Result<Response<()>, Status> {
-        let self_id = request.into_inner();
 
-        let (watch_tx, mut watch_rx) = watch::channel(false);
-
-        self.init_hole_punch.insert(self_id, watch_tx);
-
-        watch_rx.changed().await.map_err(|e| Status::new(Code::Internal, e.to_string()))?;
-        
-        Ok(Response::new(()))
-        
-    }
-    
     /// init_hole_punch() is used to notify a seeding peer that they should begin the udp hole punching procedure.
     /// This function should be called right before the calling peer initiates their own hole punching procedure
-    /// as UDP hole punching is time-sensitive.
+    /// as UDP hole punching is time-sensitive. It returns once the seeder received the trigger.
     async fn init_punch(
         &self,
-        request: Request<PeerId>
+        request: Request<ConnectionIds>
     ) -> Result<Response<()>, Status> {
-       
-        let peer_id = request.into_inner();
-  
-        for i in 0..5 {
-            match self.init_hole_punch.get(&peer_id) {
-                Some(notify_handle) => {
-                    println!("Hole Punch notifier received by Leecher");
-                    notify_handle.send(true).map_err(|e| Status::internal(e.to_string()))?;
-                    break;
-                },
-                None => {
-                    Err(Status::internal("no seeding peer"))?;
-                    sleep(Duration::from_millis(250)).await;
-                    if i == 4 {
-                        return Err(Status::invalid_argument("invalid peer id"))?;
-                    }
-                }
-            }
-        }
-        
+        let caller = self.caller(&request).await?;
+        let r = request.into_inner();
+        let seeder_id = r.connection_peer.ok_or(Status::invalid_argument("missing peer id"))?;
+        let self_id = r.self_id.ok_or(Status::invalid_argument("missing self"))?;
+        self.owned_peer(&caller, &self_id).await?;
+
+        self.push(&seeder_id, server_event::Event::PunchTrigger(self_id)).await?;
+        println!("Hole Punch notifier received by Leecher");
+
         Ok(Response::new(()))
     }
 
-    /// this function is used to advertise a client owns a file that can be shared 
+    /// this function is used to advertise a client owns a file that can be shared, clients can only
+    /// advertise files as themselves. The scope decides who sees the advertisement, advertising a
+    /// file again replaces its scope.
     async fn advertise(
         &self,
         request: Request<FileMessage>,
     ) -> Result<Response<ClientId>, Status> {
+        let caller = self.caller(&request).await?;
         let r = request.into_inner();
 
         let file_hash = r.hash.ok_or(Status::invalid_argument("missing file hash"))?;
@@ -166,29 +795,83 @@ impl Connector for ConnectionService {
             Some(id) => id,
             None => return Err(Status::invalid_argument("Client missing")),
         };
+        check_owner(&caller, &client_id)?;
+        let fingerprint = self.fingerprint_of(&client_id).await?;
 
-        self.file_tracker.insert(file_hash.clone(), info_hash);
+        let scope = r.scope.unwrap_or_default();
+        match scope.visibility() {
+            Visibility::Group if !self.groups.is_member(&scope.group, &fingerprint).await? => {
+                return Err(Status::permission_denied("files can only be shared with groups the client is in"));
+            }
+            Visibility::InviteOnly if scope.invited.is_empty() => {
+                return Err(Status::invalid_argument("invite-only files need at least one invited client"));
+            }
+            _ => {}
+        }
+        check_bitfield(&r.bitfield, &info_hash)?;
 
-        self.seeder_list
-            .entry(file_hash)
-            .or_insert_with(Vec::new)
-            .push(client_id.clone());
+        //the advertisement goes first so forget_file() sees it before it unlists the file
+        let advertisement = Advertisement { scope: Some(scope), advertised_at: unix_now(), bitfield: r.bitfield };
+        self.coordinator.hash_set(&seeders_key(&file_hash), &client_id.uid, advertisement.encode_to_vec()).await?;
+        self.coordinator.hash_set(FILES, &file_field(&file_hash), info_hash.encode_to_vec()).await?;
 
         Ok( Response::new(client_id) )
     }
 
+    /// update_pieces() records the pieces a client got since it advertised a file it is downloading,
+    /// an empty bitfield makes it a full seeder of the file
+    async fn update_pieces(
+        &self,
+        request: Request<PieceUpdate>
+    ) -> Result<Response<()>, Status> {
+        let caller = self.caller(&request).await?;
+        let update = request.into_inner();
+        let client_id = update.id.ok_or(Status::invalid_argument("Client missing"))?;
+        let file_hash = update.hash.ok_or(Status::invalid_argument("missing file hash"))?;
+        check_owner(&caller, &client_id)?;
+
+        let info_hash = self.info_hash_of(&file_hash).await?
+            .ok_or_else(|| Status::not_found("no such file"))?;
+        check_bitfield(&update.bitfield, &info_hash)?;
+
+        let mut advertisement = self.coordinator.hash_get(&seeders_key(&file_hash), &client_id.uid).await?
+            .and_then(|payload| Advertisement::decode(&payload[..]).ok())
+            .ok_or_else(|| Status::failed_precondition("the file has to be advertised first"))?;
+        advertisement.bitfield = update.bitfield;
+        self.coordinator.hash_set(&seeders_key(&file_hash), &client_id.uid, advertisement.encode_to_vec()).await?;
+
+        Ok(Response::new(()))
+    }
+
+    /// get_registration_challenge() hands out a nonce for register_client(), and like it needs no
+    /// session token. The nonce can be registered with once, on any instance, within CHALLENGE_TTL.
+    async fn get_registration_challenge(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<RegistrationChallenge>, Status> {
+        let nonce = rand::random::<[u8; 32]>().to_vec();
+        self.coordinator.set(&challenge_key(&nonce), Vec::new(), Some(CHALLENGE_TTL)).await?;
+        Ok(Response::new(RegistrationChallenge { nonce }))
+    }
+
+    /// register_client() is the only call that does not need a session token. The client presents an
+    /// API key instead if the tracker requires one, and gets the session token for every other call.
+    /// It proves its certificate is its own by signing a nonce from get_registration_challenge()
+    /// with the certificate's key. A client that registered with the certificate before is
+    /// forgotten, so a client that went away without delisting can come back.
     async fn register_client(
         &self,
         request: Request<ClientRegistry>,
-    ) -> Result<Response<ClientId>, Status> {
-        
+    ) -> Result<Response<Registration>, Status> {
+        self.api_keys.check(&request.get_ref().api_key)?;
+
         let mut uid;
         
         loop {
             let uuid = Uuid::new_v4();
             uid = ClientId{ uid: uuid.to_string()};
             
-            if !self.client_registry.contains_key(&uid) {
+            if self.record_of(&uid).await?.is_none() {
                 break;
             }
 
@@ -197,10 +880,51 @@ impl Connector for ConnectionService {
         if uid.uid.is_empty() {
             return Err(Status::internal("failed to generate uid"))?
         }
-        
-        self.client_registry.insert(uid.clone(), request.into_inner().peer_id);
 
-        Ok(Response::new(uid ))
+        let registry = request.into_inner();
+        if registry.cert_fingerprint.len() != 32 {
+            return Err(Status::invalid_argument("certificate fingerprint must be 32 bytes"));
+        }
+        if registry.noise_key.len() != 32 {
+            return Err(Status::invalid_argument("noise key must be 32 bytes"));
+        }
+        self.redeem_challenge(&registry.nonce).await?;
+        verify_possession(&registry.certificate, &registry.cert_fingerprint, &registry.nonce, &registry.signature)?;
+
+        if let Some(previous) = self.bound_to(&identity_key("fp", &registry.cert_fingerprint)).await? {
+            self.forget(&previous).await?;
+        }
+        let mut keys = vec![identity_key("noise", &registry.noise_key)];
+        keys.extend(registry.peer_id.map(|peer_id| identity_key("peer", &peer_id.encode_to_vec())));
+        self.claim(&uid, &keys).await?;
+
+        share_identity(self.coordinator.as_ref(), &PeerIdentity {
+            client_id: Some(uid.clone()),
+            cert_fingerprint: registry.cert_fingerprint.clone(),
+            noise_key: registry.noise_key.clone(),
+        }, registry.peer_id.as_ref()).await?;
+
+        let record = ClientRecord {
+            peer_id: registry.peer_id,
+            cert_fingerprint: registry.cert_fingerprint,
+            noise_key: registry.noise_key,
+        };
+        self.coordinator.hash_set(CLIENTS, &uid.uid, record.encode_to_vec()).await?;
+
+        let session_token = self.session_tokens.issue(&uid);
+        Ok(Response::new(Registration { client_id: Some(uid), session_token }))
+    }
+
+    /// renew_session_token() hands the caller a new session token in place of the one it called
+    /// with, so a client can keep calling for as long as it stays registered whether or not it
+    /// holds a session.
+    async fn renew_session_token(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<Registration>, Status> {
+        let caller = self.caller(&request).await?;
+        let session_token = self.session_tokens.issue(&caller);
+        Ok(Response::new(Registration { client_id: Some(caller), session_token }))
     }
 
     
@@ -210,100 +934,87 @@ impl Connector for ConnectionService {
         &self,
         request: Request<FullId>
     ) -> Result<Response<ClientId>, Status> {
-    
+        let caller = self.caller(&request).await?;
         let r = request.into_inner();
         let self_id = r.self_id.ok_or(Status::invalid_argument("self id not provided"))?;
         let peer_id = r.peer_id.ok_or(Status::invalid_argument("peer id not provided"))?;
+        check_owner(&caller, &self_id)?;
+        self.claim(&self_id, &[identity_key("peer", &peer_id.encode_to_vec())]).await?;
         
-        self.client_registry.insert(self_id.clone(), Some(peer_id));
+        let mut record = self.record_of(&self_id).await?
+            .ok_or_else(|| Status::not_found("client not registered"))?;
+        let identity = PeerIdentity {
+            client_id: Some(self_id.clone()),
+            cert_fingerprint: record.cert_fingerprint.clone(),
+            noise_key: record.noise_key.clone(),
+        };
+        let previous = record.peer_id.replace(peer_id);
+        self.coordinator.hash_set(CLIENTS, &self_id.uid, record.encode_to_vec()).await?;
+
+        if let Some(previous) = previous.filter(|previous| *previous != peer_id) {
+            self.release(&self_id, &identity_key("peer", &previous.encode_to_vec())).await?;
+        }
+        share_identity(self.coordinator.as_ref(), &identity, Some(&peer_id)).await?;
 
         Ok(Response::new(self_id))
     
     }
 
 
-    /// get_cer() is used by the client end of the quic connection to get the server's self-signed certificate
-    /// it should be called and waited upon once init_cert_sender() has been called
-    /// get_cert() SHOULD NOT be called unless init_cert_sender() has completed
-    async fn get_cert(
-        &self,
-        request: Request<PeerId>
-    ) -> Result<Response<Cert>, Status> {
-        let self_addr = request.into_inner();
-
-        let (cert_tx, mut cert_rx) = mpsc::channel::<Cert>(1);
-
-        self.cert_sender.insert(self_addr, cert_tx);
-        let cert = cert_rx.recv().await.ok_or(Status::internal("failed to receive cert from server"))?;
-
-        Ok(Response::new(cert))
-    }
-
-    ///Send Cert should be used by the server side of quic connection to communicate self-signed cert to the client
-    async fn send_cert(
-        &self,
-        request: Request<CertMessage>
-    ) -> Result<Response<()>, Status> {
-        let r = request.into_inner();
-        
-        let peer_id = match r.peer_id {
-            Some(id) => id,
-            None => return Err(Status::invalid_argument("peer id not returned upon signal from server"))?
-        };
-
-        for i in 0..5 {
-            match self.cert_sender.remove(&peer_id) {
-                Some(entry) => {
-                    let cert_tx = entry.1;
-                    cert_tx.send(r.cert.clone().unwrap()).await
-                    .map_err(|e| Status::internal(e.to_string()))?;
-                    break;
-                }
-                None => {
-                    sleep(Duration::from_millis(250)).await;
-                    if i == 4 {
-                        return Err(Status::invalid_argument("Cert sender not registered"))?;
-                    }
-                    continue
-                }
-            }
-        }
-        
-        Ok(Response::new(()))
-    }
-
     async fn get_client_id(
         &self,
         request: Request<PeerId>,
     ) -> Result<Response<ClientId>, Status> {
+        self.caller(&request).await?;
         let peer = request.into_inner();
 
-        let registry = self.client_registry.clone();
-
-        let client_id = registry
-            .iter()
-            .find_map(|entry| {
-                let (client_id, saved_peer) = entry.pair();
-                if saved_peer.unwrap() == peer {
-                    Some(client_id.clone())
-                } else {
-                    None
-                }
-            })
+        let client_id = self.identity_of(&peer).await?
+            .and_then(|identity| identity.client_id)
             .ok_or_else(|| Status::not_found("No client registered for that peer"))?;
 
         Ok(Response::new(client_id))
     }
     
+    /// verify_peer() is used by a seeder to check the certificate (direct connections) or the
+    /// Noise key (relayed connections) a peer authenticated with belongs to a registered client.
+    /// It returns everything the client published so the seeder can apply its access rules.
+    async fn verify_peer(
+        &self,
+        request: Request<PeerFingerprint>,
+    ) -> Result<Response<PeerIdentity>, Status> {
+        self.caller(&request).await?;
+        let r = request.into_inner();
+        if r.cert_fingerprint.is_empty() && r.noise_key.is_empty() {
+            return Err(Status::invalid_argument("missing peer key"));
+        }
+
+        let key = if r.cert_fingerprint.is_empty() {
+            identity_key("noise", &r.noise_key)
+        } else {
+            identity_key("fp", &r.cert_fingerprint)
+        };
+        let identity = self.coordinator.get(&key).await?
+            .and_then(|payload| PeerIdentity::decode(&payload[..]).ok())
+            .ok_or_else(|| Status::not_found("No client registered with that key"))?;
+
+        Ok(Response::new(identity))
+    }
+
+    /// get_all_files() lists the files at least one of their seeders shared with the caller, piece
+    /// hashes included. browse_catalog() is much lighter once there are more than a few files.
     async fn get_all_files(
         &self,
-        _request: Request<()>
+        request: Request<()>
     ) -> Result<Response<FileList>, Status> {
-        
-        let info_hashes = self.file_tracker
-            .iter()
-            // get all the values (1) in the map
-            .map(|x| x.value().clone()).collect::<Vec<_>>();
+        let caller = self.caller(&request).await?;
+        let fingerprint = self.fingerprint_of(&caller).await?;
+
+        let mut info_hashes = Vec::new();
+        for (file_hash, info_hash) in self.known_files().await? {
+            if self.can_see_file(&file_hash, &caller, &fingerprint).await? {
+                info_hashes.push(info_hash);
+            }
+        }
         
         Ok(Response::new(
             FileList {
@@ -313,26 +1024,130 @@ impl Connector for ConnectionService {
         
     }
 
+    /// browse_catalog() pages through the files shared with the caller, searching, filtering and sorting
+    /// them as the query asks. Files are summarized without their piece hashes, get_info_hash()
+    /// returns those for the file the caller picks.
+    async fn browse_catalog(
+        &self,
+        request: Request<CatalogQuery>
+    ) -> Result<Response<CatalogPage>, Status> {
+        let caller = self.caller(&request).await?;
+        let fingerprint = self.fingerprint_of(&caller).await?;
+        let query = request.into_inner();
+
+        let mut files = Vec::new();
+        for (file_hash, info_hash) in self.known_files().await? {
+            let seeders = self.visible_seeders(&file_hash, &caller, &fingerprint).await?;
+            let federated = self.federated_seeders(&file_hash, &fingerprint).await?;
+            let Some(advertised_at) = seeders.iter().map(|(_, advertisement)| advertisement.advertised_at)
+                .chain(federated.iter().map(|(_, advertised_at)| *advertised_at))
+                .max() else { continue };
+            let stats = self.swarms.stats(&file_hash).await?;
+            let seeders = seeders.iter().filter(|(_, advertisement)| advertisement.bitfield.is_empty()).count()
+                + federated.iter().filter(|(peer, _)| peer.bitfield.is_empty()).count();
+            files.push(FileSummary {
+                hash: Some(file_hash),
+                name: info_hash.name.clone(),
+                file_length: info_hash.file_length,
+                piece_length: info_hash.piece_length,
+                piece_count: info_hash.pieces.len() as u32,
+                seeders: seeders as u32,
+                advertised_at,
+                leechers: stats.leechers,
+                completed: stats.completed,
+            });
+        }
+
+        Ok(Response::new(catalog::page(files, &query)?))
+    }
+
+    /// get_info_hash() returns everything about one file the caller can see, piece hashes included
+    async fn get_info_hash(
+        &self,
+        request: Request<FileHash>
+    ) -> Result<Response<InfoHash>, Status> {
+        let caller = self.caller(&request).await?;
+        let fingerprint = self.fingerprint_of(&caller).await?;
+        let file_hash = request.into_inner();
+
+        if !self.can_see_file(&file_hash, &caller, &fingerprint).await? {
+            return Err(Status::not_found("no such file"));
+        }
+        let info_hash = match self.info_hash_of(&file_hash).await? {
+            Some(info_hash) => info_hash,
+            None => self.federation.info_hash(&file_hash).await?.ok_or_else(|| Status::not_found("no such file"))?,
+        };
+
+        Ok(Response::new(info_hash))
+    }
+
+    /// announce() records how far a client got with a file it can see and how much it moved. Clients
+    /// announce STARTED and COMPLETED or STOPPED around a download and PROGRESS in between, seeders
+    /// announce PROGRESS to report what they uploaded.
+    async fn announce(
+        &self,
+        request: Request<Announce>
+    ) -> Result<Response<()>, Status> {
+        let caller = self.caller(&request).await?;
+        let announce = request.into_inner();
+        let client_id = announce.id.clone().ok_or(Status::invalid_argument("Client missing"))?;
+        let file_hash = announce.hash.clone().ok_or(Status::invalid_argument("missing file hash"))?;
+        check_owner(&caller, &client_id)?;
+        let fingerprint = self.fingerprint_of(&client_id).await?;
+
+        if !self.can_see_file(&file_hash, &caller, &fingerprint).await? {
+            return Err(Status::not_found("no such file"));
+        }
+        self.swarms.announce(&file_hash, &client_id, &announce).await?;
+
+        Ok(Response::new(()))
+    }
+
+    /// get_file_stats() returns how many clients seed and download a file the caller can see, how
+    /// often it was downloaded and how many bytes its swarm reported moving
+    async fn get_file_stats(
+        &self,
+        request: Request<FileHash>
+    ) -> Result<Response<FileStats>, Status> {
+        let caller = self.caller(&request).await?;
+        let fingerprint = self.fingerprint_of(&caller).await?;
+        let file_hash = request.into_inner();
+
+        let seeders = self.visible_seeders(&file_hash, &caller, &fingerprint).await?;
+        let federated = self.federated_seeders(&file_hash, &fingerprint).await?;
+        if seeders.is_empty() && federated.is_empty() {
+            return Err(Status::not_found("no such file"));
+        }
+        let stats = self.swarms.stats(&file_hash).await?;
+        let seeders = seeders.iter().filter(|(_, advertisement)| advertisement.bitfield.is_empty()).count()
+            + federated.iter().filter(|(peer, _)| peer.bitfield.is_empty()).count();
+
+        Ok(Response::new(FileStats {
+            hash: Some(file_hash),
+            seeders: seeders as u32,
+            leechers: stats.leechers,
+            completed: stats.completed,
+            downloaded: stats.downloaded,
+            uploaded: stats.uploaded,
+        }))
+    }
+
     async fn delete_file(
         &self,
         request: Request<FileDelete>
     ) -> Result<Response<()>, Status> {
+        let caller = self.caller(&request).await?;
         let req = request.into_inner();
         let self_id = req.id.ok_or(Status::invalid_argument("missing self id"))?;
         let file_hash = req.hash.ok_or(Status::invalid_argument("missing file hash"))?;
+        check_owner(&caller, &self_id)?;
         
-        if let Some(mut entry) = self.seeder_list.get_mut(&file_hash) {
-            let seeders = entry.value_mut();
-            seeders.retain(|client_id| *client_id != self_id );
-           
-            
-            //If this was the last seeder who had this file, we want ot remove it from both
-            //file_tracker and seeder_list. So that it is no longer advertised to peers.
-            if seeders.is_empty() {
-                drop(entry);
-                self.seeder_list.remove(&file_hash);
-                self.file_tracker.remove(&file_hash);
-            }
+        self.coordinator.hash_delete(&seeders_key(&file_hash), &self_id.uid).await?;
+
+        //If this was the last seeder who had this file, we want ot remove it from the files
+        //So that it is no longer advertised to peers.
+        if self.advertisements(&file_hash).await?.is_empty() {
+            self.forget_file(&file_hash).await?;
         }
 
        Ok(Response::new(()))
@@ -342,35 +1157,114 @@ impl Connector for ConnectionService {
         &self,
         request: Request<ClientId>,
     ) -> Result<Response<()>, Status> {
+        let caller = self.caller(&request).await?;
         let client_id = request.into_inner();
-        
-        if let Some(client_registry_entry) = self.client_registry.remove(&client_id) {
-            
-            //if peer_id is found anywhere remove it
-            if let Some(peer_id) = client_registry_entry.1 {
-                if self.seed_notifier.contains_key(&peer_id) {
-                    self.seed_notifier.remove(&peer_id);    
-                }
-                if self.cert_sender.contains_key(&peer_id) {
-                    self.cert_sender.remove(&peer_id);
-                }
-                if self.init_hole_punch.contains_key(&peer_id) {
-                    self.init_hole_punch.remove(&peer_id);
-                }
-            }
-            
-            //remove from seeding list
-            self.seeder_list.iter_mut()
-                .for_each(|mut entry| {
-                    entry.value_mut().retain(|id| *id != client_id);
-                    if entry.value().is_empty() {
-                        self.file_tracker.remove(entry.key());
-                    }
-                });
+        check_owner(&caller, &client_id)?;
+        self.forget(&client_id).await?;
+
+        Ok(Response::new(()))
+    }
+
+    /// get_relay_credentials() hands a registered client short lived credentials for the UDP TURN relay,
+    /// along with where to reach it.
+    async fn get_relay_credentials(
+        &self,
+        request: Request<ClientId>,
+    ) -> Result<Response<RelayCredentials>, Status> {
+        let caller = self.caller(&request).await?;
+        let client_id = request.into_inner();
+        check_owner(&caller, &client_id)?;
+        let relay = self.relay.as_ref()
+            .ok_or_else(|| Status::unavailable("This server does not run a UDP TURN relay"))?;
+
+        if self.record_of(&client_id).await?.is_none() {
+            return Err(Status::not_found("Client not registered"));
         }
 
+        let ipaddr = match relay.external_ip {
+            std::net::IpAddr::V4(ip) => u32::from(ip),
+            std::net::IpAddr::V6(_) => return Err(Status::unavailable("UDP TURN relay is not reachable over IPv4")),
+        };
+
+        let (username, password) = relay.credentials_for(&client_id.uid);
+        Ok(Response::new(RelayCredentials {
+            username,
+            password,
+            realm: relay.realm.clone(),
+            ipaddr,
+            port: relay.port as u32,
+        }))
+    }
+
+    /// create_group() creates a private group owned by the caller
+    async fn create_group(
+        &self,
+        request: Request<GroupName>,
+    ) -> Result<Response<()>, Status> {
+        let caller = self.caller(&request).await?;
+        let fingerprint = self.fingerprint_of(&caller).await?;
+
+        self.groups.create(&request.into_inner().name, &fingerprint).await?;
+        Ok(Response::new(()))
+    }
+
+    /// add_group_member() lets the owner of a group add a client by its certificate fingerprint
+    async fn add_group_member(
+        &self,
+        request: Request<GroupMember>,
+    ) -> Result<Response<()>, Status> {
+        let caller = self.caller(&request).await?;
+        let fingerprint = self.fingerprint_of(&caller).await?;
+        let r = request.into_inner();
+        if r.cert_fingerprint.len() != 32 {
+            return Err(Status::invalid_argument("certificate fingerprint must be 32 bytes"));
+        }
+
+        self.groups.add_member(&r.group, &fingerprint, &r.cert_fingerprint).await?;
+        Ok(Response::new(()))
+    }
+
+    /// remove_group_member() lets the owner of a group remove a member, or a member leave
+    async fn remove_group_member(
+        &self,
+        request: Request<GroupMember>,
+    ) -> Result<Response<()>, Status> {
+        let caller = self.caller(&request).await?;
+        let fingerprint = self.fingerprint_of(&caller).await?;
+        let r = request.into_inner();
+
+        self.groups.remove_member(&r.group, &fingerprint, &r.cert_fingerprint).await?;
         Ok(Response::new(()))
     }
+
+    /// list_groups() lists the groups the caller is in
+    async fn list_groups(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<GroupList>, Status> {
+        let caller = self.caller(&request).await?;
+        let fingerprint = self.fingerprint_of(&caller).await?;
+
+        Ok(Response::new(GroupList { groups: self.groups.list_for(&fingerprint).await? }))
+    }
+
+    /// get_dht_nodes() hands out a random sample of the other registered clients, each of them runs
+    /// a DHT node on its public address. The tracker does not know their node ids.
+    async fn get_dht_nodes(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<DhtNodeList>, Status> {
+        let caller = self.caller(&request).await?;
+
+        let nodes = self.records().await?
+            .into_iter()
+            .filter(|(client_id, _)| *client_id != caller)
+            .filter_map(|(_, record)| record.peer_id)
+            .map(|peer_id| DhtNode { id: Vec::new(), ipaddr: peer_id.ipaddr, port: peer_id.port })
+            .choose_multiple(&mut rand::thread_rng(), DHT_BOOTSTRAP_NODES);
+
+        Ok(Response::new(DhtNodeList { nodes }))
+    }
 }
 
 
@@ -380,14 +1274,284 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
     let address = format!("0.0.0.0:{}", port).parse()?;
     
-    let connection_service = ConnectionService::default();
-    let turn_service = TurnService::default();
+    //the UDP TURN relay runs next to the gRPC services when it is configured
+    let relay = RelayConfig::from_env()?.map(Arc::new);
+    if let Some(config) = relay.clone() {
+        let turn_relay = TurnRelay::bind(config).await?;
+        tokio::spawn(async move {
+            if let Err(e) = turn_relay.run().await {
+                eprintln!("TURN relay stopped: {}", e);
+            }
+        });
+    }
+
+    //the tracker issues relay tokens and the TURN service checks them, so both share the signer
+    let relay_tokens = Arc::new(RelayTokens::from_env()?);
+    //instances behind the same load balancer rendezvous through a shared coordinator
+    let coordinator = coordinator::from_env().await?;
+    //every call but register_client is authenticated with the session token it hands out
+    let session_tokens = Arc::new(SessionTokens::from_env()?);
+    let auth = SessionAuth::new(session_tokens.clone());
+    //federated trackers replicate what their clients advertise to each other
+    let federation = Arc::new(Federation::from_env(coordinator.clone()));
+    let connection_service = Arc::new(ConnectionService::new(relay, relay_tokens.clone(), coordinator.clone(), ApiKeys::from_env(), session_tokens, federation.clone()));
+    let turn_service = TurnService::new(env::var("TURN_ADMIN_TOKEN").ok(), RateLimits::from_env()?, relay_tokens, coordinator);
+
+    let replicated = connection_service.clone();
+    tokio::spawn(federation.clone().replicate_forever(move || {
+        let replicated = replicated.clone();
+        async move { replicated.replica().await }
+    }));
     
     Server::builder()
-        .add_service(ConnectorServer::new(connection_service))
-        .add_service(TurnServer::new(turn_service))
+        .add_service(InterceptedService::new(ConnectorServer::from_arc(connection_service), auth.clone()))
+        .add_service(TurnServer::with_interceptor(turn_service, auth))
+        //authenticated with the federation key rather than a session token
+        .add_service(FederationServer::new(FederationService::new(federation)))
         .serve(address)
         .await?;
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::Ed25519KeyPair;
+    use sha2::{Digest, Sha256};
+    use crate::auth::{Authenticated, REGISTRATION_CONTEXT};
+    use crate::coordinator::InMemoryCoordinator;
+
+    /// how a PKCS#8 document of an Ed25519 key starts, its seed follows
+    const ED25519_PKCS8_PREFIX: [u8; 16] = [0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20];
+
+    /// two instances behind the same load balancer, sharing their coordinator and token secret
+    fn instances() -> (ConnectionService, ConnectionService) {
+        let coordinator: Arc<dyn Coordinator> = Arc::new(InMemoryCoordinator::default());
+        let session_tokens = Arc::new(SessionTokens::default());
+        let relay_tokens = Arc::new(RelayTokens::default());
+        let instance = || ConnectionService::new(
+            None,
+            relay_tokens.clone(),
+            coordinator.clone(),
+            ApiKeys::default(),
+            session_tokens.clone(),
+            Arc::new(Federation::from_env(coordinator.clone())),
+        );
+        (instance(), instance())
+    }
+
+    /// the certificate of a client made up from n, its key has n for a seed so it is the same every time
+    fn certificate(n: u8) -> Vec<u8> {
+        let key = rcgen::KeyPair::try_from(&[&ED25519_PKCS8_PREFIX[..], &[n; 32]].concat()[..]).unwrap();
+        rcgen::CertificateParams::new(vec!["peer".to_string()]).unwrap().self_signed(&key).unwrap().der().to_vec()
+    }
+
+    /// the fingerprint of the certificate of a client made up from n
+    fn fingerprint(n: u8) -> Vec<u8> {
+        Sha256::digest(certificate(n)).to_vec()
+    }
+
+    /// what a client made up from n registers with, signed for a challenge the service handed out
+    async fn registry(service: &ConnectionService, n: u8, noise_key: Vec<u8>, peer_id: Option<PeerId>) -> ClientRegistry {
+        let nonce = service.get_registration_challenge(Request::new(())).await.unwrap().into_inner().nonce;
+        let key = Ed25519KeyPair::from_seed_unchecked(&[n; 32]).unwrap();
+        ClientRegistry {
+            peer_id,
+            cert_fingerprint: fingerprint(n),
+            noise_key,
+            api_key: String::new(),
+            certificate: certificate(n),
+            signature: key.sign(&[REGISTRATION_CONTEXT, &nonce].concat()).as_ref().to_vec(),
+            nonce,
+        }
+    }
+
+    /// registers a client whose keys and connection details are made up from n
+    async fn register(service: &ConnectionService, n: u8) -> (ClientId, PeerId) {
+        let peer_id = PeerId { ipaddr: n as u32, port: 1000 + n as u32, ..PeerId::default() };
+        let registry = registry(service, n, vec![n; 32], Some(peer_id)).await;
+        let registration = service.register_client(Request::new(registry)).await.unwrap().into_inner();
+        (registration.client_id.unwrap(), peer_id)
+    }
+
+    /// a call made by the client, as SessionAuth lets it through
+    fn as_client<T>(client_id: &ClientId, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(Authenticated(client_id.clone()));
+        request
+    }
+
+    #[tokio::test]
+    async fn clients_registered_on_one_instance_are_verified_on_another() {
+        let (a, b) = instances();
+        let (seeder, _) = register(&a, 1).await;
+        let (leecher, _) = register(&b, 2).await;
+
+        let by_fingerprint = b.verify_peer(as_client(&leecher, PeerFingerprint {
+            cert_fingerprint: fingerprint(1),
+            noise_key: Vec::new(),
+        })).await.unwrap().into_inner();
+        assert_eq!(by_fingerprint.client_id, Some(seeder.clone()));
+
+        let by_noise_key = b.verify_peer(as_client(&leecher, PeerFingerprint {
+            cert_fingerprint: Vec::new(),
+            noise_key: vec![1; 32],
+        })).await.unwrap().into_inner();
+        assert_eq!(by_noise_key.client_id, Some(seeder));
+    }
+
+    #[tokio::test]
+    async fn registrations_must_prove_their_certificate() {
+        let (a, b) = instances();
+        register(&a, 1).await;
+
+        //another client's fingerprint, with our own certificate and signature
+        let mut stolen = registry(&b, 9, vec![9; 32], None).await;
+        stolen.cert_fingerprint = fingerprint(1);
+        assert_eq!(b.register_client(Request::new(stolen)).await.unwrap_err().code(), tonic::Code::Unauthenticated);
+
+        //another client's certificate, signed with our key
+        let mut forged = registry(&b, 9, vec![9; 32], None).await;
+        forged.cert_fingerprint = fingerprint(1);
+        forged.certificate = certificate(1);
+        assert_eq!(b.register_client(Request::new(forged)).await.unwrap_err().code(), tonic::Code::Unauthenticated);
+
+        //a nonce is only good for one registration, on any instance
+        let replayed = registry(&a, 9, vec![9; 32], None).await;
+        b.register_client(Request::new(replayed.clone())).await.unwrap();
+        assert_eq!(a.register_client(Request::new(replayed)).await.unwrap_err().code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn keys_held_on_one_instance_cannot_be_registered_on_another() {
+        let (a, b) = instances();
+        let (client, _) = register(&a, 1).await;
+
+        let stolen = registry(&b, 2, vec![1; 32], None).await;
+        assert_eq!(b.register_client(Request::new(stolen)).await.unwrap_err().code(), tonic::Code::AlreadyExists);
+
+        //once the client delisted its keys are free again
+        a.delist_client(as_client(&client, client.clone())).await.unwrap();
+        let freed = registry(&b, 2, vec![1; 32], None).await;
+        b.register_client(Request::new(freed)).await.unwrap();
+        assert_eq!(a.verify_peer(as_client(&client, PeerFingerprint::default())).await.unwrap_err().code(),
+                   tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn registering_a_certificate_again_replaces_its_registration() {
+        let (a, b) = instances();
+        let (gone, _) = register(&a, 1).await;
+        let (back, _) = register(&b, 1).await;
+        assert_ne!(gone, back);
+
+        //the client that went away without delisting is forgotten, its keys belong to the new registration
+        assert_eq!(a.renew_session_token(as_client(&gone, ())).await.unwrap_err().code(), tonic::Code::Unauthenticated);
+        let identity = a.verify_peer(as_client(&back, PeerFingerprint {
+            cert_fingerprint: fingerprint(1),
+            noise_key: Vec::new(),
+        })).await.unwrap().into_inner();
+        assert_eq!(identity.client_id, Some(back));
+    }
+
+    #[tokio::test]
+    async fn session_tokens_are_renewed_for_registered_clients_only() {
+        let (a, b) = instances();
+        let (client, _) = register(&a, 1).await;
+
+        let renewed = b.renew_session_token(as_client(&client, ())).await.unwrap().into_inner();
+        assert_eq!(renewed.client_id, Some(client.clone()));
+        assert_eq!(a.session_tokens.verify(&renewed.session_token).unwrap(), client);
+
+        a.delist_client(as_client(&client, client.clone())).await.unwrap();
+        assert_eq!(b.renew_session_token(as_client(&client, ())).await.unwrap_err().code(),
+                   tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn events_pushed_on_one_instance_reach_a_session_on_another() {
+        let (a, b) = instances();
+        let (_, seeder) = register(&a, 1).await;
+        let (leecher_id, leecher) = register(&b, 2).await;
+
+        //stands in for the seeder's session on instance a, acknowledging what it is pushed
+        let mut session = a.coordinator.subscribe(&session_topic(&seeder)).await.unwrap();
+        let coordinator = a.coordinator.clone();
+        let received = tokio::spawn(async move {
+            let event = ServerEvent::decode(&session.recv().await.unwrap()[..]).unwrap();
+            coordinator.publish(&ack_topic(event.event_id), Vec::new()).await.unwrap();
+            event.event
+        });
+
+        b.init_punch(as_client(&leecher_id, ConnectionIds {
+            connection_peer: Some(seeder),
+            self_id: Some(leecher),
+        })).await.unwrap();
+        assert_eq!(received.await.unwrap(), Some(server_event::Event::PunchTrigger(leecher)));
+    }
+
+    /// advertises a file made up from n, shared with everyone or with a group
+    async fn advertise(service: &ConnectionService, client_id: &ClientId, n: u8, group: Option<&str>) -> FileHash {
+        let file_hash = FileHash { hash: vec![n; 20] };
+        let mut scope = Scope::default();
+        if let Some(group) = group {
+            scope.set_visibility(Visibility::Group);
+            scope.group = group.to_string();
+        }
+        service.advertise(as_client(client_id, FileMessage {
+            id: Some(client_id.clone()),
+            hash: Some(file_hash.clone()),
+            info_hash: Some(InfoHash { name: format!("file {}", n), ..InfoHash::default() }),
+            scope: Some(scope),
+            bitfield: Vec::new(),
+        })).await.unwrap();
+        file_hash
+    }
+
+    #[tokio::test]
+    async fn files_advertised_on_one_instance_are_found_on_another() {
+        let (a, b) = instances();
+        let (seeder, seeder_peer) = register(&a, 1).await;
+        let (leecher, _) = register(&b, 2).await;
+        let file_hash = advertise(&a, &seeder, 7, None).await;
+
+        let peers = b.get_file_peer_list(as_client(&leecher, file_hash.clone())).await.unwrap().into_inner();
+        assert_eq!(peers.list.iter().map(|peer| peer.id).collect::<Vec<_>>(), vec![Some(seeder_peer)]);
+        let files = b.get_all_files(as_client(&leecher, ())).await.unwrap().into_inner();
+        assert_eq!(files.info_hashes.len(), 1);
+
+        //the seeder's next call lands on the other instance
+        b.delete_file(as_client(&seeder, FileDelete { id: Some(seeder.clone()), hash: Some(file_hash) })).await.unwrap();
+        assert!(a.get_all_files(as_client(&leecher, ())).await.unwrap().into_inner().info_hashes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn groups_made_on_one_instance_scope_files_on_another() {
+        let (a, b) = instances();
+        let (owner, _) = register(&a, 1).await;
+        let (member, _) = register(&b, 2).await;
+        let (outsider, _) = register(&b, 3).await;
+
+        a.create_group(as_client(&owner, GroupName { name: "team".to_string() })).await.unwrap();
+        b.add_group_member(as_client(&owner, GroupMember { group: "team".to_string(), cert_fingerprint: fingerprint(2) })).await.unwrap();
+        let file_hash = advertise(&a, &owner, 7, Some("team")).await;
+
+        assert!(b.get_info_hash(as_client(&member, file_hash.clone())).await.is_ok());
+        assert_eq!(b.get_info_hash(as_client(&outsider, file_hash)).await.unwrap_err().code(), tonic::Code::NotFound);
+        let groups = a.list_groups(as_client(&member, ())).await.unwrap().into_inner().groups;
+        assert_eq!(groups.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pushing_to_a_client_without_a_session_fails() {
+        let (a, b) = instances();
+        let (_, seeder) = register(&a, 1).await;
+        let (leecher_id, leecher) = register(&b, 2).await;
+
+        let status = b.init_punch(as_client(&leecher_id, ConnectionIds {
+            connection_peer: Some(seeder),
+            self_id: Some(leecher),
+        })).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+}
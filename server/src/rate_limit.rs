@@ -0,0 +1,282 @@
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tonic::Status;
+use crate::coordinator::Coordinator;
+
+/// defaults applied when the matching environment variable is not set, 0 disables a limit
+const DEFAULT_SESSION_RATE: u64 = 2 * 1024 * 1024;
+const DEFAULT_SESSION_QUOTA: u64 = 1024 * 1024 * 1024;
+const DEFAULT_CLIENT_RATE: u64 = 4 * 1024 * 1024;
+const DEFAULT_CLIENT_QUOTA: u64 = 4 * 1024 * 1024 * 1024;
+
+/// a client's quota is the bytes it may relay within one window, counted over every instance
+pub const QUOTA_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// bytes a shared budget charges before it adds them to the usage in the coordinator, so the
+/// coordinator is not asked for every packet. An instance can overshoot a quota by this much.
+const QUOTA_FLUSH: u64 = 1024 * 1024;
+
+/// RateLimits holds the byte rates (per second) and total quotas (in bytes) the relay enforces.
+/// None means unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimits {
+    /// TURN_SESSION_RATE
+    pub session_rate: Option<u64>,
+    /// TURN_SESSION_QUOTA
+    pub session_quota: Option<u64>,
+    /// TURN_CLIENT_RATE, shared by every session a client relays through
+    pub client_rate: Option<u64>,
+    /// TURN_CLIENT_QUOTA, per QUOTA_WINDOW
+    pub client_quota: Option<u64>,
+}
+
+impl RateLimits {
+
+    ///from_env()
+    ///
+    /// function:
+    /// Reads the relay limits, falling back to the defaults for anything that is not set.
+    pub fn from_env() -> Result<RateLimits, Box<dyn std::error::Error>> {
+        Ok(RateLimits {
+            session_rate: limit_from_env("TURN_SESSION_RATE", DEFAULT_SESSION_RATE)?,
+            session_quota: limit_from_env("TURN_SESSION_QUOTA", DEFAULT_SESSION_QUOTA)?,
+            client_rate: limit_from_env("TURN_CLIENT_RATE", DEFAULT_CLIENT_RATE)?,
+            client_quota: limit_from_env("TURN_CLIENT_QUOTA", DEFAULT_CLIENT_QUOTA)?,
+        })
+    }
+}
+
+/// limit_from_env (
+///     key: the environment variable to read
+///     default: the limit used if it is not set
+/// )
+/// helper function to read one limit, where 0 means unlimited
+fn limit_from_env(key: &str, default: u64) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let limit = match env::var(key) {
+        Ok(value) => value.parse()?,
+        Err(_) => default,
+    };
+    Ok((limit != 0).then_some(limit))
+}
+
+/// TokenBucket refills at `rate` bytes per second up to one second worth of burst.
+/// Taking more than is available leaves the bucket in debt, which the caller pays off by waiting.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+
+    ///new()
+    /// parameters:
+    ///     - rate: bytes per second let through on average
+    ///
+    /// function:
+    /// Creates a full bucket.
+    pub fn new(rate: u64) -> Self {
+        TokenBucket { rate: rate as f64, capacity: rate as f64, tokens: rate as f64, updated: Instant::now() }
+    }
+
+    ///take()
+    /// parameters:
+    ///     - bytes: the size of the packet passing through
+    ///
+    /// function:
+    /// Takes tokens for a packet and returns how long to hold it so the rate is kept.
+    pub fn take(&mut self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Budget combines a rate limit with a total quota for one session or one client. The rate is
+/// kept by each instance, the usage of a shared budget is counted in the coordinator.
+#[derive(Debug)]
+pub struct Budget {
+    /// what this budget belongs to, used in error messages
+    name: &'static str,
+    bucket: Option<Mutex<TokenBucket>>,
+    quota: Option<u64>,
+    /// bytes charged, for a shared budget those not added to the coordinator yet
+    used: AtomicU64,
+    last_used: Mutex<Instant>,
+    shared: Option<SharedUsage>,
+}
+
+/// where a shared budget's usage is counted
+#[derive(Debug)]
+struct SharedUsage {
+    /// names the budget's counters, one per QUOTA_WINDOW
+    key: String,
+    coordinator: Arc<dyn Coordinator>,
+    /// the window and the usage in it the coordinator returned last
+    known: Mutex<(u64, u64)>,
+}
+
+impl Budget {
+
+    ///new()
+    /// parameters:
+    ///     - name: what the budget belongs to, "session" or "client"
+    ///     - rate: bytes per second, None for no rate limit
+    ///     - quota: total bytes, None for no quota
+    ///
+    /// function:
+    /// Creates an unused budget.
+    pub fn new(name: &'static str, rate: Option<u64>, quota: Option<u64>) -> Self {
+        Budget {
+            name,
+            bucket: rate.map(|rate| Mutex::new(TokenBucket::new(rate))),
+            quota,
+            used: AtomicU64::new(0),
+            last_used: Mutex::new(Instant::now()),
+            shared: None,
+        }
+    }
+
+    ///shared()
+    /// parameters:
+    ///     - name: what the budget belongs to
+    ///     - rate: bytes per second on this instance, None for no rate limit
+    ///     - quota: bytes per QUOTA_WINDOW over every instance, None for no quota
+    ///     - key: names the budget in the coordinator, the same on every instance
+    ///     - coordinator: the backend shared with the other instances
+    ///
+    /// function:
+    /// Creates a budget whose usage every instance charging the same key adds up.
+    pub fn shared(name: &'static str, rate: Option<u64>, quota: Option<u64>, key: String, coordinator: Arc<dyn Coordinator>) -> Self {
+        Budget {
+            shared: Some(SharedUsage { key, coordinator, known: Mutex::new((0, 0)) }),
+            ..Budget::new(name, rate, quota)
+        }
+    }
+
+    ///charge()
+    /// parameters:
+    ///     - bytes: the size of the packet passing through
+    ///
+    /// function:
+    /// Charges a packet against the budget. Returns how long the packet has to be held to keep
+    /// the rate, or resource_exhausted once the quota is used up.
+    pub async fn charge(&self, bytes: u64) -> Result<Duration, Status> {
+        *self.last_used.lock().unwrap() = Instant::now();
+
+        let mut used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if let Some(shared) = &self.shared {
+            let window = unix_now() / QUOTA_WINDOW.as_secs();
+            if used >= QUOTA_FLUSH {
+                let flushed = self.used.swap(0, Ordering::Relaxed);
+                let key = format!("quota:{}:{}", shared.key, window);
+                let total = shared.coordinator.add(&key, flushed, QUOTA_WINDOW).await?;
+                *shared.known.lock().unwrap() = (window, total);
+            }
+            let (known_window, known) = *shared.known.lock().unwrap();
+            used = self.used.load(Ordering::Relaxed) + if known_window == window { known } else { 0 };
+        }
+
+        if let Some(quota) = self.quota {
+            if used > quota {
+                return Err(Status::resource_exhausted(format!("relay {} quota of {} bytes used up", self.name, quota)));
+            }
+        }
+
+        match &self.bucket {
+            Some(bucket) => Ok(bucket.lock().unwrap().take(bytes)),
+            None => Ok(Duration::ZERO),
+        }
+    }
+
+    ///idle_for()
+    ///
+    /// function:
+    /// Returns how long ago the budget was last charged.
+    pub fn idle_for(&self) -> Duration {
+        self.last_used.lock().unwrap().elapsed()
+    }
+}
+
+/// unix_now ()
+/// helper function for the current unix time in seconds, quota windows are counted from the epoch
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinator::InMemoryCoordinator;
+
+    #[test]
+    fn bucket_lets_a_burst_through_and_holds_what_goes_over() {
+        let mut bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(600), Duration::ZERO);
+
+        //400 tokens left, 1000 more puts the bucket 600 in debt, which takes 0.6s to pay off
+        let delay = bucket.take(1000);
+        assert!(delay > Duration::from_millis(550) && delay <= Duration::from_millis(600), "{:?}", delay);
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate_up_to_its_capacity() {
+        let mut bucket = TokenBucket::new(1000);
+        bucket.take(1000);
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(bucket.take(250), Duration::ZERO);
+
+        std::thread::sleep(Duration::from_millis(1200));
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        assert!(bucket.take(100) > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn budget_is_exhausted_past_its_quota() {
+        let budget = Budget::new("session", None, Some(1000));
+        assert_eq!(budget.charge(600).await.unwrap(), Duration::ZERO);
+        assert_eq!(budget.charge(400).await.unwrap(), Duration::ZERO);
+        assert_eq!(budget.charge(1).await.unwrap_err().code(), tonic::Code::ResourceExhausted);
+
+        let unlimited = Budget::new("session", None, None);
+        assert!(unlimited.charge(u32::MAX as u64).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn budget_holds_packets_to_its_rate() {
+        let budget = Budget::new("client", Some(1000), None);
+        assert_eq!(budget.charge(1000).await.unwrap(), Duration::ZERO);
+        assert!(budget.charge(500).await.unwrap() > Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn shared_budgets_add_up_the_usage_of_every_instance() {
+        let coordinator: Arc<dyn Coordinator> = Arc::new(InMemoryCoordinator::default());
+        let quota = Some(3 * QUOTA_FLUSH);
+        let first = Budget::shared("client", None, quota, "client-1".to_string(), coordinator.clone());
+        let second = Budget::shared("client", None, quota, "client-1".to_string(), coordinator.clone());
+        let other = Budget::shared("client", None, quota, "client-2".to_string(), coordinator.clone());
+
+        first.charge(QUOTA_FLUSH).await.unwrap();
+        second.charge(QUOTA_FLUSH).await.unwrap();
+        first.charge(QUOTA_FLUSH).await.unwrap();
+        assert_eq!(second.charge(QUOTA_FLUSH).await.unwrap_err().code(), tonic::Code::ResourceExhausted);
+        other.charge(QUOTA_FLUSH).await.unwrap();
+
+        //an instance that forgot the client, or never saw it, still knows what it used
+        let fresh = Budget::shared("client", None, quota, "client-1".to_string(), coordinator);
+        assert!(fresh.charge(QUOTA_FLUSH).await.is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use tonic::Status;
+use uuid::Uuid;
+use crate::turn::Role;
+
+/// how long a peer has to open its relay session with a token. A session that is already open can
+/// be resumed with its token for as long as it lives.
+const RELAY_TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// RelayTokens issues and checks the tokens that admit a peer to one side of a TURN session.
+/// A token names the session and the role in it and is signed, so neither can be guessed or changed.
+#[derive(Debug)]
+pub struct RelayTokens {
+    /// key tokens are signed with, from TURN_TOKEN_SECRET so several instances can share it,
+    /// otherwise regenerated every run
+    secret: Vec<u8>,
+}
+
+/// what a valid token grants
+#[derive(Debug, Clone)]
+pub struct RelayClaims {
+    pub session_id: String,
+    pub role: Role,
+    /// whether the token is too old to open a new session
+    pub expired: bool,
+}
+
+impl Default for RelayTokens {
+    fn default() -> Self {
+        RelayTokens { secret: rand::random::<[u8; 32]>().to_vec() }
+    }
+}
+
+impl RelayTokens {
+
+    ///from_env()
+    ///
+    /// function:
+    /// Reads the token secret, generating one if TURN_TOKEN_SECRET is not set.
+    pub fn from_env() -> Result<RelayTokens, Box<dyn std::error::Error>> {
+        let secret = match env::var("TURN_TOKEN_SECRET") {
+            Ok(secret) => hex::decode(secret)?,
+            Err(_) => return Ok(RelayTokens::default()),
+        };
+        Ok(RelayTokens { secret })
+    }
+
+    ///issue()
+    ///
+    /// function:
+    /// Creates a new relay session and returns its id along with the seeder's and the leecher's token.
+    pub fn issue(&self) -> (String, String, String) {
+        let session_id = Uuid::new_v4().simple().to_string();
+        let expires = unix_now() + RELAY_TOKEN_LIFETIME.as_secs();
+        let seeder = self.sign_claims(&session_id, Role::Seeder, expires);
+        let leecher = self.sign_claims(&session_id, Role::Leecher, expires);
+        (session_id, seeder, leecher)
+    }
+
+    ///verify()
+    /// parameters:
+    ///     - token: the token a peer presented to the relay
+    ///
+    /// function:
+    /// Checks the token's signature and returns what it grants.
+    #[allow(clippy::result_large_err)]
+    pub fn verify(&self, token: &str) -> Result<RelayClaims, Status> {
+        let invalid = || Status::unauthenticated("invalid relay token");
+
+        let (claims, mac) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let mac = hex::decode(mac).map_err(|_| invalid())?;
+        let mut expected = Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        expected.update(claims.as_bytes());
+        expected.verify_slice(&mac).map_err(|_| invalid())?;
+
+        let mut parts = claims.split('.');
+        let (Some(session_id), Some(role), Some(expires), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let role = Role::parse(role).ok_or_else(invalid)?;
+        let expires: u64 = expires.parse().map_err(|_| invalid())?;
+
+        Ok(RelayClaims { session_id: session_id.to_string(), role, expired: expires < unix_now() })
+    }
+
+    ///sign_claims()
+    /// parameters:
+    ///     - session_id: the session the token admits to
+    ///     - role: the side of the session it admits to
+    ///     - expires: unix time after which it can no longer open the session
+    ///
+    /// function:
+    /// Builds a token of the form session.role.expires.signature.
+    fn sign_claims(&self, session_id: &str, role: Role, expires: u64) -> String {
+        let claims = format!("{}.{}.{}", session_id, role.as_str(), expires);
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(claims.as_bytes());
+        format!("{}.{}", claims, hex::encode(mac.finalize().into_bytes()))
+    }
+}
+
+/// unix_now ()
+/// helper function for the current unix time in seconds
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issued_tokens_admit_their_own_side() {
+        let tokens = RelayTokens::default();
+        let (session_id, seeder, leecher) = tokens.issue();
+
+        let claims = tokens.verify(&seeder).unwrap();
+        assert_eq!(claims.session_id, session_id);
+        assert_eq!(claims.role, Role::Seeder);
+        assert!(!claims.expired);
+        assert_eq!(tokens.verify(&leecher).unwrap().role, Role::Leecher);
+    }
+
+    #[test]
+    fn expired_tokens_are_flagged() {
+        let tokens = RelayTokens::default();
+        let token = tokens.sign_claims("session", Role::Seeder, unix_now() - 1);
+
+        assert!(tokens.verify(&token).unwrap().expired);
+    }
+
+    #[test]
+    fn forged_tokens_are_rejected() {
+        let tokens = RelayTokens::default();
+        let (_, seeder, _) = tokens.issue();
+
+        //the role changed without signing it again
+        let forged = seeder.replacen(".seeder.", ".leecher.", 1);
+        assert!(tokens.verify(&forged).is_err());
+        //a later expiry without signing it again
+        let (claims, mac) = seeder.rsplit_once('.').unwrap();
+        let (prefix, _) = claims.rsplit_once('.').unwrap();
+        assert!(tokens.verify(&format!("{}.{}.{}", prefix, u64::MAX, mac)).is_err());
+        //signed by another instance's secret
+        assert!(RelayTokens::default().verify(&seeder).is_err());
+        assert!(tokens.verify("not a token").is_err());
+        assert!(tokens.verify("session.seeder.1.zz").is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use std::sync::Arc;
+use std::time::Duration;
+use prost::Message;
+use tonic::Status;
+use crate::auth::unix_now;
+use crate::connection::connection::{Announce, AnnounceEvent, ClientId, FileHash};
+use crate::coordinator::Coordinator;
+
+/// a client that has not announced for this long is no longer counted as a leecher
+const PEER_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// what a client announced is kept this long after it went quiet, so totals it announces when it
+/// comes back are not counted twice
+const BASELINE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// the hash listing every file with a swarm, so a delisted client can be removed from all of them
+const SWARMS: &str = "swarms";
+
+/// what a client last announced for a file
+#[derive(Clone, PartialEq, Message)]
+struct Progress {
+    #[prost(uint64, tag = "1")]
+    downloaded: u64,
+    #[prost(uint64, tag = "2")]
+    uploaded: u64,
+    #[prost(uint64, tag = "3")]
+    left: u64,
+    /// the client stopped the download, it is no longer a leecher until it starts again
+    #[prost(bool, tag = "4")]
+    stopped: bool,
+    /// unix time of the announce
+    #[prost(uint64, tag = "5")]
+    last_seen: u64,
+}
+
+/// the counts get_file_stats and the catalog report for a file
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SwarmStats {
+    pub leechers: u32,
+    pub completed: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+/// Swarms keeps the announces of every file in the coordinator. A file's swarm is a hash of what
+/// each client last announced and a hash of its totals: downloads announced as completed and the
+/// bytes moved, summed over every client.
+#[derive(Debug)]
+pub struct Swarms {
+    coordinator: Arc<dyn Coordinator>,
+}
+
+impl Swarms {
+
+    ///new()
+    /// parameters:
+    ///     - coordinator: where the swarms are kept
+    ///
+    /// function:
+    /// Creates the swarms of every instance sharing the coordinator.
+    pub fn new(coordinator: Arc<dyn Coordinator>) -> Swarms {
+        Swarms { coordinator }
+    }
+
+    ///announce()
+    /// parameters:
+    ///     - file_hash: the file announced
+    ///     - client_id: the client announcing
+    ///     - announce: the client's totals and what happened
+    ///
+    /// function:
+    /// Records an announce. Clients report totals since they started, so only what changed since
+    /// their previous announce is added to the file's byte counts, which is why a stopped client
+    /// is remembered too. A download is counted as completed only by a client that was still
+    /// missing part of the file, so repeating the event does not count it again.
+    pub async fn announce(&self, file_hash: &FileHash, client_id: &ClientId, announce: &Announce) -> Result<(), Status> {
+        let now = unix_now();
+        let previous = self.coordinator.hash_get(&peers_key(file_hash), &client_id.uid).await?
+            .and_then(|payload| Progress::decode(&payload[..]).ok())
+            .filter(|previous| now.saturating_sub(previous.last_seen) < BASELINE_TIMEOUT.as_secs());
+
+        let (downloaded, uploaded) = previous.as_ref()
+            .map(|previous| (previous.downloaded, previous.uploaded))
+            .unwrap_or_default();
+        let was_leeching = previous.is_some_and(|previous| previous.left > 0);
+
+        let totals = totals_key(file_hash);
+        self.coordinator.hash_set(SWARMS, &hex::encode(&file_hash.hash), Vec::new()).await?;
+        self.coordinator.hash_add(&totals, "downloaded", announce.downloaded.saturating_sub(downloaded)).await?;
+        self.coordinator.hash_add(&totals, "uploaded", announce.uploaded.saturating_sub(uploaded)).await?;
+
+        let mut left = announce.left;
+        match announce.event() {
+            AnnounceEvent::Completed => {
+                if was_leeching {
+                    self.coordinator.hash_add(&totals, "completed", 1).await?;
+                }
+                left = 0;
+            }
+            AnnounceEvent::Started | AnnounceEvent::Progress | AnnounceEvent::Stopped => {}
+        }
+        let progress = Progress {
+            downloaded: announce.downloaded,
+            uploaded: announce.uploaded,
+            left,
+            stopped: announce.event() == AnnounceEvent::Stopped,
+            last_seen: now,
+        };
+        self.coordinator.hash_set(&peers_key(file_hash), &client_id.uid, progress.encode_to_vec()).await
+    }
+
+    ///stats()
+    /// parameters:
+    ///     - file_hash: the file
+    ///
+    /// function:
+    /// Returns the counts of a file, leechers are the clients still missing part of it that
+    /// announced recently. Clients that went quiet for BASELINE_TIMEOUT are forgotten on the way.
+    pub async fn stats(&self, file_hash: &FileHash) -> Result<SwarmStats, Status> {
+        let now = unix_now();
+        let mut leechers = 0;
+        for (uid, payload) in self.coordinator.hash_entries(&peers_key(file_hash)).await? {
+            let Ok(progress) = Progress::decode(&payload[..]) else { continue };
+            let quiet = now.saturating_sub(progress.last_seen);
+            if quiet >= BASELINE_TIMEOUT.as_secs() {
+                self.coordinator.hash_delete(&peers_key(file_hash), &uid).await?;
+            } else if progress.left > 0 && !progress.stopped && quiet < PEER_TIMEOUT.as_secs() {
+                leechers += 1;
+            }
+        }
+
+        let mut stats = SwarmStats { leechers, ..SwarmStats::default() };
+        for (total, payload) in self.coordinator.hash_entries(&totals_key(file_hash)).await? {
+            let value = String::from_utf8_lossy(&payload).parse().unwrap_or(0);
+            match total.as_str() {
+                "completed" => stats.completed = value,
+                "downloaded" => stats.downloaded = value,
+                "uploaded" => stats.uploaded = value,
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+
+    ///forget()
+    /// parameters:
+    ///     - client_id: a client that delisted itself
+    ///
+    /// function:
+    /// Removes the client from every swarm, the bytes it moved stay counted.
+    pub async fn forget(&self, client_id: &ClientId) -> Result<(), Status> {
+        for (file, _) in self.coordinator.hash_entries(SWARMS).await? {
+            let Ok(hash) = hex::decode(file) else { continue };
+            self.coordinator.hash_delete(&peers_key(&FileHash { hash }), &client_id.uid).await?;
+        }
+        Ok(())
+    }
+
+    ///remove()
+    /// parameters:
+    ///     - file_hash: a file the tracker no longer lists
+    ///
+    /// function:
+    /// Drops everything recorded about the file.
+    pub async fn remove(&self, file_hash: &FileHash) -> Result<(), Status> {
+        self.coordinator.hash_delete(SWARMS, &hex::encode(&file_hash.hash)).await?;
+        self.coordinator.delete(&peers_key(file_hash)).await?;
+        self.coordinator.delete(&totals_key(file_hash)).await
+    }
+}
+
+/// peers_key (
+///     file_hash: a file
+/// )
+/// helper function for the hash of what each client of the file last announced
+fn peers_key(file_hash: &FileHash) -> String {
+    format!("swarm:{}", hex::encode(&file_hash.hash))
+}
+
+/// totals_key (
+///     file_hash: a file
+/// )
+/// helper function for the hash of the file's completed downloads and bytes moved
+fn totals_key(file_hash: &FileHash) -> String {
+    format!("swarm-totals:{}", hex::encode(&file_hash.hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinator::InMemoryCoordinator;
+
+    fn file() -> FileHash {
+        FileHash { hash: vec![1; 20] }
+    }
+
+    fn client(n: u8) -> ClientId {
+        ClientId { uid: n.to_string() }
+    }
+
+    fn announce(event: AnnounceEvent, downloaded: u64, left: u64) -> Announce {
+        let mut announce = Announce { downloaded, left, ..Announce::default() };
+        announce.set_event(event);
+        announce
+    }
+
+    #[tokio::test]
+    async fn completions_count_only_for_clients_that_were_leeching() {
+        let swarms = Swarms::new(Arc::new(InMemoryCoordinator::default()));
+        swarms.announce(&file(), &client(1), &announce(AnnounceEvent::Started, 0, 100)).await.unwrap();
+        assert_eq!(swarms.stats(&file()).await.unwrap().leechers, 1);
+
+        swarms.announce(&file(), &client(1), &announce(AnnounceEvent::Completed, 100, 0)).await.unwrap();
+        //the same event again, and a client that never announced it was missing anything
+        swarms.announce(&file(), &client(1), &announce(AnnounceEvent::Completed, 100, 0)).await.unwrap();
+        swarms.announce(&file(), &client(2), &announce(AnnounceEvent::Completed, 0, 0)).await.unwrap();
+
+        let stats = swarms.stats(&file()).await.unwrap();
+        assert_eq!(stats.completed, 1);
+        assert_eq!(stats.leechers, 0);
+    }
+
+    #[tokio::test]
+    async fn bytes_are_counted_once_across_a_stop() {
+        let swarms = Swarms::new(Arc::new(InMemoryCoordinator::default()));
+        swarms.announce(&file(), &client(1), &announce(AnnounceEvent::Started, 0, 100)).await.unwrap();
+        swarms.announce(&file(), &client(1), &announce(AnnounceEvent::Progress, 40, 60)).await.unwrap();
+        swarms.announce(&file(), &client(1), &announce(AnnounceEvent::Stopped, 50, 50)).await.unwrap();
+        assert_eq!(swarms.stats(&file()).await.unwrap().leechers, 0);
+
+        //totals are since the client started, resuming the download does not add them again
+        swarms.announce(&file(), &client(1), &announce(AnnounceEvent::Started, 50, 50)).await.unwrap();
+        swarms.announce(&file(), &client(1), &announce(AnnounceEvent::Completed, 100, 0)).await.unwrap();
+
+        let stats = swarms.stats(&file()).await.unwrap();
+        assert_eq!(stats.downloaded, 100);
+        assert_eq!(stats.completed, 1);
+    }
+
+    #[tokio::test]
+    async fn forgotten_clients_keep_their_bytes_counted() {
+        let swarms = Swarms::new(Arc::new(InMemoryCoordinator::default()));
+        swarms.announce(&file(), &client(1), &announce(AnnounceEvent::Started, 30, 100)).await.unwrap();
+        swarms.forget(&client(1)).await.unwrap();
+
+        let stats = swarms.stats(&file()).await.unwrap();
+        assert_eq!(stats.leechers, 0);
+        assert_eq!(stats.downloaded, 30);
+
+        swarms.remove(&file()).await.unwrap();
+        assert_eq!(swarms.stats(&file()).await.unwrap().downloaded, 0);
+    }
+}
@@ -1,133 +1,806 @@
 use crate::connection::connection::*;
-use crate::connection::connection::turn_packet::Body;
 use crate::turn_server::Turn;
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::{sync::{mpsc, RwLock, Barrier}};
+use crate::coordinator::{Coordinator, Subscription};
+use crate::rate_limit::{Budget, RateLimits};
+use crate::relay_token::{RelayClaims, RelayTokens};
+use crate::auth::{authenticated, secret_matches};
+use dashmap::DashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use prost::Message;
+use tokio::{sync::{mpsc, watch, RwLock, Semaphore}};
+use tokio::time::{interval, sleep, timeout};
 use tokio_stream::{StreamExt, wrappers::ReceiverStream};
 use tonic::{async_trait, Request, Response, Status, metadata::MetadataMap};
 
+/// how long the first side of a session waits for the other one to register
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// how long a side that dropped out has to register again before the session is torn down
+const RESUME_GRACE: Duration = Duration::from_secs(15);
+
+/// how long a client has to stay off the relay before this instance drops its budget. Its rate starts
+/// over, what it used of its quota is kept in the coordinator until the quota window ends.
+const CLIENT_BUDGET_RESET: Duration = Duration::from_secs(60 * 60);
+
+/// bytes that may be on their way to one side before the other side's sends have to wait
+const FORWARD_CREDITS: usize = 4 * 1024 * 1024;
+
+/// packets waiting in a lane, on top of the credit limit so tiny packets cannot pile up
+const LANE_DEPTH: usize = 1024;
+
+/// packets buffered in a side's register stream while gRPC writes them out
+const SIDE_DEPTH: usize = 16;
+
+/// how long a lane waits for a packet to be acknowledged before it counts it as lost with the
+/// stream it was handed to and takes its credits back
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// a side's presence entry expires this long after the instance holding it stops refreshing it
+const PRESENCE_TTL: Duration = Duration::from_secs(60);
+const PRESENCE_REFRESH: Duration = Duration::from_secs(20);
+
 /// role within the turn service session
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Role {
     Seeder,
     Leecher,
 }
 
-/// represents a session between a leecher and seeder in the turn system
+impl Role {
+
+    /// other ()
+    /// returns the role on the other end of the session
+    pub fn other(self) -> Role {
+        match self {
+            Role::Seeder => Role::Leecher,
+            Role::Leecher => Role::Seeder,
+        }
+    }
+
+    /// as_str ()
+    /// returns the name of the role used in tokens, topics and events
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Seeder => "seeder",
+            Role::Leecher => "leecher",
+        }
+    }
+
+    /// parse (
+    ///     name: a name returned by as_str
+    /// )
+    /// returns the role with that name
+    pub fn parse(name: &str) -> Option<Role> {
+        match name {
+            "seeder" => Some(Role::Seeder),
+            "leecher" => Some(Role::Leecher),
+            _ => None,
+        }
+    }
+
+    /// index ()
+    /// returns where the role is kept in per-side arrays
+    fn index(self) -> usize {
+        match self {
+            Role::Seeder => 0,
+            Role::Leecher => 1,
+        }
+    }
+}
+
+type SideSender = mpsc::Sender<Result<TurnPacket, Status>>;
+
+type Sessions = Arc<RwLock<HashMap<String, Arc<Session>>>>;
+
+/// what the instances holding a session tell each other about it through the coordinator
+enum SessionEvent {
+    /// a side registered, on any instance
+    Joined(Role),
+    /// a side's stream ended, it has RESUME_GRACE to register again
+    Left(Role),
+    /// the session's quota is used up
+    Exhausted(String),
+}
+
+impl SessionEvent {
+
+    /// encode ()
+    /// returns the event as published on the session's events topic
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            SessionEvent::Joined(role) => format!("joined {}", role.as_str()),
+            SessionEvent::Left(role) => format!("left {}", role.as_str()),
+            SessionEvent::Exhausted(message) => format!("exhausted {}", message),
+        }.into_bytes()
+    }
+
+    /// decode (
+    ///     payload: an event as published by encode
+    /// )
+    /// returns the event, None if it is malformed
+    fn decode(payload: &[u8]) -> Option<SessionEvent> {
+        let (kind, rest) = std::str::from_utf8(payload).ok()?.split_once(' ')?;
+        match kind {
+            "joined" => Some(SessionEvent::Joined(Role::parse(rest)?)),
+            "left" => Some(SessionEvent::Left(Role::parse(rest)?)),
+            "exhausted" => Some(SessionEvent::Exhausted(rest.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// side_topic (
+///     session_id: the session
+///     role: the side the packets are for
+/// )
+/// helper function for the topic a side's packets are published on
+fn side_topic(session_id: &str, role: Role) -> String {
+    format!("relay:{}:to:{}", session_id, role.as_str())
+}
+
+/// ack_topic (
+///     session_id: the session
+///     lane: the lane that published the packets
+/// )
+/// helper function for the topic the receiving instance acknowledges a lane's packets on
+fn ack_topic(session_id: &str, lane: &str) -> String {
+    format!("relay:{}:ack:{}", session_id, lane)
+}
+
+/// events_topic (
+///     session_id: the session
+/// )
+/// helper function for the topic the session's events are published on
+fn events_topic(session_id: &str) -> String {
+    format!("relay:{}:events", session_id)
+}
+
+/// presence_key (
+///     session_id: the session
+///     role: the side
+/// )
+/// helper function for the entry that exists while a side is registered on any instance
+fn presence_key(session_id: &str, role: Role) -> String {
+    format!("relay:{}:present:{}", session_id, role.as_str())
+}
+
+/// represents a session between a leecher and seeder in the turn system, as seen from this instance.
+/// Either side may be registered here or on another instance, packets always travel through the
+/// coordinator so it does not matter which. It is shared with the send streams of this instance, so
+/// relaying a packet never touches the map of all sessions.
 pub struct Session {
-    seeder:  Option<mpsc::Sender<Result<TurnPacket, Status>>>,
-    leecher: Option<mpsc::Sender<Result<TurnPacket, Status>>>,
-    barrier: Arc<Barrier>,
+    session_id: String,
+    coordinator: Arc<dyn Coordinator>,
+    /// the sides registered on this instance, replaced when a side resumes
+    seeder:  watch::Sender<Option<SideSender>>,
+    leecher: watch::Sender<Option<SideSender>>,
+    /// whether the seeder and the leecher are registered on any instance, registrations wait for both
+    present: watch::Sender<[bool; 2]>,
+    created: Instant,
+    /// when a side dropped out, None while both are present
+    detached_since: std::sync::Mutex<Option<Instant>>,
+    /// set once the session is torn down, its send streams stop with it
+    ended: AtomicBool,
+    to_seeder: Lane,
+    to_leecher: Lane,
+    stats: Arc<SessionStats>,
+    /// rate limit and quota shared by both directions of the session
+    budget: Budget,
+    /// dropped along with the session, which stops the task following its events
+    _alive: watch::Sender<()>,
+}
+
+/// counters updated on every relayed packet
+#[derive(Default)]
+pub struct SessionStats {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    /// packets that could not be delivered because their side was gone
+    dropped: AtomicU64,
+    /// milliseconds after the session was created that a packet was last relayed
+    last_activity_ms: AtomicU64,
+}
+
+/// Lane carries the packets of one direction to the task that publishes them. A sender has to take
+/// credits for every byte it queues and the task hands them back once the instance holding the
+/// receiving side acknowledges that its stream took the packet, so a slow side only ever holds up
+/// the peer it is talking to.
+struct Lane {
+    queue: mpsc::Sender<Queued>,
+    credits: Arc<Semaphore>,
+}
+
+/// a packet waiting in a lane with the credits it holds
+struct Queued {
+    pkt: TurnPacket,
+    credits: u32,
+}
+
+/// a packet as published on a side's topic
+#[derive(Clone, PartialEq, Message)]
+struct Relayed {
+    /// the lane that published it, empty for notices from the relay which are not acknowledged
+    #[prost(string, tag = "1")]
+    lane: String,
+    /// numbers the packets of a lane, an acknowledgement covers every packet up to it
+    #[prost(uint64, tag = "2")]
+    seq: u64,
+    #[prost(message, optional, tag = "3")]
+    pkt: Option<TurnPacket>,
+}
+
+/// where a lane publishes and who it answers to
+struct LaneTopics {
+    /// what the receiving instance acknowledges the lane's packets with
+    id: String,
+    /// where the lane's packets are published
+    to: String,
+    /// where the sending side is told its packets reached no one
+    from: String,
+}
+
+impl Lane {
+
+    /// open (
+    ///     session_id: the session the lane belongs to
+    ///     to: the side its packets are for
+    ///     coordinator: what they are published through
+    ///     stats: counters of the session
+    /// )
+    /// creates a lane and spawns the task that delivers its packets
+    async fn open(session_id: &str, to: Role, coordinator: Arc<dyn Coordinator>, stats: Arc<SessionStats>) -> Result<Self, Status> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let acks = coordinator.subscribe(&ack_topic(session_id, &id)).await?;
+        let topics = LaneTopics {
+            id,
+            to: side_topic(session_id, to),
+            from: side_topic(session_id, to.other()),
+        };
+        let (queue, rx) = mpsc::channel(LANE_DEPTH);
+        let credits = Arc::new(Semaphore::new(FORWARD_CREDITS));
+        tokio::spawn(deliver(rx, acks, topics, coordinator, credits.clone(), stats));
+        Ok(Lane { queue, credits })
+    }
+
+    /// push (
+    ///     pkt: the packet to relay
+    /// )
+    /// waits for enough credits and queues the packet for delivery
+    #[allow(clippy::result_large_err)]
+    async fn push(&self, pkt: TurnPacket) -> Result<(), Status> {
+        let credits = pkt.encoded_len().clamp(1, FORWARD_CREDITS) as u32;
+        self.credits.acquire_many(credits).await
+            .map_err(|_| Status::unavailable("Relay session ended"))?
+            .forget();
+        self.queue.send(Queued { pkt, credits }).await
+            .map_err(|_| Status::unavailable("Relay session ended"))
+    }
+}
+
+/// deliver (
+///     queue: the packets of one lane
+///     acks: what the receiving instance acknowledges them with
+///     topics: where they are published and who sent them
+///     coordinator: what they are published through
+///     credits: the lane's credits, returned as packets are acknowledged
+///     stats: counters of the session
+/// )
+/// delivers the packets of one lane in order until the session is dropped. Packets for a side
+/// that is resuming reach no one and are dropped, the sender is told once for each run of them and
+/// the peers recover them end to end with a new handshake. Packets that are never acknowledged
+/// give their credits back after ACK_TIMEOUT.
+async fn deliver(
+    mut queue: mpsc::Receiver<Queued>,
+    mut acks: Subscription,
+    topics: LaneTopics,
+    coordinator: Arc<dyn Coordinator>,
+    credits: Arc<Semaphore>,
+    stats: Arc<SessionStats>,
+) {
+    // the packets handed to a side's instance, with their credits and when they were published
+    let mut in_flight: BTreeMap<u64, (u32, Instant)> = BTreeMap::new();
+    let mut seq = 0;
+    let mut dropping = false;
+    let mut sweep = interval(ACK_TIMEOUT);
+    loop {
+        tokio::select! {
+            queued = queue.recv() => {
+                let Some(Queued { pkt, credits: held }) = queued else { return };
+                seq += 1;
+                let relayed = Relayed { lane: topics.id.clone(), seq, pkt: Some(pkt) };
+                if matches!(coordinator.publish(&topics.to, relayed.encode_to_vec()).await, Ok(reached) if reached > 0) {
+                    in_flight.insert(seq, (held, Instant::now()));
+                    dropping = false;
+                    continue;
+                }
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+                credits.add_permits(held as usize);
+                if !dropping {
+                    dropping = true;
+                    let notice = Relayed { pkt: Some(TurnPacket { body: Some(turn_packet::Body::Undelivered(true)), ..Default::default() }), ..Default::default() };
+                    let _ = coordinator.publish(&topics.from, notice.encode_to_vec()).await;
+                }
+            }
+            Some(ack) = acks.recv() => {
+                let Some(acked) = std::str::from_utf8(&ack).ok().and_then(|ack| ack.parse::<u64>().ok()) else { continue };
+                let rest = in_flight.split_off(&(acked + 1));
+                let held: u32 = std::mem::replace(&mut in_flight, rest).into_values().map(|(held, _)| held).sum();
+                credits.add_permits(held as usize);
+            }
+            _ = sweep.tick() => {
+                let lost: Vec<u64> = in_flight.iter()
+                    .filter(|(_, (_, sent))| sent.elapsed() >= ACK_TIMEOUT)
+                    .map(|(seq, _)| *seq)
+                    .collect();
+                for seq in lost {
+                    if let Some((held, _)) = in_flight.remove(&seq) {
+                        credits.add_permits(held as usize);
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl Session {
 
-    /// new (
-    ///     barrier: used to synchronize Seeder and Leecher
+    /// open (
+    ///     session_id: the session
+    ///     coordinator: what the session's packets and events travel through
+    ///     limits: the rate and quota applied to the session
+    ///     sessions: every session of this instance, for tearing this one down
     /// )
-    /// creates a new Session for the turn service, with a barrier used to sync up the seeder and leecher
-    pub fn new(barrier: Arc<Barrier>) -> Self {
-        Session { seeder: None, leecher: None, barrier }
+    /// creates this instance's view of a session along with its delivery tasks, and starts
+    /// following what the other instances say about it
+    async fn open(
+        session_id: String,
+        coordinator: Arc<dyn Coordinator>,
+        limits: &RateLimits,
+        sessions: Sessions,
+    ) -> Result<Arc<Self>, Status> {
+        let events = coordinator.subscribe(&events_topic(&session_id)).await?;
+        let stats = Arc::new(SessionStats::default());
+        let (alive, alive_rx) = watch::channel(());
+
+        let session = Arc::new(Session {
+            to_seeder: Lane::open(&session_id, Role::Seeder, coordinator.clone(), stats.clone()).await?,
+            to_leecher: Lane::open(&session_id, Role::Leecher, coordinator.clone(), stats.clone()).await?,
+            session_id,
+            coordinator,
+            seeder: watch::Sender::new(None),
+            leecher: watch::Sender::new(None),
+            present: watch::Sender::new([false; 2]),
+            created: Instant::now(),
+            detached_since: std::sync::Mutex::new(None),
+            ended: AtomicBool::new(false),
+            stats,
+            budget: Budget::new("session", limits.session_rate, limits.session_quota),
+            _alive: alive,
+        });
+        tokio::spawn(follow_events(Arc::downgrade(&session), sessions, events, alive_rx));
+        Ok(session)
     }
 
-    /// add_seeder (
-    ///     tx: Sender we use to relay to the seeder
+    /// slot (
+    ///     role: which side of the session
     /// )
-    /// register a seeder with the Session, error if already has one
-    pub fn add_seeder(&mut self, tx: mpsc::Sender<Result<TurnPacket, Status>>) -> Result<(), Status> {
-        println!("add_seeder called");
-        if self.seeder.is_some() {
-            return Err(Status::resource_exhausted("Seeder already registered"));
+    /// returns where that side is kept when it is registered on this instance
+    fn slot(&self, role: Role) -> &watch::Sender<Option<SideSender>> {
+        match role {
+            Role::Seeder => &self.seeder,
+            Role::Leecher => &self.leecher,
         }
-        self.seeder = Some(tx);
-        Ok(())
     }
 
-    /// add_leecher (
-    ///     tx: Sender we use to relay to the leecher
+    /// lane (
+    ///     to: the side the packets are for
     /// )
-    /// register a leecher with the Session, error if already has one
-    pub fn add_leecher(&mut self, tx: mpsc::Sender<Result<TurnPacket, Status>>) -> Result<(), Status> {
-        println!("add_leecher called");
-        if self.leecher.is_some() {
-            return Err(Status::resource_exhausted("Leecher already registered"));
+    /// returns the lane that carries packets to that side
+    fn lane(&self, to: Role) -> &Lane {
+        match to {
+            Role::Seeder => &self.to_seeder,
+            Role::Leecher => &self.to_leecher,
         }
-        self.leecher = Some(tx);
+    }
+
+    /// side (
+    ///     role: which side of the session
+    /// )
+    /// returns the stream of that side, if it is registered on this instance
+    pub fn side(&self, role: Role) -> Option<SideSender> {
+        self.slot(role).borrow().clone()
+    }
+
+    /// has_local_sides ()
+    /// returns whether either side is registered on this instance
+    fn has_local_sides(&self) -> bool {
+        self.side(Role::Seeder).is_some() || self.side(Role::Leecher).is_some()
+    }
+
+    /// mark (
+    ///     role: which side of the session
+    ///     present: whether it is registered on any instance
+    /// )
+    /// records where a side is and returns when it dropped out, if this marked it gone
+    fn mark(&self, role: Role, present: bool) -> Option<Instant> {
+        let changed = self.present.send_if_modified(|sides| {
+            let changed = sides[role.index()] != present;
+            sides[role.index()] = present;
+            changed
+        });
+
+        let mut detached_since = self.detached_since.lock().unwrap();
+        if present && *self.present.borrow() == [true; 2] {
+            *detached_since = None;
+        } else if !present && changed {
+            *detached_since = Some(Instant::now());
+            return *detached_since;
+        }
+        None
+    }
+
+    /// attach (
+    ///     role: the side registering
+    ///     tx: Sender we use to relay to that side
+    /// )
+    /// registers a side with the Session. A side whose previous stream was dropped may register
+    /// again to resume the session, but a side that is still connected cannot be replaced.
+    /// Callers hold the sessions write lock, so two registrations cannot race.
+    #[allow(clippy::result_large_err)]
+    pub fn attach(&self, role: Role, tx: SideSender) -> Result<(), Status> {
+        println!("{:?} attaching to session", role);
+        if self.side(role).is_some_and(|existing| !existing.is_closed()) {
+            return Err(Status::already_exists(format!("{:?} already registered", role)));
+        }
+        self.slot(role).send_replace(Some(tx));
+        self.mark(role, true);
         Ok(())
     }
 
-    /// forward (
-    ///     pkt: TurnPacket we are relaying
+    /// detach (
+    ///     role: the side whose stream ended
+    ///     tx: the Sender that side was registered with
     /// )
-    /// this function checks a packet to see whether it is a Request or a Piece and sends it via the
-    /// correct channel. just a simple way to determine who to send the TurnPacket to in a Session
-    pub async fn forward(&self, pkt: TurnPacket) {
-        println!("Forwarding TurnPacket: {:?}", pkt);
-        match &pkt.body {
-            Some(Body::Request(_)) => {
-                if let Some(tx) = &self.seeder {
-                    let _ = tx.send(Ok(pkt.clone())).await;
-                }
+    /// removes a side unless it already re-registered with a new stream, returning when it
+    /// dropped out if it was removed
+    pub fn detach(&self, role: Role, tx: &SideSender) -> Option<Instant> {
+        let removed = self.slot(role).send_if_modified(|current| {
+            if current.as_ref().is_some_and(|current| current.same_channel(tx)) {
+                *current = None;
+                true
+            } else {
+                false
             }
-            Some(Body::Piece(_)) => {
-                if let Some(tx) = &self.leecher {
-                    let _ = tx.send(Ok(pkt.clone())).await;
+        });
+        if removed { self.mark(role, false) } else { None }
+    }
+
+    /// announce (
+    ///     event: what happened to the session
+    /// )
+    /// tells every instance holding the session, including this one
+    async fn announce(&self, event: SessionEvent) {
+        if let Err(e) = self.coordinator.publish(&events_topic(&self.session_id), event.encode()).await {
+            eprintln!("failed to announce relay session event: {}", e);
+        }
+    }
+
+    /// end (
+    ///     reason: what the sides still connected are told
+    /// )
+    /// tears the session down, ending the streams of the sides registered here
+    fn end(&self, reason: Status) {
+        self.ended.store(true, Ordering::Relaxed);
+        for role in [Role::Seeder, Role::Leecher] {
+            if let Some(tx) = self.slot(role).send_replace(None) {
+                let _ = tx.try_send(Err(reason.clone()));
+            }
+        }
+    }
+
+    /// info (
+    ///     session_id: the id the session is stored under
+    /// )
+    /// summarizes the session for the admin view
+    fn info(&self, session_id: &str) -> SessionInfo {
+        let age = self.created.elapsed();
+        let last_activity = Duration::from_millis(self.stats.last_activity_ms.load(Ordering::Relaxed));
+        let [seeder_connected, leecher_connected] = *self.present.borrow();
+        SessionInfo {
+            session_id: session_id.to_string(),
+            seeder_connected,
+            leecher_connected,
+            age_secs: age.as_secs(),
+            idle_secs: age.saturating_sub(last_activity).as_secs(),
+            packets_relayed: self.stats.packets.load(Ordering::Relaxed),
+            bytes_relayed: self.stats.bytes.load(Ordering::Relaxed),
+            packets_dropped: self.stats.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// follow_events (
+///     session: this instance's view of the session
+///     sessions: every session of this instance
+///     events: the session's events topic
+///     alive: closed once the session is dropped
+/// )
+/// keeps track of sides registering and leaving on other instances, and tears the session down
+/// when one does not come back or its quota is used up
+async fn follow_events(
+    session: Weak<Session>,
+    sessions: Sessions,
+    mut events: Subscription,
+    mut alive: watch::Receiver<()>,
+) {
+    loop {
+        let payload = tokio::select! {
+            _ = alive.changed() => return,
+            payload = events.recv() => payload,
+        };
+        let Some(payload) = payload else { return };
+        let Some(event) = SessionEvent::decode(&payload) else { continue };
+        let Some(session) = session.upgrade() else { return };
+
+        match event {
+            SessionEvent::Joined(role) => {
+                session.mark(role, true);
+            }
+            SessionEvent::Left(role) => {
+                if let Some(detached_at) = session.mark(role, false) {
+                    tokio::spawn(expire_after_grace(sessions.clone(), session.session_id.clone(), role, detached_at));
                 }
             }
-            None => {}
+            SessionEvent::Exhausted(message) => {
+                tear_down(&sessions, &session.session_id, Status::resource_exhausted(message)).await;
+            }
         }
     }
 }
 
+/// expire_after_grace (
+///     sessions: every session of this instance
+///     session_id: the session a side left
+///     role: the side that left
+///     detached_at: when it left
+/// )
+/// tears the session down if the side has not come back within RESUME_GRACE, telling the
+/// remaining side with an aborted Status
+async fn expire_after_grace(sessions: Sessions, session_id: String, role: Role, detached_at: Instant) {
+    sleep(RESUME_GRACE).await;
 
+    let expired = sessions.read().await.get(&session_id)
+        .is_some_and(|session| *session.detached_since.lock().unwrap() == Some(detached_at));
+    if expired {
+        println!("{:?} did not resume session {}, tearing it down", role, session_id);
+        tear_down(&sessions, &session_id, Status::aborted("Peer left the relay session")).await;
+    }
+}
+
+/// tear_down (
+///     sessions: every session of this instance
+///     session_id: the session to end
+///     reason: what the sides still connected are told
+/// )
+/// removes a session from this instance and ends it
+async fn tear_down(sessions: &Sessions, session_id: &str, reason: Status) {
+    if let Some(session) = sessions.write().await.remove(session_id) {
+        session.end(reason);
+    }
+}
 
-#[derive(Default)]
 pub struct TurnService {
-    sessions: Arc<RwLock<HashMap<String, Session>>>,
+    sessions: Sessions,
+    /// token operators present to list sessions, listing is disabled without one
+    admin_token: Option<String>,
+    limits: RateLimits,
+    /// rate limit and quota of every client relaying through us, keyed by its client id
+    clients: Arc<DashMap<String, Arc<Budget>>>,
+    /// checks the tokens the tracker issued for every session
+    tokens: Arc<RelayTokens>,
+    /// carries packets and events between the instances the two sides are registered on
+    coordinator: Arc<dyn Coordinator>,
 }
 
 impl TurnService {
 
+    /// new (
+    ///     admin_token: token required by list_sessions, None disables it
+    ///     limits: the rates and quotas relayed traffic is held to
+    ///     tokens: the signer shared with the tracker that hands out relay tokens
+    ///     coordinator: the backend shared with the other instances
+    /// )
+    /// creates the turn service
+    pub fn new(
+        admin_token: Option<String>,
+        limits: RateLimits,
+        tokens: Arc<RelayTokens>,
+        coordinator: Arc<dyn Coordinator>,
+    ) -> Self {
+        TurnService {
+            sessions: Sessions::default(),
+            admin_token,
+            limits,
+            clients: Arc::new(DashMap::new()),
+            tokens,
+            coordinator,
+        }
+    }
+
+    /// client_budget (
+    ///     client: the id of the client sending
+    /// )
+    /// returns the budget of a client, dropping those of clients that have been gone long enough
+    fn client_budget(&self, client: String) -> Arc<Budget> {
+        self.clients.retain(|_, budget| Arc::strong_count(budget) > 1 || budget.idle_for() < CLIENT_BUDGET_RESET);
+        self.clients
+            .entry(client.clone())
+            .or_insert_with(|| Arc::new(Budget::shared(
+                "client",
+                self.limits.client_rate,
+                self.limits.client_quota,
+                client,
+                self.coordinator.clone(),
+            )))
+            .clone()
+    }
+
+    /// session (
+    ///     session_id: the session to look up
+    /// )
+    /// returns this instance's view of a session, opening it if this instance has not seen it yet
+    async fn session(&self, session_id: &str) -> Result<Arc<Session>, Status> {
+        let mut all = self.sessions.write().await;
+        if let Some(session) = all.get(session_id) {
+            return Ok(session.clone());
+        }
+        let session = Session::open(session_id.to_string(), self.coordinator.clone(), &self.limits, self.sessions.clone()).await?;
+        all.insert(session_id.to_string(), session.clone());
+        Ok(session)
+    }
+
+    /// release (
+    ///     session: a session this instance no longer uses on behalf of a side
+    /// )
+    /// forgets a session once neither side is registered here and no send stream still uses it
+    async fn release(&self, session: Arc<Session>) {
+        let mut all = self.sessions.write().await;
+        let unused = all.get(&session.session_id)
+            .is_some_and(|current| Arc::ptr_eq(current, &session) && !session.has_local_sides() && Arc::strong_count(&session) == 2);
+        if unused {
+            all.remove(&session.session_id);
+        }
+    }
+
     /// register_for_session (
-    ///     session_id: session_id we are registering to
-    ///     role: role for session (Seeder or Leecher)
+    ///     claims: the session and role the client's relay token admits it to
     /// )
-    /// registers a client for the turn service as either a seeder or a leecher
+    /// registers a client for the turn service as either a seeder or a leecher, and waits up to
+    /// RENDEZVOUS_TIMEOUT for the other side, which may register on another instance. A side
+    /// resuming a session does not wait, and may do so with a token that is too old to open a new one.
     async fn register_for_session(
         &self,
-        session_id: String,
-        role: Role,
+        claims: RelayClaims,
     ) -> Result<ReceiverStream<Result<TurnPacket, Status>>, Status> {
+        let RelayClaims { session_id, role, expired } = claims;
+        let other_present = presence_key(&session_id, role.other());
 
-        // create the channels we will use to relay packets
-        let (tx, rx) = mpsc::channel::<Result<TurnPacket, Status>>(128);
-
-        // acquire a write lock for sessions
-        let mut all = self.sessions.write().await;
+        if expired && self.coordinator.get(&other_present).await?.is_none() {
+            return Err(Status::unauthenticated("relay token expired"));
+        }
 
-        let session = all.entry(session_id.clone())
-            .or_insert_with(|| {
-                // create a Barrier for the seeder and leecher
-                let barrier = Arc::new(Barrier::new(2));
-                Session::new(barrier)
-            });
+        // create the channels we will use to relay packets, our packets arrive on our side's topic
+        // whichever instance the other side sends from
+        let (tx, rx) = mpsc::channel::<Result<TurnPacket, Status>>(SIDE_DEPTH);
+        let packets = self.coordinator.subscribe(&side_topic(&session_id, role)).await?;
 
-        // fill the right slot
-        match role {
-            Role::Seeder => session.add_seeder(tx)?,
-            Role::Leecher => session.add_leecher(tx)?,
+        // fill the right slot, then let the instances holding the session know we are here
+        let session = self.session(&session_id).await?;
+        {
+            let _all = self.sessions.write().await;
+            session.attach(role, tx.clone())?;
+        }
+        self.coordinator.set(&presence_key(&session_id, role), Vec::new(), Some(PRESENCE_TTL)).await?;
+        session.announce(SessionEvent::Joined(role)).await;
+        if self.coordinator.get(&other_present).await?.is_some() {
+            session.mark(role.other(), true);
         }
 
         // wait for both seeder and leecher to get here, then move on
-        let barrier = session.barrier.clone();
-        drop(all);
-        barrier.wait().await;
+        let mut present = session.present.subscribe();
+        if timeout(RENDEZVOUS_TIMEOUT, present.wait_for(|sides| *sides == [true; 2])).await.is_err() {
+            session.detach(role, &tx);
+            let _ = self.coordinator.delete(&presence_key(&session_id, role)).await;
+            self.release(session).await;
+            return Err(Status::deadline_exceeded("Peer did not join the relay session in time"));
+        }
+
+        // the side is present for as long as it keeps the returned stream open
+        tokio::spawn(feed_side(packets, tx.clone(), self.coordinator.clone(), session_id.clone()));
+        tokio::spawn(watch_side(self.sessions.clone(), session, role, tx));
 
         Ok(ReceiverStream::new(rx))
     }
 }
 
+/// feed_side (
+///     packets: the topic the side's packets are published on
+///     tx: the side's stream
+///     coordinator: what the packets are acknowledged through
+///     session_id: the session the side belongs to
+/// )
+/// hands the packets published for a side to its stream until the stream is closed, and acknowledges
+/// them to the lane that sent them once the stream took them. A burst of packets is acknowledged
+/// once, after its last packet.
+async fn feed_side(mut packets: Subscription, tx: SideSender, coordinator: Arc<dyn Coordinator>, session_id: String) {
+    let mut unacked: HashMap<String, u64> = HashMap::new();
+    loop {
+        let payload = tokio::select! {
+            _ = tx.closed() => return,
+            payload = packets.recv() => payload,
+        };
+        let Some(payload) = payload else { return };
+        let Ok(Relayed { lane, seq, pkt: Some(pkt) }) = Relayed::decode(&payload[..]) else { continue };
+        if tx.send(Ok(pkt)).await.is_err() {
+            return;
+        }
+        if !lane.is_empty() {
+            unacked.insert(lane, seq);
+        }
+        if packets.is_empty() {
+            for (lane, seq) in unacked.drain() {
+                let _ = coordinator.publish(&ack_topic(&session_id, &lane), seq.to_string().into_bytes()).await;
+            }
+        }
+    }
+}
+
+/// watch_side (
+///     sessions: every session of this instance
+///     session: the session the side belongs to
+///     role: which side it is
+///     tx: the Sender it was registered with
+/// )
+/// keeps a side's presence alive until it drops its stream, then detaches it. Once neither side
+/// is registered here this instance forgets the session, otherwise it is torn down if the side
+/// does not resume within RESUME_GRACE, in which case the remaining side is told with an aborted Status.
+async fn watch_side(sessions: Sessions, session: Arc<Session>, role: Role, tx: SideSender) {
+    let key = presence_key(&session.session_id, role);
+    let mut refresh = interval(PRESENCE_REFRESH);
+    refresh.tick().await;
+    loop {
+        tokio::select! {
+            _ = tx.closed() => break,
+            _ = refresh.tick() => {
+                if session.ended.load(Ordering::Relaxed) {
+                    break;
+                }
+                if let Err(e) = session.coordinator.set(&key, Vec::new(), Some(PRESENCE_TTL)).await {
+                    eprintln!("failed to refresh relay presence: {}", e);
+                }
+            }
+        }
+    }
+    tx.closed().await;
+
+    let Some(detached_at) = session.detach(role, &tx) else { return };
+    let _ = session.coordinator.delete(&key).await;
+    session.announce(SessionEvent::Left(role)).await;
+
+    if session.has_local_sides() {
+        expire_after_grace(sessions, session.session_id.clone(), role, detached_at).await;
+    } else {
+        println!("session {} has no sides left on this instance", session.session_id);
+        let mut all = sessions.write().await;
+        if all.get(&session.session_id).is_some_and(|current| Arc::ptr_eq(current, &session)) {
+            all.remove(&session.session_id);
+        }
+    }
+}
+
 #[async_trait]
 impl Turn for TurnService {
     type registerStream = ReceiverStream<Result<TurnPacket, Status>>;
@@ -135,61 +808,127 @@ impl Turn for TurnService {
     /// register (
     ///     req: RegisterRequest we are processing in order to add to the turn service
     /// )
-    /// client passes a RegisterRequest and gets registered for turn
+    /// client passes a RegisterRequest with its relay token and gets registered for turn
     async fn register(
         &self,
         req: Request<RegisterRequest>,
     ) -> Result<Response<ReceiverStream<Result<TurnPacket, Status>>>, Status> {
+        authenticated(&req)?;
         let req = req.into_inner();
 
-        // gets role of requester and registers within the turn system
-        let role = if req.is_seeder { Role::Seeder } else { Role::Leecher };
-        let stream = self.register_for_session(req.session_id, role).await?;
+        // the token tells us the session and the role of the requester
+        let claims = self.tokens.verify(&req.relay_token)?;
+        let stream = self.register_for_session(claims).await?;
         Ok(Response::new(stream))
     }
 
     /// send (
     ///     req: TurnPacket stream we use to relay with
     /// )
-    /// initiates the turn relay across a session
+    /// initiates the turn relay across a session. Packets are held back to keep the session's and
+    /// the client's byte rate and until the other side has room for them. Once either quota is used
+    /// up both sides are told with resource_exhausted, and once the session is torn down the stream
+    /// fails with unavailable. The stream may land on a different instance than the side's register
+    /// stream, packets reach the other side either way.
     async fn send(
         &self,
         req: Request<tonic::Streaming<TurnPacket>>,
     ) -> Result<Response<()>, Status> {
-        // unpack metadata
-        let metadata = req.metadata();
-        let session_id = extract_header(metadata, "x-session-id")?;
-        let _role = if extract_header(metadata, "x-role")? == "seeder" {
-            Role::Seeder
-        } else {
-            Role::Leecher
+        let caller = authenticated(&req)?;
+
+        // unpack metadata, the relay token in x-session-id tells us the session and our role in it
+        let claims = self.tokens.verify(&extract_header(req.metadata(), "x-session-id")?)?;
+        let role = claims.role;
+        // the budget follows the registered client, an address or header could be changed to get a fresh one
+        let client = self.client_budget(caller.uid);
+
+        // the session is looked up once, relaying does not touch the map of all sessions again
+        let session = self.session(&claims.session_id).await?;
+        let res = relay(&session, role, &client, req.into_inner()).await;
+        self.release(session).await;
+
+        res.map(Response::new)
+    }
+
+    /// list_sessions (
+    ///     req: must carry the admin token in x-admin-token
+    /// )
+    /// lists every session this instance holds with its traffic counters
+    async fn list_sessions(
+        &self,
+        req: Request<()>,
+    ) -> Result<Response<SessionList>, Status> {
+        let Some(admin_token) = &self.admin_token else {
+            return Err(Status::permission_denied("Session listing is disabled"));
         };
+        if !secret_matches(&extract_header(req.metadata(), "x-admin-token")?, admin_token) {
+            return Err(Status::permission_denied("Invalid admin token"));
+        }
 
-        // the main loop we use to relay data via TURN
-        let mut inbound = req.into_inner();
-        loop {
-            match inbound.next().await {
-                Some(Ok(pkt)) => {
-                    // forward the packet to the correct seeder/leecher
-                    if let Some(session) = self.sessions.read().await.get(&session_id) {
-                        session.forward(pkt).await;
-                    }
-                }
-                Some(Err(e)) => {
-                    return Err(Status::internal(format!(
-                        "error reading inbound TURN packet: {:?}",
-                        e
-                    )));
+        let sessions = self.sessions.read().await
+            .iter()
+            .map(|(session_id, session)| session.info(session_id))
+            .collect();
+
+        Ok(Response::new(SessionList { sessions }))
+    }
+
+}
+
+/// relay (
+///     session: the session the packets belong to
+///     role: the side sending them
+///     client: the budget of the client sending them
+///     inbound: the packets
+/// )
+/// the main loop we use to relay data via TURN
+#[allow(clippy::result_large_err)]
+async fn relay(
+    session: &Session,
+    role: Role,
+    client: &Budget,
+    mut inbound: tonic::Streaming<TurnPacket>,
+) -> Result<(), Status> {
+    let lane = session.lane(role.other());
+    loop {
+        match inbound.next().await {
+            Some(Ok(pkt)) => {
+                if session.ended.load(Ordering::Relaxed) {
+                    return Err(Status::unavailable("Relay session ended"));
                 }
-                None => {
-                    break;
+
+                // charge the packet to both budgets and hold it as long as the stricter one asks
+                let len = pkt.encoded_len() as u64;
+                let delay = match (session.budget.charge(len).await, client.charge(len).await) {
+                    (Ok(session_delay), Ok(client_delay)) => session_delay.max(client_delay),
+                    (Err(status), _) | (_, Err(status)) => {
+                        // every instance holding the session ends it, which tells both sides
+                        session.announce(SessionEvent::Exhausted(status.message().to_string())).await;
+                        return Err(status);
+                    }
+                };
+                if !delay.is_zero() {
+                    sleep(delay).await;
                 }
+
+                session.stats.packets.fetch_add(1, Ordering::Relaxed);
+                session.stats.bytes.fetch_add(len, Ordering::Relaxed);
+                session.stats.last_activity_ms.store(session.created.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+                // queue the packet for the other side, waiting while it is behind
+                lane.push(pkt).await?;
+            }
+            Some(Err(e)) => {
+                return Err(Status::internal(format!(
+                    "error reading inbound TURN packet: {:?}",
+                    e
+                )));
+            }
+            None => {
+                return Ok(());
             }
         }
-
-        Ok(Response::new(()))
     }
-
 }
 
 /// extract_header (
@@ -202,4 +941,72 @@ fn extract_header(md: &MetadataMap, key: &str) -> Result<String, Status> {
         .and_then(|v| v.to_str().ok())
         .map(|s| s.to_owned())
         .ok_or_else(|| Status::invalid_argument(format!("missing metadata `{}`", key)))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinator::InMemoryCoordinator;
+
+    /// sealed (
+    ///     len: how many bytes the packet carries
+    /// )
+    /// helper function for a packet as a side would send it
+    fn sealed(len: usize) -> TurnPacket {
+        TurnPacket {
+            session_id: "s".to_string(),
+            body: Some(turn_packet::Body::Sealed(SealedFrame { chunks: vec![vec![0; len]] })),
+        }
+    }
+
+    /// settles (
+    ///     check: what has to become true
+    /// )
+    /// helper function that gives the lane's tasks a moment to get there
+    async fn settles(check: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if check() {
+                return true;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn credits_come_back_once_the_receiving_stream_takes_the_packet() {
+        let coordinator: Arc<dyn Coordinator> = Arc::new(InMemoryCoordinator::default());
+        let stats = Arc::new(SessionStats::default());
+        let packets = coordinator.subscribe(&side_topic("s", Role::Seeder)).await.unwrap();
+        let lane = Lane::open("s", Role::Seeder, coordinator.clone(), stats.clone()).await.unwrap();
+
+        lane.push(sealed(1000)).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        // published, but no stream took it yet
+        assert!(lane.credits.available_permits() < FORWARD_CREDITS);
+
+        let (tx, mut rx) = mpsc::channel(SIDE_DEPTH);
+        tokio::spawn(feed_side(packets, tx, coordinator.clone(), "s".to_string()));
+        assert!(settles(|| lane.credits.available_permits() == FORWARD_CREDITS).await);
+        assert_eq!(rx.recv().await.unwrap().unwrap(), sealed(1000));
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn a_sender_is_told_once_when_its_packets_reach_no_one() {
+        let coordinator: Arc<dyn Coordinator> = Arc::new(InMemoryCoordinator::default());
+        let stats = Arc::new(SessionStats::default());
+        let mut to_leecher = coordinator.subscribe(&side_topic("s", Role::Leecher)).await.unwrap();
+        let lane = Lane::open("s", Role::Seeder, coordinator.clone(), stats.clone()).await.unwrap();
+
+        lane.push(sealed(1000)).await.unwrap();
+        lane.push(sealed(1000)).await.unwrap();
+        assert!(settles(|| stats.dropped.load(Ordering::Relaxed) == 2).await);
+        assert_eq!(lane.credits.available_permits(), FORWARD_CREDITS);
+
+        let notice = Relayed::decode(&to_leecher.recv().await.unwrap()[..]).unwrap();
+        assert!(notice.lane.is_empty());
+        assert_eq!(notice.pkt.unwrap().body, Some(turn_packet::Body::Undelivered(true)));
+        assert!(to_leecher.try_recv().is_err());
+    }
+}
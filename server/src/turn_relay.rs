@@ -0,0 +1,568 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use crate::auth::secret_matches;
+use crate::stun::{attr, decode_channel_data, encode_channel_data, is_channel_data, long_term_key,
+                  method, Class, StunMessage};
+
+/// how long credentials handed out by get_relay_credentials stay valid
+const CREDENTIAL_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// how long a nonce stays valid before the client is asked to retry with a fresh one
+const NONCE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// allocation lifetimes (RFC 8656 section 3.2)
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(10 * 60);
+const MAX_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// permissions and channel bindings expire unless they are refreshed (RFC 8656 sections 9 and 12)
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(5 * 60);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// channel numbers a client may bind
+const CHANNEL_RANGE: std::ops::RangeInclusive<u16> = 0x4000..=0x4FFF;
+
+/// the only REQUESTED-TRANSPORT we relay, UDP
+const TRANSPORT_UDP: u8 = 17;
+
+/// RelayConfig holds the settings of the UDP TURN relay and the secret its credentials are derived from.
+/// The relay is only started when TURN_PORT is set.
+#[derive(Debug)]
+pub struct RelayConfig {
+    /// port the relay listens on (TURN_PORT)
+    pub port: u16,
+    /// public address relayed transport addresses are allocated on (TURN_EXTERNAL_IP)
+    pub external_ip: IpAddr,
+    /// realm of the long-term credentials (TURN_REALM)
+    pub realm: String,
+    /// key credentials and nonces are signed with, regenerated every run
+    secret: [u8; 32],
+}
+
+impl RelayConfig {
+
+    ///from_env()
+    ///
+    /// function:
+    /// Reads the relay settings, returning None if no relay should be run.
+    pub fn from_env() -> Result<Option<RelayConfig>, Box<dyn std::error::Error>> {
+        let port = match env::var("TURN_PORT") {
+            Ok(port) => port.parse()?,
+            Err(_) => return Ok(None),
+        };
+        let external_ip = env::var("TURN_EXTERNAL_IP")
+            .map_err(|_| "TURN_EXTERNAL_IP must be set when TURN_PORT is")?
+            .parse()?;
+        let realm = env::var("TURN_REALM").unwrap_or_else(|_| "helpful-serf".to_string());
+
+        Ok(Some(RelayConfig { port, external_ip, realm, secret: rand::random() }))
+    }
+
+    ///credentials_for()
+    /// parameters:
+    ///     - uid: the client the credentials are issued to
+    ///
+    /// function:
+    /// Issues a username and password pair that expires after CREDENTIAL_LIFETIME. The password
+    /// is derived from the username, so the relay does not need to keep any state per user.
+    pub fn credentials_for(&self, uid: &str) -> (String, String) {
+        let expiry = unix_now() + CREDENTIAL_LIFETIME.as_secs();
+        let username = format!("{}:{}", expiry, uid);
+        let password = hex::encode(self.sign(username.as_bytes()));
+        (username, password)
+    }
+
+    ///password_for()
+    /// parameters:
+    ///     - username: the USERNAME of a request
+    ///
+    /// function:
+    /// Returns the password of a username issued by credentials_for, or None if it expired.
+    fn password_for(&self, username: &str) -> Option<String> {
+        let (expiry, _) = username.split_once(':')?;
+        if expiry.parse::<u64>().ok()? < unix_now() {
+            return None;
+        }
+        Some(hex::encode(self.sign(username.as_bytes())))
+    }
+
+    ///nonce_for()
+    /// parameters:
+    ///     - client: the address the nonce is handed to
+    ///
+    /// function:
+    /// Creates a nonce bound to the client's address that expires after NONCE_LIFETIME.
+    fn nonce_for(&self, client: SocketAddr) -> String {
+        let expiry = unix_now() + NONCE_LIFETIME.as_secs();
+        self.sign_nonce(expiry, client)
+    }
+
+    ///nonce_is_valid()
+    fn nonce_is_valid(&self, nonce: &str, client: SocketAddr) -> bool {
+        let Some((expiry, _)) = nonce.split_once('-') else {
+            return false;
+        };
+        match u64::from_str_radix(expiry, 16) {
+            Ok(expiry) => expiry >= unix_now() && secret_matches(&self.sign_nonce(expiry, client), nonce),
+            Err(_) => false,
+        }
+    }
+
+    // Formats a nonce as its expiry followed by a signature over the expiry and client address
+    fn sign_nonce(&self, expiry: u64, client: SocketAddr) -> String {
+        let signature = self.sign(format!("{:x}|{}", expiry, client).as_bytes());
+        format!("{:x}-{}", expiry, hex::encode(&signature[..8]))
+    }
+
+    // HMAC-SHA1 with the relay secret
+    fn sign(&self, data: &[u8]) -> [u8; 20] {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).expect("hmac accepts any key length");
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+}
+
+/// An allocation is a relayed transport address held for one client 5-tuple (RFC 8656 section 2.2).
+#[derive(Debug)]
+struct Allocation {
+    /// address of the client that owns the allocation
+    client: SocketAddr,
+    /// socket bound for the relayed transport address
+    relay: UdpSocket,
+    /// the client id the allocation's credentials were issued to, later requests must carry
+    /// credentials of the same client, which may be renewed ones
+    uid: String,
+    /// the Allocate request that created the allocation and our answer, a retransmission of the
+    /// request is answered the same way (RFC 8656 section 7.2)
+    transaction_id: [u8; 12],
+    response: StunMessage,
+    state: Mutex<AllocationState>,
+    /// woken when the allocation is removed so its relay task stops
+    closed: Notify,
+}
+
+#[derive(Debug)]
+struct AllocationState {
+    expires: Instant,
+    /// peer IP addresses the client may exchange data with
+    permissions: HashMap<IpAddr, Instant>,
+    /// channel number -> (peer, expiry)
+    channels: HashMap<u16, (SocketAddr, Instant)>,
+}
+
+impl AllocationState {
+    fn is_permitted(&self, ip: IpAddr, now: Instant) -> bool {
+        self.permissions.get(&ip).is_some_and(|expiry| *expiry > now)
+    }
+
+    fn channel_for(&self, peer: SocketAddr, now: Instant) -> Option<u16> {
+        self.channels.iter()
+            .find(|(_, (addr, expiry))| *addr == peer && *expiry > now)
+            .map(|(channel, _)| *channel)
+    }
+}
+
+/// TurnRelay is a UDP TURN server (RFC 8656) running next to the gRPC services. Unlike the gRPC
+/// relay it forwards raw datagrams, so peers can run their normal QUIC protocol over it.
+/// Clients authenticate with long-term credentials issued by get_relay_credentials.
+#[derive(Debug)]
+pub struct TurnRelay {
+    socket: Arc<UdpSocket>,
+    config: Arc<RelayConfig>,
+    /// allocations keyed by the client address they belong to
+    allocations: DashMap<SocketAddr, Arc<Allocation>>,
+}
+
+impl TurnRelay {
+
+    ///bind()
+    /// parameters:
+    ///     - config: relay settings
+    ///
+    /// function:
+    /// Binds the relay's listening socket.
+    pub async fn bind(config: Arc<RelayConfig>) -> std::io::Result<Arc<TurnRelay>> {
+        let socket = UdpSocket::bind(("0.0.0.0", config.port)).await?;
+        println!("TURN relay listening on {}", socket.local_addr()?);
+        Ok(Arc::new(TurnRelay { socket: Arc::new(socket), config, allocations: DashMap::new() }))
+    }
+
+    ///run()
+    ///
+    /// function:
+    /// Serves clients until the socket fails. Expired allocations are swept on a separate task.
+    pub async fn run(self: Arc<Self>) -> std::io::Result<()> {
+        tokio::spawn(self.clone().sweep());
+
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf).await?;
+            let raw = &buf[..len];
+
+            if is_channel_data(raw) {
+                self.relay_channel_data(raw, from).await;
+            } else if let Some(msg) = StunMessage::decode(raw) {
+                match msg.class {
+                    Class::Request => self.handle_request(raw, msg, from).await,
+                    Class::Indication if msg.method == method::SEND => self.relay_send_indication(msg, from).await,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    ///handle_request()
+    /// parameters:
+    ///     - raw: the datagram the request was decoded from, needed to check MESSAGE-INTEGRITY
+    ///     - msg: the request
+    ///     - from: the client that sent it
+    ///
+    /// function:
+    /// Authenticates a request and dispatches it. Binding requests are answered without credentials
+    /// so the relay doubles as a STUN server.
+    async fn handle_request(&self, raw: &[u8], msg: StunMessage, from: SocketAddr) {
+        if msg.method == method::BINDING {
+            let mut reply = msg.reply(Class::Success);
+            reply.add_xor_addr(attr::XOR_MAPPED_ADDRESS, from);
+            self.send_to(reply.encode(None), from).await;
+            return;
+        }
+
+        let key = match self.authenticate(raw, &msg, from) {
+            Ok(key) => key,
+            Err(reply) => {
+                self.send_to(reply.encode(None), from).await;
+                return;
+            }
+        };
+
+        let allocation = self.allocations.get(&from).map(|a| a.clone());
+        let reply = match (msg.method, allocation) {
+            (method::ALLOCATE, None) => self.allocate(&msg, from).await,
+            (_, Some(allocation)) if Some(allocation.uid.as_str()) != msg.get_str(attr::USERNAME).and_then(uid_of) => {
+                error(&msg, 441, "Wrong Credentials")
+            }
+            (method::ALLOCATE, Some(allocation)) if allocation.transaction_id == msg.transaction_id => {
+                allocation.response.clone()
+            }
+            (method::ALLOCATE, Some(_)) => error(&msg, 437, "Allocation Mismatch"),
+            (_, None) => error(&msg, 437, "Allocation Mismatch"),
+            (method::REFRESH, Some(allocation)) => self.refresh(&msg, &allocation),
+            (method::CREATE_PERMISSION, Some(allocation)) => create_permission(&msg, &allocation),
+            (method::CHANNEL_BIND, Some(allocation)) => channel_bind(&msg, &allocation),
+            _ => error(&msg, 400, "Bad Request"),
+        };
+
+        self.send_to(reply.encode(Some(&key)), from).await;
+    }
+
+    ///authenticate()
+    ///
+    /// function:
+    /// Applies the long-term credential mechanism (RFC 8489 section 9.2). Returns the key
+    /// responses are signed with, or the challenge to send back.
+    fn authenticate(&self, raw: &[u8], msg: &StunMessage, from: SocketAddr) -> Result<Vec<u8>, StunMessage> {
+        let challenge = |code, reason| {
+            let mut reply = error(msg, code, reason);
+            reply.add(attr::REALM, self.config.realm.clone().into_bytes());
+            reply.add(attr::NONCE, self.config.nonce_for(from).into_bytes());
+            reply
+        };
+
+        let (Some(username), Some(nonce)) = (msg.get_str(attr::USERNAME), msg.get_str(attr::NONCE)) else {
+            return Err(challenge(401, "Unauthorized"));
+        };
+        if !self.config.nonce_is_valid(nonce, from) {
+            return Err(challenge(438, "Stale Nonce"));
+        }
+        let Some(password) = self.config.password_for(username) else {
+            return Err(challenge(401, "Unauthorized"));
+        };
+
+        let key = long_term_key(username, &self.config.realm, &password);
+        if !msg.verify_integrity(raw, &key) {
+            return Err(challenge(401, "Unauthorized"));
+        }
+        Ok(key)
+    }
+
+    ///allocate()
+    ///
+    /// function:
+    /// Binds a relayed transport address for the client and starts relaying datagrams peers send to it.
+    async fn allocate(&self, msg: &StunMessage, from: SocketAddr) -> StunMessage {
+        match msg.get(attr::REQUESTED_TRANSPORT).and_then(|t| t.first()) {
+            Some(&TRANSPORT_UDP) => {}
+            Some(_) => return error(msg, 442, "Unsupported Transport Protocol"),
+            None => return error(msg, 400, "Bad Request"),
+        }
+
+        let relay = match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(relay) => relay,
+            Err(e) => {
+                eprintln!("failed to bind relayed address: {}", e);
+                return error(msg, 508, "Insufficient Capacity");
+            }
+        };
+        let relayed_addr = match relay.local_addr() {
+            Ok(local) => SocketAddr::new(self.config.external_ip, local.port()),
+            Err(_) => return error(msg, 508, "Insufficient Capacity"),
+        };
+
+        let lifetime = requested_lifetime(msg);
+        let mut reply = msg.reply(Class::Success);
+        reply.add_xor_addr(attr::XOR_RELAYED_ADDRESS, relayed_addr);
+        reply.add(attr::LIFETIME, (lifetime.as_secs() as u32).to_be_bytes().to_vec());
+        reply.add_xor_addr(attr::XOR_MAPPED_ADDRESS, from);
+
+        let allocation = Arc::new(Allocation {
+            client: from,
+            relay,
+            uid: msg.get_str(attr::USERNAME).and_then(uid_of).unwrap_or_default().to_string(),
+            transaction_id: msg.transaction_id,
+            response: reply.clone(),
+            state: Mutex::new(AllocationState {
+                expires: Instant::now() + lifetime,
+                permissions: HashMap::new(),
+                channels: HashMap::new(),
+            }),
+            closed: Notify::new(),
+        });
+        self.allocations.insert(from, allocation.clone());
+        tokio::spawn(relay_from_peers(allocation, self.socket.clone()));
+        println!("TURN allocated {} for {}", relayed_addr, from);
+
+        reply
+    }
+
+    ///refresh()
+    ///
+    /// function:
+    /// Extends an allocation's lifetime, or deletes it when the client asks for a lifetime of 0.
+    fn refresh(&self, msg: &StunMessage, allocation: &Allocation) -> StunMessage {
+        let lifetime = requested_lifetime(msg);
+        if lifetime.is_zero() {
+            self.remove(allocation.client);
+        } else {
+            allocation.state.lock().unwrap().expires = Instant::now() + lifetime;
+        }
+
+        let mut reply = msg.reply(Class::Success);
+        reply.add(attr::LIFETIME, (lifetime.as_secs() as u32).to_be_bytes().to_vec());
+        reply
+    }
+
+    ///relay_send_indication()
+    ///
+    /// function:
+    /// Forwards the data of a Send indication to a permitted peer.
+    async fn relay_send_indication(&self, msg: StunMessage, from: SocketAddr) {
+        let Some(allocation) = self.allocations.get(&from).map(|a| a.clone()) else {
+            return;
+        };
+        let (Some(peer), Some(data)) = (msg.get_xor_addr(attr::XOR_PEER_ADDRESS), msg.get(attr::DATA)) else {
+            return;
+        };
+
+        let permitted = allocation.state.lock().unwrap().is_permitted(peer.ip(), Instant::now());
+        if permitted {
+            let _ = allocation.relay.send_to(data, peer).await;
+        }
+    }
+
+    ///relay_channel_data()
+    ///
+    /// function:
+    /// Forwards a ChannelData message to the peer bound to its channel.
+    async fn relay_channel_data(&self, raw: &[u8], from: SocketAddr) {
+        let Some(allocation) = self.allocations.get(&from).map(|a| a.clone()) else {
+            return;
+        };
+        let Some((channel, data)) = decode_channel_data(raw) else {
+            return;
+        };
+
+        let peer = {
+            let state = allocation.state.lock().unwrap();
+            let now = Instant::now();
+            state.channels.get(&channel)
+                .filter(|(_, expiry)| *expiry > now)
+                .map(|(peer, _)| *peer)
+                .filter(|peer| state.is_permitted(peer.ip(), now))
+        };
+        if let Some(peer) = peer {
+            let _ = allocation.relay.send_to(data, peer).await;
+        }
+    }
+
+    ///sweep()
+    ///
+    /// function:
+    /// Periodically removes allocations whose lifetime ran out, along with expired permissions and channels.
+    async fn sweep(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            let now = Instant::now();
+
+            let mut expired = Vec::new();
+            for entry in self.allocations.iter() {
+                let mut state = entry.state.lock().unwrap();
+                if state.expires <= now {
+                    expired.push(*entry.key());
+                    continue;
+                }
+                state.permissions.retain(|_, expiry| *expiry > now);
+                state.channels.retain(|_, (_, expiry)| *expiry > now);
+            }
+
+            for client in expired {
+                println!("TURN allocation for {} expired", client);
+                self.remove(client);
+            }
+        }
+    }
+
+    // Deletes an allocation and stops its relay task
+    fn remove(&self, client: SocketAddr) {
+        if let Some((_, allocation)) = self.allocations.remove(&client) {
+            allocation.closed.notify_one();
+        }
+    }
+
+    // Sends a datagram to a client, a lost response is retransmitted by the client
+    async fn send_to(&self, buf: Vec<u8>, to: SocketAddr) {
+        if let Err(e) = self.socket.send_to(&buf, to).await {
+            eprintln!("failed to send to TURN client {}: {}", to, e);
+        }
+    }
+}
+
+/// relay_from_peers (
+///     allocation: the allocation whose relayed address is read
+///     socket: the relay's listening socket, used to reach the client
+/// )
+/// forwards datagrams from permitted peers to the client, over a channel if one is bound
+/// for the peer and as a Data indication otherwise. Runs until the allocation is removed.
+async fn relay_from_peers(allocation: Arc<Allocation>, socket: Arc<UdpSocket>) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (len, peer) = tokio::select! {
+            _ = allocation.closed.notified() => return,
+            res = allocation.relay.recv_from(&mut buf) => match res {
+                Ok(res) => res,
+                Err(_) => continue,
+            },
+        };
+
+        let channel = {
+            let state = allocation.state.lock().unwrap();
+            let now = Instant::now();
+            if !state.is_permitted(peer.ip(), now) {
+                continue;
+            }
+            state.channel_for(peer, now)
+        };
+
+        let out = match channel {
+            Some(channel) => encode_channel_data(channel, &buf[..len]),
+            None => {
+                let mut indication = StunMessage::new(method::DATA, Class::Indication, rand::random());
+                indication.add_xor_addr(attr::XOR_PEER_ADDRESS, peer);
+                indication.add(attr::DATA, buf[..len].to_vec());
+                indication.encode(None)
+            }
+        };
+        let _ = socket.send_to(&out, allocation.client).await;
+    }
+}
+
+/// create_permission (
+///     msg: the CreatePermission request
+///     allocation: the allocation the permissions are installed on
+/// )
+/// installs or refreshes a permission for every XOR-PEER-ADDRESS in the request
+fn create_permission(msg: &StunMessage, allocation: &Allocation) -> StunMessage {
+    let peers = msg.get_xor_addrs(attr::XOR_PEER_ADDRESS);
+    if peers.is_empty() {
+        return error(msg, 400, "Bad Request");
+    }
+
+    let expiry = Instant::now() + PERMISSION_LIFETIME;
+    let mut state = allocation.state.lock().unwrap();
+    for peer in peers {
+        state.permissions.insert(peer.ip(), expiry);
+    }
+    msg.reply(Class::Success)
+}
+
+/// channel_bind (
+///     msg: the ChannelBind request
+///     allocation: the allocation the channel is bound on
+/// )
+/// binds or refreshes a channel to a peer, which also installs a permission for the peer
+fn channel_bind(msg: &StunMessage, allocation: &Allocation) -> StunMessage {
+    let channel = msg.get(attr::CHANNEL_NUMBER)
+        .and_then(|v| Some(u16::from_be_bytes([*v.first()?, *v.get(1)?])));
+    let (Some(channel), Some(peer)) = (channel, msg.get_xor_addr(attr::XOR_PEER_ADDRESS)) else {
+        return error(msg, 400, "Bad Request");
+    };
+    if !CHANNEL_RANGE.contains(&channel) {
+        return error(msg, 400, "Bad Request");
+    }
+
+    let now = Instant::now();
+    let mut state = allocation.state.lock().unwrap();
+
+    //a channel is bound to exactly one peer and a peer to exactly one channel
+    let channel_taken = state.channels.get(&channel).is_some_and(|(addr, _)| *addr != peer);
+    let peer_taken = state.channel_for(peer, now).is_some_and(|bound| bound != channel);
+    if channel_taken || peer_taken {
+        return error(msg, 400, "Bad Request");
+    }
+
+    state.channels.insert(channel, (peer, now + CHANNEL_LIFETIME));
+    state.permissions.insert(peer.ip(), now + PERMISSION_LIFETIME);
+    msg.reply(Class::Success)
+}
+
+/// requested_lifetime (
+///     msg: an Allocate or Refresh request
+/// )
+/// helper function returning the LIFETIME a client asked for, capped at MAX_LIFETIME
+fn requested_lifetime(msg: &StunMessage) -> Duration {
+    msg.get_u32(attr::LIFETIME)
+        .map(|secs| Duration::from_secs(secs as u64).min(MAX_LIFETIME))
+        .unwrap_or(DEFAULT_LIFETIME)
+}
+
+/// uid_of (
+///     username: a username issued by credentials_for
+/// )
+/// helper function returning the client id a username was issued to
+fn uid_of(username: &str) -> Option<&str> {
+    username.split_once(':').map(|(_, uid)| uid)
+}
+
+/// error (
+///     msg: the request being answered
+///     code: STUN error code
+///     reason: reason phrase
+/// )
+/// helper function to build an error response
+fn error(msg: &StunMessage, code: u16, reason: &str) -> StunMessage {
+    let mut reply = msg.reply(Class::Error);
+    reply.add_error(code, reason);
+    reply
+}
+
+// Seconds since the unix epoch, credentials and nonces carry their expiry in this form
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}